@@ -0,0 +1,38 @@
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Mutex,
+};
+
+use crate::{platform, Signal};
+
+static FORWARD: AtomicBool = AtomicBool::new(false);
+static CHILDREN: Mutex<Vec<u32>> = Mutex::new(Vec::new());
+
+/// Adds a child process that termination signals are forwarded to, see
+/// [`InitOptions::forward_to_children`](crate::InitOptions::forward_to_children). `pid` is as
+/// returned by [`Child::id`](std::process::Child::id).
+pub fn register_child(pid: u32) {
+    let mut children = CHILDREN.lock().unwrap();
+    if !children.contains(&pid) {
+        children.push(pid);
+    }
+}
+
+/// Stops forwarding termination signals to a child, which should be done once it has been waited
+/// on so the pid can't be reused by an unrelated process
+pub fn unregister_child(pid: u32) {
+    CHILDREN.lock().unwrap().retain(|p| *p != pid);
+}
+
+pub(crate) fn set_forwarding(forward: bool) {
+    FORWARD.store(forward, Ordering::SeqCst);
+}
+
+/// Called from the signal thread once [`TERMINATE`](crate::TERMINATE) is set. Signalling the
+/// children directly rather than our process group keeps the signal from coming back to us and
+/// counting towards a forced exit.
+pub(crate) fn forward(sig: Signal) {
+    if FORWARD.load(Ordering::SeqCst) {
+        platform::forward_to_children(sig, &CHILDREN.lock().unwrap());
+    }
+}
@@ -0,0 +1,135 @@
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Condvar, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::TERMINATE;
+
+struct Deadlines {
+    pending: Vec<(u64, Instant)>,
+    timer_running: bool,
+}
+
+static DEADLINES: Mutex<Deadlines> = Mutex::new(Deadlines {
+    pending: Vec::new(),
+    timer_running: false,
+});
+static DEADLINES_CHANGED: Condvar = Condvar::new();
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+/// Sets [`TERMINATE`] once `timeout` has elapsed, unless the returned guard is dropped first. With
+/// several deadlines the earliest one terminates.
+///
+/// ```
+/// use std::time::Duration;
+///
+/// let Ok(mut handle) = gracile::init_handle() else {
+///     panic!("Couldn't install the signal handlers");
+/// };
+/// let _deadline = gracile::terminate_after(Duration::from_millis(50));
+/// // Returns once the deadline passes, without any signal
+/// handle.wait();
+/// assert!(gracile::TERMINATE.get());
+/// ```
+pub fn terminate_after(timeout: Duration) -> DeadlineGuard {
+    let guard = DeadlineGuard {
+        id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+        deadline: Instant::now() + timeout,
+    };
+
+    let mut deadlines = DEADLINES.lock().unwrap();
+    deadlines.pending.push((guard.id, guard.deadline));
+    if deadlines.timer_running {
+        DEADLINES_CHANGED.notify_one();
+    } else {
+        deadlines.timer_running = true;
+        thread::spawn(run_timer);
+    }
+
+    guard
+}
+
+/// Exits once there are no deadlines left, to be started again by the next one
+fn run_timer() {
+    let mut deadlines = DEADLINES.lock().unwrap();
+    loop {
+        let Some(earliest) = deadlines.pending.iter().map(|(_, d)| *d).min() else {
+            deadlines.timer_running = false;
+            return;
+        };
+
+        let now = Instant::now();
+        if now >= earliest {
+            deadlines.pending.retain(|(_, d)| *d > now);
+            drop(deadlines);
+            TERMINATE.set();
+            deadlines = DEADLINES.lock().unwrap();
+            continue;
+        }
+
+        deadlines = DEADLINES_CHANGED
+            .wait_timeout(deadlines, earliest - now)
+            .unwrap()
+            .0;
+    }
+}
+
+/// Cancels its deadline when dropped, see [`terminate_after`]
+#[must_use = "dropping the guard cancels the deadline"]
+pub struct DeadlineGuard {
+    id: u64,
+    deadline: Instant,
+}
+
+impl DeadlineGuard {
+    pub fn deadline(&self) -> Instant {
+        self.deadline
+    }
+
+    /// Zero once the deadline has passed
+    pub fn remaining(&self) -> Duration {
+        self.deadline.saturating_duration_since(Instant::now())
+    }
+}
+
+impl Drop for DeadlineGuard {
+    fn drop(&mut self) {
+        let mut deadlines = DEADLINES.lock().unwrap();
+        deadlines.pending.retain(|(id, _)| *id != self.id);
+        DEADLINES_CHANGED.notify_one();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        shutdown,
+        tests::{isolate, live_handle},
+    };
+
+    #[test]
+    fn deadline_wakes_live_wait() {
+        let _lock = isolate();
+        let mut handle = live_handle();
+
+        let deadline = terminate_after(Duration::from_millis(50));
+        assert!(handle.wait_timeout(Duration::from_secs(10)));
+        assert_eq!(deadline.remaining(), Duration::ZERO);
+
+        shutdown(true).unwrap();
+    }
+
+    #[test]
+    fn dropped_deadline_doesnt_terminate() {
+        let _lock = isolate();
+
+        drop(terminate_after(Duration::from_millis(20)));
+        thread::sleep(Duration::from_millis(100));
+        assert!(!TERMINATE.get());
+    }
+}
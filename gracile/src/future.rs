@@ -0,0 +1,172 @@
+use std::{
+    future::Future,
+    marker::PhantomData,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    task::{Context, Poll, Waker},
+};
+
+use crate::{TermHandle, TERMINATE};
+
+static WAKERS: Mutex<Vec<(u64, Waker)>> = Mutex::new(Vec::new());
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+/// Called by [`Terminate::set`](crate::Terminate::set) after the flag is stored
+pub(crate) fn wake_all() {
+    let wakers = std::mem::take(&mut *WAKERS.lock().unwrap());
+    for (_, waker) in wakers {
+        waker.wake();
+    }
+}
+
+/// Resolves once [`TERMINATE`] is set, see [`TermHandle::terminated`] and
+/// [`TerminateToken::cancelled`]
+#[must_use = "futures do nothing unless polled"]
+pub struct Terminated<'a> {
+    id: u64,
+    _handle: PhantomData<&'a ()>,
+}
+
+impl<'a> Terminated<'a> {
+    fn new() -> Self {
+        Self {
+            id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+            _handle: PhantomData,
+        }
+    }
+}
+
+impl<'a> Future for Terminated<'a> {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        if TERMINATE.get() {
+            return Poll::Ready(());
+        }
+
+        {
+            let mut wakers = WAKERS.lock().unwrap();
+            match wakers.iter_mut().find(|(id, _)| *id == self.id) {
+                Some((_, waker)) => waker.clone_from(cx.waker()),
+                None => wakers.push((self.id, cx.waker().clone())),
+            }
+        }
+
+        // Terminate may have been set between the first check and registering
+        if TERMINATE.get() {
+            Poll::Ready(())
+        } else {
+            Poll::Pending
+        }
+    }
+}
+
+impl<'a> Drop for Terminated<'a> {
+    fn drop(&mut self) {
+        if let Ok(mut wakers) = WAKERS.lock() {
+            wakers.retain(|(id, _)| *id != self.id);
+        }
+    }
+}
+
+/// A cheap, cloneable view of the termination state, similar to a cancellation token
+#[derive(Debug, Clone, Default)]
+pub struct TerminateToken {
+    _private: (),
+}
+
+impl TerminateToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn is_terminated(&self) -> bool {
+        TERMINATE.get()
+    }
+
+    /// Resolves immediately if termination already happened, otherwise once it does
+    pub fn cancelled(&self) -> Terminated<'_> {
+        Terminated::new()
+    }
+}
+
+impl TermHandle {
+    /// Resolves immediately if [`TERMINATE`] is already set, otherwise once it is. Wakeups come
+    /// from whichever thread sets it, usually the signal thread, so any executor works.
+    pub fn terminated(&self) -> Terminated<'_> {
+        Terminated::new()
+    }
+
+    pub fn token(&self) -> TerminateToken {
+        TerminateToken::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        pin::pin,
+        sync::{atomic::AtomicBool, Arc},
+        task::Wake,
+    };
+
+    use super::*;
+    use crate::tests::isolate;
+
+    /// Records whether it was woken
+    #[derive(Default)]
+    struct Flag(AtomicBool);
+
+    impl Wake for Flag {
+        fn wake(self: Arc<Self>) {
+            self.0.store(true, Ordering::SeqCst);
+        }
+    }
+
+    /// Polls `fut` once while pending, sets [`TERMINATE`], then checks it was woken and is ready
+    fn wakes_on_set(fut: Terminated<'_>) {
+        let flag = Arc::new(Flag::default());
+        let waker = Waker::from(flag.clone());
+        let mut cx = Context::from_waker(&waker);
+        let mut fut = pin!(fut);
+
+        assert!(fut.as_mut().poll(&mut cx).is_pending());
+        assert!(!flag.0.load(Ordering::SeqCst));
+
+        TERMINATE.set();
+        assert!(flag.0.load(Ordering::SeqCst));
+        assert!(fut.poll(&mut cx).is_ready());
+    }
+
+    #[test]
+    fn terminated_wakes_on_set() {
+        let _lock = isolate();
+        let handle = TermHandle::default();
+        wakes_on_set(handle.terminated());
+    }
+
+    #[test]
+    fn cancelled_wakes_on_set() {
+        let _lock = isolate();
+        let token = TerminateToken::new();
+        wakes_on_set(token.cancelled());
+        assert!(token.is_terminated());
+    }
+
+    #[test]
+    fn ready_if_already_set() {
+        let _lock = isolate();
+        TERMINATE.set();
+        let flag = Arc::new(Flag::default());
+        let waker = Waker::from(flag.clone());
+        let mut cx = Context::from_waker(&waker);
+
+        let handle = TermHandle::default();
+        assert!(pin!(handle.terminated()).poll(&mut cx).is_ready());
+        assert!(pin!(handle.token().cancelled()).poll(&mut cx).is_ready());
+        assert!(!flag.0.load(Ordering::SeqCst));
+    }
+}
@@ -0,0 +1,112 @@
+use std::{mem, sync::Mutex};
+
+use crate::TERMINATE;
+
+type Callback = Box<dyn FnOnce() + Send>;
+
+static ON_TERMINATE: Mutex<Vec<Callback>> = Mutex::new(Vec::new());
+
+/// Adds a callback run on the thread that first sets [`TERMINATE`]. Callbacks added after
+/// termination only run if it is reset by [`shutdown`](crate::shutdown) and set again.
+pub fn on_terminate(f: impl FnOnce() + Send + 'static) {
+    ON_TERMINATE.lock().unwrap().push(Box::new(f));
+}
+
+/// Called by [`Terminate::set`](crate::Terminate::set) when it first sets the flag
+pub(crate) fn run_callbacks() {
+    let callbacks = mem::take(&mut *ON_TERMINATE.lock().unwrap());
+    for f in callbacks {
+        f();
+    }
+}
+
+/// Returns a guard that sets [`TERMINATE`] when dropped, unless disarmed
+///
+/// ```
+/// use std::{fs, io, thread};
+///
+/// let Ok(mut handle) = gracile::init_handle() else {
+///     panic!("Couldn't install the signal handlers");
+/// };
+/// let worker = thread::spawn(|| -> io::Result<()> {
+///     let guard = gracile::guard();
+///     // Fails, so the early return drops the guard armed
+///     fs::read("/this/file/does/not/exist")?;
+///     guard.disarm();
+///     Ok(())
+/// });
+/// // Woken by the guard rather than a signal
+/// handle.wait();
+/// assert!(gracile::TERMINATE.get());
+/// assert!(worker.join().unwrap().is_err());
+/// ```
+pub fn guard() -> TerminateGuard {
+    TerminateGuard { _private: () }
+}
+
+/// Terminates when it goes out of scope, including by an early return or a panic. See [`guard`].
+#[must_use = "dropping the guard terminates immediately"]
+pub struct TerminateGuard {
+    _private: (),
+}
+
+impl TerminateGuard {
+    /// Drops the guard without terminating
+    pub fn disarm(self) {
+        mem::forget(self);
+    }
+}
+
+impl Drop for TerminateGuard {
+    fn drop(&mut self) {
+        TERMINATE.set();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::mpsc, time::Duration};
+
+    use super::*;
+    use crate::{
+        shutdown,
+        tests::{isolate, live_handle},
+    };
+
+    #[test]
+    fn drop_wakes_live_wait() {
+        let _lock = isolate();
+        let mut handle = live_handle();
+
+        let guard = guard();
+        let dropper = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(50));
+            drop(guard);
+        });
+        assert!(handle.wait_timeout(Duration::from_secs(10)));
+        handle.wait();
+        dropper.join().unwrap();
+
+        shutdown(true).unwrap();
+    }
+
+    #[test]
+    fn disarm_doesnt_terminate() {
+        let _lock = isolate();
+
+        guard().disarm();
+        assert!(!TERMINATE.get());
+    }
+
+    #[test]
+    fn drop_runs_callbacks_once() {
+        let _lock = isolate();
+
+        let (tx, rx) = mpsc::channel();
+        on_terminate(move || tx.send(()).unwrap());
+        drop(guard());
+        drop(guard());
+        assert!(TERMINATE.get());
+        assert_eq!(rx.try_iter().count(), 1);
+    }
+}
@@ -1,129 +1,692 @@
 use std::{
-    fmt::{self, Display, Formatter},
+    error::Error,
+    fmt::{self, Debug, Display, Formatter},
     io,
+    os::raw::c_int,
     sync::{
-        atomic::{AtomicBool, Ordering},
-        Arc,
+        atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering},
+        Arc, Condvar, Mutex, PoisonError,
     },
-    thread,
+    thread::{self, JoinHandle},
+    time::Duration,
 };
 
-use flume::{Receiver, Sender};
-use signal_hook::{consts::TERM_SIGNALS, flag};
+use flume::{Receiver, SendTimeoutError, Sender, TrySendError};
+use signal_hook::consts::TERM_SIGNALS;
 
+pub use children::{register_child, unregister_child};
+pub use deadline::{terminate_after, DeadlineGuard};
+#[cfg(feature = "async")]
+pub use future::{TerminateToken, Terminated};
+pub use guard::{guard, on_terminate, TerminateGuard};
+pub use options::{ForceExit, InitOptions};
+pub use panic::{allow_panics, terminate_on_panic};
+pub use run::{run_until_terminate, run_until_terminate_with_cleanup, TerminatedError};
+#[cfg(all(windows, feature = "windows-service"))]
+pub use service::{init_service_handler, ScmSink, ServiceState, ServiceStatus, StatusSink};
+pub use signal::Signal;
+pub use subscription::{subscribe, TermSubscription};
+
+mod children;
+mod deadline;
+#[cfg(feature = "async")]
+mod future;
+mod guard;
+mod options;
+mod panic;
 mod platform;
+mod run;
+#[cfg(feature = "sd-notify")]
+mod sd_notify;
+#[cfg(all(windows, feature = "windows-service"))]
+mod service;
+mod signal;
+mod subscription;
 
 pub enum InitError {
     IO(io::Error),
     Duplicate,
+    InvalidSignal(i32),
 }
 
 impl Display for InitError {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
         match self {
-            InitError::IO(e) => e.fmt(f),
+            InitError::IO(e) => Display::fmt(e, f),
             InitError::Duplicate => write!(f, "Duplicate signal handlers added"),
+            InitError::InvalidSignal(sig) => {
+                write!(
+                    f,
+                    "Signal {} can't be registered as a termination signal",
+                    sig
+                )
+            }
         }
     }
 }
 
 pub struct Terminate {
     inner: AtomicBool,
+    lock: Mutex<()>,
+    cvar: Condvar,
 }
 
 impl Terminate {
     const fn new() -> Self {
         Self {
             inner: AtomicBool::new(false),
+            lock: Mutex::new(()),
+            cvar: Condvar::new(),
         }
     }
 
     pub fn set(&self) {
-        self.inner.store(true, Ordering::SeqCst);
+        let was_set = self.inner.swap(true, Ordering::SeqCst);
+        // Taking the lock means a waiter is either before its check or already waiting
+        drop(self.lock.lock());
+        self.cvar.notify_all();
+        subscription::notify_all();
+        platform::mark_terminated();
+
+        #[cfg(feature = "async")]
+        future::wake_all();
+
+        if !was_set {
+            guard::run_callbacks();
+        }
     }
 
     pub fn get(&self) -> bool {
         self.inner.load(Ordering::SeqCst)
     }
+
+    /// Blocks until set
+    pub fn wait(&self) {
+        let lock = self.lock.lock().unwrap_or_else(PoisonError::into_inner);
+        drop(self.cvar.wait_while(lock, |_| !self.get()));
+    }
+
+    /// Blocks until set or until `timeout` elapses, returning whether it was set
+    pub fn wait_timeout(&self, timeout: Duration) -> bool {
+        let lock = self.lock.lock().unwrap_or_else(PoisonError::into_inner);
+        drop(self.cvar.wait_timeout_while(lock, timeout, |_| !self.get()));
+        self.get()
+    }
+
+    fn reset(&self) {
+        self.inner.store(false, Ordering::SeqCst);
+        platform::clear_terminated();
+    }
 }
 
 pub static TERMINATE: Terminate = Terminate::new();
 
+static CAUSE: Mutex<Option<Signal>> = Mutex::new(None);
+
+/// An error sent through an [`ErrHandle`], keeping the underlying error around so the consumer can
+/// branch on it rather than only having a formatted message
+pub struct TermError {
+    pub context: String,
+    pub source: Option<Box<dyn Error + Send + Sync>>,
+}
+
+impl TermError {
+    pub fn new(
+        context: impl Into<String>,
+        source: impl Into<Box<dyn Error + Send + Sync>>,
+    ) -> Self {
+        Self {
+            context: context.into(),
+            source: Some(source.into()),
+        }
+    }
+
+    pub fn msg(context: impl Into<String>) -> Self {
+        Self {
+            context: context.into(),
+            source: None,
+        }
+    }
+
+    /// The kind of the source error, if it is an [`io::Error`]
+    pub fn io_kind(&self) -> Option<io::ErrorKind> {
+        self.source
+            .as_ref()?
+            .downcast_ref::<io::Error>()
+            .map(io::Error::kind)
+    }
+}
+
+impl Display for TermError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match &self.source {
+            Some(source) => write!(f, "{}: {}", self.context, source),
+            None => write!(f, "{}", self.context),
+        }
+    }
+}
+
+impl Debug for TermError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TermError")
+            .field("context", &self.context)
+            .field("source", &self.source)
+            .finish()
+    }
+}
+
+impl Error for TermError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        self.source
+            .as_ref()
+            .map(|e| e.as_ref() as &(dyn Error + 'static))
+    }
+}
+
+impl From<String> for TermError {
+    fn from(context: String) -> Self {
+        Self::msg(context)
+    }
+}
+
+impl From<&str> for TermError {
+    fn from(context: &str) -> Self {
+        Self::msg(context)
+    }
+}
+
+/// A message received on [`TermHandle::err_rx`]. Only [`ErrMsg::Fatal`] is meant to end the run.
+#[derive(Debug)]
+pub enum ErrMsg {
+    Warn(TermError),
+    Fatal(TermError),
+}
+
+impl Display for ErrMsg {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            ErrMsg::Warn(e) => write!(f, "Warning: {}", e),
+            ErrMsg::Fatal(e) => write!(f, "Error: {}", e),
+        }
+    }
+}
+
+/// Messages that can't be delivered, because the [`TermHandle`] is gone or the send timed out,
+/// are written to stderr instead and counted in [`TermHandle::dropped_errors`]
 #[derive(Clone)]
 pub struct ErrHandle {
-    tx: Sender<String>,
+    tx: Sender<ErrMsg>,
+    dropped: Arc<AtomicUsize>,
 }
 
 impl ErrHandle {
-    fn new(tx: Sender<String>) -> Self {
-        Self { tx }
+    fn new(tx: Sender<ErrMsg>, dropped: Arc<AtomicUsize>) -> Self {
+        Self { tx, dropped }
     }
 
-    pub fn term_err(&self, err: String) {
-        let _ = self.tx.send(err);
+    /// Blocks until the error is received
+    pub fn term_err(&self, err: impl Into<TermError>) {
+        self.send(ErrMsg::Fatal(err.into()), None);
+    }
+
+    /// Like [`ErrHandle::term_err`], but gives up after `timeout`, returning whether the error was
+    /// received
+    pub fn term_err_timeout(&self, err: impl Into<TermError>, timeout: Duration) -> bool {
+        self.send(ErrMsg::Fatal(err.into()), Some(timeout))
+    }
+
+    /// Reports a problem the run can recover from
+    pub fn warn(&self, err: impl Into<TermError>) {
+        self.send(ErrMsg::Warn(err.into()), None);
+    }
+
+    fn send(&self, msg: ErrMsg, timeout: Option<Duration>) -> bool {
+        let res = match timeout {
+            Some(timeout) => self
+                .tx
+                .send_timeout(msg, timeout)
+                .map_err(SendTimeoutError::into_inner),
+            None => self.tx.send(msg).map_err(|e| e.into_inner()),
+        };
+
+        match res {
+            Ok(()) => true,
+            Err(msg) => {
+                self.dropped.fetch_add(1, Ordering::Relaxed);
+                eprintln!("{}", msg);
+                false
+            }
+        }
     }
 }
 
 pub struct TermHandle {
-    rx: Option<Receiver<()>>,
-    pub err_rx: Receiver<String>,
+    rx: Receiver<()>,
+    /// Keeps `rx` connected for a handle not backed by the signal thread, so it never yields
+    keepalive: Option<Sender<()>>,
+    pub err_rx: Receiver<ErrMsg>,
     pub err_handle: ErrHandle,
+    /// Receives the reload signals set with [`InitOptions::reload_signals`]. Clones of it share
+    /// messages, use [`TermHandle::subscribe_reload`] for another receiver that sees every reload.
+    pub reload_rx: Receiver<Signal>,
 }
 
 impl TermHandle {
-    fn new(rx: Receiver<()>) -> Self {
-        Self::new_inner(Some(rx))
+    fn new(rx: Receiver<()>, err_capacity: usize) -> Self {
+        Self::new_inner(rx, None, err_capacity)
     }
 
-    fn new_inner(rx: Option<Receiver<()>>) -> Self {
-        let (tx, err_rx) = flume::bounded(0);
+    fn new_inner(rx: Receiver<()>, keepalive: Option<Sender<()>>, err_capacity: usize) -> Self {
+        let (tx, err_rx) = flume::bounded(err_capacity);
         Self {
             rx,
+            keepalive,
             err_rx,
-            err_handle: ErrHandle::new(tx),
+            err_handle: ErrHandle::new(tx, Arc::default()),
+            reload_rx: subscribe_reload(),
         }
     }
 
-    pub fn rx(&mut self) -> &Receiver<()> {
-        self.rx.get_or_insert_with(|| flume::bounded(0).1)
+    /// How many messages sent through [`TermHandle::err_handle`] or its clones weren't received
+    pub fn dropped_errors(&self) -> usize {
+        self.err_handle.dropped.load(Ordering::Relaxed)
+    }
+
+    /// A new receiver for reload signals. Reloads arriving while one is still unreceived are
+    /// coalesced.
+    pub fn subscribe_reload(&self) -> Receiver<Signal> {
+        subscribe_reload()
+    }
+
+    /// Receives once the signal thread catches a termination signal. For a
+    /// [default](TermHandle::default) handle it never yields, use [`TERMINATE`] or [`subscribe`]
+    /// to also hear about termination from other sources.
+    pub fn rx(&self) -> &Receiver<()> {
+        &self.rx
+    }
+
+    /// Whether this handle is backed by installed signal handlers, rather than being a
+    /// [default](TermHandle::default) one or outliving [`shutdown`]
+    pub fn is_live(&self) -> bool {
+        self.keepalive.is_none() && !self.rx.is_disconnected()
+    }
+
+    /// The signal that caused termination, if termination was caused by a signal. Set before
+    /// [`TERMINATE`] and the channel broadcast.
+    pub fn cause(&self) -> Option<Signal> {
+        *CAUSE.lock().unwrap()
+    }
+
+    /// Blocks until termination, returning immediately if [`TERMINATE`] is already set. Waits on
+    /// [`TERMINATE`] itself rather than [`TermHandle::rx`], which only the signal thread sends on,
+    /// so guards, deadlines and panics wake it too
+    pub fn wait(&mut self) {
+        TERMINATE.wait();
+    }
+
+    /// Blocks until termination or until `timeout` elapses, returning whether termination occurred
+    pub fn wait_timeout(&mut self, timeout: Duration) -> bool {
+        TERMINATE.wait_timeout(timeout)
     }
 }
 
+const TERMINATE_POLL: Duration = Duration::from_millis(50);
+
+/// A handle for when the signal handlers couldn't be installed. Everything but
+/// [`TermHandle::rx`] works as usual.
 impl Default for TermHandle {
     fn default() -> Self {
-        Self::new_inner(None)
+        let (tx, rx) = flume::bounded(0);
+        Self::new_inner(rx, Some(tx), 0)
     }
 }
 
-impl Drop for TermHandle {
-    fn drop(&mut self) {
-        if TERMINATE.get() {
-            drop(self.rx.take());
-        }
+static RELOAD_SUBSCRIBERS: Mutex<Vec<Sender<Signal>>> = Mutex::new(Vec::new());
+
+fn subscribe_reload() -> Receiver<Signal> {
+    let (tx, rx) = flume::bounded(1);
+    RELOAD_SUBSCRIBERS.lock().unwrap().push(tx);
+    rx
+}
+
+fn broadcast_reload(sig: Signal) {
+    RELOAD_SUBSCRIBERS
+        .lock()
+        .unwrap()
+        .retain(|tx| !matches!(tx.try_send(sig), Err(TrySendError::Disconnected(_))));
+}
+
+/// A pipe that becomes readable once [`TERMINATE`] is set, for adding termination to an existing
+/// event loop. It must not be read from, its state is only cleared by [`shutdown`].
+#[cfg(unix)]
+pub fn termination_fd() -> Result<std::os::fd::RawFd, io::Error> {
+    let fd = platform::termination_fd()?;
+    // Checked after creating it, set may have run before it existed
+    if TERMINATE.get() {
+        platform::mark_terminated();
     }
+    Ok(fd)
 }
 
-/// # Safety
-/// Should only be called once
-pub unsafe fn init_handle() -> Result<TermHandle, InitError> {
-    let (tx, rx) = flume::bounded(0);
+/// A manual-reset event that is set once [`TERMINATE`] is set, for adding termination to an
+/// existing wait loop. It must not be reset or closed, its state is only cleared by [`shutdown`].
+#[cfg(windows)]
+pub fn termination_handle() -> Result<std::os::windows::io::RawHandle, io::Error> {
+    let handle = platform::termination_handle()?;
+    // Checked after creating it, set may have run before it existed
+    if TERMINATE.get() {
+        platform::mark_terminated();
+    }
+    Ok(handle)
+}
+
+static SIGNALS_RECEIVED: AtomicU32 = AtomicU32::new(0);
+type RepeatCallback = Arc<dyn Fn(u32) + Send + Sync>;
+static ON_REPEAT: Mutex<Option<RepeatCallback>> = Mutex::new(None);
+
+/// How many termination signals have been received, whether or not they forced an exit
+pub fn signals_received() -> u32 {
+    SIGNALS_RECEIVED.load(Ordering::SeqCst)
+}
+
+/// Calls `f` with [`signals_received`] for every termination signal after the first, replacing
+/// any previous callback. It runs on the thread waiting for signals, so shouldn't block for long.
+pub fn on_repeat(f: impl Fn(u32) + Send + Sync + 'static) {
+    *ON_REPEAT.lock().unwrap() = Some(Arc::new(f));
+}
+
+static INITIALIZED: AtomicBool = AtomicBool::new(false);
+static SHUTTING_DOWN: AtomicBool = AtomicBool::new(false);
+static WATCHER: Mutex<Option<Watcher>> = Mutex::new(None);
+
+struct Watcher {
+    /// Both termination and reload signals
+    signals: Vec<c_int>,
+    shutdown: Option<platform::ShutdownHandle>,
+    thread: Option<JoinHandle<()>>,
+}
+
+/// Installs the termination signal handlers. Only the first call does so, every later call until
+/// [`shutdown`] returns [`InitError::Duplicate`], even if the first failed partway through
+/// registering handlers.
+pub fn init_handle() -> Result<TermHandle, InitError> {
+    init_handle_with(TERM_SIGNALS)
+}
+
+/// Like [`init_handle`], but treats `signals` as the termination signals instead of
+/// [`TERM_SIGNALS`]
+pub fn init_handle_with(signals: &[c_int]) -> Result<TermHandle, InitError> {
+    init_handle_with_options(InitOptions::default().signals(signals))
+}
+
+pub fn init_handle_with_options(opts: InitOptions) -> Result<TermHandle, InitError> {
+    let InitOptions {
+        signals,
+        reload_signals,
+        force_exit,
+        exit_code,
+        err_capacity,
+        forward_to_children,
+    } = opts;
+
+    if let Some(sig) = signals
+        .iter()
+        .chain(&reload_signals)
+        .find(|s| !platform::is_valid_signal(**s))
+    {
+        return Err(InitError::InvalidSignal(*sig));
+    }
+
+    if let Some(sig) = reload_signals.iter().find(|s| signals.contains(s)) {
+        return Err(InitError::InvalidSignal(*sig));
+    }
 
-    for sig in TERM_SIGNALS {
-        let stop_now = Arc::new(AtomicBool::new(false));
-        flag::register_conditional_shutdown(*sig, 1, Arc::clone(&stop_now))
-            .map_err(InitError::IO)?;
-        flag::register(*sig, stop_now).map_err(InitError::IO)?;
+    if INITIALIZED.swap(true, Ordering::SeqCst) {
+        return Err(InitError::Duplicate);
     }
 
-    platform::init_os_handler().map_err(InitError::IO)?;
+    let mut watcher = WATCHER.lock().unwrap();
+    let all_signals: Vec<_> = signals.iter().chain(&reload_signals).copied().collect();
+
+    let registered = (|| {
+        if let ForceExit::AfterSignals(after) = force_exit {
+            for sig in &signals {
+                platform::register_force_exit(*sig, after, exit_code).map_err(InitError::IO)?;
+            }
+        }
 
-    thread::spawn(move || match platform::block_for_sig() {
-        Ok(_) => {
-            TERMINATE.set();
-            while tx.send(()).is_ok() {}
+        platform::init_os_handler(&all_signals).map_err(|e| match e.kind() {
+            io::ErrorKind::AlreadyExists => InitError::Duplicate,
+            _ => InitError::IO(e),
+        })
+    })();
+    let handle = match registered {
+        Ok(handle) => handle,
+        Err(e) => {
+            // Undo whatever was registered before the failure, so init can be tried again
+            let _ = platform::cleanup_os_handler(&all_signals);
+            INITIALIZED.store(false, Ordering::SeqCst);
+            return Err(e);
         }
-        Err(e) => eprintln!("Error blocking for signal: {}", e),
+    };
+
+    let (tx, rx) = flume::bounded(0);
+    let watcher = watcher.insert(Watcher {
+        signals: all_signals,
+        shutdown: Some(handle.shutdown_handle()),
+        thread: None,
     });
+    children::set_forwarding(forward_to_children);
+    let all_signals = watcher.signals.clone();
+    watcher.thread = Some(thread::spawn(move || {
+        // In case it was spawned from a thread with the signals masked
+        if let Err(e) = platform::set_thread_mask(&all_signals, false) {
+            eprintln!("Error unmasking signals: {}", e);
+        }
+
+        let mut handle = handle;
+        let mut tx = Some(tx);
+        let mut broadcast = None;
+        loop {
+            match platform::block_for_sig(&mut handle, &reload_signals, broadcast_reload) {
+                Ok(Some(sig)) => {
+                    let received = SIGNALS_RECEIVED.fetch_add(1, Ordering::SeqCst) + 1;
+                    let Some(tx) = tx.take() else {
+                        let on_repeat = ON_REPEAT.lock().unwrap().clone();
+                        if let Some(on_repeat) = on_repeat {
+                            on_repeat(received);
+                        }
+                        continue;
+                    };
+
+                    *CAUSE.lock().unwrap() = Some(sig);
+                    TERMINATE.set();
+                    #[cfg(feature = "sd-notify")]
+                    sd_notify::notify("STOPPING=1");
+                    children::forward(sig);
 
-    Ok(TermHandle::new(rx))
+                    // On its own thread so this one can keep counting signals
+                    broadcast = Some(thread::spawn(move || {
+                        while !SHUTTING_DOWN.load(Ordering::SeqCst) {
+                            if let Err(SendTimeoutError::Disconnected(())) =
+                                tx.send_timeout((), TERMINATE_POLL)
+                            {
+                                break;
+                            }
+                        }
+                    }));
+                }
+                Ok(None) => break,
+                Err(e) => {
+                    eprintln!("Error blocking for signal: {}", e);
+                    break;
+                }
+            }
+        }
+
+        if let Some(broadcast) = broadcast {
+            let _ = broadcast.join();
+        }
+    }));
+
+    #[cfg(feature = "sd-notify")]
+    sd_notify::notify("READY=1");
+
+    Ok(TermHandle::new(rx, err_capacity))
+}
+
+/// Unregisters the termination signal handlers and stops the thread waiting for them, so
+/// [`init_handle`] can be called again. The signals get their default dispositions back.
+///
+/// [`TERMINATE`], [`TermHandle::cause`] and [`signals_received`] are cleared when
+/// `reset_terminate` is set. Does nothing if the handlers aren't installed.
+pub fn shutdown(reset_terminate: bool) -> Result<(), io::Error> {
+    let Some(watcher) = WATCHER.lock().unwrap().take() else {
+        return Ok(());
+    };
+
+    SHUTTING_DOWN.store(true, Ordering::SeqCst);
+    if let Some(handle) = &watcher.shutdown {
+        platform::shutdown_os_handler(handle);
+    }
+    if let Some(thread) = watcher.thread {
+        let _ = thread.join();
+    }
+    SHUTTING_DOWN.store(false, Ordering::SeqCst);
+
+    let res = platform::cleanup_os_handler(&watcher.signals);
+
+    if reset_terminate {
+        *CAUSE.lock().unwrap() = None;
+        SIGNALS_RECEIVED.store(0, Ordering::SeqCst);
+        TERMINATE.reset();
+    }
+
+    children::set_forwarding(false);
+    INITIALIZED.store(false, Ordering::SeqCst);
+    res
+}
+
+/// Blocks the termination and reload signals from being delivered to the calling thread, so they
+/// can only interrupt the thread waiting for them. Does nothing before [`init_handle`] or on
+/// Windows, where signals never interrupt arbitrary threads.
+pub fn mask_term_signals_for_current_thread() -> Result<(), io::Error> {
+    match &*WATCHER.lock().unwrap() {
+        Some(watcher) => platform::set_thread_mask(&watcher.signals, true),
+        None => Ok(()),
+    }
+}
+
+/// Spawns a thread that calls [`mask_term_signals_for_current_thread`] before running `f`
+pub fn spawn_masked<F, T>(f: F) -> JoinHandle<T>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    thread::spawn(move || {
+        if let Err(e) = mask_term_signals_for_current_thread() {
+            eprintln!("Error masking signals: {}", e);
+        }
+        f()
+    })
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::sync::MutexGuard;
+
+    use super::*;
+
+    /// Held by every test that touches the process-wide termination state, which they'd otherwise
+    /// trip over each other on
+    static LOCK: Mutex<()> = Mutex::new(());
+
+    /// Takes [`LOCK`] and clears [`TERMINATE`], for a test to start from a clean state
+    pub(crate) fn isolate() -> MutexGuard<'static, ()> {
+        let lock = LOCK.lock().unwrap_or_else(PoisonError::into_inner);
+        TERMINATE.reset();
+        lock
+    }
+
+    /// A live handle, which is shut down along with the signal handlers once done with
+    pub(crate) fn live_handle() -> TermHandle {
+        let handle = init_handle().unwrap_or_else(|e| panic!("init_handle failed: {}", e));
+        assert!(handle.is_live());
+        handle
+    }
+
+    pub(crate) fn set_after(delay: Duration, f: impl FnOnce() + Send + 'static) -> JoinHandle<()> {
+        thread::spawn(move || {
+            thread::sleep(delay);
+            f();
+        })
+    }
+
+    #[test]
+    fn live_wait_returns_on_set() {
+        let _lock = isolate();
+        let mut handle = live_handle();
+
+        let setter = set_after(Duration::from_millis(50), || TERMINATE.set());
+        handle.wait();
+        assert!(TERMINATE.get());
+        setter.join().unwrap();
+
+        shutdown(true).unwrap();
+    }
+
+    #[test]
+    fn live_wait_timeout() {
+        let _lock = isolate();
+        let mut handle = live_handle();
+
+        assert!(!handle.wait_timeout(Duration::from_millis(20)));
+        let setter = set_after(Duration::from_millis(50), || TERMINATE.set());
+        assert!(handle.wait_timeout(Duration::from_secs(10)));
+        setter.join().unwrap();
+
+        shutdown(true).unwrap();
+    }
+
+    #[test]
+    fn concurrent_init_one_ok() {
+        let _lock = isolate();
+
+        let barrier = Arc::new(std::sync::Barrier::new(2));
+        let inits = (0..2)
+            .map(|_| {
+                let barrier = barrier.clone();
+                thread::spawn(move || {
+                    barrier.wait();
+                    init_handle()
+                })
+            })
+            .collect::<Vec<_>>();
+        let results = inits
+            .into_iter()
+            .map(|t| t.join().unwrap())
+            .collect::<Vec<_>>();
+
+        assert_eq!(results.iter().filter(|r| r.is_ok()).count(), 1);
+        assert!(results
+            .iter()
+            .any(|r| matches!(r, Err(InitError::Duplicate))));
+        // Until shutdown, even a lone call is a duplicate
+        assert!(matches!(init_handle(), Err(InitError::Duplicate)));
+
+        shutdown(true).unwrap();
+        drop(live_handle());
+        shutdown(true).unwrap();
+    }
+
+    #[test]
+    fn default_wait_returns_on_set() {
+        let _lock = isolate();
+        let mut handle = TermHandle::default();
+
+        let setter = set_after(Duration::from_millis(50), || TERMINATE.set());
+        handle.wait();
+        assert!(TERMINATE.get());
+        setter.join().unwrap();
+    }
 }
@@ -0,0 +1,85 @@
+use std::os::raw::c_int;
+
+use signal_hook::consts::TERM_SIGNALS;
+
+/// What to do when termination signals keep arriving after the first one
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ForceExit {
+    /// Exit immediately, without any cleanup, once the same signal has been received this many
+    /// times. `AfterSignals(1)` makes the first signal exit immediately.
+    AfterSignals(u32),
+    /// Never exit forcefully, every signal after the first is ignored
+    Never,
+}
+
+/// Options for [`init_handle_with_options`](crate::init_handle_with_options)
+///
+/// The defaults match [`init_handle`](crate::init_handle): [`TERM_SIGNALS`] terminate, and a
+/// second instance of the same signal exits with code 1.
+///
+/// On Windows Ctrl-C and Ctrl-Break are counted by the console control handler, while closing the
+/// console, logging off and shutting down never force an exit, the system kills the process itself
+/// a few seconds after the event.
+#[derive(Debug, Clone)]
+pub struct InitOptions {
+    pub(crate) signals: Vec<c_int>,
+    pub(crate) reload_signals: Vec<c_int>,
+    pub(crate) force_exit: ForceExit,
+    pub(crate) exit_code: i32,
+    pub(crate) err_capacity: usize,
+    pub(crate) forward_to_children: bool,
+}
+
+impl Default for InitOptions {
+    fn default() -> Self {
+        Self {
+            signals: TERM_SIGNALS.to_vec(),
+            reload_signals: Vec::new(),
+            force_exit: ForceExit::AfterSignals(2),
+            exit_code: 1,
+            err_capacity: 0,
+            forward_to_children: false,
+        }
+    }
+}
+
+impl InitOptions {
+    pub fn signals(mut self, signals: &[c_int]) -> Self {
+        self.signals = signals.to_vec();
+        self
+    }
+
+    /// Signals delivered to [`TermHandle::reload_rx`](crate::TermHandle::reload_rx) instead of
+    /// terminating, for example `SIGHUP`. On Windows only `SIGBREAK` (Ctrl-Break) is useful here.
+    /// None of these may also be termination signals.
+    pub fn reload_signals(mut self, signals: &[c_int]) -> Self {
+        self.reload_signals = signals.to_vec();
+        self
+    }
+
+    pub fn force_exit(mut self, force_exit: ForceExit) -> Self {
+        self.force_exit = force_exit;
+        self
+    }
+
+    /// The exit code used by [`ForceExit::AfterSignals`]
+    pub fn exit_code(mut self, exit_code: i32) -> Self {
+        self.exit_code = exit_code;
+        self
+    }
+
+    /// How many errors [`TermHandle::err_rx`](crate::TermHandle::err_rx) can hold before
+    /// [`ErrHandle`](crate::ErrHandle) blocks. The default of 0 blocks until each one is received.
+    pub fn err_capacity(mut self, err_capacity: usize) -> Self {
+        self.err_capacity = err_capacity;
+        self
+    }
+
+    /// Forward the termination signal to the children added with
+    /// [`register_child`](crate::register_child). On Windows they are sent Ctrl-Break instead,
+    /// which only reaches children created with `CREATE_NEW_PROCESS_GROUP`.
+    pub fn forward_to_children(mut self, forward: bool) -> Self {
+        self.forward_to_children = forward;
+        self
+    }
+}
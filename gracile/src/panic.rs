@@ -0,0 +1,153 @@
+use std::{
+    panic::{self, PanicHookInfo},
+    sync::{Arc, Mutex},
+    thread,
+    time::Duration,
+};
+
+use crate::{ErrHandle, TERMINATE};
+
+type PanicHook = Box<dyn Fn(&PanicHookInfo<'_>) + Sync + Send + 'static>;
+
+struct PanicState {
+    prev: Arc<PanicHook>,
+    err_handle: ErrHandle,
+}
+
+static PANIC_STATE: Mutex<Option<PanicState>> = Mutex::new(None);
+
+/// How long a panicking thread waits for the error to be received. The main thread panicking
+/// would otherwise wait forever for itself.
+const PANIC_REPORT_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Makes a panic on any thread terminate, after running the previous panic hook. The panic is
+/// sent through `err_handle` as a fatal error before [`TERMINATE`] is set, so a consumer selecting
+/// on both sees the error. Calling this again only replaces `err_handle`.
+pub fn terminate_on_panic(err_handle: ErrHandle) {
+    let mut state = PANIC_STATE.lock().unwrap();
+    if let Some(state) = &mut *state {
+        state.err_handle = err_handle;
+        return;
+    }
+
+    let prev = Arc::new(panic::take_hook());
+    *state = Some(PanicState {
+        prev: Arc::clone(&prev),
+        err_handle,
+    });
+
+    panic::set_hook(Box::new(move |info| {
+        prev(info);
+
+        let payload = info
+            .payload()
+            .downcast_ref::<&str>()
+            .copied()
+            .or_else(|| info.payload().downcast_ref::<String>().map(String::as_str))
+            .unwrap_or("Box<dyn Any>");
+        let location = info
+            .location()
+            .map_or_else(|| "unknown location".to_string(), ToString::to_string);
+        let msg = format!(
+            "Thread '{}' panicked at {}: {}",
+            thread::current().name().unwrap_or("<unnamed>"),
+            location,
+            payload
+        );
+
+        // Cloned so the lock isn't held while blocking on the send
+        let err_handle = PANIC_STATE
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .as_ref()
+            .map(|s| s.err_handle.clone());
+        if let Some(err_handle) = err_handle {
+            err_handle.term_err_timeout(msg, PANIC_REPORT_TIMEOUT);
+        }
+
+        TERMINATE.set();
+    }));
+}
+
+/// Restores the panic hook replaced by [`terminate_on_panic`]
+pub fn allow_panics() {
+    let mut state = PANIC_STATE.lock().unwrap();
+    let Some(PanicState { prev, .. }) = state.take() else {
+        return;
+    };
+
+    // Dropping our hook drops its reference to the previous one
+    drop(panic::take_hook());
+    match Arc::try_unwrap(prev) {
+        Ok(prev) => panic::set_hook(prev),
+        Err(prev) => panic::set_hook(Box::new(move |info| prev(info))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        shutdown,
+        tests::{isolate, live_handle},
+        ErrMsg, TermHandle,
+    };
+
+    #[test]
+    fn panic_terminates_with_message() {
+        let _lock = isolate();
+        let first = TermHandle::default();
+        let handle = TermHandle::default();
+        terminate_on_panic(first.err_handle.clone());
+        // Only replaces where the panic is sent
+        terminate_on_panic(handle.err_handle.clone());
+
+        let panicker = thread::Builder::new()
+            .name("panicker".to_string())
+            .spawn(|| panic!("{} on purpose", 1))
+            .unwrap();
+        match handle.err_rx.recv_timeout(Duration::from_secs(10)) {
+            Ok(ErrMsg::Fatal(e)) => {
+                assert!(
+                    e.context.starts_with("Thread 'panicker' panicked at "),
+                    "{}",
+                    e
+                );
+                assert!(e.context.contains(file!()), "{}", e);
+                assert!(e.context.ends_with(": 1 on purpose"), "{}", e);
+            }
+            res => panic!("Expected the panic as a fatal error, got {:?}", res),
+        }
+        // The hook has run by the time the thread is joined
+        assert!(panicker.join().is_err());
+        assert!(TERMINATE.get());
+        assert!(first.err_rx.is_empty());
+
+        allow_panics();
+        TERMINATE.reset();
+        assert!(thread::spawn(|| panic!("after allow_panics"))
+            .join()
+            .is_err());
+        assert!(!TERMINATE.get());
+        assert!(handle.err_rx.is_empty());
+    }
+
+    #[test]
+    fn panic_wakes_live_wait() {
+        let _lock = isolate();
+        let mut handle = live_handle();
+        terminate_on_panic(handle.err_handle.clone());
+
+        let panicker = thread::spawn(|| panic!("on purpose"));
+        // The error is sent before TERMINATE is set, and waits to be received
+        match handle.err_rx.recv_timeout(Duration::from_secs(10)) {
+            Ok(ErrMsg::Fatal(e)) => assert!(e.context.ends_with("on purpose"), "{}", e),
+            res => panic!("Expected the panic as a fatal error, got {:?}", res),
+        }
+        assert!(handle.wait_timeout(Duration::from_secs(10)));
+        assert!(panicker.join().is_err());
+
+        allow_panics();
+        shutdown(true).unwrap();
+    }
+}
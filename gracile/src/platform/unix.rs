@@ -1,27 +1,206 @@
-use signal_hook::{consts::TERM_SIGNALS, iterator::Signals};
-use std::io;
+use crate::Signal;
+use signal_hook::{
+    consts::FORBIDDEN,
+    iterator::{backend::Handle, Signals},
+    low_level, SigId,
+};
+use std::{
+    io,
+    os::{
+        fd::{AsRawFd, FromRawFd, OwnedFd, RawFd},
+        raw::c_int,
+    },
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Mutex, OnceLock,
+    },
+};
 
-static mut SIGNALS: Option<Signals> = None;
+static FORCE_EXIT_IDS: Mutex<Vec<SigId>> = Mutex::new(Vec::new());
+/// Actions standing in for the default dispositions after [`cleanup_os_handler`], signal-hook
+/// can't restore the real ones
+static DEFAULT_IDS: Mutex<Vec<SigId>> = Mutex::new(Vec::new());
 
-/// # Safety
-/// shut up
-#[inline]
-pub unsafe fn init_os_handler() -> Result<(), io::Error> {
-    SIGNALS = Some(Signals::new(TERM_SIGNALS)?);
+pub fn is_valid_signal(sig: c_int) -> bool {
+    sig > 0 && !FORBIDDEN.contains(&sig)
+}
+
+pub fn register_force_exit(sig: c_int, after: u32, exit_code: i32) -> Result<(), io::Error> {
+    let received = AtomicU32::new(0);
+    // Safety: only touches an atomic and calls the async-signal-safe _exit
+    let id = unsafe {
+        low_level::register(sig, move || {
+            if received.fetch_add(1, Ordering::SeqCst) + 1 >= after {
+                low_level::exit(exit_code);
+            }
+        })
+    }?;
+    FORCE_EXIT_IDS.lock().unwrap().push(id);
     Ok(())
 }
 
-/// # Safety
-/// shut up
+/// Blocks or unblocks `signals` for the calling thread only
+pub fn set_thread_mask(signals: &[c_int], block: bool) -> Result<(), io::Error> {
+    // Safety: the set is initialised by sigemptyset before use
+    unsafe {
+        let mut set = std::mem::zeroed::<libc::sigset_t>();
+        libc::sigemptyset(&mut set);
+        for sig in signals {
+            libc::sigaddset(&mut set, *sig);
+        }
+
+        let how = if block {
+            libc::SIG_BLOCK
+        } else {
+            libc::SIG_UNBLOCK
+        };
+        match libc::pthread_sigmask(how, &set, std::ptr::null_mut()) {
+            0 => Ok(()),
+            e => Err(io::Error::from_raw_os_error(e)),
+        }
+    }
+}
+
+/// Sends `sig` on to each of `children`
+pub fn forward_to_children(sig: Signal, children: &[u32]) {
+    let Some(sig) = sig.raw() else {
+        return;
+    };
+
+    for pid in children {
+        // Safety: kill has no memory safety requirements
+        if unsafe { libc::kill(*pid as libc::pid_t, sig) } != 0 {
+            eprintln!(
+                "Error forwarding {} to child {}: {}",
+                Signal::from_raw(sig),
+                pid,
+                io::Error::last_os_error()
+            );
+        }
+    }
+}
+
+/// Owns the registered signals, moved into the thread that blocks for them
+pub struct PlatformHandle {
+    signals: Signals,
+}
+
+impl PlatformHandle {
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        ShutdownHandle(self.signals.handle())
+    }
+}
+
+pub struct ShutdownHandle(Handle);
+
+#[inline]
+pub fn init_os_handler(signals: &[c_int]) -> Result<PlatformHandle, io::Error> {
+    for id in DEFAULT_IDS.lock().unwrap().drain(..) {
+        low_level::unregister(id);
+    }
+
+    Ok(PlatformHandle {
+        signals: Signals::new(signals)?,
+    })
+}
+
+/// Signals in `reload` are passed to `on_reload` and waited past, the first other signal is
+/// returned. Returns `None` once [`shutdown_os_handler`] has been called. The signals are
+/// unregistered when `handle` is dropped.
 #[inline]
-pub unsafe fn block_for_sig() -> Result<(), io::Error> {
-    if let Some(ref mut signals) = SIGNALS {
-        loop {
-            if signals.wait().count() > 0 {
-                break;
+pub fn block_for_sig(
+    handle: &mut PlatformHandle,
+    reload: &[c_int],
+    mut on_reload: impl FnMut(Signal),
+) -> Result<Option<Signal>, io::Error> {
+    let signals = &mut handle.signals;
+    loop {
+        for sig in signals.wait() {
+            if !reload.contains(&sig) {
+                return Ok(Some(Signal::from_raw(sig)));
             }
+            on_reload(Signal::from_raw(sig));
         }
+
+        if signals.is_closed() {
+            return Ok(None);
+        }
+    }
+}
+
+/// Wakes [`block_for_sig`], which returns `None`
+pub fn shutdown_os_handler(handle: &ShutdownHandle) {
+    handle.0.close();
+}
+
+/// Called once the thread blocking for signals has exited. Unregisters every action added for
+/// `signals` and emulates their default dispositions in their place.
+pub fn cleanup_os_handler(signals: &[c_int]) -> Result<(), io::Error> {
+    for id in FORCE_EXIT_IDS.lock().unwrap().drain(..) {
+        low_level::unregister(id);
+    }
+
+    let mut defaults = DEFAULT_IDS.lock().unwrap();
+    for sig in signals {
+        let sig = *sig;
+        // Safety: emulate_default_handler is async-signal-safe
+        defaults.push(unsafe {
+            low_level::register(sig, move || {
+                let _ = low_level::emulate_default_handler(sig);
+            })
+        }?);
     }
 
     Ok(())
 }
+
+/// Read and write ends of a non-blocking pipe that has a byte written to it on termination
+static TERMINATION_PIPE: OnceLock<(OwnedFd, OwnedFd)> = OnceLock::new();
+
+fn termination_pipe() -> Result<&'static (OwnedFd, OwnedFd), io::Error> {
+    if let Some(pipe) = TERMINATION_PIPE.get() {
+        return Ok(pipe);
+    }
+
+    let mut fds = [0; 2];
+    // Safety: fds has room for both ends, which are owned from here on
+    let pipe = unsafe {
+        if libc::pipe(fds.as_mut_ptr()) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let pipe = (OwnedFd::from_raw_fd(fds[0]), OwnedFd::from_raw_fd(fds[1]));
+        for fd in fds {
+            if libc::fcntl(fd, libc::F_SETFL, libc::O_NONBLOCK) != 0
+                || libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) != 0
+            {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        pipe
+    };
+
+    // Losing a race drops our pipe in favour of the other
+    let _ = TERMINATION_PIPE.set(pipe);
+    Ok(TERMINATION_PIPE.get().unwrap())
+}
+
+pub fn termination_fd() -> Result<RawFd, io::Error> {
+    let pipe = termination_pipe()?;
+    Ok(pipe.0.as_raw_fd())
+}
+
+/// Makes the termination fd readable, if it has been created
+pub fn mark_terminated() {
+    if let Some((_, tx)) = TERMINATION_PIPE.get() {
+        // A full pipe is already readable
+        unsafe { libc::write(tx.as_raw_fd(), [0u8].as_ptr().cast(), 1) };
+    }
+}
+
+/// Drains the termination fd so it's no longer readable
+pub fn clear_terminated() {
+    if let Some((rx, _)) = TERMINATION_PIPE.get() {
+        let mut buf = [0u8; 64];
+        while unsafe { libc::read(rx.as_raw_fd(), buf.as_mut_ptr().cast(), buf.len()) } > 0 {}
+    }
+}
@@ -1,55 +1,375 @@
-use signal_hook::{consts::TERM_SIGNALS, low_level};
+use crate::Signal;
+use signal_hook::{
+    consts::{SIGBREAK, SIGINT, SIGTERM},
+    low_level, SigId,
+};
 use std::{
     io::{self, ErrorKind},
+    os::{raw::c_int, windows::io::RawHandle},
     ptr,
+    sync::{
+        atomic::{AtomicBool, AtomicI32, AtomicPtr, AtomicU32, Ordering},
+        Mutex,
+    },
 };
 use winapi::{
     ctypes::c_long,
-    shared::{minwindef::BOOL, ntdef::HANDLE},
+    shared::minwindef::{BOOL, DWORD, FALSE, TRUE},
     um::{
-        synchapi::{ReleaseSemaphore, WaitForSingleObject},
+        consoleapi::SetConsoleCtrlHandler,
+        handleapi::CloseHandle,
+        processthreadsapi::ExitProcess,
+        synchapi::{
+            CreateEventA, ReleaseSemaphore, ResetEvent, SetEvent, Sleep, WaitForSingleObject,
+        },
         winbase::{CreateSemaphoreA, INFINITE, WAIT_FAILED, WAIT_OBJECT_0},
+        wincon::{
+            GenerateConsoleCtrlEvent, CTRL_BREAK_EVENT, CTRL_CLOSE_EVENT, CTRL_C_EVENT,
+            CTRL_LOGOFF_EVENT, CTRL_SHUTDOWN_EVENT,
+        },
+        winnt::HANDLE,
     },
 };
 
 const MAX_SEM_COUNT: c_long = 255;
-static mut SEMAPHORE: HANDLE = 0 as HANDLE;
+static SEMAPHORE: AtomicPtr<winapi::ctypes::c_void> = AtomicPtr::new(ptr::null_mut());
+
+/// Positive values are CRT signal numbers, negative values are console control events, see
+/// [`encode_event`]
+static LAST_SIGNAL: AtomicI32 = AtomicI32::new(0);
+
+static HANDLE_CTRL_C: AtomicBool = AtomicBool::new(false);
+static HANDLE_CTRL_BREAK: AtomicBool = AtomicBool::new(false);
+
+/// 0 disables the forced exit
+static FORCE_EXIT_CTRL_C_AFTER: AtomicU32 = AtomicU32::new(0);
+static FORCE_EXIT_CTRL_BREAK_AFTER: AtomicU32 = AtomicU32::new(0);
+static FORCE_EXIT_CODE: AtomicI32 = AtomicI32::new(1);
+static CTRL_C_RECEIVED: AtomicU32 = AtomicU32::new(0);
+static CTRL_BREAK_RECEIVED: AtomicU32 = AtomicU32::new(0);
+
+/// Tells [`block_for_sig`] the semaphore was released by [`shutdown_os_handler`] rather than a
+/// signal
+static SHUTDOWN: AtomicBool = AtomicBool::new(false);
+static CRT_IDS: Mutex<Vec<SigId>> = Mutex::new(Vec::new());
+/// Actions standing in for the default dispositions of CRT signals after [`cleanup_os_handler`],
+/// signal-hook can't restore the real ones
+static DEFAULT_IDS: Mutex<Vec<SigId>> = Mutex::new(Vec::new());
+
+fn os_handler() -> BOOL {
+    unsafe { ReleaseSemaphore(SEMAPHORE.load(Ordering::SeqCst), 1, ptr::null_mut()) }
+}
+
+fn encode_event(event: DWORD) -> i32 {
+    -(event as i32) - 1
+}
+
+/// Added to service stop and shutdown events so they can't be confused with console events
+const SERVICE_EVENT_OFFSET: DWORD = 0x100;
+const SERVICE_STOP_EVENT: DWORD = SERVICE_EVENT_OFFSET;
+const SERVICE_SHUTDOWN_EVENT: DWORD = SERVICE_EVENT_OFFSET + 1;
+
+/// Wakes [`block_for_sig`] as if a signal arrived, returning false if the handler isn't
+/// initialised
+#[cfg(feature = "windows-service")]
+pub fn service_stop(shutdown: bool) -> bool {
+    if SEMAPHORE.load(Ordering::SeqCst).is_null() {
+        return false;
+    }
+
+    let event = if shutdown {
+        SERVICE_SHUTDOWN_EVENT
+    } else {
+        SERVICE_STOP_EVENT
+    };
+    LAST_SIGNAL.store(encode_event(event), Ordering::SeqCst);
+    os_handler() != 0
+}
+
+fn force_exit_check(after: &AtomicU32, received: &AtomicU32) {
+    let after = after.load(Ordering::SeqCst);
+    if after > 0 && received.fetch_add(1, Ordering::SeqCst) + 1 >= after {
+        unsafe { ExitProcess(FORCE_EXIT_CODE.load(Ordering::SeqCst) as u32) };
+    }
+}
+
+/// Runs on a thread the system creates for each event.
+///
+/// For close, logoff and shutdown the process is killed as soon as this returns, so after waking
+/// the signal thread it sleeps and lets the main thread drain and exit on its own. The system only
+/// waits about 5 seconds (`HungAppTimeout`) before killing the process regardless.
+unsafe extern "system" fn console_handler(event: DWORD) -> BOOL {
+    match event {
+        CTRL_C_EVENT if HANDLE_CTRL_C.load(Ordering::SeqCst) => {
+            force_exit_check(&FORCE_EXIT_CTRL_C_AFTER, &CTRL_C_RECEIVED);
+        }
+        CTRL_BREAK_EVENT if HANDLE_CTRL_BREAK.load(Ordering::SeqCst) => {
+            force_exit_check(&FORCE_EXIT_CTRL_BREAK_AFTER, &CTRL_BREAK_RECEIVED);
+        }
+        CTRL_CLOSE_EVENT | CTRL_LOGOFF_EVENT | CTRL_SHUTDOWN_EVENT => {}
+        _ => return FALSE,
+    }
+
+    LAST_SIGNAL.store(encode_event(event), Ordering::SeqCst);
+    os_handler();
+
+    if matches!(
+        event,
+        CTRL_CLOSE_EVENT | CTRL_LOGOFF_EVENT | CTRL_SHUTDOWN_EVENT
+    ) {
+        Sleep(INFINITE);
+    }
+
+    TRUE
+}
+
+/// The CRT only raises these for console processes, everything else it emulates can't be a
+/// termination request
+pub fn is_valid_signal(sig: c_int) -> bool {
+    matches!(sig, SIGINT | SIGTERM | SIGBREAK)
+}
+
+/// Ctrl-C and Ctrl-Break arrive through the console handler, which counts them itself. Only
+/// signals raised within the process go through the CRT.
+pub fn register_force_exit(sig: c_int, after: u32, exit_code: i32) -> Result<(), io::Error> {
+    match sig {
+        SIGINT | SIGBREAK => {
+            let force_exit_after = match sig {
+                SIGINT => &FORCE_EXIT_CTRL_C_AFTER,
+                _ => &FORCE_EXIT_CTRL_BREAK_AFTER,
+            };
+            force_exit_after.store(after.max(1), Ordering::SeqCst);
+            FORCE_EXIT_CODE.store(exit_code, Ordering::SeqCst);
+            Ok(())
+        }
+        _ => {
+            let received = AtomicU32::new(0);
+            // Safety: only touches an atomic and calls _exit
+            let id = unsafe {
+                low_level::register(sig, move || {
+                    if received.fetch_add(1, Ordering::SeqCst) + 1 >= after {
+                        low_level::exit(exit_code);
+                    }
+                })
+            }?;
+            CRT_IDS.lock().unwrap().push(id);
+            Ok(())
+        }
+    }
+}
+
+/// Signals aren't delivered to arbitrary threads on Windows, the CRT and console handlers run on
+/// threads of their own
+pub fn set_thread_mask(_signals: &[c_int], _block: bool) -> Result<(), io::Error> {
+    Ok(())
+}
+
+/// Sends Ctrl-Break to each of `children`, whatever the signal was. Only reaches children created
+/// with `CREATE_NEW_PROCESS_GROUP`, whose process group ID is their process ID.
+pub fn forward_to_children(_sig: Signal, children: &[u32]) {
+    for pid in children {
+        if unsafe { GenerateConsoleCtrlEvent(CTRL_BREAK_EVENT, *pid) } == 0 {
+            eprintln!(
+                "Error forwarding Ctrl-Break to child {}: {}",
+                pid,
+                io::Error::last_os_error()
+            );
+        }
+    }
+}
+
+/// Moved into the thread that blocks for signals. The semaphore itself stays in [`SEMAPHORE`] for
+/// the handlers and is closed by [`cleanup_os_handler`].
+pub struct PlatformHandle {
+    semaphore: HANDLE,
+}
+
+// Safety: a semaphore HANDLE can be waited on from any thread
+unsafe impl Send for PlatformHandle {}
 
-unsafe fn os_handler() -> BOOL {
-    ReleaseSemaphore(SEMAPHORE, 1, ptr::null_mut())
+impl PlatformHandle {
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        ShutdownHandle
+    }
 }
 
-/// # Safety
-/// shut up
+pub struct ShutdownHandle;
+
 #[inline]
-pub unsafe fn init_os_handler() -> Result<(), io::Error> {
-    SEMAPHORE = CreateSemaphoreA(ptr::null_mut(), 0, MAX_SEM_COUNT, ptr::null());
-    if SEMAPHORE.is_null() {
+pub fn init_os_handler(signals: &[c_int]) -> Result<PlatformHandle, io::Error> {
+    let semaphore = unsafe { CreateSemaphoreA(ptr::null_mut(), 0, MAX_SEM_COUNT, ptr::null()) };
+    if semaphore.is_null() {
         return Err(io::Error::last_os_error());
     }
 
-    for sig in TERM_SIGNALS {
-        low_level::register(*sig, || {
-            os_handler();
-        })?;
+    if SEMAPHORE
+        .compare_exchange(
+            ptr::null_mut(),
+            semaphore,
+            Ordering::SeqCst,
+            Ordering::SeqCst,
+        )
+        .is_err()
+    {
+        unsafe { CloseHandle(semaphore) };
+        return Err(io::Error::new(
+            ErrorKind::AlreadyExists,
+            "Signal semaphore already created",
+        ));
     }
 
-    Ok(())
+    for id in DEFAULT_IDS.lock().unwrap().drain(..) {
+        low_level::unregister(id);
+    }
+
+    let mut crt_ids = CRT_IDS.lock().unwrap();
+    for sig in signals {
+        let sig = *sig;
+        match sig {
+            SIGINT => HANDLE_CTRL_C.store(true, Ordering::SeqCst),
+            SIGBREAK => HANDLE_CTRL_BREAK.store(true, Ordering::SeqCst),
+            _ => crt_ids.push(unsafe {
+                low_level::register(sig, move || {
+                    LAST_SIGNAL.store(sig, Ordering::SeqCst);
+                    os_handler();
+                })?
+            }),
+        }
+    }
+    drop(crt_ids);
+
+    if unsafe { SetConsoleCtrlHandler(Some(console_handler), TRUE) } == 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(PlatformHandle { semaphore })
 }
 
-/// # Safety
-/// shut up
+/// Signals in `reload` are passed to `on_reload` and waited past, the first other signal is
+/// returned. Ctrl-C and Ctrl-Break count as `SIGINT` and `SIGBREAK`. Returns `None` once
+/// [`shutdown_os_handler`] has been called.
 #[inline]
-pub unsafe fn block_for_sig() -> Result<(), io::Error> {
-    match WaitForSingleObject(SEMAPHORE, INFINITE) {
-        WAIT_OBJECT_0 => Ok(()),
-        WAIT_FAILED => Err(io::Error::last_os_error()),
-        ret => Err(io::Error::new(
-            ErrorKind::Other,
-            format!(
-                "WaitForSingleObject(), unexpected return value \"{:x}\"",
-                ret
-            ),
-        )),
+pub fn block_for_sig(
+    handle: &mut PlatformHandle,
+    reload: &[c_int],
+    mut on_reload: impl FnMut(Signal),
+) -> Result<Option<Signal>, io::Error> {
+    loop {
+        match unsafe { WaitForSingleObject(handle.semaphore, INFINITE) } {
+            WAIT_OBJECT_0 if SHUTDOWN.load(Ordering::SeqCst) => return Ok(None),
+            WAIT_OBJECT_0 => {
+                let (raw, sig) = match LAST_SIGNAL.load(Ordering::SeqCst) {
+                    sig if sig >= 0 => (Some(sig), Signal::from_raw(sig)),
+                    event => match (-event - 1) as DWORD {
+                        CTRL_C_EVENT => (Some(SIGINT), Signal::WindowsCtrlC),
+                        CTRL_BREAK_EVENT => (Some(SIGBREAK), Signal::WindowsBreak),
+                        CTRL_CLOSE_EVENT => (None, Signal::WindowsClose),
+                        CTRL_LOGOFF_EVENT => (None, Signal::WindowsLogoff),
+                        CTRL_SHUTDOWN_EVENT => (None, Signal::WindowsShutdown),
+                        SERVICE_STOP_EVENT => (None, Signal::WindowsServiceStop),
+                        SERVICE_SHUTDOWN_EVENT => (None, Signal::WindowsServiceShutdown),
+                        event => (None, Signal::Other(event as i32)),
+                    },
+                };
+
+                match raw {
+                    Some(raw) if reload.contains(&raw) => on_reload(sig),
+                    _ => return Ok(Some(sig)),
+                }
+            }
+            WAIT_FAILED => return Err(io::Error::last_os_error()),
+            ret => {
+                return Err(io::Error::other(format!(
+                    "WaitForSingleObject(), unexpected return value \"{:x}\"",
+                    ret
+                )))
+            }
+        }
+    }
+}
+
+/// Stops handling console events and CRT signals, then wakes [`block_for_sig`]
+pub fn shutdown_os_handler(_handle: &ShutdownHandle) {
+    unsafe { SetConsoleCtrlHandler(Some(console_handler), FALSE) };
+    for id in CRT_IDS.lock().unwrap().drain(..) {
+        low_level::unregister(id);
+    }
+
+    SHUTDOWN.store(true, Ordering::SeqCst);
+    os_handler();
+}
+
+/// Called once the thread blocking for signals has exited. Closes the semaphore and emulates the
+/// default dispositions of the CRT signals in `signals`, removing the console handler already
+/// restores the default for console events.
+pub fn cleanup_os_handler(signals: &[c_int]) -> Result<(), io::Error> {
+    let semaphore = SEMAPHORE.swap(ptr::null_mut(), Ordering::SeqCst);
+    if !semaphore.is_null() {
+        unsafe { CloseHandle(semaphore) };
+    }
+
+    SHUTDOWN.store(false, Ordering::SeqCst);
+    LAST_SIGNAL.store(0, Ordering::SeqCst);
+    HANDLE_CTRL_C.store(false, Ordering::SeqCst);
+    HANDLE_CTRL_BREAK.store(false, Ordering::SeqCst);
+    FORCE_EXIT_CTRL_C_AFTER.store(0, Ordering::SeqCst);
+    FORCE_EXIT_CTRL_BREAK_AFTER.store(0, Ordering::SeqCst);
+    CTRL_C_RECEIVED.store(0, Ordering::SeqCst);
+    CTRL_BREAK_RECEIVED.store(0, Ordering::SeqCst);
+
+    let mut defaults = DEFAULT_IDS.lock().unwrap();
+    for sig in signals.iter().filter(|s| !matches!(**s, SIGINT | SIGBREAK)) {
+        let sig = *sig;
+        // Safety: only resets the handler and raises the signal again
+        defaults.push(unsafe {
+            low_level::register(sig, move || {
+                let _ = low_level::emulate_default_handler(sig);
+            })
+        }?);
+    }
+
+    Ok(())
+}
+
+/// A manual-reset event set on termination, never closed once created
+static TERMINATION_EVENT: AtomicPtr<winapi::ctypes::c_void> = AtomicPtr::new(ptr::null_mut());
+
+pub fn termination_handle() -> Result<RawHandle, io::Error> {
+    let mut event = TERMINATION_EVENT.load(Ordering::SeqCst);
+    if event.is_null() {
+        let new = unsafe { CreateEventA(ptr::null_mut(), TRUE, FALSE, ptr::null()) };
+        if new.is_null() {
+            return Err(io::Error::last_os_error());
+        }
+
+        event = match TERMINATION_EVENT.compare_exchange(
+            ptr::null_mut(),
+            new,
+            Ordering::SeqCst,
+            Ordering::SeqCst,
+        ) {
+            Ok(_) => new,
+            Err(existing) => {
+                unsafe { CloseHandle(new) };
+                existing
+            }
+        };
+    }
+
+    Ok(event.cast())
+}
+
+/// Sets the termination event, if it has been created
+pub fn mark_terminated() {
+    let event = TERMINATION_EVENT.load(Ordering::SeqCst);
+    if !event.is_null() {
+        unsafe { SetEvent(event) };
+    }
+}
+
+pub fn clear_terminated() {
+    let event = TERMINATION_EVENT.load(Ordering::SeqCst);
+    if !event.is_null() {
+        unsafe { ResetEvent(event) };
     }
 }
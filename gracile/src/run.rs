@@ -0,0 +1,126 @@
+use std::{
+    error::Error,
+    fmt::{self, Display, Formatter},
+    panic, thread,
+};
+
+use flume::Selector;
+
+use crate::subscribe;
+
+/// Returned by [`run_until_terminate`] when termination happens before the work finishes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TerminatedError;
+
+impl Display for TerminatedError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "Terminated before the work finished")
+    }
+}
+
+impl Error for TerminatedError {}
+
+/// Runs `f` on its own thread and waits for it, unless termination happens first. The thread is
+/// then left running, `f` should check [`TERMINATE`](crate::TERMINATE) to stop early. A panic in
+/// `f` is resumed on the calling thread.
+pub fn run_until_terminate<T, F>(f: F) -> Result<T, TerminatedError>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    run_until_terminate_with_cleanup(f, || {})
+}
+
+/// Like [`run_until_terminate`], but calls `cleanup` if termination happens first
+pub fn run_until_terminate_with_cleanup<T, F, C>(f: F, cleanup: C) -> Result<T, TerminatedError>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+    C: FnOnce(),
+{
+    let term_sub = subscribe();
+    let (tx, rx) = flume::bounded(1);
+    let handle = thread::spawn(move || {
+        let _ = tx.send(f());
+    });
+
+    let res = Selector::new()
+        .recv(&rx, Some)
+        .recv(term_sub.rx(), |_| None)
+        .wait()
+        // Both may be ready at once, finished work wins
+        .or_else(|| rx.try_recv().ok().map(Ok));
+
+    match res {
+        Some(Ok(v)) => Ok(v),
+        // The sender was dropped without sending, so f panicked
+        Some(Err(_)) => match handle.join() {
+            Err(payload) => panic::resume_unwind(payload),
+            Ok(()) => unreachable!("The result is always sent before the thread exits"),
+        },
+        None => {
+            cleanup();
+            Err(TerminatedError)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::atomic::{AtomicBool, Ordering},
+        time::Duration,
+    };
+
+    use super::*;
+    use crate::{
+        tests::{isolate, set_after},
+        TERMINATE,
+    };
+
+    #[test]
+    fn completion_first() {
+        let _lock = isolate();
+
+        let cleaned_up = AtomicBool::new(false);
+        let res =
+            run_until_terminate_with_cleanup(|| 5, || cleaned_up.store(true, Ordering::SeqCst));
+        assert_eq!(res, Ok(5));
+        assert!(!cleaned_up.load(Ordering::SeqCst));
+        assert!(!TERMINATE.get());
+    }
+
+    #[test]
+    fn signal_first() {
+        let _lock = isolate();
+
+        // Keeps the work from finishing until after termination is noticed
+        let (release_tx, release_rx) = flume::bounded::<()>(0);
+        let (done_tx, done_rx) = flume::bounded(1);
+        let setter = set_after(Duration::from_millis(50), || TERMINATE.set());
+        let cleaned_up = AtomicBool::new(false);
+        let res = run_until_terminate_with_cleanup(
+            move || {
+                let _ = release_rx.recv();
+                let _ = done_tx.send(TERMINATE.get());
+            },
+            || cleaned_up.store(true, Ordering::SeqCst),
+        );
+        assert_eq!(res, Err(TerminatedError));
+        assert!(cleaned_up.load(Ordering::SeqCst));
+        setter.join().unwrap();
+
+        // The abandoned work carries on, and can see why it was abandoned
+        drop(release_tx);
+        assert_eq!(done_rx.recv_timeout(Duration::from_secs(10)), Ok(true));
+    }
+
+    #[test]
+    fn panic_resumed() {
+        let _lock = isolate();
+
+        let res = panic::catch_unwind(|| run_until_terminate(|| panic!("on purpose")));
+        assert!(res.is_err());
+        assert!(!TERMINATE.get());
+    }
+}
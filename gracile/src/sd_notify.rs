@@ -0,0 +1,101 @@
+//! The systemd notification protocol, a datagram with newline separated `KEY=VALUE` assignments
+//! sent to the socket in `NOTIFY_SOCKET`
+
+/// Silently does nothing if `NOTIFY_SOCKET` isn't set, or the message can't be sent
+#[cfg(unix)]
+pub(crate) fn notify(state: &str) {
+    use std::{env, os::unix::net::UnixDatagram};
+
+    let Some(path) = env::var_os("NOTIFY_SOCKET") else {
+        return;
+    };
+    let Ok(socket) = UnixDatagram::unbound() else {
+        return;
+    };
+
+    let bytes = path.as_encoded_bytes();
+    let _ = match bytes.strip_prefix(b"@") {
+        #[cfg(target_os = "linux")]
+        Some(name) => {
+            use std::os::{linux::net::SocketAddrExt, unix::net::SocketAddr};
+
+            SocketAddr::from_abstract_name(name)
+                .and_then(|addr| socket.send_to_addr(state.as_bytes(), &addr))
+        }
+        #[cfg(not(target_os = "linux"))]
+        Some(_) => return,
+        None => socket.send_to(state.as_bytes(), &path),
+    };
+}
+
+#[cfg(not(unix))]
+pub(crate) fn notify(_state: &str) {}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use std::{env, fs, os::unix::net::UnixDatagram, process, time::Duration};
+
+    use signal_hook::{consts::SIGTERM, low_level};
+
+    use super::*;
+    use crate::{
+        shutdown,
+        tests::{isolate, live_handle},
+        TERMINATE,
+    };
+
+    fn recv(socket: &UnixDatagram) -> String {
+        let mut buf = [0; 64];
+        let len = socket.recv(&mut buf).unwrap();
+        String::from_utf8_lossy(&buf[..len]).into_owned()
+    }
+
+    #[test]
+    fn ready_and_stopping() {
+        let _lock = isolate();
+        let path = env::temp_dir().join(format!("gracile-notify-{}", process::id()));
+        let _ = fs::remove_file(&path);
+        let socket = UnixDatagram::bind(&path).unwrap();
+        socket
+            .set_read_timeout(Some(Duration::from_secs(10)))
+            .unwrap();
+        env::set_var("NOTIFY_SOCKET", &path);
+
+        let mut handle = live_handle();
+        assert_eq!(recv(&socket), "READY=1");
+        low_level::raise(SIGTERM).unwrap();
+        assert_eq!(recv(&socket), "STOPPING=1");
+        assert!(handle.wait_timeout(Duration::from_secs(10)));
+        assert!(TERMINATE.get());
+
+        env::remove_var("NOTIFY_SOCKET");
+        shutdown(true).unwrap();
+        let _ = fs::remove_file(&path);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn abstract_socket() {
+        use std::os::{linux::net::SocketAddrExt, unix::net::SocketAddr};
+
+        let _lock = isolate();
+        let name = format!("gracile-notify-{}", process::id());
+        let addr = SocketAddr::from_abstract_name(&name).unwrap();
+        let socket = UnixDatagram::bind_addr(&addr).unwrap();
+        socket
+            .set_read_timeout(Some(Duration::from_secs(10)))
+            .unwrap();
+
+        env::set_var("NOTIFY_SOCKET", format!("@{}", name));
+        notify("READY=1");
+        env::remove_var("NOTIFY_SOCKET");
+        assert_eq!(recv(&socket), "READY=1");
+    }
+
+    #[test]
+    fn unset_is_a_no_op() {
+        let _lock = isolate();
+        env::remove_var("NOTIFY_SOCKET");
+        notify("READY=1");
+    }
+}
@@ -0,0 +1,303 @@
+//! Stopping through the service control manager, which never reaches the console handler
+
+use std::{
+    ffi::CString,
+    io::{self, ErrorKind},
+    sync::{Mutex, OnceLock},
+    time::Duration,
+};
+
+use winapi::{
+    shared::{
+        minwindef::{DWORD, LPVOID},
+        winerror::{ERROR_CALL_NOT_IMPLEMENTED, NO_ERROR},
+    },
+    um::{
+        winnt::SERVICE_WIN32_OWN_PROCESS,
+        winsvc::{
+            RegisterServiceCtrlHandlerExA, SetServiceStatus, SERVICE_ACCEPT_PRESHUTDOWN,
+            SERVICE_ACCEPT_SHUTDOWN, SERVICE_ACCEPT_STOP, SERVICE_CONTROL_INTERROGATE,
+            SERVICE_CONTROL_PRESHUTDOWN, SERVICE_CONTROL_SHUTDOWN, SERVICE_CONTROL_STOP,
+            SERVICE_RUNNING, SERVICE_STATUS, SERVICE_STATUS_HANDLE, SERVICE_STOPPED,
+            SERVICE_STOP_PENDING,
+        },
+    },
+};
+
+use crate::{platform, TERMINATE};
+
+/// How long the SCM is told to wait for the next checkpoint before assuming the service hung
+const STOP_WAIT_HINT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ServiceState {
+    Running,
+    StopPending,
+    Stopped { exit_code: u32 },
+}
+
+/// Where service state is reported, the SCM outside of tests
+pub trait StatusSink {
+    fn report(&self, state: ServiceState, checkpoint: u32, wait_hint: Duration) -> io::Result<()>;
+}
+
+/// Tracks the state reported to a [`StatusSink`], numbering the checkpoints while stopping
+pub struct ServiceStatus<S> {
+    sink: S,
+    state: Mutex<(ServiceState, u32)>,
+}
+
+impl<S: StatusSink> ServiceStatus<S> {
+    pub fn new(sink: S) -> Self {
+        Self {
+            sink,
+            state: Mutex::new((ServiceState::Running, 0)),
+        }
+    }
+
+    pub fn state(&self) -> ServiceState {
+        self.state.lock().unwrap().0
+    }
+
+    pub fn running(&self) -> io::Result<()> {
+        self.set(ServiceState::Running)
+    }
+
+    /// Reports progress while draining, each call bumping the checkpoint. Does nothing once
+    /// stopped.
+    pub fn stop_pending(&self) -> io::Result<()> {
+        self.set(ServiceState::StopPending)
+    }
+
+    pub fn stopped(&self, exit_code: u32) -> io::Result<()> {
+        self.set(ServiceState::Stopped { exit_code })
+    }
+
+    fn set(&self, state: ServiceState) -> io::Result<()> {
+        let mut lock = self.state.lock().unwrap();
+        let (current, checkpoint) = &mut *lock;
+        if let ServiceState::Stopped { .. } = current {
+            return Ok(());
+        }
+
+        let wait_hint = match state {
+            ServiceState::StopPending => {
+                *checkpoint += 1;
+                STOP_WAIT_HINT
+            }
+            _ => {
+                *checkpoint = 0;
+                Duration::ZERO
+            }
+        };
+        *current = state;
+        self.sink.report(state, *checkpoint, wait_hint)
+    }
+}
+
+/// Reports to the SCM through the handle returned when registering the control handler
+pub struct ScmSink(SERVICE_STATUS_HANDLE);
+
+// Safety: the handle is only passed to SetServiceStatus, which may be called from any thread
+unsafe impl Send for ScmSink {}
+unsafe impl Sync for ScmSink {}
+
+impl StatusSink for ScmSink {
+    fn report(&self, state: ServiceState, checkpoint: u32, wait_hint: Duration) -> io::Result<()> {
+        let (current_state, exit_code, controls_accepted) = match state {
+            ServiceState::Running => (
+                SERVICE_RUNNING,
+                NO_ERROR,
+                SERVICE_ACCEPT_STOP | SERVICE_ACCEPT_SHUTDOWN | SERVICE_ACCEPT_PRESHUTDOWN,
+            ),
+            ServiceState::StopPending => (SERVICE_STOP_PENDING, NO_ERROR, 0),
+            ServiceState::Stopped { exit_code } => (SERVICE_STOPPED, exit_code, 0),
+        };
+
+        let mut status = SERVICE_STATUS {
+            dwServiceType: SERVICE_WIN32_OWN_PROCESS,
+            dwCurrentState: current_state,
+            dwControlsAccepted: controls_accepted,
+            dwWin32ExitCode: exit_code,
+            dwServiceSpecificExitCode: 0,
+            dwCheckPoint: checkpoint,
+            dwWaitHint: wait_hint.as_millis().min(DWORD::MAX as u128) as DWORD,
+        };
+        if unsafe { SetServiceStatus(self.0, &mut status) } == 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
+
+static SERVICE: OnceLock<ServiceStatus<ScmSink>> = OnceLock::new();
+
+unsafe extern "system" fn service_handler(
+    control: DWORD,
+    _event_type: DWORD,
+    _event_data: LPVOID,
+    _context: LPVOID,
+) -> DWORD {
+    handle_control(SERVICE.get(), control, platform::service_stop)
+}
+
+/// What [`service_handler`] does with `control`, reporting to `service` and waking the signal
+/// thread with `service_stop`, which takes whether it's a shutdown and returns whether there was
+/// a signal thread to wake
+fn handle_control<S: StatusSink>(
+    service: Option<&ServiceStatus<S>>,
+    control: DWORD,
+    service_stop: impl FnOnce(bool) -> bool,
+) -> DWORD {
+    match control {
+        SERVICE_CONTROL_STOP | SERVICE_CONTROL_SHUTDOWN | SERVICE_CONTROL_PRESHUTDOWN => {
+            if let Some(service) = service {
+                let _ = service.stop_pending();
+            }
+
+            // Without the signal thread there's no cause or channel broadcast, only the flag
+            if !service_stop(control != SERVICE_CONTROL_STOP) {
+                TERMINATE.set();
+            }
+            NO_ERROR
+        }
+        SERVICE_CONTROL_INTERROGATE => NO_ERROR,
+        _ => ERROR_CALL_NOT_IMPLEMENTED,
+    }
+}
+
+/// Registers a control handler for the service `service_name` and reports it as running. Stop,
+/// shutdown and preshutdown requests terminate like a signal would, and report the service as
+/// stopping. Call [`ServiceStatus::stop_pending`] while draining, if it takes more than a few
+/// seconds, and [`ServiceStatus::stopped`] once done.
+///
+/// Must be called from the service's main function, after `StartServiceCtrlDispatcher` has
+/// started it, and at most once.
+pub fn init_service_handler(
+    service_name: &str,
+) -> Result<&'static ServiceStatus<ScmSink>, io::Error> {
+    let name =
+        CString::new(service_name).map_err(|e| io::Error::new(ErrorKind::InvalidInput, e))?;
+
+    if SERVICE.get().is_some() {
+        return Err(io::Error::new(
+            ErrorKind::AlreadyExists,
+            "Service control handler already registered",
+        ));
+    }
+
+    let handle = unsafe {
+        RegisterServiceCtrlHandlerExA(name.as_ptr(), Some(service_handler), std::ptr::null_mut())
+    };
+    if handle.is_null() {
+        return Err(io::Error::last_os_error());
+    }
+
+    let service = SERVICE.get_or_init(|| ServiceStatus::new(ScmSink(handle)));
+    service.running()?;
+    Ok(service)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+
+    use winapi::um::winsvc::{SERVICE_CONTROL_PAUSE, SERVICE_CONTROL_STOP};
+
+    use super::*;
+    use crate::tests::isolate;
+
+    /// Records every report, in place of the SCM
+    #[derive(Default)]
+    struct MockSink(Mutex<Vec<(ServiceState, u32, Duration)>>);
+
+    impl StatusSink for MockSink {
+        fn report(
+            &self,
+            state: ServiceState,
+            checkpoint: u32,
+            wait_hint: Duration,
+        ) -> io::Result<()> {
+            self.0.lock().unwrap().push((state, checkpoint, wait_hint));
+            Ok(())
+        }
+    }
+
+    fn reports(service: &ServiceStatus<MockSink>) -> Vec<(ServiceState, u32, Duration)> {
+        service.sink.0.lock().unwrap().clone()
+    }
+
+    #[test]
+    fn stop_reports_pending_and_wakes_signal_thread() {
+        let _lock = isolate();
+        let service = ServiceStatus::new(MockSink::default());
+        service.running().unwrap();
+
+        let woken = Cell::new(None);
+        let res = handle_control(Some(&service), SERVICE_CONTROL_STOP, |shutdown| {
+            woken.set(Some(shutdown));
+            true
+        });
+        assert_eq!(res, NO_ERROR);
+        assert_eq!(woken.get(), Some(false));
+        // The signal thread sets it once woken, not the handler
+        assert!(!TERMINATE.get());
+        assert_eq!(
+            reports(&service),
+            [
+                (ServiceState::Running, 0, Duration::ZERO),
+                (ServiceState::StopPending, 1, STOP_WAIT_HINT),
+            ]
+        );
+    }
+
+    #[test]
+    fn shutdown_without_signal_thread_sets_terminate() {
+        let _lock = isolate();
+        let service = ServiceStatus::new(MockSink::default());
+
+        for control in [SERVICE_CONTROL_SHUTDOWN, SERVICE_CONTROL_PRESHUTDOWN] {
+            let res = handle_control(Some(&service), control, |shutdown| {
+                assert!(shutdown);
+                false
+            });
+            assert_eq!(res, NO_ERROR);
+        }
+        assert!(TERMINATE.get());
+        // Each stop request is another checkpoint
+        let checkpoints: Vec<_> = reports(&service).iter().map(|r| r.1).collect();
+        assert_eq!(checkpoints, [1, 2]);
+    }
+
+    #[test]
+    fn other_controls_dont_stop() {
+        let _lock = isolate();
+        let service = ServiceStatus::new(MockSink::default());
+
+        let stop = |_| panic!("Only stop controls should wake the signal thread");
+        assert_eq!(
+            handle_control(Some(&service), SERVICE_CONTROL_INTERROGATE, stop),
+            NO_ERROR
+        );
+        assert_eq!(
+            handle_control(Some(&service), SERVICE_CONTROL_PAUSE, stop),
+            ERROR_CALL_NOT_IMPLEMENTED
+        );
+        assert!(reports(&service).is_empty());
+        assert!(!TERMINATE.get());
+    }
+
+    #[test]
+    fn nothing_reported_once_stopped() {
+        let service = ServiceStatus::new(MockSink::default());
+        service.stopped(3).unwrap();
+        service.stop_pending().unwrap();
+        service.running().unwrap();
+
+        assert_eq!(service.state(), ServiceState::Stopped { exit_code: 3 });
+        assert_eq!(
+            reports(&service),
+            [(ServiceState::Stopped { exit_code: 3 }, 0, Duration::ZERO)]
+        );
+    }
+}
@@ -0,0 +1,88 @@
+use std::{
+    fmt::{self, Display, Formatter},
+    os::raw::c_int,
+};
+
+#[cfg(unix)]
+use signal_hook::consts::{SIGHUP, SIGINT, SIGQUIT, SIGTERM};
+
+#[cfg(windows)]
+use signal_hook::consts::{SIGBREAK, SIGINT, SIGTERM};
+
+/// The event that caused termination, see [`TermHandle::cause`](crate::TermHandle::cause), or a
+/// reload, see [`TermHandle::reload_rx`](crate::TermHandle::reload_rx)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Signal {
+    Int,
+    Term,
+    Quit,
+    Hup,
+    WindowsCtrlC,
+    WindowsBreak,
+    WindowsClose,
+    WindowsLogoff,
+    WindowsShutdown,
+    WindowsServiceStop,
+    WindowsServiceShutdown,
+    Other(i32),
+}
+
+impl Signal {
+    pub fn from_raw(sig: c_int) -> Self {
+        match sig {
+            #[cfg(unix)]
+            SIGINT => Self::Int,
+            #[cfg(unix)]
+            SIGTERM => Self::Term,
+            #[cfg(unix)]
+            SIGQUIT => Self::Quit,
+            #[cfg(unix)]
+            SIGHUP => Self::Hup,
+            #[cfg(windows)]
+            SIGINT => Self::WindowsCtrlC,
+            #[cfg(windows)]
+            SIGBREAK => Self::WindowsBreak,
+            #[cfg(windows)]
+            SIGTERM => Self::Term,
+            sig => Self::Other(sig),
+        }
+    }
+
+    /// The signal number, `None` for Windows console events that aren't signals
+    pub fn raw(&self) -> Option<c_int> {
+        match self {
+            #[cfg(unix)]
+            Self::Int => Some(SIGINT),
+            #[cfg(unix)]
+            Self::Quit => Some(SIGQUIT),
+            #[cfg(unix)]
+            Self::Hup => Some(SIGHUP),
+            #[cfg(windows)]
+            Self::WindowsCtrlC => Some(SIGINT),
+            #[cfg(windows)]
+            Self::WindowsBreak => Some(SIGBREAK),
+            Self::Term => Some(SIGTERM),
+            Self::Other(sig) => Some(*sig),
+            _ => None,
+        }
+    }
+}
+
+impl Display for Signal {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Int => write!(f, "SIGINT"),
+            Self::Term => write!(f, "SIGTERM"),
+            Self::Quit => write!(f, "SIGQUIT"),
+            Self::Hup => write!(f, "SIGHUP"),
+            Self::WindowsCtrlC => write!(f, "Ctrl-C"),
+            Self::WindowsBreak => write!(f, "Ctrl-Break"),
+            Self::WindowsClose => write!(f, "console close"),
+            Self::WindowsLogoff => write!(f, "logoff"),
+            Self::WindowsShutdown => write!(f, "system shutdown"),
+            Self::WindowsServiceStop => write!(f, "service stop"),
+            Self::WindowsServiceShutdown => write!(f, "service shutdown"),
+            Self::Other(sig) => write!(f, "signal {}", sig),
+        }
+    }
+}
@@ -0,0 +1,60 @@
+use std::{
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use flume::{Receiver, RecvTimeoutError, Sender};
+
+use crate::TERMINATE;
+
+static SUBSCRIBERS: Mutex<Vec<Sender<()>>> = Mutex::new(Vec::new());
+
+/// Called by [`Terminate::set`](crate::Terminate::set) after the flag is stored. Each subscriber
+/// is sent one message and then disconnected.
+pub(crate) fn notify_all() {
+    let subscribers = std::mem::take(&mut *SUBSCRIBERS.lock().unwrap());
+    for tx in subscribers {
+        let _ = tx.try_send(());
+    }
+}
+
+/// Returns a new [`TermSubscription`], already signalled if [`TERMINATE`] is set
+pub fn subscribe() -> TermSubscription {
+    let (tx, rx) = flume::bounded(1);
+
+    // Holding the lock while checking means a concurrent set either sees this subscriber or this
+    // sees the flag
+    let mut subscribers = SUBSCRIBERS.lock().unwrap();
+    if TERMINATE.get() {
+        let _ = tx.try_send(());
+    } else {
+        subscribers.push(tx);
+    }
+
+    TermSubscription { rx }
+}
+
+/// A receiver of its own that gets exactly one message on termination, after which it is
+/// disconnected. See [`subscribe`].
+pub struct TermSubscription {
+    rx: Receiver<()>,
+}
+
+impl TermSubscription {
+    pub fn rx(&self) -> &Receiver<()> {
+        &self.rx
+    }
+
+    /// Blocks until termination
+    pub fn wait(&self) {
+        let _ = self.rx.recv();
+    }
+
+    /// Blocks until termination or until `timeout` elapses, returning whether termination occurred
+    pub fn wait_timeout(&self, timeout: Duration) -> bool {
+        match self.rx.recv_deadline(Instant::now() + timeout) {
+            Ok(()) | Err(RecvTimeoutError::Disconnected) => true,
+            Err(RecvTimeoutError::Timeout) => false,
+        }
+    }
+}
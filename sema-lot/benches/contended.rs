@@ -0,0 +1,83 @@
+//! Contended acquire/release round trips, for comparing the wait backends. Run it once as is for
+//! parking_lot's mutex and condvar, and once with `--features futex` for the futex on Linux:
+//!
+//! ```text
+//! cargo bench -p sema-lot --bench contended
+//! cargo bench -p sema-lot --bench contended --features futex
+//! ```
+
+use std::{
+    hint::black_box,
+    sync::Arc,
+    thread,
+    time::{Duration, Instant},
+};
+
+use sema_lot::Semaphore;
+
+const ROUND_TRIPS: u32 = 100_000;
+const THREADS: usize = 4;
+const ACQUIRES: u32 = 100_000;
+
+/// Two threads handing a permit back and forth, every acquire has to wait to be woken
+fn ping_pong() -> Duration {
+    let ping = Arc::new(Semaphore::new(0));
+    let pong = Arc::new(Semaphore::new(0));
+    let other = thread::spawn({
+        let (ping, pong) = (Arc::clone(&ping), Arc::clone(&pong));
+        move || {
+            for _ in 0..ROUND_TRIPS {
+                ping.acquire();
+                pong.release();
+            }
+        }
+    });
+
+    let start = Instant::now();
+    for _ in 0..ROUND_TRIPS {
+        ping.release();
+        pong.acquire();
+    }
+    let elapsed = start.elapsed();
+    other.join().unwrap();
+    elapsed / ROUND_TRIPS
+}
+
+/// Threads fighting over a single permit
+fn one_permit() -> Duration {
+    let sem = Arc::new(Semaphore::new(1));
+    let start = Instant::now();
+    let threads: Vec<_> = (0..THREADS)
+        .map(|_| {
+            let sem = Arc::clone(&sem);
+            thread::spawn(move || {
+                for i in 0..ACQUIRES {
+                    sem.with(|| black_box(i));
+                }
+            })
+        })
+        .collect();
+    for t in threads {
+        t.join().unwrap();
+    }
+    start.elapsed() / (ACQUIRES * THREADS as u32)
+}
+
+/// The best of a few runs, for other load on the machine to matter less
+fn best_of(f: fn() -> Duration) -> Duration {
+    (0..5).map(|_| f()).min().unwrap()
+}
+
+fn main() {
+    let backend = match cfg!(all(feature = "futex", target_os = "linux")) {
+        true => "futex",
+        false => "parking_lot",
+    };
+    println!("Backend: {}", backend);
+    println!("Ping-pong round trip: {:?}", best_of(ping_pong));
+    println!(
+        "Acquire/release, {} threads on 1 permit: {:?}",
+        THREADS,
+        best_of(one_permit)
+    );
+}
@@ -1,67 +1,329 @@
-use std::sync::atomic::{AtomicBool, AtomicIsize, Ordering};
+use std::{
+    fmt::{self, Display, Formatter},
+    mem,
+    sync::atomic::Ordering,
+    time::{Duration, Instant},
+};
 
-use parking_lot::{Condvar, Mutex};
+#[cfg(feature = "metrics")]
+use std::sync::atomic::AtomicU64;
+
+use sync::{fence, AtomicBool, AtomicIsize, AtomicUsize, WaitQueue};
+
+mod sync;
+
+#[derive(Debug)]
+pub struct OverRelease {
+    pub max: isize,
+}
+
+impl Display for OverRelease {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Semaphore released without a matching acquire, count would exceed max of {}",
+            self.max
+        )
+    }
+}
+
+/// Upper bounds of the wait time histogram buckets in [`SemaphoreMetrics::wait_histogram`], the
+/// final bucket holds every wait longer than the last bound
+#[cfg(feature = "metrics")]
+pub const WAIT_BUCKETS: [Duration; 7] = [
+    Duration::from_micros(10),
+    Duration::from_micros(100),
+    Duration::from_millis(1),
+    Duration::from_millis(10),
+    Duration::from_millis(100),
+    Duration::from_secs(1),
+    Duration::from_secs(10),
+];
+
+#[cfg(feature = "metrics")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SemaphoreMetrics {
+    pub contended: u64,
+    pub blocked: Duration,
+    pub wait_histogram: [u64; WAIT_BUCKETS.len() + 1],
+}
+
+#[cfg(feature = "metrics")]
+#[derive(Default)]
+struct Metrics {
+    contended: AtomicU64,
+    blocked_nanos: AtomicU64,
+    wait_histogram: [AtomicU64; WAIT_BUCKETS.len() + 1],
+}
+
+#[cfg(feature = "metrics")]
+impl Metrics {
+    fn record(&self, waited: Duration) {
+        self.contended.fetch_add(1, Ordering::Relaxed);
+        self.blocked_nanos
+            .fetch_add(waited.as_nanos() as u64, Ordering::Relaxed);
+
+        let bucket = WAIT_BUCKETS
+            .iter()
+            .position(|b| waited < *b)
+            .unwrap_or(WAIT_BUCKETS.len());
+        self.wait_histogram[bucket].fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn snapshot(&self) -> SemaphoreMetrics {
+        SemaphoreMetrics {
+            contended: self.contended.load(Ordering::Relaxed),
+            blocked: Duration::from_nanos(self.blocked_nanos.load(Ordering::Relaxed)),
+            wait_histogram: self
+                .wait_histogram
+                .each_ref()
+                .map(|b| b.load(Ordering::Relaxed)),
+        }
+    }
+
+    fn reset(&self) {
+        self.contended.store(0, Ordering::Relaxed);
+        self.blocked_nanos.store(0, Ordering::Relaxed);
+        for bucket in &self.wait_histogram {
+            bucket.store(0, Ordering::Relaxed);
+        }
+    }
+}
 
 pub struct Semaphore {
     count: AtomicIsize,
-    locked: AtomicBool,
-    lock: Mutex<()>,
-    cvar: Condvar,
+    max: AtomicIsize,
+    waiters: AtomicUsize,
+    many_waiters: AtomicUsize,
+    draining: AtomicBool,
+    queue: WaitQueue,
+    #[cfg(feature = "metrics")]
+    metrics: Metrics,
+}
+
+// Not part of the synchronization logic, so always a std atomic even under loom
+static LEAKED_PERMITS: std::sync::atomic::AtomicUsize = std::sync::atomic::AtomicUsize::new(0);
+
+/// The total number of permits lost by dropping a [`RawPermit`] instead of reattaching it with
+/// [`Semaphore::guard_from_raw`]
+pub fn leaked_permits() -> usize {
+    LEAKED_PERMITS.load(Ordering::SeqCst)
 }
 
 pub struct SemaphoreGuard<'a> {
     sem: &'a Semaphore,
+    permits: usize,
+}
+
+impl<'a> SemaphoreGuard<'a> {
+    fn new(sem: &'a Semaphore) -> Self {
+        Self { sem, permits: 1 }
+    }
+
+    pub fn permits(&self) -> usize {
+        self.permits
+    }
+
+    /// Moves `n` of this guard's permits into a new guard, leaving this one with the rest, which may
+    /// be zero. Returns `None` if this guard holds fewer than `n` permits.
+    pub fn split(&mut self, n: usize) -> Option<SemaphoreGuard<'a>> {
+        self.permits = self.permits.checked_sub(n)?;
+        Some(SemaphoreGuard {
+            sem: self.sem,
+            permits: n,
+        })
+    }
+
+    /// Moves all of `other`'s permits into this guard
+    ///
+    /// # Panics
+    /// If `other` was acquired from a different semaphore
+    pub fn merge(&mut self, mut other: SemaphoreGuard<'a>) {
+        assert!(
+            std::ptr::eq(self.sem, other.sem),
+            "Merged guards from different semaphores"
+        );
+        self.permits += mem::take(&mut other.permits);
+    }
+
+    /// Detaches the permits from the guard's lifetime so they can be stored or sent elsewhere. They
+    /// are only released again once the token is passed back to [`Semaphore::guard_from_raw`].
+    pub fn into_raw(self) -> RawPermit {
+        let raw = RawPermit {
+            sem: self.sem.id(),
+            permits: self.permits,
+        };
+        mem::forget(self);
+        raw
+    }
 }
 
 impl<'a> Drop for SemaphoreGuard<'a> {
     fn drop(&mut self) {
-        self.sem.release();
+        self.sem.release_many(self.permits);
+    }
+}
+
+/// Proof of permits acquired from a [`Semaphore`], without borrowing it.
+///
+/// Dropping a `RawPermit` leaks its permits, which is counted in [`leaked_permits`].
+#[must_use = "dropping a RawPermit leaks its permits"]
+#[derive(Debug)]
+pub struct RawPermit {
+    sem: usize,
+    permits: usize,
+}
+
+impl RawPermit {
+    pub fn permits(&self) -> usize {
+        self.permits
+    }
+}
+
+impl Drop for RawPermit {
+    fn drop(&mut self) {
+        if self.permits > 0 {
+            LEAKED_PERMITS.fetch_add(self.permits, Ordering::SeqCst);
+        }
+    }
+}
+
+/// Holds every permit of a [`Semaphore`], see [`Semaphore::acquire_all`]
+pub struct DrainGuard<'a> {
+    sem: &'a Semaphore,
+    permits: usize,
+}
+
+impl<'a> Drop for DrainGuard<'a> {
+    fn drop(&mut self) {
+        self.sem.draining.store(false, Ordering::SeqCst);
+        self.sem.release_many(self.permits);
+        self.sem.wake_all();
     }
 }
 
 impl Semaphore {
+    /// A negative `initial` count creates a blocked semaphore, see [`Semaphore::new_blocked`]
     pub fn new(initial: isize) -> Self {
+        Self::with_max(initial, isize::MAX)
+    }
+
+    /// A semaphore that starts `deficit` permits in debt, requiring `deficit + 1` releases before
+    /// the first acquire can succeed. Useful to hold back workers until a number of preconditions
+    /// have each released once.
+    pub fn new_blocked(deficit: usize) -> Self {
+        Self::new(-(deficit.min(isize::MAX as usize) as isize))
+    }
+
+    /// A semaphore whose count may never be released above `max`, see [`Semaphore::checked_release`]
+    pub fn with_max(initial: isize, max: isize) -> Self {
+        assert!(
+            initial <= max,
+            "Initial count {} exceeds max {}",
+            initial,
+            max
+        );
+
         Self {
             count: AtomicIsize::new(initial),
-            locked: AtomicBool::new(false),
-            lock: Mutex::new(()),
-            cvar: Condvar::new(),
+            max: AtomicIsize::new(max),
+            waiters: AtomicUsize::new(0),
+            many_waiters: AtomicUsize::new(0),
+            draining: AtomicBool::new(false),
+            queue: WaitQueue::default(),
+            #[cfg(feature = "metrics")]
+            metrics: Metrics::default(),
         }
     }
 
     pub fn acquire(&self) {
-        let mut lock = None;
-        loop {
-            let mut count = self.count.load(Ordering::SeqCst);
-            loop {
-                if count > 0 {
-                    match self.count.compare_exchange_weak(
-                        count,
-                        count - 1,
-                        Ordering::SeqCst,
-                        Ordering::Relaxed,
-                    ) {
-                        Ok(_) => return,
-                        Err(c) => count = c,
-                    }
-                } else if let Some(ref mut lock) = lock {
-                    self.cvar.wait(lock);
-                    break;
+        self.acquire_many(1);
+    }
+
+    /// Acquires `n` permits at once, waiting until they're all available rather than holding some
+    /// while waiting for the rest.
+    pub fn acquire_many(&self, n: usize) {
+        if !self.try_acquire_many(n) {
+            self.acquire_slow(n, || false, None);
+        }
+    }
+
+    /// Like [`Semaphore::acquire`], but gives up and returns `false` if no permit became available
+    /// within `timeout`.
+    pub fn acquire_timeout(&self, timeout: Duration) -> bool {
+        self.try_acquire() || self.acquire_slow(1, || false, Some(Instant::now() + timeout))
+    }
+
+    /// Like [`Semaphore::acquire`], but gives up and returns `false` once `cancel` returns `true`.
+    ///
+    /// `cancel` is checked every time a waiter wakes, so whoever sets the cancellation condition
+    /// should follow it with [`Semaphore::wake_all`] to have blocked waiters notice promptly.
+    pub fn acquire_interruptible(&self, cancel: impl Fn() -> bool) -> bool {
+        self.try_acquire() || self.acquire_slow(1, cancel, None)
+    }
+
+    /// Like [`Semaphore::acquire_many`], but gives up and returns `false` once `cancel` returns
+    /// `true`, see [`Semaphore::acquire_interruptible`].
+    pub fn acquire_many_interruptible(&self, n: usize, cancel: impl Fn() -> bool) -> bool {
+        self.try_acquire_many(n) || self.acquire_slow(n, cancel, None)
+    }
+
+    fn acquire_slow(&self, n: usize, cancel: impl Fn() -> bool, deadline: Option<Instant>) -> bool {
+        #[cfg(feature = "metrics")]
+        let start = Instant::now();
+
+        self.waiters.fetch_add(1, Ordering::SeqCst);
+        if n > 1 {
+            self.many_waiters.fetch_add(1, Ordering::SeqCst);
+        }
+        // Pairs with the fence in releases, so either they see this waiter or it sees their permits
+        fence(Ordering::SeqCst);
+
+        let acquired = self
+            .queue
+            .wait(deadline, || {
+                if cancel() {
+                    Some(false)
+                } else if self.try_acquire_many(n) {
+                    Some(true)
                 } else {
-                    self.locked.store(true, Ordering::SeqCst);
-                    lock = Some(self.lock.lock());
+                    None
                 }
-            }
+            })
+            .unwrap_or(false);
+
+        if n > 1 {
+            self.many_waiters.fetch_sub(1, Ordering::SeqCst);
         }
+        self.waiters.fetch_sub(1, Ordering::SeqCst);
+
+        #[cfg(feature = "metrics")]
+        self.metrics.record(start.elapsed());
+
+        // A cancelled waiter may have consumed the wakeup meant for a released permit, pass it on
+        if !acquired && self.count.load(Ordering::SeqCst) > 0 {
+            self.queue.notify_one();
+        }
+
+        acquired
     }
 
     pub fn try_acquire(&self) -> bool {
+        self.try_acquire_many(1)
+    }
+
+    pub fn try_acquire_many(&self, n: usize) -> bool {
+        if self.draining.load(Ordering::SeqCst) {
+            return false;
+        }
+
+        let n = n.min(isize::MAX as usize) as isize;
         let mut count = self.count.load(Ordering::SeqCst);
         loop {
-            if count > 0 {
+            if count >= n {
                 match self.count.compare_exchange_weak(
                     count,
-                    count - 1,
+                    count - n,
                     Ordering::SeqCst,
                     Ordering::Relaxed,
                 ) {
@@ -74,31 +336,420 @@ impl Semaphore {
         }
     }
 
+    /// Releases a permit, panicking in debug builds if this would exceed the configured max.
+    /// In release builds the excess permit is discarded instead.
     pub fn release(&self) {
-        let mut lock = None;
-        if self.locked.load(Ordering::SeqCst) {
-            lock = Some(self.lock.lock());
-            self.locked.store(false, Ordering::SeqCst);
+        self.release_many(1);
+    }
+
+    /// Releases `n` permits, with the same over-release handling as [`Semaphore::release`]
+    pub fn release_many(&self, n: usize) {
+        if let Err(e) = self.checked_release_many(n) {
+            debug_assert!(false, "{}", e);
+        }
+    }
+
+    pub fn checked_release(&self) -> Result<(), OverRelease> {
+        self.checked_release_many(1)
+    }
+
+    pub fn checked_release_many(&self, n: usize) -> Result<(), OverRelease> {
+        if n == 0 {
+            return Ok(());
+        }
+
+        let n = n.min(isize::MAX as usize) as isize;
+        let max = self.max.load(Ordering::SeqCst);
+        let mut count = self.count.load(Ordering::SeqCst);
+        let over = loop {
+            let new_count = count.saturating_add(n).min(max);
+            if new_count == count {
+                break true;
+            }
+
+            match self.count.compare_exchange_weak(
+                count,
+                new_count,
+                Ordering::SeqCst,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => break new_count - count < n,
+                Err(c) => count = c,
+            }
+        };
+
+        // Orders the count update before reading the waiters, see `acquire_slow`
+        fence(Ordering::SeqCst);
+        if self.waiters.load(Ordering::SeqCst) > 0 {
+            // A pending drain or multi-permit waiter must not miss the wakeup to an acquirer that
+            // will just wait again
+            if self.draining.load(Ordering::SeqCst) || self.many_waiters.load(Ordering::SeqCst) > 0
+            {
+                self.queue.notify_all();
+            } else {
+                self.queue.notify(n as usize);
+            }
+        }
+
+        match over {
+            true => Err(OverRelease { max }),
+            false => Ok(()),
         }
-        self.count.fetch_add(1, Ordering::SeqCst);
-        drop(lock);
-        self.cvar.notify_one();
     }
 
-    pub fn access(&self) -> SemaphoreGuard {
+    /// Adds `n` new permits, raising the max by the same amount
+    pub fn add_permits(&self, n: usize) {
+        if n == 0 {
+            return;
+        }
+
+        let n = n as isize;
+        let _ = self
+            .max
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |max| {
+                Some(max.saturating_add(n))
+            });
+        self.count.fetch_add(n, Ordering::SeqCst);
+
+        fence(Ordering::SeqCst);
+        if self.waiters.load(Ordering::SeqCst) > 0 {
+            if self.draining.load(Ordering::SeqCst) || self.many_waiters.load(Ordering::SeqCst) > 0
+            {
+                self.queue.notify_all();
+            } else {
+                self.queue.notify(n as usize);
+            }
+        }
+    }
+
+    /// Takes every permit up to the configured max, blocking until all outstanding permits have
+    /// been released. Once a drain is pending, other acquirers queue behind it until the returned
+    /// guard is dropped.
+    ///
+    /// # Panics
+    /// If the semaphore has no max, i.e. wasn't created with [`Semaphore::with_max`]
+    pub fn acquire_all(&self) -> DrainGuard<'_> {
+        assert!(
+            self.max() != isize::MAX,
+            "acquire_all requires a semaphore created with Semaphore::with_max"
+        );
+
+        self.waiters.fetch_add(1, Ordering::SeqCst);
+        fence(Ordering::SeqCst);
+        self.queue.wait(None, || {
+            (!self.draining.swap(true, Ordering::SeqCst)).then_some(())
+        });
+
+        let mut taken = 0;
+        self.queue.wait(None, || {
+            let remaining = self.max() - taken;
+            let mut count = self.count.load(Ordering::SeqCst);
+            while count > 0 && remaining > 0 {
+                let take = count.min(remaining);
+                match self.count.compare_exchange_weak(
+                    count,
+                    count - take,
+                    Ordering::SeqCst,
+                    Ordering::Relaxed,
+                ) {
+                    Ok(_) => {
+                        taken += take;
+                        break;
+                    }
+                    Err(c) => count = c,
+                }
+            }
+
+            (taken >= self.max()).then_some(())
+        });
+        self.waiters.fetch_sub(1, Ordering::SeqCst);
+
+        DrainGuard {
+            sem: self,
+            permits: taken as usize,
+        }
+    }
+
+    /// Wakes every blocked waiter so it re-checks its cancellation condition, without granting any
+    /// permits.
+    pub fn wake_all(&self) {
+        if self.waiters.load(Ordering::SeqCst) > 0 {
+            self.queue.notify_all();
+        }
+    }
+
+    fn id(&self) -> usize {
+        self as *const Self as usize
+    }
+
+    /// Reattaches permits previously detached with [`SemaphoreGuard::into_raw`]
+    pub fn guard_from_raw(&self, mut raw: RawPermit) -> SemaphoreGuard<'_> {
+        debug_assert_eq!(
+            raw.sem,
+            self.id(),
+            "RawPermit reattached to a different semaphore"
+        );
+
+        SemaphoreGuard {
+            sem: self,
+            permits: mem::take(&mut raw.permits),
+        }
+    }
+
+    pub fn access(&self) -> SemaphoreGuard<'_> {
         self.acquire();
-        SemaphoreGuard { sem: self }
+        SemaphoreGuard::new(self)
+    }
+
+    pub fn access_many(&self, n: usize) -> SemaphoreGuard<'_> {
+        self.acquire_many(n);
+        SemaphoreGuard {
+            sem: self,
+            permits: n,
+        }
+    }
+
+    pub fn try_access_many(&self, n: usize) -> Option<SemaphoreGuard<'_>> {
+        if self.try_acquire_many(n) {
+            Some(SemaphoreGuard {
+                sem: self,
+                permits: n,
+            })
+        } else {
+            None
+        }
+    }
+
+    pub fn access_interruptible(&self, cancel: impl Fn() -> bool) -> Option<SemaphoreGuard<'_>> {
+        if self.acquire_interruptible(cancel) {
+            Some(SemaphoreGuard::new(self))
+        } else {
+            None
+        }
+    }
+
+    pub fn access_many_interruptible(
+        &self,
+        n: usize,
+        cancel: impl Fn() -> bool,
+    ) -> Option<SemaphoreGuard<'_>> {
+        if self.acquire_many_interruptible(n, cancel) {
+            Some(SemaphoreGuard {
+                sem: self,
+                permits: n,
+            })
+        } else {
+            None
+        }
     }
 
-    pub fn try_access(&self) -> Option<SemaphoreGuard> {
+    pub fn try_access(&self) -> Option<SemaphoreGuard<'_>> {
         if self.try_acquire() {
-            Some(SemaphoreGuard { sem: self })
+            Some(SemaphoreGuard::new(self))
         } else {
             None
         }
     }
 
+    pub fn access_timeout(&self, timeout: Duration) -> Option<SemaphoreGuard<'_>> {
+        if self.acquire_timeout(timeout) {
+            Some(SemaphoreGuard::new(self))
+        } else {
+            None
+        }
+    }
+
+    /// Runs `f` while holding a permit.
+    ///
+    /// The permit is held by a guard for the duration of the call, so it is released even if `f`
+    /// panics; the panic then continues unwinding out of this function.
+    pub fn with<R>(&self, f: impl FnOnce() -> R) -> R {
+        let _guard = self.access();
+        f()
+    }
+
+    /// Runs `f` while holding a permit if one is immediately available, otherwise returns `None`
+    /// without calling `f`. Panics in `f` release the permit as with [`Semaphore::with`].
+    pub fn try_with<R>(&self, f: impl FnOnce() -> R) -> Option<R> {
+        let _guard = self.try_access()?;
+        Some(f())
+    }
+
+    /// Runs `f` while holding a permit if one becomes available within `timeout`, otherwise returns
+    /// `None` without calling `f`. Panics in `f` release the permit as with [`Semaphore::with`].
+    pub fn with_timeout<R>(&self, timeout: Duration, f: impl FnOnce() -> R) -> Option<R> {
+        let _guard = self.access_timeout(timeout)?;
+        Some(f())
+    }
+
+    /// The number of available permits. A negative count is the number of releases still needed
+    /// before the semaphore stops being blocked, see [`Semaphore::new_blocked`]
     pub fn count(&self) -> isize {
         self.count.load(Ordering::SeqCst)
     }
+
+    pub fn max(&self) -> isize {
+        self.max.load(Ordering::SeqCst)
+    }
+
+    /// Contention totals for acquires that had to block, uncontended acquires aren't recorded
+    #[cfg(feature = "metrics")]
+    pub fn metrics(&self) -> SemaphoreMetrics {
+        self.metrics.snapshot()
+    }
+
+    #[cfg(feature = "metrics")]
+    pub fn reset_metrics(&self) {
+        self.metrics.reset();
+    }
+}
+
+#[cfg(all(test, not(loom)))]
+mod tests {
+    use std::{
+        panic::{self, AssertUnwindSafe},
+        sync::Arc,
+        thread,
+    };
+
+    use super::*;
+
+    #[test]
+    fn blocked_needs_deficit_plus_one_releases() {
+        let sem = Semaphore::new_blocked(3);
+        assert_eq!(sem.count(), -3);
+        for remaining in (0..3).rev() {
+            sem.release();
+            assert_eq!(sem.count(), -remaining);
+            assert!(!sem.try_acquire());
+        }
+        sem.release();
+        assert!(sem.try_acquire());
+        assert_eq!(sem.count(), 0);
+    }
+
+    #[test]
+    fn release_below_deficit_stays_blocked() {
+        let sem = Semaphore::new_blocked(2);
+        sem.release_many(2);
+        assert!(!sem.acquire_timeout(Duration::from_millis(10)));
+        assert_eq!(sem.count(), 0);
+    }
+
+    /// A waiter blocked through the deficit is woken once the count rises above zero
+    #[test]
+    fn blocked_waiter_woken() {
+        let sem = Arc::new(Semaphore::new_blocked(2));
+        let waiter = thread::spawn({
+            let sem = Arc::clone(&sem);
+            move || sem.acquire()
+        });
+        for _ in 0..3 {
+            sem.release();
+        }
+        waiter.join().unwrap();
+        assert_eq!(sem.count(), 0);
+    }
+
+    #[test]
+    fn blocked_clamps_deficit() {
+        let sem = Semaphore::new_blocked(usize::MAX);
+        assert_eq!(sem.count(), -isize::MAX);
+        sem.release_many(usize::MAX);
+        assert_eq!(sem.count(), 0);
+        assert!(!sem.try_acquire());
+        sem.release();
+        assert!(sem.try_acquire());
+    }
+
+    #[test]
+    fn negative_new_is_blocked() {
+        let sem = Semaphore::new(-2);
+        assert_eq!(sem.count(), Semaphore::new_blocked(2).count());
+        sem.release_many(2);
+        assert!(!sem.try_acquire());
+        sem.release();
+        assert!(sem.try_acquire());
+    }
+
+    #[test]
+    fn checked_release_over_max() {
+        let sem = Semaphore::with_max(1, 2);
+        assert!(sem.checked_release().is_ok());
+        assert!(matches!(sem.checked_release(), Err(OverRelease { max: 2 })));
+        assert_eq!(sem.count(), 2);
+
+        assert!(sem.try_acquire_many(2));
+        // As much is released as fits under the max
+        assert!(sem.checked_release_many(5).is_err());
+        assert_eq!(sem.count(), 2);
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "exceed max")]
+    fn raw_over_release_panics() {
+        Semaphore::with_max(1, 1).release();
+    }
+
+    /// A stray release while a guard is out means the guard's own release is one too many
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "exceed max")]
+    fn forged_guard_over_release_panics() {
+        let sem = Semaphore::with_max(1, 1);
+        let raw = sem.access().into_raw();
+        sem.release();
+        drop(sem.guard_from_raw(raw));
+    }
+
+    #[test]
+    #[cfg(not(debug_assertions))]
+    fn over_release_discarded() {
+        let sem = Semaphore::with_max(1, 1);
+        sem.release();
+        assert_eq!(sem.count(), 1);
+    }
+
+    #[test]
+    fn add_permits_raises_max() {
+        let sem = Semaphore::with_max(1, 1);
+        sem.add_permits(2);
+        assert_eq!((sem.count(), sem.max()), (3, 3));
+        assert!(sem.checked_release().is_err());
+        assert_eq!(sem.count(), 3);
+    }
+
+    #[test]
+    fn with_holds_permit() {
+        let sem = Semaphore::new(1);
+        assert_eq!(sem.with(|| sem.count()), 0);
+        assert_eq!(sem.count(), 1);
+        assert_eq!(sem.try_with(|| sem.try_with(|| ())), Some(None));
+        assert_eq!(sem.with_timeout(Duration::ZERO, || 1), Some(1));
+
+        let _guard = sem.access();
+        assert_eq!(sem.try_with(|| ()), None);
+        assert_eq!(sem.with_timeout(Duration::from_millis(10), || ()), None);
+    }
+
+    /// A panic in the closure still releases the permit, then carries on unwinding
+    #[test]
+    fn with_releases_on_panic() {
+        let sem = Semaphore::new(1);
+        let unwound = panic::catch_unwind(AssertUnwindSafe(|| sem.with(|| panic!("in with"))));
+        assert!(unwound.is_err());
+        assert_eq!(sem.count(), 1);
+
+        let unwound =
+            panic::catch_unwind(AssertUnwindSafe(|| sem.try_with(|| panic!("in try_with"))));
+        assert!(unwound.is_err());
+        assert_eq!(sem.count(), 1);
+
+        let unwound = panic::catch_unwind(AssertUnwindSafe(|| {
+            sem.with_timeout(Duration::ZERO, || panic!("in with_timeout"))
+        }));
+        assert!(unwound.is_err());
+        assert_eq!(sem.count(), 1);
+    }
 }
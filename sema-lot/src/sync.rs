@@ -0,0 +1,284 @@
+//! The synchronization primitives used by [`Semaphore`](crate::Semaphore), swapped for loom's
+//! model checked versions when building with `--cfg loom`, and for a futex on Linux with the
+//! `futex` feature.
+
+use std::time::Instant;
+
+#[cfg(not(loom))]
+pub(crate) use std::sync::atomic::{fence, AtomicBool, AtomicIsize, AtomicUsize};
+
+#[cfg(loom)]
+pub(crate) use loom::sync::atomic::{fence, AtomicBool, AtomicIsize, AtomicUsize};
+
+/// Blocks threads until a condition they check becomes true.
+///
+/// Whoever makes a condition true must do so before calling one of the notify methods, so a waiter
+/// either sees the change while checking or is woken by the notification.
+#[derive(Default)]
+pub(crate) struct WaitQueue(imp::WaitQueue);
+
+impl WaitQueue {
+    /// Calls `check` until it returns `Some`, blocking between calls until notified. Once the
+    /// `deadline` passes `check` is given one final chance.
+    pub(crate) fn wait<T>(
+        &self,
+        deadline: Option<Instant>,
+        check: impl FnMut() -> Option<T>,
+    ) -> Option<T> {
+        self.0.wait(deadline, check)
+    }
+
+    pub(crate) fn notify_one(&self) {
+        self.0.notify(1);
+    }
+
+    pub(crate) fn notify_all(&self) {
+        self.0.notify(usize::MAX);
+    }
+
+    pub(crate) fn notify(&self, n: usize) {
+        self.0.notify(n);
+    }
+}
+
+#[cfg(not(any(loom, all(feature = "futex", target_os = "linux"))))]
+mod imp {
+    use std::time::Instant;
+
+    use parking_lot::{Condvar, Mutex};
+
+    #[derive(Default)]
+    pub(crate) struct WaitQueue {
+        lock: Mutex<()>,
+        cvar: Condvar,
+    }
+
+    impl WaitQueue {
+        pub(crate) fn wait<T>(
+            &self,
+            deadline: Option<Instant>,
+            mut check: impl FnMut() -> Option<T>,
+        ) -> Option<T> {
+            let mut lock = self.lock.lock();
+            loop {
+                if let Some(v) = check() {
+                    return Some(v);
+                }
+
+                match deadline {
+                    Some(deadline) => {
+                        if self.cvar.wait_until(&mut lock, deadline).timed_out() {
+                            return check();
+                        }
+                    }
+                    None => self.cvar.wait(&mut lock),
+                }
+            }
+        }
+
+        pub(crate) fn notify(&self, n: usize) {
+            drop(self.lock.lock());
+            match n {
+                0 => {}
+                1 => {
+                    self.cvar.notify_one();
+                }
+                _ => {
+                    self.cvar.notify_all();
+                }
+            }
+        }
+    }
+}
+
+#[cfg(loom)]
+mod imp {
+    use std::time::{Duration, Instant};
+
+    use loom::sync::{Condvar, Mutex};
+
+    #[derive(Default)]
+    pub(crate) struct WaitQueue {
+        lock: Mutex<()>,
+        cvar: Condvar,
+    }
+
+    impl WaitQueue {
+        /// Loom doesn't model time, so a timed wait is a spurious wakeup that may or may not time
+        /// out
+        pub(crate) fn wait<T>(
+            &self,
+            deadline: Option<Instant>,
+            mut check: impl FnMut() -> Option<T>,
+        ) -> Option<T> {
+            let mut lock = self.lock.lock().unwrap();
+            loop {
+                if let Some(v) = check() {
+                    return Some(v);
+                }
+
+                match deadline {
+                    Some(_) => {
+                        let (l, res) = self.cvar.wait_timeout(lock, Duration::ZERO).unwrap();
+                        if res.timed_out() {
+                            drop(l);
+                            return check();
+                        }
+                        lock = l;
+                    }
+                    None => lock = self.cvar.wait(lock).unwrap(),
+                }
+            }
+        }
+
+        pub(crate) fn notify(&self, n: usize) {
+            drop(self.lock.lock().unwrap());
+            match n {
+                0 => {}
+                1 => self.cvar.notify_one(),
+                _ => self.cvar.notify_all(),
+            }
+        }
+    }
+}
+
+#[cfg(all(not(loom), feature = "futex", target_os = "linux"))]
+mod imp {
+    use std::{
+        ptr,
+        sync::atomic::{AtomicU32, Ordering},
+        time::Instant,
+    };
+
+    /// Waiters sleep on a sequence number bumped by every notification, so a notification between
+    /// reading it and the `FUTEX_WAIT` makes the wait return immediately instead of being lost.
+    #[derive(Default)]
+    pub(crate) struct WaitQueue {
+        seq: AtomicU32,
+    }
+
+    impl WaitQueue {
+        pub(crate) fn wait<T>(
+            &self,
+            deadline: Option<Instant>,
+            mut check: impl FnMut() -> Option<T>,
+        ) -> Option<T> {
+            loop {
+                let seq = self.seq.load(Ordering::SeqCst);
+                if let Some(v) = check() {
+                    return Some(v);
+                }
+
+                let timeout = match deadline {
+                    Some(deadline) => {
+                        let remaining = deadline.saturating_duration_since(Instant::now());
+                        if remaining.is_zero() {
+                            return check();
+                        }
+
+                        Some(libc::timespec {
+                            tv_sec: remaining.as_secs().min(libc::time_t::MAX as u64)
+                                as libc::time_t,
+                            tv_nsec: remaining.subsec_nanos() as libc::c_long,
+                        })
+                    }
+                    None => None,
+                };
+
+                // Spurious returns (EINTR, EAGAIN, ETIMEDOUT) all just loop back to the check
+                unsafe {
+                    libc::syscall(
+                        libc::SYS_futex,
+                        self.seq.as_ptr(),
+                        libc::FUTEX_WAIT | libc::FUTEX_PRIVATE_FLAG,
+                        seq,
+                        timeout
+                            .as_ref()
+                            .map_or(ptr::null(), |t| t as *const libc::timespec),
+                    );
+                }
+            }
+        }
+
+        pub(crate) fn notify(&self, n: usize) {
+            if n == 0 {
+                return;
+            }
+
+            self.seq.fetch_add(1, Ordering::SeqCst);
+            unsafe {
+                libc::syscall(
+                    libc::SYS_futex,
+                    self.seq.as_ptr(),
+                    libc::FUTEX_WAKE | libc::FUTEX_PRIVATE_FLAG,
+                    n.min(i32::MAX as usize) as libc::c_int,
+                );
+            }
+        }
+    }
+}
+
+#[cfg(all(loom, test))]
+mod tests {
+    use std::time::Duration;
+
+    use loom::{sync::Arc, thread};
+
+    use crate::Semaphore;
+
+    /// Both acquirers have to be woken by the releases, whichever order it all happens in. Bounded
+    /// as the full model of three threads takes too long to check
+    #[test]
+    fn two_acquirers_one_releaser() {
+        let mut builder = loom::model::Builder::new();
+        builder.preemption_bound.get_or_insert(4);
+        builder.check(|| {
+            let sem = Arc::new(Semaphore::new(0));
+            let acquirers: Vec<_> = (0..2)
+                .map(|_| {
+                    let sem = Arc::clone(&sem);
+                    thread::spawn(move || sem.acquire())
+                })
+                .collect();
+
+            sem.release();
+            sem.release();
+            for acquirer in acquirers {
+                acquirer.join().unwrap();
+            }
+            assert_eq!(sem.count(), 0);
+        });
+    }
+
+    /// Only one of two racing `try_acquire`s gets the single permit
+    #[test]
+    fn try_acquire_race() {
+        loom::model(|| {
+            let sem = Arc::new(Semaphore::new(1));
+            let other = thread::spawn({
+                let sem = Arc::clone(&sem);
+                move || sem.try_acquire()
+            });
+
+            let acquired = sem.try_acquire();
+            assert!(acquired ^ other.join().unwrap());
+            assert_eq!(sem.count(), 0);
+        });
+    }
+
+    /// A timed acquire either gets the released permit or leaves it behind, it's never lost
+    #[test]
+    fn timed_acquire() {
+        loom::model(|| {
+            let sem = Arc::new(Semaphore::new(0));
+            let acquirer = thread::spawn({
+                let sem = Arc::clone(&sem);
+                move || sem.acquire_timeout(Duration::from_secs(1))
+            });
+
+            sem.release();
+            let acquired = acquirer.join().unwrap();
+            assert_eq!(sem.count(), if acquired { 0 } else { 1 });
+        });
+    }
+}
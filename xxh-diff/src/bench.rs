@@ -0,0 +1,112 @@
+//! `--bench`, which hashes as normal but only measures how fast it went
+
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use crate::{data_fmt::HashResult, progress::fmt_bytes};
+
+/// Changes to a pool's thread count this close to the last are merged into it, other than into the
+/// count it started with
+const MERGE: Duration = Duration::from_millis(100);
+
+struct ThreadCount {
+    at: Duration,
+    pool: usize,
+    threads: u32,
+    first: bool,
+}
+
+/// Where a pool records its thread count as it changes
+#[derive(Clone)]
+pub struct ThreadLog {
+    pool: usize,
+    start: Instant,
+    counts: Arc<Mutex<Vec<ThreadCount>>>,
+}
+
+impl ThreadLog {
+    pub fn record(&self, threads: u32) {
+        let at = self.start.elapsed();
+        let mut counts = self.counts.lock().unwrap();
+        match counts.iter_mut().rev().find(|c| c.pool == self.pool) {
+            Some(last) if !last.first && at - last.at < MERGE => last.threads = threads,
+            last => {
+                let first = last.is_none();
+                counts.push(ThreadCount {
+                    at,
+                    pool: self.pool,
+                    threads,
+                    first,
+                })
+            }
+        }
+    }
+}
+
+pub struct Bench {
+    start: Instant,
+    files: u64,
+    bytes: u64,
+    counts: Arc<Mutex<Vec<ThreadCount>>>,
+}
+
+impl Bench {
+    pub fn new() -> Self {
+        Self {
+            start: Instant::now(),
+            files: 0,
+            bytes: 0,
+            counts: Arc::default(),
+        }
+    }
+
+    /// The log for the `pool`th pool's thread count
+    pub fn thread_log(&self, pool: usize) -> ThreadLog {
+        ThreadLog {
+            pool,
+            start: self.start,
+            counts: Arc::clone(&self.counts),
+        }
+    }
+
+    pub fn hashed(&mut self, results: &[HashResult]) {
+        self.files += results.len() as u64;
+        self.bytes += results.iter().map(|r| r.len).sum::<u64>();
+    }
+
+    /// Prints the figures, `pools` naming the pools by their filesystems
+    pub fn report(&self, algorithm: &str, pools: &[String], interrupted: bool) {
+        let secs = self.start.elapsed().as_secs_f64();
+        match interrupted {
+            true => println!("Bench results (interrupted)"),
+            false => println!("Bench results"),
+        }
+        println!("Algorithm: {}", algorithm);
+        println!("Files hashed: {}", self.files);
+        println!(
+            "Bytes hashed: {} ({} bytes)",
+            fmt_bytes(self.bytes as f64),
+            self.bytes
+        );
+        println!("Wall time: {:.2}s", secs);
+        println!("Throughput: {}/s", fmt_bytes(self.bytes as f64 / secs));
+        if self.files > 0 {
+            println!(
+                "Average file size: {}",
+                fmt_bytes(self.bytes as f64 / self.files as f64)
+            );
+        }
+
+        println!("Thread count timeline:");
+        for count in self.counts.lock().unwrap().iter() {
+            println!(
+                "  {:>8.2}s  {}  {}",
+                count.at.as_secs_f64(),
+                pools[count.pool],
+                count.threads
+            );
+        }
+    }
+}
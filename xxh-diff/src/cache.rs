@@ -0,0 +1,114 @@
+//! How files being hashed use the OS page cache, so hashing a large tree doesn't have to push
+//! everything else out of it
+
+use std::{
+    fs::{File, OpenOptions},
+    io,
+    path::Path,
+};
+
+use clap::ValueEnum;
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CacheMode {
+    /// Left to the OS
+    Normal,
+    /// Read ahead, and drop what's been hashed from the cache once a file is done
+    DropBehind,
+    /// Bypass the cache entirely, where the filesystem supports it. Elsewhere it's drop-behind
+    Direct,
+}
+
+/// Alignment of the read buffer, direct reads need it to be a multiple of the block size
+pub const BUF_ALIGN: usize = 4096;
+
+/// The `len` bytes of `buf` starting at its first [`BUF_ALIGN`] aligned byte. `buf` needs to be
+/// [`BUF_ALIGN`] bigger than `len`
+pub fn aligned(buf: &mut [u8], len: usize) -> &mut [u8] {
+    let offset = buf.as_ptr().align_offset(BUF_ALIGN);
+    &mut buf[offset..offset + len]
+}
+
+/// Opened non-blocking, which regular files ignore, so a FIFO that's taken a file's place doesn't
+/// block opening it
+#[cfg(unix)]
+pub fn open(path: &Path, mode: CacheMode) -> io::Result<File> {
+    use std::os::unix::fs::OpenOptionsExt;
+
+    let file = match mode {
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        CacheMode::Direct => {
+            match OpenOptions::new()
+                .read(true)
+                .custom_flags(libc::O_DIRECT | libc::O_NONBLOCK)
+                .open(path)
+            {
+                // Not supported by the filesystem, such as tmpfs
+                Err(e) if e.raw_os_error() == Some(libc::EINVAL) => {
+                    return open(path, CacheMode::DropBehind)
+                }
+                res => return res,
+            }
+        }
+        _ => OpenOptions::new()
+            .read(true)
+            .custom_flags(libc::O_NONBLOCK)
+            .open(path)?,
+    };
+
+    if mode != CacheMode::Normal {
+        fadvise(&file, 0, Advice::Sequential);
+    }
+    Ok(file)
+}
+
+#[cfg(windows)]
+pub fn open(path: &Path, mode: CacheMode) -> io::Result<File> {
+    use std::os::windows::fs::OpenOptionsExt;
+
+    const FILE_FLAG_SEQUENTIAL_SCAN: u32 = 0x0800_0000;
+    const FILE_FLAG_NO_BUFFERING: u32 = 0x2000_0000;
+
+    let flags = match mode {
+        CacheMode::Normal => 0,
+        CacheMode::DropBehind => FILE_FLAG_SEQUENTIAL_SCAN,
+        CacheMode::Direct => FILE_FLAG_NO_BUFFERING,
+    };
+    OpenOptions::new().read(true).custom_flags(flags).open(path)
+}
+
+/// Called once the first `len` bytes of `file` have been hashed
+pub fn done(file: &File, mode: CacheMode, len: u64) {
+    #[cfg(unix)]
+    if mode != CacheMode::Normal {
+        fadvise(file, len, Advice::DontNeed);
+    }
+    #[cfg(windows)]
+    let _ = (file, mode, len);
+}
+
+#[cfg(unix)]
+enum Advice {
+    Sequential,
+    DontNeed,
+}
+
+/// Advice on how the first `len` bytes of `file` will be used, 0 for all of it. It's only
+/// advice, so failing is fine
+#[cfg(unix)]
+fn fadvise(file: &File, len: u64, advice: Advice) {
+    #[cfg(any(target_os = "linux", target_os = "android", target_os = "freebsd"))]
+    {
+        use std::os::fd::AsRawFd;
+
+        let advice = match advice {
+            Advice::Sequential => libc::POSIX_FADV_SEQUENTIAL,
+            Advice::DontNeed => libc::POSIX_FADV_DONTNEED,
+        };
+        let len = libc::off_t::try_from(len).unwrap_or(0);
+        // Safety: only takes the descriptor, which `file` keeps open
+        unsafe { libc::posix_fadvise(file.as_raw_fd(), 0, len, advice) };
+    }
+    #[cfg(not(any(target_os = "linux", target_os = "android", target_os = "freebsd")))]
+    let _ = (file, len, advice);
+}
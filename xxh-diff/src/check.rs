@@ -0,0 +1,219 @@
+use std::{
+    io::{self, ErrorKind, Write},
+    path::PathBuf,
+    process,
+    time::Duration,
+};
+
+use clap::Args;
+use hashbrown::HashSet;
+
+use crate::data_fmt::{DataErr, Footer, ReadStatus, ReadXxhDiffDataInner, XxhDiffData};
+
+#[derive(Args, Debug)]
+pub struct CheckArgs {
+    data_file: String,
+}
+
+/// The exit code for a file that's readable but wasn't cleanly closed
+const DIRTY_EXIT_CODE: i32 = 2;
+
+/// What reading a whole data file found
+struct Scan {
+    records: u64,
+    duplicates: u64,
+    /// Corrupt sections skipped over
+    skipped: u64,
+    truncated_at: Option<u64>,
+    footer: Option<Footer>,
+    /// Of the bytes the footer's checksum covers, as they are now
+    checksum: u32,
+    /// Without a footer, or stopped at a partial record
+    dirty: bool,
+}
+
+impl Scan {
+    /// A file without a footer has nothing to match
+    fn footer_matches(&self) -> bool {
+        self.footer
+            .is_none_or(|f| f.records == self.records && f.checksum == self.checksum)
+    }
+
+    fn is_corrupt(&self) -> bool {
+        self.skipped > 0 || !self.footer_matches()
+    }
+}
+
+fn scan(data: &mut XxhDiffData) -> Result<Scan, String> {
+    let mut records = 0;
+    let mut duplicates = 0;
+    let mut paths = HashSet::new();
+    loop {
+        match data.read_skip_corrupt() {
+            Ok(record) => {
+                records += 1;
+                if !paths.insert(record.path().to_path_buf()) {
+                    duplicates += 1;
+                }
+            }
+            Err(DataErr::Empty) => break,
+            Err(e) => return Err(format!("Error reading from data file: {}", e)),
+        }
+    }
+    drop(paths);
+
+    let checksum = data
+        .checksum()
+        .map_err(|e| format!("Error reading from data file: {}", e))?;
+    let (skipped, dirty) = match &*data {
+        XxhDiffData::Read(
+            _,
+            ReadXxhDiffDataInner {
+                skipped, status, ..
+            },
+        ) => (*skipped, matches!(status, ReadStatus::Dirty)),
+        XxhDiffData::Write(..) => (0, false),
+    };
+
+    Ok(Scan {
+        records,
+        duplicates,
+        skipped,
+        truncated_at: data.truncated_at(),
+        footer: data.footer(),
+        checksum,
+        dirty,
+    })
+}
+
+pub fn check(args: CheckArgs) -> Result<(), String> {
+    let mut data = match XxhDiffData::open(&PathBuf::from(&args.data_file), Duration::ZERO) {
+        Ok(d) => d,
+        Err(e) if matches!(e.kind(), DataErr::IOErr(e) if e.kind() == ErrorKind::NotFound) => {
+            return Err("Data file not found".to_string())
+        }
+        Err(e) => return Err(format!("Error opening data file: {}", e)),
+    };
+    let scan = scan(&mut data)?;
+
+    println!("Records: {}", scan.records);
+    println!("Duplicate paths: {}", scan.duplicates);
+    println!("Corrupt sections: {}", scan.skipped);
+    match scan.truncated_at {
+        Some(offset) => println!("Partial record at the end: at byte {}", offset),
+        None => println!("Partial record at the end: none"),
+    }
+    match scan.footer {
+        Some(_) if scan.footer_matches() => println!("Footer: matches"),
+        Some(footer) => println!(
+            "Footer: doesn't match, it has {} records with checksum {:08x} but there are {} with checksum {:08x}",
+            footer.records, footer.checksum, scan.records, scan.checksum
+        ),
+        None => println!("Footer: none, the file wasn't cleanly closed"),
+    }
+
+    if scan.is_corrupt() {
+        return Err("Data file is corrupt".to_string());
+    }
+    if scan.dirty {
+        let _ = io::stdout().flush();
+        process::exit(DIRTY_EXIT_CODE);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, path::Path};
+
+    use super::*;
+    use crate::data_fmt::{HashAlgorithm, HashResult, HashValue};
+
+    /// The footer's marker, record count and checksum, then its own checksum
+    const FOOTER_SIZE: usize = 1 + 8 + 4 + 4;
+
+    /// Writes a cleanly closed file with `/a`, `/b` and `/a` again
+    fn write_data(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("xxh-diff-{}-{}", process::id(), name));
+        let _ = fs::remove_file(&path);
+        let hashed = |path: &str| HashResult {
+            path: PathBuf::from(path),
+            hash: HashValue::U64(1),
+            len: 2,
+            mtime: 3,
+            chunks: None,
+            hashed_at: 4,
+        };
+        let mut data =
+            XxhDiffData::new(&path, false, None, HashAlgorithm::default(), Duration::ZERO).unwrap();
+        data.write(&[&hashed("/a"), &hashed("/b"), &hashed("/a")])
+            .unwrap();
+        data.close().unwrap();
+        path
+    }
+
+    fn scan_file(path: &Path) -> Scan {
+        let mut data = XxhDiffData::open(path, Duration::ZERO).unwrap();
+        let scan = scan(&mut data).unwrap();
+        data.close().unwrap();
+        scan
+    }
+
+    #[test]
+    fn clean_round_trip() {
+        let path = write_data("check-clean.xxhd");
+        let scan = scan_file(&path);
+        assert_eq!((scan.records, scan.duplicates, scan.skipped), (3, 1, 0));
+        assert_eq!(
+            scan.footer.map(|f| (f.records, f.checksum)),
+            Some((3, scan.checksum))
+        );
+        assert!(!scan.dirty && !scan.is_corrupt());
+        fs::remove_file(path).unwrap();
+    }
+
+    /// A record failing its CRC is skipped, and leaves the file not matching its footer
+    #[test]
+    fn corrupt_record_rejected() {
+        let path = write_data("check-record.xxhd");
+        let mut bytes = fs::read(&path).unwrap();
+        let b = bytes.windows(2).position(|w| w == b"/b").unwrap();
+        bytes[b + 1] ^= 1;
+        fs::write(&path, &bytes).unwrap();
+
+        let scan = scan_file(&path);
+        assert_eq!((scan.records, scan.skipped), (2, 1));
+        assert!(!scan.footer_matches());
+        assert!(scan.is_corrupt());
+        fs::remove_file(path).unwrap();
+    }
+
+    /// A footer failing its own CRC isn't trusted, it's a corrupt section after the records. One
+    /// that passes it but doesn't match the records is corruption too
+    #[test]
+    fn corrupt_footer_rejected() {
+        let path = write_data("check-footer.xxhd");
+        let clean = fs::read(&path).unwrap();
+        let footer = clean.len() - FOOTER_SIZE;
+
+        let mut bytes = clean.clone();
+        bytes[footer + 1] ^= 1;
+        fs::write(&path, &bytes).unwrap();
+        let scan = scan_file(&path);
+        assert!(scan.footer.is_none());
+        assert_eq!((scan.records, scan.skipped), (3, 1));
+        assert!(scan.is_corrupt());
+
+        let mut bytes = clean;
+        bytes[footer + 1] ^= 1;
+        let footer_checksum = crc32fast::hash(&bytes[footer..bytes.len() - 4]);
+        let len = bytes.len();
+        bytes[len - 4..].copy_from_slice(&footer_checksum.to_le_bytes());
+        fs::write(&path, &bytes).unwrap();
+        let scan = scan_file(&path);
+        assert_eq!(scan.footer.map(|f| f.records), Some(2));
+        assert_eq!((scan.records, scan.skipped), (3, 0));
+        assert!(scan.is_corrupt());
+        fs::remove_file(path).unwrap();
+    }
+}
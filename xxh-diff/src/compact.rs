@@ -0,0 +1,104 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use clap::Args;
+use hashbrown::HashMap;
+
+use crate::data_fmt::{
+    DataErr, HashAlgorithm, HashResult, ReadXxhDiffDataInner, Record, XxhDiffData,
+};
+
+#[derive(Args, Debug)]
+pub struct CompactArgs {
+    data_file: String,
+}
+
+pub fn compact(args: CompactArgs) -> Result<(), String> {
+    compact_file(&PathBuf::from(args.data_file))
+}
+
+/// Rewrites `path` keeping only the last record for each path, followed by an index of them. The
+/// new file is written next to it and renamed over it once complete, so an interrupted compaction
+/// leaves the original untouched
+pub fn compact_file(path: &Path) -> Result<(), String> {
+    let before = fs::metadata(path)
+        .map_err(|e| format!("Error reading data file metadata: {}", e))?
+        .len();
+
+    // Locked exclusively until it's replaced, so no records can be appended in the meantime
+    let mut data = XxhDiffData::new(path, true, None, HashAlgorithm::default(), Duration::ZERO)
+        .map_err(|e| format!("Error opening data file: {}", e))?;
+    // `None` for records that were deleted
+    let mut records: Vec<Option<HashResult>> = Vec::new();
+    let mut indices = HashMap::new();
+    let mut superseded = 0;
+    loop {
+        match data.read_skip_corrupt() {
+            Ok(Record::Hash(result)) => match indices.get(&result.path) {
+                Some(&i) => {
+                    records[i] = Some(result);
+                    superseded += 1;
+                }
+                None => {
+                    indices.insert(result.path.clone(), records.len());
+                    records.push(Some(result));
+                }
+            },
+            // The tombstone goes along with the record it deletes, a later record for the path
+            // starts afresh
+            Ok(Record::Deleted(path)) => {
+                if let Some(i) = indices.remove(&path) {
+                    records[i] = None;
+                    superseded += 1;
+                }
+                superseded += 1;
+            }
+            Err(DataErr::Empty) => break,
+            Err(e) => return Err(format!("Error reading from data file: {}", e)),
+        }
+    }
+    drop(indices);
+
+    let root = data.root().map(Path::to_path_buf);
+    let algorithm = data.algorithm();
+    if let XxhDiffData::Read(_, ReadXxhDiffDataInner { skipped, .. }) = data {
+        if skipped > 0 {
+            eprintln!(
+                "Warning: Dropping {} corrupt section(s) of the data file",
+                skipped
+            );
+        }
+    }
+
+    let mut tmp_path = path.as_os_str().to_owned();
+    tmp_path.push(".compact-tmp");
+    let tmp_path = PathBuf::from(tmp_path);
+
+    let mut compacted = XxhDiffData::reset(&tmp_path, root.as_deref(), algorithm, Duration::ZERO)
+        .map_err(|e| format!("Error creating compacted data file: {}", e))?;
+    compacted
+        .write_indexed(&records.iter().flatten().collect::<Vec<_>>())
+        .map_err(|e| format!("Error writing compacted data file: {}", e))?;
+    compacted
+        .close()
+        .map_err(|e| format!("Error writing compacted data file: {}", e))?;
+
+    fs::rename(&tmp_path, path)
+        .map_err(|e| format!("Error replacing data file with compacted file: {}", e))?;
+    drop(data);
+
+    let after = fs::metadata(path)
+        .map_err(|e| format!("Error reading data file metadata: {}", e))?
+        .len();
+    eprintln!(
+        "Compacted {}: {} -> {} bytes, removed {} superseded or deleted record(s)",
+        path.display(),
+        before,
+        after,
+        superseded
+    );
+    Ok(())
+}
@@ -1,20 +1,309 @@
 use std::{
+    error::Error,
     fmt::Display,
     fmt::{self, Formatter},
-    fs::File,
-    io::{self, ErrorKind, Read, Seek, SeekFrom, Write},
-    mem::MaybeUninit,
+    fs::{File, Metadata, TryLockError},
+    io::{self, BufReader, ErrorKind, Read, Seek, SeekFrom, Write},
+    mem,
     path::{Path, PathBuf},
+    thread,
+    time::{Duration, Instant, UNIX_EPOCH},
 };
 
-use crate::raw_path_bytes::RawPathBytes;
+use clap::ValueEnum;
+use crc32fast::Hasher;
+use hashbrown::HashMap;
+#[cfg(feature = "encrypt")]
+use sequoia_openpgp::Cert;
+use twox_hash::XxHash64;
 
-#[derive(Debug)]
-pub struct HashResult(pub PathBuf, pub u64);
+#[cfg(feature = "encrypt")]
+use crate::encrypt::{self, Encryptor};
+
+use crate::raw_path_bytes::{
+    unix_to_portable, utf16le_to_portable, PortablePathBytes, RawPathBytes,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HashValue {
+    U64(u64),
+    U128(u128),
+    /// A digest's bytes in the order it's made, as blake3 and sha256 are hex encoded
+    U256([u8; 32]),
+}
+
+impl Display for HashValue {
+    /// Zero padded hex
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::U64(h) => write!(f, "{:016x}", h),
+            Self::U128(h) => write!(f, "{:032x}", h),
+            Self::U256(h) => h.iter().try_for_each(|b| write!(f, "{:02x}", b)),
+        }
+    }
+}
+
+impl HashValue {
+    pub fn write_le(&self, buf: &mut Vec<u8>) {
+        match self {
+            Self::U64(h) => buf.extend_from_slice(&h.to_le_bytes()),
+            Self::U128(h) => buf.extend_from_slice(&h.to_le_bytes()),
+            Self::U256(h) => buf.extend_from_slice(h),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct HashResult {
+    pub path: PathBuf,
+    pub hash: HashValue,
+    pub len: u64,
+    /// Nanoseconds since the unix epoch, [`UNKNOWN_MTIME`] for records written before version 4
+    pub mtime: i64,
+    pub chunks: Option<ChunkHashes>,
+    /// Seconds since the unix epoch the file was hashed at, [`UNKNOWN_HASHED_AT`] for records
+    /// written before version 14 and imported ones
+    pub hashed_at: i64,
+}
+
+pub const UNKNOWN_MTIME: i64 = i64::MIN;
+pub const UNKNOWN_HASHED_AT: i64 = i64::MIN;
+
+impl HashResult {
+    pub fn has_metadata(&self) -> bool {
+        self.mtime != UNKNOWN_MTIME
+    }
+
+    /// Whether the file this was hashed from still has the same size and mtime
+    pub fn metadata_matches(&self, len: u64, mtime: i64) -> bool {
+        self.has_metadata() && self.len == len && self.mtime == mtime
+    }
+}
+
+/// Hashes of a file's `size` byte blocks in order, made with the same algorithm as its hash. The
+/// last block is short unless the file's length is a multiple of `size`
+#[derive(Debug, Clone)]
+pub struct ChunkHashes {
+    pub size: u64,
+    pub hashes: Vec<HashValue>,
+}
+
+impl ChunkHashes {
+    /// The number of chunks that differ from `old`'s, and the byte ranges they cover, joined where
+    /// they're next to each other. A chunk only one of them has differs, `len` is the length of the
+    /// file these are for. `None` if they weren't hashed in the same size blocks
+    pub fn diff(&self, old: &ChunkHashes, len: u64) -> Option<(usize, Vec<(u64, u64)>)> {
+        if self.size != old.size {
+            return None;
+        }
+
+        let mut changed = 0;
+        let mut ranges: Vec<(u64, u64)> = Vec::new();
+        for i in 0..self.hashes.len().max(old.hashes.len()) {
+            if self.hashes.get(i).is_some() && self.hashes.get(i) == old.hashes.get(i) {
+                continue;
+            }
+            changed += 1;
+
+            let start = i as u64 * self.size;
+            let end = match i < self.hashes.len() {
+                true => (start + self.size).min(len),
+                // Past the end of the file since it shrank
+                false => start + self.size,
+            };
+            match ranges.last_mut() {
+                Some(last) if last.1 == start => last.1 = end,
+                _ => ranges.push((start, end)),
+            }
+        }
+        Some((changed, ranges))
+    }
+}
+
+pub fn file_mtime(metadata: &Metadata) -> i64 {
+    match metadata.modified().map(|m| m.duration_since(UNIX_EPOCH)) {
+        Ok(Ok(d)) => d.as_nanos().try_into().unwrap_or(UNKNOWN_MTIME),
+        Ok(Err(e)) => i64::try_from(e.duration().as_nanos()).map_or(UNKNOWN_MTIME, |n| -n),
+        Err(_) => UNKNOWN_MTIME,
+    }
+}
+
+/// The time for [`HashResult::hashed_at`] of a file hashed now
+pub fn hashed_now() -> i64 {
+    UNIX_EPOCH
+        .elapsed()
+        .map_or(UNKNOWN_HASHED_AT, |d| d.as_secs() as i64)
+}
+
+pub enum Record {
+    Hash(HashResult),
+    /// Tombstone for a path whose earlier records are for a file that has since been deleted
+    Deleted(PathBuf),
+}
+
+impl Record {
+    pub fn path(&self) -> &Path {
+        match self {
+            Self::Hash(result) => &result.path,
+            Self::Deleted(path) => path,
+        }
+    }
+
+    fn path_mut(&mut self) -> &mut PathBuf {
+        match self {
+            Self::Hash(result) => &mut result.path,
+            Self::Deleted(path) => path,
+        }
+    }
+}
+
+/// What [`write_records`] writes
+enum RecordRef<'a> {
+    Hash(&'a HashResult),
+    Deleted(&'a Path),
+}
+
+/// What a data file's hashes were made with. Files bigger than `split` are hashed as ranges of
+/// that many bytes, and their hash is the hash of the ranges' little endian hashes in order
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum HashAlgorithm {
+    Xxh64 {
+        seed: u64,
+        split: Option<u64>,
+    },
+    /// XXH3 with a 128-bit hash
+    Xxh128 {
+        seed: u64,
+        split: Option<u64>,
+    },
+    /// XXH3 with a 64-bit hash
+    Xxh3 {
+        seed: u64,
+        split: Option<u64>,
+    },
+    /// Unseeded, the header's seed is always 0
+    Blake3 {
+        split: Option<u64>,
+    },
+    /// Unseeded, the header's seed is always 0
+    Sha256 {
+        split: Option<u64>,
+    },
+}
+
+impl HashAlgorithm {
+    /// The width of the hashes, which can't be compared at all against a different width
+    pub fn bits(&self) -> u32 {
+        match self {
+            Self::Xxh64 { .. } | Self::Xxh3 { .. } => u64::BITS,
+            Self::Xxh128 { .. } => u128::BITS,
+            Self::Blake3 { .. } | Self::Sha256 { .. } => U256_BYTES * 8,
+        }
+    }
+
+    pub fn split(&self) -> Option<u64> {
+        match self {
+            Self::Xxh64 { split, .. }
+            | Self::Xxh128 { split, .. }
+            | Self::Xxh3 { split, .. }
+            | Self::Blake3 { split }
+            | Self::Sha256 { split } => *split,
+        }
+    }
+}
+
+/// What every file written before version 8 was hashed with
+impl Default for HashAlgorithm {
+    fn default() -> Self {
+        Self::Xxh64 {
+            seed: 0,
+            split: None,
+        }
+    }
+}
+
+impl Display for HashAlgorithm {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Xxh64 { seed: 0, .. } => write!(f, "xxh64"),
+            Self::Xxh64 { seed, .. } => write!(f, "xxh64 with seed {}", seed),
+            Self::Xxh128 { seed: 0, .. } => write!(f, "xxh3-128"),
+            Self::Xxh128 { seed, .. } => write!(f, "xxh3-128 with seed {}", seed),
+            Self::Xxh3 { seed: 0, .. } => write!(f, "xxh3"),
+            Self::Xxh3 { seed, .. } => write!(f, "xxh3 with seed {}", seed),
+            Self::Blake3 { .. } => write!(f, "blake3"),
+            Self::Sha256 { .. } => write!(f, "sha256"),
+        }?;
+        match self.split() {
+            Some(split) => write!(f, ", split into {} MiB ranges", split / (1024 * 1024)),
+            None => Ok(()),
+        }
+    }
+}
+
+/// What a data file's paths are stored as
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum PathEncoding {
+    /// Unix's raw path bytes, implied for files written on Unix before version 10
+    Unix,
+    /// Windows' UTF-16LE path bytes, implied for files written on Windows before version 10
+    Utf16Le,
+    /// [`PortablePathBytes`], written since version 10
+    Portable,
+}
+
+impl PathEncoding {
+    /// What files written before version 10 have, which can't be told apart by anything but where
+    /// they were written
+    #[cfg(unix)]
+    const NATIVE: Self = Self::Unix;
+    #[cfg(windows)]
+    const NATIVE: Self = Self::Utf16Le;
+
+    /// The other platform's native encoding can only be read
+    fn is_writable(self) -> bool {
+        self == Self::Portable || self == Self::NATIVE
+    }
+
+    /// `None` if paths can't be written in this encoding
+    fn encode(self, path: &Path) -> Option<Vec<u8>> {
+        match self {
+            Self::Portable => Some(path.to_path_buf().to_portable_bytes()),
+            _ if self == Self::NATIVE => path.to_path_buf().try_as_bytes().ok(),
+            Self::Unix | Self::Utf16Le => None,
+        }
+    }
+
+    fn decode(self, bytes: Vec<u8>) -> Result<PathBuf, Vec<u8>> {
+        let portable = match self {
+            Self::Portable => return PathBuf::from_portable_bytes(bytes),
+            _ if self == Self::NATIVE => return PathBuf::try_from_bytes(bytes),
+            Self::Unix => Some(unix_to_portable(&bytes)),
+            Self::Utf16Le => utf16le_to_portable(&bytes),
+        };
+        match portable {
+            Some(portable) => PathBuf::from_portable_bytes(portable).map_err(|_| bytes),
+            None => Err(bytes),
+        }
+    }
+}
+
+/// When writes are waited on to reach the disk
+#[derive(ValueEnum, Clone, Copy, Debug)]
+pub enum SyncPolicy {
+    /// Left to the OS
+    Never,
+    /// After each call to write
+    Batch,
+    /// After every record
+    Always,
+}
 
 pub enum ReadStatus {
     Open,
     Stopped,
+    /// Stopped at the end of a file without a footer, which wasn't cleanly closed or predates them
+    Dirty,
     Error,
 }
 
@@ -30,221 +319,2868 @@ impl ReadStatus {
 
 pub struct ReadXxhDiffDataInner {
     pub status: ReadStatus,
+    /// Number of corrupt regions skipped by [`XxhDiffData::read_skip_corrupt`]
+    pub skipped: u64,
     initial_len: u64,
-    cursor_pos: Option<u64>,
+    /// Offset of the next record, tracked here rather than asked of the file for every record
+    pos: u64,
+    /// Set once a lookup has moved the buffered reader away from `pos` and it must seek back
+    reposition: bool,
+    /// Offset of the partial record reading stopped at, see [`DataWriter::truncate_to_valid`]
+    truncated_at: Option<u64>,
+    /// Where the header ends, the start of what the footer's checksum covers
+    records_start: u64,
+    /// What relative paths are joined to and written relative to
+    root: Option<PathBuf>,
+    algorithm: HashAlgorithm,
+    encoding: PathEncoding,
+    index: Option<Index>,
+    footer: Option<Footer>,
+    footer_state: FooterState,
+    /// Whether the file was opened for writing, only then is it synced on close
+    writable: bool,
+    /// For the context of errors
+    path: PathBuf,
+    sync: SyncPolicy,
+    /// The time of the last time marker read, for the records after it
+    hashed_at: i64,
+    /// The time of the last time marker written, `None` before one has been
+    marked_at: Option<i64>,
+}
+
+pub struct WriteXxhDiffDataInner {
+    /// What paths are written relative to
+    root: Option<PathBuf>,
+    algorithm: HashAlgorithm,
+    encoding: PathEncoding,
+    footer_state: FooterState,
+    /// For the context of errors
+    path: PathBuf,
+    sync: SyncPolicy,
+    /// The time of the last time marker written, `None` before one has been
+    marked_at: Option<i64>,
+}
+
+/// The footer a cleanly closed file ends with, after its records and any index
+#[derive(Debug, Clone, Copy)]
+pub struct Footer {
+    /// Including tombstones
+    pub records: u64,
+    /// CRC32 of every byte between the header and the footer
+    pub checksum: u32,
+    offset: u64,
+}
+
+/// What's needed to write the footer back once a file is closed
+#[derive(Default)]
+struct FooterState {
+    /// The number of records and a running checksum of everything after the header. `None` for a
+    /// file opened without a footer, which can't be vouched for without reading it all
+    tally: Option<(u64, u32)>,
+    /// Where the footer the file was opened with starts, it's cut off before anything is appended
+    stale_at: Option<u64>,
+}
+
+impl FooterState {
+    fn new_file() -> Self {
+        Self {
+            tally: Some((0, 0)),
+            stale_at: None,
+        }
+    }
+
+    fn from_footer(footer: Option<Footer>) -> Self {
+        match footer {
+            Some(footer) => Self {
+                tally: Some((footer.records, footer.checksum)),
+                stale_at: Some(footer.offset),
+            },
+            None => Self::default(),
+        }
+    }
+
+    /// Adds `bytes` written after the header to the checksum
+    fn update(&mut self, bytes: &[u8]) {
+        if let Some((_, checksum)) = &mut self.tally {
+            let mut hasher = Hasher::new_with_initial(*checksum);
+            hasher.update(bytes);
+            *checksum = hasher.finalize();
+        }
+    }
+}
+
+/// The index block written after the records by compaction, for [`XxhDiffData::lookup`]. Records
+/// appended later aren't in it, those are read into `tail` along with the index on first use
+struct Index {
+    offset: u64,
+    len: u64,
+    /// Boxed since most files are read through without ever loading it
+    loaded: Option<Box<LoadedIndex>>,
+}
+
+struct LoadedIndex {
+    /// Hashes of stored path bytes and the offsets of their records, sorted
+    entries: Vec<(u64, u64)>,
+    /// `None` for paths deleted since
+    tail: HashMap<PathBuf, Option<(HashValue, Option<ChunkHashes>)>>,
 }
 
 impl ReadXxhDiffDataInner {
-    fn new(file: &mut File) -> io::Result<Self> {
-        let initial_len = file.seek(SeekFrom::End(0))?;
-        let status = match initial_len {
-            0 => ReadStatus::Stopped,
-            _ => ReadStatus::Open,
+    /// `root` and `algorithm` are only used if the file is empty and it's `writable`. Leaves `file`
+    /// where the records start
+    fn new(
+        file: &mut PosFile,
+        path: &Path,
+        writable: bool,
+        root: Option<&Path>,
+        algorithm: HashAlgorithm,
+    ) -> Result<Self, DataErr> {
+        let mut initial_len = file.seek(SeekFrom::End(0)).map_err(DataErr::IOErr)?;
+        let mut footer_state = FooterState::default();
+        if initial_len == 0 {
+            if !writable {
+                return Ok(Self {
+                    status: ReadStatus::Dirty,
+                    skipped: 0,
+                    initial_len,
+                    pos: 0,
+                    reposition: false,
+                    truncated_at: None,
+                    records_start: 0,
+                    root: None,
+                    algorithm,
+                    encoding: PathEncoding::Portable,
+                    index: None,
+                    footer: None,
+                    footer_state,
+                    writable,
+                    path: path.to_path_buf(),
+                    sync: SyncPolicy::Never,
+                    hashed_at: UNKNOWN_HASHED_AT,
+                    marked_at: None,
+                });
+            }
+
+            // Appended to like a new file
+            write_header(&mut file.file, root, algorithm).map_err(DataErr::IOErr)?;
+            initial_len = file.seek(SeekFrom::End(0)).map_err(DataErr::IOErr)?;
+            footer_state = FooterState::new_file();
+        }
+        file.rewind().map_err(DataErr::IOErr)?;
+
+        let Header {
+            mut data_start,
+            root,
+            index_offset,
+            algorithm,
+            encoding,
+            footer,
+        } = read_header(file, initial_len)?;
+        let records_start = data_start;
+        if let Some(footer) = footer {
+            initial_len = footer.offset;
+            footer_state = FooterState::from_footer(Some(footer));
+        }
+
+        let index = match index_offset {
+            Some(offset) => {
+                let index = read_index_len(file, offset, initial_len)?;
+                if index.offset == data_start {
+                    data_start += index.len;
+                }
+                file.seek(SeekFrom::Start(data_start))
+                    .map_err(DataErr::IOErr)?;
+                Some(index)
+            }
+            None => None,
+        };
+
+        let status = match initial_len > data_start {
+            true => ReadStatus::Open,
+            false => end_status(footer),
         };
-        file.rewind()?;
 
         Ok(Self {
             status,
+            skipped: 0,
             initial_len,
-            cursor_pos: None,
+            pos: data_start,
+            reposition: false,
+            truncated_at: None,
+            records_start,
+            root,
+            algorithm,
+            encoding,
+            index,
+            footer,
+            footer_state,
+            writable,
+            path: path.to_path_buf(),
+            sync: SyncPolicy::Never,
+            hashed_at: UNKNOWN_HASHED_AT,
+            marked_at: None,
         })
     }
-}
 
-pub enum XxhDiffData {
-    Read(File, ReadXxhDiffDataInner),
-    Write(File),
-}
+    /// Reads the next record, see [`XxhDiffData::read_skip_corrupt`]
+    fn read(
+        &mut self,
+        file: &mut BufReader<PosFile>,
+        skip_corrupt: bool,
+    ) -> Result<Record, DataErr> {
+        let Self {
+            status,
+            skipped,
+            initial_len,
+            pos,
+            reposition,
+            truncated_at,
+            root,
+            algorithm,
+            encoding,
+            index,
+            footer,
+            hashed_at,
+            ..
+        } = self;
+        let hash_bits = algorithm.bits();
 
-const U64_BYTES: u32 = u64::BITS / 8;
-const USIZE_BYTES: u32 = usize::BITS / 8;
-const HEAD_SIZE: u32 = U64_BYTES + USIZE_BYTES;
+        if status.is_stop() {
+            return Err(DataErr::Empty);
+        }
 
-#[derive(Debug)]
-pub enum DataErr {
-    Empty,
-    IOErr(io::Error),
-    ParseErr(String),
-}
+        if *reposition {
+            if let Err(e) = file.seek(SeekFrom::Start(*pos)) {
+                *status = ReadStatus::Error;
+                return Err(DataErr::IOErr(e));
+            }
+            *reposition = false;
+        }
 
-impl Display for DataErr {
-    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
-        match self {
-            Self::Empty => write!(f, "No more data"),
-            Self::IOErr(e) => e.fmt(f),
-            Self::ParseErr(e) => write!(f, "{}", e),
+        let (record, relative) = loop {
+            match read_record(file, *pos, *initial_len, *encoding, hash_bits, hashed_at) {
+                Ok((r, len, relative)) => {
+                    *pos += len;
+                    break (r, relative);
+                }
+                // Only a partial record left by a run that was killed mid-write if no
+                // complete record follows it
+                Err(DataErr::Truncated { offset }) => {
+                    // Any time marker skipped over isn't known to not be there
+                    *hashed_at = UNKNOWN_HASHED_AT;
+                    match resync(file, offset + 1, *initial_len, *encoding, hash_bits) {
+                        Ok(None) => {
+                            *truncated_at = Some(offset);
+                            *status = ReadStatus::Dirty;
+                            return Err(DataErr::Empty);
+                        }
+                        Ok(Some(next)) if skip_corrupt => {
+                            *skipped += 1;
+                            *pos = next;
+                        }
+                        Ok(Some(_)) => {
+                            *status = ReadStatus::Error;
+                            return Err(DataErr::Corrupt { offset });
+                        }
+                        Err(e) => {
+                            *status = ReadStatus::Error;
+                            return Err(DataErr::IOErr(e));
+                        }
+                    }
+                }
+                Err(DataErr::Corrupt { offset }) if skip_corrupt => {
+                    *skipped += 1;
+                    *hashed_at = UNKNOWN_HASHED_AT;
+                    match resync(file, offset + 1, *initial_len, *encoding, hash_bits) {
+                        Ok(Some(next)) => *pos = next,
+                        Ok(None) => {
+                            *status = end_status(*footer);
+                            return Err(DataErr::Empty);
+                        }
+                        Err(e) => {
+                            *status = ReadStatus::Error;
+                            return Err(DataErr::IOErr(e));
+                        }
+                    }
+                }
+                Err(e) => {
+                    *status = ReadStatus::Error;
+                    return Err(e);
+                }
+            }
+        };
+
+        if let Some(index) = index.as_ref().filter(|i| i.offset == *pos) {
+            *pos += index.len;
+            if let Err(e) = file.seek_relative(index.len as i64) {
+                *status = ReadStatus::Error;
+                return Err(DataErr::IOErr(e));
+            }
+        }
+
+        if *pos >= *initial_len {
+            *status = end_status(*footer);
         }
+
+        resolve_path(record, relative, root.as_deref()).inspect_err(|_| {
+            *status = ReadStatus::Error;
+        })
     }
 }
 
-impl XxhDiffData {
-    pub fn new(path: &Path, read_required: bool) -> io::Result<Self> {
-        let mut opts = File::options();
-        let opts = opts
-            .append(true)
-            .create_new(!read_required)
-            .read(read_required);
-        match opts.open(path) {
-            Ok(file) => XxhDiffData::from_file(file, read_required),
-            Err(e) => match e.kind() {
-                ErrorKind::AlreadyExists => {
-                    let file = opts.read(true).create_new(false).open(path)?;
-                    XxhDiffData::from_file(file, true)
-                }
-                _ => Err(e),
-            },
-        }
+/// What reading stops with once there are no more records
+fn end_status(footer: Option<Footer>) -> ReadStatus {
+    match footer {
+        Some(_) => ReadStatus::Stopped,
+        None => ReadStatus::Dirty,
     }
+}
 
-    fn from_file(mut file: File, read: bool) -> io::Result<Self> {
-        match read {
-            true => {
-                let inner = ReadXxhDiffDataInner::new(&mut file)?;
-                Ok(Self::Read(file, inner))
-            }
-            false => Ok(Self::Write(file)),
-        }
+struct Header {
+    data_start: u64,
+    root: Option<PathBuf>,
+    index_offset: Option<u64>,
+    algorithm: HashAlgorithm,
+    encoding: PathEncoding,
+    footer: Option<Footer>,
+}
+
+/// Reads the header and any footer, leaving the file where the records start. Files written before
+/// the header was added start straight away with a record
+fn read_header(file: &mut (impl Read + Seek), len: u64) -> Result<Header, DataErr> {
+    // A header field at `offset` running past the end of the file
+    let truncated = |offset| DataErr::Truncated { offset };
+
+    let mut first = [0; 1];
+    file.read_exact(&mut first).map_err(DataErr::IOErr)?;
+    if is_valid_hlen(first[0], HashAlgorithm::default().bits()) {
+        file.rewind().map_err(DataErr::IOErr)?;
+        return Ok(Header {
+            data_start: 0,
+            root: None,
+            index_offset: None,
+            algorithm: HashAlgorithm::default(),
+            encoding: PathEncoding::NATIVE,
+            footer: None,
+        });
     }
 
-    pub fn reset(path: &Path) -> io::Result<Self> {
-        Ok(XxhDiffData::Write(
-            File::options()
-                .write(true)
-                .truncate(true)
-                .create(true)
-                .open(path)?,
-        ))
+    let mut header = [0; HEADER_SIZE as usize];
+    header[0] = first[0];
+    if len < HEADER_SIZE {
+        return Err(DataErr::BadMagic);
     }
+    file.read_exact(&mut header[1..]).map_err(DataErr::IOErr)?;
 
-    pub fn is_read(&self) -> bool {
-        matches!(self, Self::Read(..))
+    let (magic, version) = header.split_at(MAGIC.len());
+    if magic != MAGIC {
+        return Err(DataErr::BadMagic);
     }
 
-    pub fn read(&mut self) -> Result<HashResult, DataErr> {
-        match self {
-            Self::Write(_) => Err(DataErr::Empty),
-            Self::Read(
-                file,
-                ReadXxhDiffDataInner {
-                    status,
-                    initial_len,
-                    cursor_pos,
-                },
-            ) => {
-                if status.is_stop() {
-                    return Err(DataErr::Empty);
-                }
+    let version = u16::from_le_bytes(version.try_into().unwrap());
+    let mut data_start = HEADER_SIZE;
+    let index_offset = match version {
+        1..=5 => None,
+        6..=FORMAT_VERSION => {
+            data_start += INDEX_OFFSET_SIZE;
+            if len < data_start {
+                return Err(truncated(data_start - INDEX_OFFSET_SIZE));
+            }
+            let mut index_offset = [0; INDEX_OFFSET_SIZE as usize];
+            file.read_exact(&mut index_offset).map_err(DataErr::IOErr)?;
+            match u64::from_le_bytes(index_offset) {
+                0 => None,
+                offset => Some(offset),
+            }
+        }
+        version => return Err(DataErr::UnsupportedVersion(version)),
+    };
 
-                if let Some(cursor_pos) = cursor_pos.take() {
-                    if let Err(e) = file.seek(SeekFrom::Start(cursor_pos)) {
-                        *status = ReadStatus::Error;
-                        return Err(DataErr::IOErr(e));
-                    }
-                }
+    let algorithm = match version {
+        1..=7 => HashAlgorithm::default(),
+        _ => {
+            data_start += ALGORITHM_SIZE;
+            if len < data_start {
+                return Err(truncated(data_start - ALGORITHM_SIZE));
+            }
+            let mut algorithm = [0; ALGORITHM_SIZE as usize];
+            file.read_exact(&mut algorithm).map_err(DataErr::IOErr)?;
+            let (id, seed) = algorithm.split_at(1);
+            let seed = u64::from_le_bytes(seed.try_into().unwrap());
 
-                let mut hlen: MaybeUninit<[u8; 1]> = MaybeUninit::uninit();
-                let hlen = unsafe { hlen.assume_init_mut() };
-                if let Err(e) = file.read_exact(hlen) {
-                    *status = ReadStatus::Error;
-                    return Err(DataErr::IOErr(e));
+            let split = match version {
+                8..=12 => None,
+                _ => {
+                    data_start += SPLIT_SIZE;
+                    if len < data_start {
+                        return Err(truncated(data_start - SPLIT_SIZE));
+                    }
+                    let mut split = [0; SPLIT_SIZE as usize];
+                    file.read_exact(&mut split).map_err(DataErr::IOErr)?;
+                    match u64::from_le_bytes(split) {
+                        0 => None,
+                        split => Some(split),
+                    }
                 }
+            };
 
-                let mut head: Vec<u8> = vec![0; hlen[0] as usize];
-                if let Err(e) = file.read_exact(&mut head) {
-                    *status = ReadStatus::Error;
-                    return Err(DataErr::IOErr(e));
+            match id[0] {
+                XXH64_ID => HashAlgorithm::Xxh64 { seed, split },
+                XXH128_ID => HashAlgorithm::Xxh128 { seed, split },
+                XXH3_ID => HashAlgorithm::Xxh3 { seed, split },
+                BLAKE3_ID => HashAlgorithm::Blake3 { split },
+                SHA256_ID => HashAlgorithm::Sha256 { split },
+                id => {
+                    return Err(DataErr::ParseErr(format!(
+                        "Unknown hash algorithm {} in data file header",
+                        id
+                    )))
                 }
+            }
+        }
+    };
 
-                if head.len() != HEAD_SIZE as usize {
-                    *status = ReadStatus::Error;
+    let encoding = match version {
+        1..=9 => PathEncoding::NATIVE,
+        _ => {
+            data_start += PATH_ENCODING_SIZE;
+            if len < data_start {
+                return Err(truncated(data_start - PATH_ENCODING_SIZE));
+            }
+            let mut id = [0; PATH_ENCODING_SIZE as usize];
+            file.read_exact(&mut id).map_err(DataErr::IOErr)?;
+            match id[0] {
+                UNIX_PATHS_ID => PathEncoding::Unix,
+                UTF16LE_PATHS_ID => PathEncoding::Utf16Le,
+                PORTABLE_PATHS_ID => PathEncoding::Portable,
+                id => {
                     return Err(DataErr::ParseErr(format!(
-                        "Wrong number of bytes in head: {:?}",
-                        head
-                    )));
+                        "Unknown path encoding {} in data file header",
+                        id
+                    )))
                 }
+            }
+        }
+    };
 
-                let (hash_head, head_path_len) = head.split_at(U64_BYTES as usize);
-                let hash = u64::from_le_bytes(hash_head.try_into().unwrap());
-                let path_len = usize::from_le_bytes(head_path_len.try_into().unwrap());
+    let root = match version {
+        1..=4 => None,
+        _ => {
+            data_start += ROOT_LEN_SIZE;
+            if len < data_start {
+                return Err(truncated(data_start - ROOT_LEN_SIZE));
+            }
+            let mut root_len = [0; ROOT_LEN_SIZE as usize];
+            file.read_exact(&mut root_len).map_err(DataErr::IOErr)?;
+            let root_len = u32::from_le_bytes(root_len);
+            data_start += u64::from(root_len);
+            if len < data_start {
+                return Err(truncated(data_start - u64::from(root_len)));
+            }
 
-                let mut path_buf: Vec<u8> = vec![0; path_len];
-                if let Err(e) = file.read_exact(&mut path_buf) {
-                    *status = ReadStatus::Error;
-                    return Err(DataErr::IOErr(e));
+            match root_len {
+                0 => None,
+                _ => {
+                    let mut root = vec![0; root_len as usize];
+                    file.read_exact(&mut root).map_err(DataErr::IOErr)?;
+                    match encoding.decode(root) {
+                        Ok(root) => Some(root),
+                        Err(r) => {
+                            return Err(DataErr::ParseErr(format!(
+                                "Couldn't parse root path bytes {:?} to path buf",
+                                r
+                            )))
+                        }
+                    }
                 }
+            }
+        }
+    };
 
-                let path_buf = match PathBuf::try_from_bytes(path_buf) {
-                    Ok(p) => p,
-                    Err(p) => {
-                        *status = ReadStatus::Error;
-                        return Err(DataErr::ParseErr(format!(
-                            "Couldn't parse path bytes {:?} to path buf",
-                            p
-                        )));
-                    }
-                };
+    let footer = match version {
+        1..=11 => None,
+        _ => read_footer(file, data_start, len)?,
+    };
 
-                let pos = match file.stream_position() {
-                    Ok(p) => p,
-                    Err(e) => {
-                        *status = ReadStatus::Error;
-                        return Err(DataErr::IOErr(e));
-                    }
-                };
+    Ok(Header {
+        data_start,
+        root,
+        index_offset,
+        algorithm,
+        encoding,
+        footer,
+    })
+}
 
-                if pos >= *initial_len {
-                    *status = ReadStatus::Stopped;
+/// Reads the footer from the end of the file if it has one, then returns to `data_start`
+fn read_footer(
+    file: &mut (impl Read + Seek),
+    data_start: u64,
+    len: u64,
+) -> Result<Option<Footer>, DataErr> {
+    if len < data_start + FOOTER_SIZE {
+        return Ok(None);
+    }
 
-                    if pos > *initial_len {
-                        return Err(DataErr::Empty);
-                    }
-                }
+    let mut footer = [0; FOOTER_SIZE as usize];
+    file.seek(SeekFrom::Start(len - FOOTER_SIZE))
+        .map_err(DataErr::IOErr)?;
+    file.read_exact(&mut footer).map_err(DataErr::IOErr)?;
+    file.seek(SeekFrom::Start(data_start))
+        .map_err(DataErr::IOErr)?;
 
-                Ok(HashResult(path_buf, hash))
-            }
-        }
+    let (body, footer_checksum) = footer.split_at(FOOTER_SIZE as usize - CHECKSUM_SIZE as usize);
+    if body[0] != FOOTER_MARKER
+        || u32::from_le_bytes(footer_checksum.try_into().unwrap()) != crc32fast::hash(body)
+    {
+        return Ok(None);
     }
+    let (records, checksum) = body[1..].split_at(U64_BYTES as usize);
+    Ok(Some(Footer {
+        records: u64::from_le_bytes(records.try_into().unwrap()),
+        checksum: u32::from_le_bytes(checksum.try_into().unwrap()),
+        offset: len - FOOTER_SIZE,
+    }))
+}
 
-    pub fn write(&mut self, results: &[&HashResult]) -> Result<(), DataErr> {
-        if results.is_empty() {
-            return Ok(());
-        }
+/// Writes the footer if it's been cut off or the file is new, and there's a tally to write
+fn write_footer(file: &mut impl Write, footer_state: &FooterState) -> io::Result<()> {
+    let (Some((records, checksum)), None) = (footer_state.tally, footer_state.stale_at) else {
+        return Ok(());
+    };
 
-        let (file, cursor_pos) = match self {
-            Self::Read(file, ReadXxhDiffDataInner { cursor_pos, .. }) => (file, Some(cursor_pos)),
-            Self::Write(f) => (f, None),
-        };
+    let mut footer = Vec::with_capacity(FOOTER_SIZE as usize);
+    footer.push(FOOTER_MARKER);
+    footer.extend_from_slice(&records.to_le_bytes());
+    footer.extend_from_slice(&checksum.to_le_bytes());
+    let footer_checksum = crc32fast::hash(&footer);
+    footer.extend_from_slice(&footer_checksum.to_le_bytes());
+    file.write_all(&footer)?;
+    file.flush()
+}
 
-        match cursor_pos {
-            Some(c) if c.is_none() => *c = Some(file.stream_position().map_err(DataErr::IOErr)?),
-            _ => {}
-        }
+/// Reads the number of entries in the index at `offset` to find its length
+fn read_index_len(file: &mut (impl Read + Seek), offset: u64, end: u64) -> Result<Index, DataErr> {
+    let corrupt = || DataErr::Corrupt { offset };
+    if offset + INDEX_PREFIX_SIZE > end {
+        return Err(corrupt());
+    }
 
-        for result in results {
-            fn write_result(
-                file: &mut File,
-                HashResult(path, hash): &HashResult,
-            ) -> Result<(), DataErr> {
-                let path_bytes = match path.try_as_bytes() {
-                    Ok(p) => p,
-                    Err(p) => {
-                        return Err(DataErr::ParseErr(format!(
-                            "Couldn't convert path buf {} to bytes",
-                            p.display()
-                        )))
-                    }
-                };
-                file.write_all(&[HEAD_SIZE as u8]).map_err(DataErr::IOErr)?;
-                file.write_all(&hash.to_le_bytes())
-                    .map_err(DataErr::IOErr)?;
-                file.write_all(&path_bytes.len().to_le_bytes())
-                    .map_err(DataErr::IOErr)?;
-                file.write_all(&path_bytes).map_err(DataErr::IOErr)
+    let mut prefix = [0; INDEX_PREFIX_SIZE as usize];
+    file.seek(SeekFrom::Start(offset)).map_err(DataErr::IOErr)?;
+    file.read_exact(&mut prefix).map_err(DataErr::IOErr)?;
+    let (marker, count) = prefix.split_at(1);
+    let count = u64::from_le_bytes(count.try_into().unwrap());
+    let len = count
+        .checked_mul(INDEX_ENTRY_SIZE)
+        .and_then(|l| l.checked_add(INDEX_PREFIX_SIZE + CHECKSUM_SIZE))
+        .ok_or_else(corrupt)?;
+    if marker[0] != INDEX_MARKER || offset + len > end {
+        return Err(corrupt());
+    }
+
+    Ok(Index {
+        offset,
+        len,
+        loaded: None,
+    })
+}
+
+/// Reads the index's entries and every record after it
+fn load_index(
+    file: &mut BufReader<PosFile>,
+    index: &Index,
+    end: u64,
+    root: Option<&Path>,
+    encoding: PathEncoding,
+    hash_bits: u32,
+) -> Result<LoadedIndex, DataErr> {
+    let entries_len = index.len - INDEX_PREFIX_SIZE - CHECKSUM_SIZE;
+    let mut entries = vec![
+        0;
+        usize::try_from(entries_len).map_err(|_| DataErr::Corrupt {
+            offset: index.offset,
+        })?
+    ];
+    let mut checksum = [0; CHECKSUM_SIZE as usize];
+    file.seek(SeekFrom::Start(index.offset + INDEX_PREFIX_SIZE))
+        .map_err(DataErr::IOErr)?;
+    file.read_exact(&mut entries).map_err(DataErr::IOErr)?;
+    file.read_exact(&mut checksum).map_err(DataErr::IOErr)?;
+    if u32::from_le_bytes(checksum) != crc32fast::hash(&entries) {
+        return Err(DataErr::Corrupt {
+            offset: index.offset,
+        });
+    }
+
+    let entries = entries
+        .chunks_exact(INDEX_ENTRY_SIZE as usize)
+        .map(|e| {
+            let (path_hash, offset) = e.split_at(U64_BYTES as usize);
+            (
+                u64::from_le_bytes(path_hash.try_into().unwrap()),
+                u64::from_le_bytes(offset.try_into().unwrap()),
+            )
+        })
+        .collect();
+
+    let mut tail = HashMap::new();
+    let mut pos = index.offset + index.len;
+    // Lookups don't give the time, it's only read through
+    let mut hashed_at = UNKNOWN_HASHED_AT;
+    while pos < end {
+        let read = read_record(file, pos, end, encoding, hash_bits, &mut hashed_at);
+        let (record, len, relative) = match read {
+            Ok(r) => r,
+            // A partial record left by a run that was killed mid-write
+            Err(DataErr::Truncated { offset }) => {
+                match resync(file, offset + 1, end, encoding, hash_bits) {
+                    Ok(None) => break,
+                    Ok(Some(_)) => return Err(DataErr::Corrupt { offset }),
+                    Err(e) => return Err(DataErr::IOErr(e)),
+                }
             }
+            Err(e) => return Err(e),
+        };
+        match resolve_path(record, relative, root)? {
+            Record::Hash(result) => tail.insert(result.path, Some((result.hash, result.chunks))),
+            Record::Deleted(path) => tail.insert(path, None),
+        };
+        pos += len;
+    }
+
+    Ok(LoadedIndex { entries, tail })
+}
+
+fn path_hash(path_bytes: &[u8]) -> u64 {
+    let mut hasher = XxHash64::default();
+    std::hash::Hasher::write(&mut hasher, path_bytes);
+    std::hash::Hasher::finish(&hasher)
+}
+
+/// The bytes `path` is stored as, relative to `root` if it's under it
+fn stored_path<'a>(
+    path: &'a Path,
+    root: Option<&Path>,
+    encoding: PathEncoding,
+) -> Result<(Vec<u8>, bool), &'a Path> {
+    match root.and_then(|r| path.strip_prefix(r).ok()) {
+        Some(relative) => encoding.encode(relative).map(|b| (b, true)).ok_or(path),
+        None => encoding.encode(path).map(|b| (b, false)).ok_or(path),
+    }
+}
 
-            write_result(file, result)?;
+/// Joins a relative path read from a record to `root`
+fn resolve_path(
+    mut record: Record,
+    relative: bool,
+    root: Option<&Path>,
+) -> Result<Record, DataErr> {
+    if relative {
+        match root {
+            Some(root) => *record.path_mut() = root.join(record.path()),
+            None => {
+                return Err(DataErr::ParseErr(format!(
+                    "Relative path {} in data file without a root",
+                    record.path().display()
+                )))
+            }
         }
+    }
+    Ok(record)
+}
 
-        file.flush().map_err(DataErr::IOErr)
+/// Whether `hlen` fits the hash and a path length of 1 to 8 bytes, is a head with metadata, with or
+/// without chunks, or is a tombstone's, in a file whose hashes are `hash_bits` wide
+fn is_valid_hlen(hlen: u8, hash_bits: u32) -> bool {
+    if hash_bits == U256_BYTES * 8 {
+        return matches!(
+            hlen as u32,
+            LONG_META_HEAD_SIZE | LONG_CHUNKED_HEAD_SIZE | DELETED_HEAD_SIZE
+        );
+    }
+    matches!(
+        hlen as u32,
+        META_HEAD_SIZE
+            | WIDE_META_HEAD_SIZE
+            | CHUNKED_HEAD_SIZE
+            | WIDE_CHUNKED_HEAD_SIZE
+            | DELETED_HEAD_SIZE
+    ) || (U64_BYTES as u8 + 1..=HEAD_SIZE as u8).contains(&hlen)
+}
+
+/// Reads the record at `offset`, none of which may lie past `end`, returning it with its length
+/// and whether its path is relative to the root. A record that would run past `end` is
+/// [`DataErr::Truncated`]. `hash_bits` is the width of the file's hashes. A time marker before
+/// the record sets `hashed_at` and is counted in its length
+fn read_record(
+    file: &mut impl Read,
+    offset: u64,
+    end: u64,
+    encoding: PathEncoding,
+    hash_bits: u32,
+    hashed_at: &mut i64,
+) -> Result<(Record, u64, bool), DataErr> {
+    let corrupt = || DataErr::Corrupt { offset };
+    let truncated = || DataErr::Truncated { offset };
+
+    let mut hlen = [0; 1];
+    file.read_exact(&mut hlen).map_err(DataErr::IOErr)?;
+    if hlen[0] == TIME_MARKER {
+        *hashed_at = read_time_marker(file, offset, end)?;
+        let next = offset + TIME_MARKER_SIZE;
+        let (record, len, relative) = read_record(file, next, end, encoding, hash_bits, hashed_at)?;
+        return Ok((record, TIME_MARKER_SIZE + len, relative));
+    }
+    let checked = hlen[0] & CHECKSUM_FLAG != 0;
+    let relative = hlen[0] & RELATIVE_FLAG != 0;
+    let hlen = hlen[0] & !(CHECKSUM_FLAG | RELATIVE_FLAG);
+    let mut remaining = end.saturating_sub(offset + 1);
+    if !is_valid_hlen(hlen, hash_bits) {
+        return Err(corrupt());
+    }
+    if u64::from(hlen) > remaining {
+        return Err(truncated());
+    }
+
+    let mut head: Vec<u8> = vec![0; hlen as usize];
+    file.read_exact(&mut head).map_err(DataErr::IOErr)?;
+    remaining -= u64::from(hlen);
+
+    // Read after the path
+    let long_hash = hash_bits == U256_BYTES * 8 && hlen as u32 != DELETED_HEAD_SIZE;
+    let (hash, head_rest) = match hlen as u32 {
+        DELETED_HEAD_SIZE => (None, &head[..]),
+        _ if long_hash => (None, &head[..]),
+        WIDE_META_HEAD_SIZE | WIDE_CHUNKED_HEAD_SIZE => {
+            let (hash, head_rest) = head.split_at(U128_BYTES as usize);
+            let hash = u128::from_le_bytes(hash.try_into().unwrap());
+            (Some(HashValue::U128(hash)), head_rest)
+        }
+        _ => {
+            let (hash, head_rest) = head.split_at(U64_BYTES as usize);
+            let hash = u64::from_le_bytes(hash.try_into().unwrap());
+            (Some(HashValue::U64(hash)), head_rest)
+        }
+    };
+    // `WIDE_META_HEAD_SIZE` is also `LONG_CHUNKED_HEAD_SIZE`
+    let (head_path_len, metadata, head_chunks) = match hlen as u32 {
+        META_HEAD_SIZE
+        | WIDE_META_HEAD_SIZE
+        | CHUNKED_HEAD_SIZE
+        | WIDE_CHUNKED_HEAD_SIZE
+        | LONG_META_HEAD_SIZE => {
+            let (path_len, metadata) = head_rest.split_at(U64_BYTES as usize);
+            let (len, metadata) = metadata.split_at(U64_BYTES as usize);
+            let (mtime, chunks) = metadata.split_at(U64_BYTES as usize);
+            let len = u64::from_le_bytes(len.try_into().unwrap());
+            let mtime = i64::from_le_bytes(mtime.try_into().unwrap());
+            (path_len, Some((len, mtime)), chunks)
+        }
+        _ => (head_rest, None, &[][..]),
+    };
+    // The chunk size and number of chunks
+    let chunks_head = match head_chunks {
+        [] => None,
+        _ => {
+            let (size, count) = head_chunks.split_at(U64_BYTES as usize);
+            Some((
+                u64::from_le_bytes(size.try_into().unwrap()),
+                u64::from_le_bytes(count.try_into().unwrap()),
+            ))
+        }
+    };
+    let mut path_len = [0; U64_BYTES as usize];
+    path_len[..head_path_len.len()].copy_from_slice(head_path_len);
+    let path_len = u64::from_le_bytes(path_len);
+    let hash_size = match hash {
+        Some(HashValue::U128(_)) => U128_BYTES,
+        _ if long_hash => U256_BYTES,
+        _ => U64_BYTES,
+    };
+    let long_hash_len = if long_hash { u64::from(U256_BYTES) } else { 0 };
+    let chunks_len = match chunks_head {
+        Some((_, count)) => count
+            .checked_mul(u64::from(hash_size))
+            .ok_or_else(corrupt)?,
+        None => 0,
+    };
+    let checksum_len = if checked { CHECKSUM_SIZE } else { 0 };
+    if path_len
+        .saturating_add(long_hash_len)
+        .saturating_add(chunks_len)
+        > remaining.saturating_sub(checksum_len)
+    {
+        return Err(truncated());
+    }
+
+    let mut path_buf: Vec<u8> = vec![0; usize::try_from(path_len).map_err(|_| corrupt())?];
+    file.read_exact(&mut path_buf).map_err(DataErr::IOErr)?;
+    let hash = match long_hash {
+        true => {
+            let mut hash = [0; U256_BYTES as usize];
+            file.read_exact(&mut hash).map_err(DataErr::IOErr)?;
+            Some(HashValue::U256(hash))
+        }
+        false => hash,
+    };
+    let mut chunks_buf: Vec<u8> = vec![0; usize::try_from(chunks_len).map_err(|_| corrupt())?];
+    file.read_exact(&mut chunks_buf).map_err(DataErr::IOErr)?;
+
+    if checked {
+        let mut checksum = [0; CHECKSUM_SIZE as usize];
+        file.read_exact(&mut checksum).map_err(DataErr::IOErr)?;
+        let chunks = chunks_head.map(|(size, _)| (size, &chunks_buf[..]));
+        if u32::from_le_bytes(checksum) != record_checksum(hash, metadata, &path_buf, chunks) {
+            return Err(corrupt());
+        }
+    }
+
+    let record_len = 1 + u64::from(hlen) + path_len + long_hash_len + chunks_len + checksum_len;
+    let path = match encoding.decode(path_buf) {
+        Ok(path) => path,
+        Err(p) => {
+            return Err(DataErr::ParseErr(format!(
+                "Couldn't parse path bytes {:?} to path buf",
+                p
+            )))
+        }
+    };
+    let chunks = chunks_head.map(|(size, _)| ChunkHashes {
+        size,
+        hashes: chunks_buf
+            .chunks_exact(hash_size as usize)
+            .map(|h| match hash_size {
+                U128_BYTES => HashValue::U128(u128::from_le_bytes(h.try_into().unwrap())),
+                U256_BYTES => HashValue::U256(h.try_into().unwrap()),
+                _ => HashValue::U64(u64::from_le_bytes(h.try_into().unwrap())),
+            })
+            .collect(),
+    });
+    let record = match hash {
+        Some(hash) => {
+            let (len, mtime) = metadata.unwrap_or((0, UNKNOWN_MTIME));
+            Record::Hash(HashResult {
+                path,
+                hash,
+                len,
+                mtime,
+                chunks,
+                hashed_at: *hashed_at,
+            })
+        }
+        None => Record::Deleted(path),
+    };
+    Ok((record, record_len, relative))
+}
+
+/// Reads the rest of the time marker at `offset` once its marker byte has been, returning its
+/// time. A record always follows one, so it's [`DataErr::Truncated`] without one after it
+fn read_time_marker(file: &mut impl Read, offset: u64, end: u64) -> Result<i64, DataErr> {
+    if offset + TIME_MARKER_SIZE >= end {
+        return Err(DataErr::Truncated { offset });
+    }
+    let mut marker = [0; TIME_MARKER_SIZE as usize - 1];
+    file.read_exact(&mut marker).map_err(DataErr::IOErr)?;
+    let (time, checksum) = marker.split_at(U64_BYTES as usize);
+    if u32::from_le_bytes(checksum.try_into().unwrap()) != crc32fast::hash(time) {
+        return Err(DataErr::Corrupt { offset });
+    }
+    Ok(i64::from_le_bytes(time.try_into().unwrap()))
+}
+
+/// Scans from `from` for the next record with a valid checksum, leaving the reader positioned at it
+fn resync(
+    file: &mut BufReader<PosFile>,
+    from: u64,
+    end: u64,
+    encoding: PathEncoding,
+    hash_bits: u32,
+) -> io::Result<Option<u64>> {
+    let mut buf = vec![0; 64 * 1024];
+    let mut chunk_start = from;
+    while chunk_start < end {
+        let chunk_len = buf.len().min((end - chunk_start) as usize);
+        file.seek(SeekFrom::Start(chunk_start))?;
+        file.read_exact(&mut buf[..chunk_len])?;
+
+        for (i, _) in buf[..chunk_len].iter().enumerate().filter(|(_, b)| {
+            **b & CHECKSUM_FLAG != 0
+                && is_valid_hlen(**b & !(CHECKSUM_FLAG | RELATIVE_FLAG), hash_bits)
+        }) {
+            let candidate = chunk_start + i as u64;
+            file.seek(SeekFrom::Start(candidate))?;
+            // Candidates are records, never time markers
+            let mut hashed_at = UNKNOWN_HASHED_AT;
+            match read_record(file, candidate, end, encoding, hash_bits, &mut hashed_at) {
+                Err(DataErr::Corrupt { .. } | DataErr::Truncated { .. }) => {}
+                Err(DataErr::IOErr(e)) => return Err(e),
+                _ => {
+                    file.seek(SeekFrom::Start(candidate))?;
+                    return Ok(Some(candidate));
+                }
+            }
+        }
+
+        chunk_start += chunk_len as u64;
+    }
+
+    Ok(None)
+}
+
+/// Tombstones have no `hash` and only checksum their path. `chunks` is the chunk size and the
+/// chunk hashes' bytes
+fn record_checksum(
+    hash: Option<HashValue>,
+    metadata: Option<(u64, i64)>,
+    path_bytes: &[u8],
+    chunks: Option<(u64, &[u8])>,
+) -> u32 {
+    let mut hasher = Hasher::new();
+    match hash {
+        Some(HashValue::U64(hash)) => hasher.update(&hash.to_le_bytes()),
+        Some(HashValue::U128(hash)) => hasher.update(&hash.to_le_bytes()),
+        Some(HashValue::U256(hash)) => hasher.update(&hash),
+        None => {}
+    }
+    if let Some((len, mtime)) = metadata {
+        hasher.update(&len.to_le_bytes());
+        hasher.update(&mtime.to_le_bytes());
+    }
+    hasher.update(path_bytes);
+    if let Some((size, hashes)) = chunks {
+        hasher.update(&size.to_le_bytes());
+        hasher.update(hashes);
+    }
+    hasher.finalize()
+}
+
+/// Takes an advisory lock on `file`, retrying until `wait` has passed while another process holds
+/// a conflicting one. The lock is released when the file is closed
+fn lock(file: &File, exclusive: bool, wait: Duration) -> Result<(), DataErr> {
+    let start = Instant::now();
+    loop {
+        let res = match exclusive {
+            true => file.try_lock(),
+            false => file.try_lock_shared(),
+        };
+        match res {
+            Ok(()) => return Ok(()),
+            Err(TryLockError::WouldBlock) if start.elapsed() < wait => thread::sleep(LOCK_POLL),
+            Err(TryLockError::WouldBlock) => return Err(DataErr::Locked),
+            Err(TryLockError::Error(e)) => return Err(DataErr::IOErr(e)),
+        }
+    }
+}
+
+/// New headers always have [`PathEncoding::Portable`]
+fn write_header(
+    file: &mut impl Write,
+    root: Option<&Path>,
+    algorithm: HashAlgorithm,
+) -> io::Result<()> {
+    let root = root
+        .map(|r| r.to_path_buf().to_portable_bytes())
+        .unwrap_or_default();
+    let root_len = u32::try_from(root.len())
+        .map_err(|_| io::Error::new(ErrorKind::InvalidInput, "Root path is too long"))?;
+
+    file.write_all(MAGIC)?;
+    file.write_all(&FORMAT_VERSION.to_le_bytes())?;
+    // No index, it's only written by compaction once the records are
+    file.write_all(&0u64.to_le_bytes())?;
+    let (id, seed) = match algorithm {
+        HashAlgorithm::Xxh64 { seed, .. } => (XXH64_ID, seed),
+        HashAlgorithm::Xxh128 { seed, .. } => (XXH128_ID, seed),
+        HashAlgorithm::Xxh3 { seed, .. } => (XXH3_ID, seed),
+        HashAlgorithm::Blake3 { .. } => (BLAKE3_ID, 0),
+        HashAlgorithm::Sha256 { .. } => (SHA256_ID, 0),
+    };
+    file.write_all(&[id])?;
+    file.write_all(&seed.to_le_bytes())?;
+    file.write_all(&algorithm.split().unwrap_or(0).to_le_bytes())?;
+    file.write_all(&[PORTABLE_PATHS_ID])?;
+    file.write_all(&root_len.to_le_bytes())?;
+    file.write_all(&root)?;
+    file.flush()
+}
+
+pub enum XxhDiffData {
+    Read(BufReader<PosFile>, ReadXxhDiffDataInner),
+    Write(Sink, WriteXxhDiffDataInner),
+}
+
+/// The reading half of an [`XxhDiffData`] from [`split`](XxhDiffData::split), reading what was
+/// in the file when it was opened
+pub struct DataReader {
+    file: BufReader<PosFile>,
+    inner: ReadXxhDiffDataInner,
+}
+
+/// The appending half of an [`XxhDiffData`] from [`split`](XxhDiffData::split)
+pub struct DataWriter {
+    file: Sink,
+    inner: WriteXxhDiffDataInner,
+    /// Where the file ended when it was split, the records after it were written through this
+    initial_len: u64,
+}
+
+/// A file read with positional reads from a position of its own, so appending through another
+/// handle to it, which shares the OS's, doesn't move it
+pub struct PosFile {
+    file: File,
+    pos: u64,
+    /// The contents of an encrypted file, decrypted up front and read instead of it
+    plain: Option<Vec<u8>>,
+}
+
+impl PosFile {
+    fn new(file: File) -> Self {
+        Self {
+            file,
+            pos: 0,
+            plain: None,
+        }
+    }
+}
+
+impl Read for PosFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = match &self.plain {
+            Some(plain) => {
+                let start = usize::try_from(self.pos).map_or(plain.len(), |p| p.min(plain.len()));
+                (&plain[start..]).read(buf)?
+            }
+            None => read_at(&self.file, buf, self.pos)?,
+        };
+        self.pos += read as u64;
+        Ok(read)
+    }
+}
+
+impl Seek for PosFile {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let (base, offset) = match pos {
+            SeekFrom::Start(pos) => (pos, 0),
+            SeekFrom::End(offset) => match &self.plain {
+                Some(plain) => (plain.len() as u64, offset),
+                None => (self.file.metadata()?.len(), offset),
+            },
+            SeekFrom::Current(offset) => (self.pos, offset),
+        };
+        self.pos = base
+            .checked_add_signed(offset)
+            .ok_or_else(|| io::Error::new(ErrorKind::InvalidInput, "Seek to a negative offset"))?;
+        Ok(self.pos)
+    }
+}
+
+#[cfg(unix)]
+fn read_at(file: &File, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+    use std::os::unix::prelude::*;
+
+    file.read_at(buf, offset)
+}
+
+/// Moves the handle's own position too, which appending ignores
+#[cfg(windows)]
+fn read_at(file: &File, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+    use std::os::windows::prelude::*;
+
+    file.seek_read(buf, offset)
+}
+
+/// The file records are written to, through an encryptor if it's encrypted
+pub struct Sink {
+    file: File,
+    #[cfg(feature = "encrypt")]
+    encryptor: Option<Encryptor>,
+}
+
+impl Sink {
+    fn new(file: File) -> Self {
+        Self {
+            file,
+            #[cfg(feature = "encrypt")]
+            encryptor: None,
+        }
+    }
+
+    #[cfg(feature = "encrypt")]
+    fn out(&mut self) -> &mut dyn Write {
+        match &mut self.encryptor {
+            Some(encryptor) => encryptor,
+            None => &mut self.file,
+        }
+    }
+
+    #[cfg(not(feature = "encrypt"))]
+    fn out(&mut self) -> &mut dyn Write {
+        &mut self.file
+    }
+
+    /// Writes the footer back if it's due, finishes any encryption, and waits for everything to
+    /// reach the disk
+    fn close(mut self, footer_state: &FooterState) -> io::Result<()> {
+        write_footer(&mut self, footer_state)?;
+        #[cfg(feature = "encrypt")]
+        if let Some(encryptor) = self.encryptor.take() {
+            encryptor.finish()?;
+        }
+        self.file.sync_all()
+    }
+}
+
+impl Write for Sink {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.out().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.out().flush()
+    }
+}
+
+/// What records are written to, with the file underneath for what can only be done to it
+trait DataOut: Write {
+    fn file(&mut self) -> &mut File;
+}
+
+impl DataOut for File {
+    fn file(&mut self) -> &mut File {
+        self
+    }
+}
+
+impl DataOut for Sink {
+    fn file(&mut self) -> &mut File {
+        &mut self.file
+    }
+}
+
+const U64_BYTES: u32 = u64::BITS / 8;
+const U128_BYTES: u32 = u128::BITS / 8;
+const U256_BYTES: u32 = 32;
+/// The hash followed by the path length. Before version 2 the path length was a `usize`, so
+/// shorter on 32-bit platforms, its size is still taken from the `hlen` byte when reading.
+const HEAD_SIZE: u32 = U64_BYTES + U64_BYTES;
+/// Set in the `hlen` byte of records followed by a CRC32 of their hash and path bytes, which is
+/// every record written since version 3
+const CHECKSUM_FLAG: u8 = 0x80;
+/// Set in the `hlen` byte of records whose path is relative to the root in the header
+const RELATIVE_FLAG: u8 = 0x40;
+/// [`HEAD_SIZE`] followed by the file's size and mtime, written since version 4
+const META_HEAD_SIZE: u32 = HEAD_SIZE + U64_BYTES + U64_BYTES;
+/// [`META_HEAD_SIZE`] with a 128-bit hash, written since version 9
+const WIDE_META_HEAD_SIZE: u32 = META_HEAD_SIZE + U64_BYTES;
+/// [`META_HEAD_SIZE`] followed by the chunk size and number of chunks, whose hashes follow the
+/// path. Written since version 11
+const CHUNKED_HEAD_SIZE: u32 = META_HEAD_SIZE + U64_BYTES + U64_BYTES;
+/// [`CHUNKED_HEAD_SIZE`] with 128-bit hashes
+const WIDE_CHUNKED_HEAD_SIZE: u32 = WIDE_META_HEAD_SIZE + U64_BYTES + U64_BYTES;
+/// The path length, file size and mtime of a record with a 256-bit hash, which follows the path
+/// as there isn't room for it in the head alongside the chunk size and count. Only in files whose
+/// algorithm has 256-bit hashes, written since version 13
+const LONG_META_HEAD_SIZE: u32 = U64_BYTES * 3;
+/// [`LONG_META_HEAD_SIZE`] followed by the chunk size and number of chunks, whose hashes follow the
+/// record's hash. The same size as [`WIDE_META_HEAD_SIZE`], they're told apart by the algorithm
+const LONG_CHUNKED_HEAD_SIZE: u32 = LONG_META_HEAD_SIZE + U64_BYTES + U64_BYTES;
+/// A tombstone's head, only the path length, which is too short for any other record. Written
+/// since version 7
+const DELETED_HEAD_SIZE: u32 = U64_BYTES;
+const CHECKSUM_SIZE: u64 = u32::BITS as u64 / 8;
+
+const MAGIC: &[u8; 8] = b"XXHDIFF\0";
+const FORMAT_VERSION: u16 = 14;
+/// The magic and version. Followed since version 6 by the index's offset, 0 without one, since
+/// version 8 by the hash algorithm, since version 13 by the size of the ranges big files are split
+/// into for hashing, 0 if they aren't, since version 10 by the path encoding, and since version 5 by
+/// the root's length and bytes
+const HEADER_SIZE: u64 = MAGIC.len() as u64 + 2;
+const INDEX_OFFSET_SIZE: u64 = U64_BYTES as u64;
+/// The algorithm's id, then its seed
+const ALGORITHM_SIZE: u64 = 1 + U64_BYTES as u64;
+const XXH64_ID: u8 = 0;
+const XXH128_ID: u8 = 1;
+const XXH3_ID: u8 = 2;
+const BLAKE3_ID: u8 = 3;
+const SHA256_ID: u8 = 4;
+const SPLIT_SIZE: u64 = U64_BYTES as u64;
+const PATH_ENCODING_SIZE: u64 = 1;
+const UNIX_PATHS_ID: u8 = 0;
+const UTF16LE_PATHS_ID: u8 = 1;
+const PORTABLE_PATHS_ID: u8 = 2;
+const ROOT_LEN_SIZE: u64 = u32::BITS as u64 / 8;
+
+/// Starts the index block in place of a record's `hlen` byte, followed by the number of entries,
+/// the entries, and a CRC32 of the entries
+const INDEX_MARKER: u8 = 0x3F;
+const INDEX_PREFIX_SIZE: u64 = 1 + U64_BYTES as u64;
+/// The xxh64 of the stored path bytes, then the record's offset
+const INDEX_ENTRY_SIZE: u64 = U64_BYTES as u64 * 2;
+
+/// Starts the footer, followed by the number of records, a CRC32 of every byte between the header
+/// and the footer, and a CRC32 of the footer up to it
+const FOOTER_MARKER: u8 = 0x3E;
+const FOOTER_SIZE: u64 = 1 + U64_BYTES as u64 + CHECKSUM_SIZE * 2;
+
+/// Comes before a record in place of its `hlen` byte, followed by the seconds since the unix
+/// epoch that it and the records after it were hashed at, and a CRC32 of them. Written since
+/// version 14, whenever the time changes
+const TIME_MARKER: u8 = 0x3D;
+const TIME_MARKER_SIZE: u64 = 1 + U64_BYTES as u64 + CHECKSUM_SIZE;
+
+const LOCK_POLL: Duration = Duration::from_millis(100);
+
+const READ_BUF_SIZE: usize = 256 * 1024;
+const WRITE_BUF_SIZE: usize = 256 * 1024;
+
+#[derive(Debug)]
+pub enum DataErr {
+    Empty,
+    IOErr(io::Error),
+    ParseErr(String),
+    BadMagic,
+    UnsupportedVersion(u16),
+    Corrupt {
+        offset: u64,
+    },
+    /// A record or header field running past the end of the file
+    Truncated {
+        offset: u64,
+    },
+    NoRoot,
+    Locked,
+    /// Another error, with the data file it came from and where in it, if known
+    InFile {
+        path: PathBuf,
+        offset: Option<u64>,
+        source: Box<DataErr>,
+    },
+}
+
+impl DataErr {
+    /// Adds the file context, [`DataErr::Empty`] is left as is since it isn't a failure. Without an
+    /// `offset`, that of a [`DataErr::Corrupt`] or [`DataErr::Truncated`] is used
+    fn in_file(self, path: &Path, offset: Option<u64>) -> Self {
+        let offset = match self {
+            Self::Corrupt { offset: at } | Self::Truncated { offset: at } => offset.or(Some(at)),
+            _ => offset,
+        };
+        match self {
+            Self::Empty | Self::InFile { .. } => self,
+            _ => Self::InFile {
+                path: path.to_path_buf(),
+                offset,
+                source: Box::new(self),
+            },
+        }
+    }
+
+    /// The error without any file context, for matching on
+    pub fn kind(&self) -> &Self {
+        match self {
+            Self::InFile { source, .. } => source,
+            _ => self,
+        }
+    }
+}
+
+impl Display for DataErr {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Empty => write!(f, "No more data"),
+            Self::IOErr(e) => e.fmt(f),
+            Self::ParseErr(e) => write!(f, "{}", e),
+            Self::BadMagic => write!(f, "Not an xxh-diff data file"),
+            Self::UnsupportedVersion(v) => write!(
+                f,
+                "Data file format version {} is not supported, expected at most {}",
+                v, FORMAT_VERSION
+            ),
+            Self::Corrupt { offset } => write!(f, "Corrupt record at byte {}", offset),
+            Self::Truncated { offset } => {
+                write!(f, "Data at byte {} runs past the end of the file", offset)
+            }
+            Self::NoRoot => write!(
+                f,
+                "Data file was created without a root, so can't store paths relative to one"
+            ),
+            Self::Locked => write!(f, "Another xxh-diff run is using this data file"),
+            Self::InFile {
+                path,
+                offset,
+                source,
+            } => match (offset, source.as_ref()) {
+                // Corrupt and Truncated already say where
+                (Some(offset), source)
+                    if !matches!(source, Self::Corrupt { .. } | Self::Truncated { .. }) =>
+                {
+                    write!(f, "{} ({} at byte {})", source, path.display(), offset)
+                }
+                _ => write!(f, "{} ({})", source, path.display()),
+            },
+        }
+    }
+}
+
+impl Error for DataErr {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            Self::IOErr(e) => Some(e),
+            Self::InFile { source, .. } => Some(source.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+impl XxhDiffData {
+    /// Paths under `root` are written relative to it. A new file stores `root` for them to be joined
+    /// back to when read, an existing file must already have a root, which `root` replaces. A new
+    /// file records that its hashes are made with `algorithm`, an existing one keeps its own.
+    ///
+    /// The file is locked exclusively, waiting up to `lock_wait` for other users to close it
+    pub fn new(
+        path: &Path,
+        read_required: bool,
+        root: Option<&Path>,
+        algorithm: HashAlgorithm,
+        lock_wait: Duration,
+    ) -> Result<Self, DataErr> {
+        let mut opts = File::options();
+        let opts = opts
+            .append(true)
+            .create_new(!read_required)
+            .read(read_required);
+        match opts.open(path) {
+            Ok(file) => {
+                XxhDiffData::from_file(file, path, read_required, root, algorithm, lock_wait)
+            }
+            Err(e) => match e.kind() {
+                ErrorKind::AlreadyExists => opts
+                    .read(true)
+                    .create_new(false)
+                    .open(path)
+                    .map_err(DataErr::IOErr)
+                    .and_then(|file| {
+                        XxhDiffData::from_file(file, path, true, root, algorithm, lock_wait)
+                    }),
+                _ => Err(DataErr::IOErr(e)),
+            },
+        }
+        .map_err(|e| e.in_file(path, None))
+    }
+
+    /// `file` must be newly created unless `read`
+    fn from_file(
+        file: File,
+        path: &Path,
+        read: bool,
+        root: Option<&Path>,
+        algorithm: HashAlgorithm,
+        lock_wait: Duration,
+    ) -> Result<Self, DataErr> {
+        lock(&file, true, lock_wait)?;
+        match read {
+            true => {
+                let mut file = PosFile::new(file);
+                let mut inner = ReadXxhDiffDataInner::new(&mut file, path, true, root, algorithm)?;
+                if let Some(root) = root {
+                    match inner.root {
+                        Some(_) => inner.root = Some(root.to_path_buf()),
+                        None => return Err(DataErr::NoRoot),
+                    }
+                }
+                Ok(Self::Read(
+                    BufReader::with_capacity(READ_BUF_SIZE, file),
+                    inner,
+                ))
+            }
+            false => {
+                let mut file = Sink::new(file);
+                write_header(&mut file, root, algorithm).map_err(DataErr::IOErr)?;
+                Ok(Self::Write(
+                    file,
+                    WriteXxhDiffDataInner {
+                        root: root.map(Path::to_path_buf),
+                        algorithm,
+                        encoding: PathEncoding::Portable,
+                        footer_state: FooterState::new_file(),
+                        path: path.to_path_buf(),
+                        sync: SyncPolicy::Never,
+                        marked_at: None,
+                    },
+                ))
+            }
+        }
+    }
+
+    /// Opens an existing data file without write access, so it can only be read. The file is
+    /// locked shared, so only against writers
+    pub fn open(path: &Path, lock_wait: Duration) -> Result<Self, DataErr> {
+        let open = || {
+            let file = File::open(path).map_err(DataErr::IOErr)?;
+            lock(&file, false, lock_wait)?;
+            Self::read_only(PosFile::new(file), path)
+        };
+        open().map_err(|e: DataErr| e.in_file(path, None))
+    }
+
+    /// Like [`open`](Self::open) for a file from [`new_encrypted`](Self::new_encrypted),
+    /// decrypting it whole with `key`. `password` is called for the password of its secret parts
+    /// if they have one
+    #[cfg(feature = "encrypt")]
+    pub fn open_decrypted(
+        path: &Path,
+        lock_wait: Duration,
+        key: &Cert,
+        password: &mut dyn FnMut() -> io::Result<String>,
+    ) -> Result<Self, DataErr> {
+        let mut open = || {
+            let file = File::open(path).map_err(DataErr::IOErr)?;
+            lock(&file, false, lock_wait)?;
+            let plain = encrypt::decrypt(&file, key, password)
+                .map_err(|e| DataErr::ParseErr(format!("Couldn't decrypt the data file: {}", e)))?;
+            Self::read_only(
+                PosFile {
+                    file,
+                    pos: 0,
+                    plain: Some(plain),
+                },
+                path,
+            )
+        };
+        open().map_err(|e: DataErr| e.in_file(path, None))
+    }
+
+    fn read_only(mut file: PosFile, path: &Path) -> Result<Self, DataErr> {
+        let inner =
+            ReadXxhDiffDataInner::new(&mut file, path, false, None, HashAlgorithm::default())?;
+        Ok(Self::Read(
+            BufReader::with_capacity(READ_BUF_SIZE, file),
+            inner,
+        ))
+    }
+
+    /// Opens `path` to be written from scratch, emptying it once it's locked so a file in use by
+    /// another run is left alone
+    fn create_locked(path: &Path, lock_wait: Duration) -> Result<File, DataErr> {
+        let file = File::options()
+            .write(true)
+            .truncate(false)
+            .create(true)
+            .open(path)
+            .map_err(DataErr::IOErr)?;
+        lock(&file, true, lock_wait)?;
+        file.set_len(0).map_err(DataErr::IOErr)?;
+        Ok(file)
+    }
+
+    pub fn reset(
+        path: &Path,
+        root: Option<&Path>,
+        algorithm: HashAlgorithm,
+        lock_wait: Duration,
+    ) -> Result<Self, DataErr> {
+        let reset = || {
+            let mut file = Sink::new(Self::create_locked(path, lock_wait)?);
+            write_header(&mut file, root, algorithm).map_err(DataErr::IOErr)?;
+            Ok(Self::new_write(file, path, root, algorithm))
+        };
+        reset().map_err(|e: DataErr| e.in_file(path, None))
+    }
+
+    /// Like [`reset`](Self::reset), but everything is encrypted to `cert` as it's written. The
+    /// file can only be read back whole with [`open_decrypted`](Self::open_decrypted), so it's
+    /// always written afresh rather than appended to
+    #[cfg(feature = "encrypt")]
+    pub fn new_encrypted(
+        path: &Path,
+        root: Option<&Path>,
+        algorithm: HashAlgorithm,
+        cert: &'static Cert,
+        lock_wait: Duration,
+    ) -> Result<Self, DataErr> {
+        let create = || {
+            let file = Self::create_locked(path, lock_wait)?;
+            let encryptor = Encryptor::new(file.try_clone().map_err(DataErr::IOErr)?, cert)
+                .map_err(DataErr::IOErr)?;
+            let mut file = Sink {
+                file,
+                encryptor: Some(encryptor),
+            };
+            write_header(&mut file, root, algorithm).map_err(DataErr::IOErr)?;
+            Ok(Self::new_write(file, path, root, algorithm))
+        };
+        create().map_err(|e: DataErr| e.in_file(path, None))
+    }
+
+    /// For a file that's just had its header written
+    fn new_write(file: Sink, path: &Path, root: Option<&Path>, algorithm: HashAlgorithm) -> Self {
+        Self::Write(
+            file,
+            WriteXxhDiffDataInner {
+                root: root.map(Path::to_path_buf),
+                algorithm,
+                encoding: PathEncoding::Portable,
+                footer_state: FooterState::new_file(),
+                path: path.to_path_buf(),
+                sync: SyncPolicy::Never,
+                marked_at: None,
+            },
+        )
+    }
+
+    /// Splits a file from [`new`](Self::new) into halves that can be used from different threads
+    /// at once, the reader with a handle of its own. The reader is `None` for a new file, which
+    /// has nothing to read
+    pub fn split(self) -> Result<(Option<DataReader>, DataWriter), DataErr> {
+        let path = self.path().to_path_buf();
+        self.split_inner().map_err(|e| e.in_file(&path, None))
+    }
+
+    fn split_inner(self) -> Result<(Option<DataReader>, DataWriter), DataErr> {
+        match self {
+            Self::Read(file, mut inner) => {
+                let writer = DataWriter {
+                    file: Sink::new(file.get_ref().file.try_clone().map_err(DataErr::IOErr)?),
+                    inner: WriteXxhDiffDataInner {
+                        root: inner.root.clone(),
+                        algorithm: inner.algorithm,
+                        encoding: inner.encoding,
+                        footer_state: mem::take(&mut inner.footer_state),
+                        path: inner.path.clone(),
+                        sync: inner.sync,
+                        marked_at: None,
+                    },
+                    initial_len: inner.initial_len,
+                };
+                Ok((Some(DataReader { file, inner }), writer))
+            }
+            Self::Write(file, inner) => {
+                let initial_len = file.file.metadata().map_err(DataErr::IOErr)?.len();
+                Ok((
+                    None,
+                    DataWriter {
+                        file,
+                        inner,
+                        initial_len,
+                    },
+                ))
+            }
+        }
+    }
+
+    pub fn root(&self) -> Option<&Path> {
+        match self {
+            Self::Read(_, inner) => inner.root.as_deref(),
+            Self::Write(_, inner) => inner.root.as_deref(),
+        }
+    }
+
+    pub fn algorithm(&self) -> HashAlgorithm {
+        match self {
+            Self::Read(_, inner) => inner.algorithm,
+            Self::Write(_, inner) => inner.algorithm,
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        match self {
+            Self::Read(_, inner) => &inner.path,
+            Self::Write(_, inner) => &inner.path,
+        }
+    }
+
+    /// Joins relative paths read from now on to `root` instead of the one stored in the file, for
+    /// when the tree it was written from has moved. Paths are still written relative to `root`
+    pub fn rebase(&mut self, root: PathBuf) {
+        match self {
+            Self::Read(_, inner) => inner.root = Some(root),
+            Self::Write(_, inner) => inner.root = Some(root),
+        }
+    }
+
+    /// Writes aren't synced unless this is set
+    pub fn set_sync(&mut self, policy: SyncPolicy) {
+        match self {
+            Self::Read(_, inner) => inner.sync = policy,
+            Self::Write(_, inner) => inner.sync = policy,
+        }
+    }
+
+    pub fn is_read(&self) -> bool {
+        matches!(self, Self::Read(..))
+    }
+
+    /// Writes the footer back if anything was written, finishes any encryption, and waits for
+    /// everything to reach the disk. A file opened without write access is only dropped, it can't
+    /// have been written and syncing it can fail
+    pub fn close(self) -> Result<(), DataErr> {
+        let path = self.path().to_path_buf();
+        let (file, footer_state) = match self {
+            Self::Read(_, inner) if !inner.writable => return Ok(()),
+            Self::Read(file, inner) => (Sink::new(file.into_inner().file), inner.footer_state),
+            Self::Write(file, inner) => (file, inner.footer_state),
+        };
+        file.close(&footer_state)
+            .map_err(|e| DataErr::IOErr(e).in_file(&path, None))
+    }
+
+    /// The footer the file was opened with, `None` if it wasn't cleanly closed
+    pub fn footer(&self) -> Option<Footer> {
+        match self {
+            Self::Read(_, inner) => inner.footer,
+            Self::Write(..) => None,
+        }
+    }
+
+    /// Offset of the partial record reading stopped at, if it did
+    pub fn truncated_at(&self) -> Option<u64> {
+        match self {
+            Self::Read(_, inner) => inner.truncated_at,
+            Self::Write(..) => None,
+        }
+    }
+
+    /// Recomputes the checksum the footer has of every byte after the header, up to the footer or
+    /// the end of the file, without disturbing [`read_skip_corrupt`](Self::read_skip_corrupt)
+    pub fn checksum(&mut self) -> Result<u32, DataErr> {
+        let Self::Read(file, inner) = self else {
+            return Ok(Hasher::new().finalize());
+        };
+        inner.reposition = true;
+
+        let mut checksum = || {
+            file.seek(SeekFrom::Start(inner.records_start))?;
+            let mut hasher = Hasher::new();
+            let mut buf = vec![0; READ_BUF_SIZE];
+            let mut remaining = inner.initial_len.saturating_sub(inner.records_start);
+            while remaining > 0 {
+                let len = buf.len().min(remaining as usize);
+                file.read_exact(&mut buf[..len])?;
+                hasher.update(&buf[..len]);
+                remaining -= len as u64;
+            }
+            Ok(hasher.finalize())
+        };
+        checksum().map_err(|e| DataErr::IOErr(e).in_file(&inner.path, None))
+    }
+
+    /// Reads the next record, but on a [`DataErr::Corrupt`] record scans forward to the next record
+    /// with a matching checksum and carries on from there, counting each skip in
+    /// [`ReadXxhDiffDataInner::skipped`]. Records written before version 3 have no checksum, so
+    /// corruption among them skips to the end of the file
+    pub fn read_skip_corrupt(&mut self) -> Result<Record, DataErr> {
+        match self {
+            Self::Read(file, inner) => {
+                let offset = inner.pos;
+                inner
+                    .read(file, true)
+                    .map_err(|e| e.in_file(&inner.path, Some(offset)))
+            }
+            Self::Write(..) => Err(DataErr::Empty),
+        }
+    }
+
+    /// Whether the file has an index for [`lookup`](Self::lookup), otherwise records can only be
+    /// found by reading through them
+    pub fn has_index(&self) -> bool {
+        matches!(self, Self::Read(_, inner) if inner.index.is_some())
+    }
+
+    /// Finds the hash and any chunk hashes recorded for `path` through the index, without disturbing
+    /// [`read_skip_corrupt`](Self::read_skip_corrupt). `None` if it isn't recorded or there's no index
+    pub fn lookup(
+        &mut self,
+        path: &Path,
+    ) -> Result<Option<(HashValue, Option<ChunkHashes>)>, DataErr> {
+        self.lookup_inner(path)
+            .map_err(|e| e.in_file(self.path(), None))
+    }
+
+    fn lookup_inner(
+        &mut self,
+        path: &Path,
+    ) -> Result<Option<(HashValue, Option<ChunkHashes>)>, DataErr> {
+        let (
+            file,
+            ReadXxhDiffDataInner {
+                initial_len,
+                reposition,
+                root,
+                algorithm,
+                encoding,
+                index: Some(index),
+                ..
+            },
+        ) = (match self {
+            Self::Read(file, inner) => (file, inner),
+            Self::Write(..) => return Ok(None),
+        })
+        else {
+            return Ok(None);
+        };
+        *reposition = true;
+
+        if index.loaded.is_none() {
+            index.loaded = Some(Box::new(load_index(
+                file,
+                index,
+                *initial_len,
+                root.as_deref(),
+                *encoding,
+                algorithm.bits(),
+            )?));
+        }
+        let LoadedIndex { entries, tail } = index.loaded.as_deref().unwrap();
+
+        if let Some(hash) = tail.get(path) {
+            return Ok(hash.clone());
+        }
+
+        let key = match stored_path(path, root.as_deref(), *encoding) {
+            Ok((path_bytes, _)) => path_hash(&path_bytes),
+            Err(_) => return Ok(None),
+        };
+        let start = entries.partition_point(|(h, _)| *h < key);
+        for (_, offset) in entries[start..].iter().take_while(|(h, _)| *h == key) {
+            file.seek(SeekFrom::Start(*offset))
+                .map_err(DataErr::IOErr)?;
+            // Indexed offsets are of records, never time markers
+            let mut hashed_at = UNKNOWN_HASHED_AT;
+            let (record, _, relative) = read_record(
+                file,
+                *offset,
+                *initial_len,
+                *encoding,
+                algorithm.bits(),
+                &mut hashed_at,
+            )?;
+            if let Record::Hash(result) = resolve_path(record, relative, root.as_deref())? {
+                if result.path == path {
+                    return Ok(Some((result.hash, result.chunks)));
+                }
+            }
+        }
+
+        Ok(None)
+    }
+
+    pub fn write(&mut self, results: &[&HashResult]) -> Result<(), DataErr> {
+        self.write_inner(results.iter().map(|r| RecordRef::Hash(r)), None)
+            .map_err(|e| e.in_file(self.path(), None))
+    }
+
+    /// Like [`write`](Self::write), then writes an index of the records after them for
+    /// [`lookup`](Self::lookup). Only for a file straight from [`reset`](Self::reset), whose
+    /// header is updated with the index's offset
+    pub fn write_indexed(&mut self, results: &[&HashResult]) -> Result<(), DataErr> {
+        self.write_indexed_inner(results)
+            .map_err(|e| e.in_file(self.path(), None))
+    }
+
+    fn write_indexed_inner(&mut self, results: &[&HashResult]) -> Result<(), DataErr> {
+        if self.is_read() {
+            return Err(DataErr::ParseErr(
+                "An index can only be written to a new data file".to_string(),
+            ));
+        }
+        #[cfg(feature = "encrypt")]
+        if matches!(
+            self,
+            Self::Write(
+                Sink {
+                    encryptor: Some(_),
+                    ..
+                },
+                _
+            )
+        ) {
+            return Err(DataErr::ParseErr(
+                "An index can't be written to an encrypted data file".to_string(),
+            ));
+        }
+
+        let mut entries = Vec::with_capacity(results.len());
+        self.write_inner(
+            results.iter().map(|r| RecordRef::Hash(r)),
+            Some(&mut entries),
+        )?;
+        entries.sort_unstable();
+
+        let Self::Write(Sink { file, .. }, WriteXxhDiffDataInner { footer_state, .. }) = self
+        else {
+            unreachable!()
+        };
+        let mut buf = Vec::with_capacity(
+            INDEX_PREFIX_SIZE as usize + entries.len() * INDEX_ENTRY_SIZE as usize,
+        );
+        buf.push(INDEX_MARKER);
+        buf.extend_from_slice(&(entries.len() as u64).to_le_bytes());
+        for (path_hash, offset) in entries {
+            buf.extend_from_slice(&path_hash.to_le_bytes());
+            buf.extend_from_slice(&offset.to_le_bytes());
+        }
+        let checksum = crc32fast::hash(&buf[INDEX_PREFIX_SIZE as usize..]);
+        buf.extend_from_slice(&checksum.to_le_bytes());
+
+        let offset = file.stream_position().map_err(DataErr::IOErr)?;
+        file.write_all(&buf).map_err(DataErr::IOErr)?;
+        footer_state.update(&buf);
+        file.seek(SeekFrom::Start(HEADER_SIZE))
+            .map_err(DataErr::IOErr)?;
+        file.write_all(&offset.to_le_bytes())
+            .map_err(DataErr::IOErr)?;
+        file.seek(SeekFrom::End(0)).map_err(DataErr::IOErr)?;
+        file.flush().map_err(DataErr::IOErr)
+    }
+
+    fn write_inner<'a>(
+        &mut self,
+        records: impl Iterator<Item = RecordRef<'a>>,
+        index: Option<&mut Vec<(u64, u64)>>,
+    ) -> Result<(), DataErr> {
+        let (file, root, encoding, footer_state, marked_at, sync): (
+            &mut dyn DataOut,
+            _,
+            _,
+            _,
+            _,
+            _,
+        ) = match self {
+            Self::Read(file, inner) => (
+                &mut file.get_mut().file,
+                &inner.root,
+                inner.encoding,
+                &mut inner.footer_state,
+                &mut inner.marked_at,
+                inner.sync,
+            ),
+            Self::Write(file, inner) => (
+                file,
+                &inner.root,
+                inner.encoding,
+                &mut inner.footer_state,
+                &mut inner.marked_at,
+                inner.sync,
+            ),
+        };
+        write_records(
+            file,
+            root.as_deref(),
+            encoding,
+            footer_state,
+            marked_at,
+            sync,
+            records,
+            index,
+        )
+    }
+}
+
+impl DataReader {
+    /// Like [`XxhDiffData::read_skip_corrupt`], but a [`DataErr::Corrupt`] record stops reading
+    pub fn read(&mut self) -> Result<Record, DataErr> {
+        let offset = self.inner.pos;
+        self.inner
+            .read(&mut self.file, false)
+            .map_err(|e| e.in_file(&self.inner.path, Some(offset)))
+    }
+
+    /// Whether there's nothing more to read
+    pub fn is_done(&self) -> bool {
+        self.inner.status.is_stop()
+    }
+
+    pub fn status(&self) -> &ReadStatus {
+        &self.inner.status
+    }
+}
+
+impl DataWriter {
+    pub fn write(&mut self, results: &[&HashResult]) -> Result<(), DataErr> {
+        self.write_inner(results.iter().map(|r| RecordRef::Hash(r)))
+    }
+
+    /// Writes tombstones for `paths`, so their earlier records are ignored when read
+    pub fn write_deleted(&mut self, paths: &[&Path]) -> Result<(), DataErr> {
+        self.write_inner(paths.iter().map(|p| RecordRef::Deleted(p)))
+    }
+
+    fn write_inner<'a>(
+        &mut self,
+        records: impl Iterator<Item = RecordRef<'a>>,
+    ) -> Result<(), DataErr> {
+        let WriteXxhDiffDataInner {
+            root,
+            encoding,
+            footer_state,
+            path,
+            sync,
+            marked_at,
+            ..
+        } = &mut self.inner;
+        write_records(
+            &mut self.file,
+            root.as_deref(),
+            *encoding,
+            footer_state,
+            marked_at,
+            *sync,
+            records,
+            None,
+        )
+        .map_err(|e| e.in_file(path, None))
+    }
+
+    /// Empties the file down to a new header, keeping hold of its lock, root and algorithm
+    pub fn truncate(&mut self) -> Result<(), DataErr> {
+        self.truncate_inner()
+            .map_err(|e| e.in_file(&self.inner.path, None))
+    }
+
+    fn truncate_inner(&mut self) -> Result<(), DataErr> {
+        self.file.file.set_len(0).map_err(DataErr::IOErr)?;
+        write_header(
+            &mut self.file,
+            self.inner.root.as_deref(),
+            self.inner.algorithm,
+        )
+        .map_err(DataErr::IOErr)?;
+        self.inner.encoding = PathEncoding::Portable;
+        self.inner.footer_state = FooterState::new_file();
+        self.inner.marked_at = None;
+        self.initial_len = self.file.file.metadata().map_err(DataErr::IOErr)?.len();
+        Ok(())
+    }
+
+    /// Cuts off the partial record `reader` stopped at, left by a run that was killed mid-write,
+    /// so the file carries on from the last complete record. Records written since the split are
+    /// moved down to follow it. Returns the number of bytes discarded
+    pub fn truncate_to_valid(&mut self, reader: &DataReader) -> Result<u64, DataErr> {
+        self.truncate_to_valid_inner(reader)
+            .map_err(|e| e.in_file(&self.inner.path, None))
+    }
+
+    fn truncate_to_valid_inner(&mut self, reader: &DataReader) -> Result<u64, DataErr> {
+        let Some(valid_len) = reader.inner.truncated_at else {
+            return Ok(0);
+        };
+
+        // The file is opened to append, so they can't be written in place
+        let file = &mut self.file.file;
+        let mut written = Vec::new();
+        file.seek(SeekFrom::Start(self.initial_len))
+            .map_err(DataErr::IOErr)?;
+        file.read_to_end(&mut written).map_err(DataErr::IOErr)?;
+        file.set_len(valid_len).map_err(DataErr::IOErr)?;
+        file.write_all(&written).map_err(DataErr::IOErr)?;
+        file.flush().map_err(DataErr::IOErr)?;
+
+        let discarded = self.initial_len - valid_len;
+        self.initial_len = valid_len;
+        Ok(discarded)
+    }
+
+    /// Writes the footer back if the file had one or was new, finishes any encryption, and waits
+    /// for everything to reach the disk
+    pub fn close(self) -> Result<(), DataErr> {
+        let Self { file, inner, .. } = self;
+        file.close(&inner.footer_state)
+            .map_err(|e| DataErr::IOErr(e).in_file(&inner.path, None))
+    }
+}
+
+/// Writes `records`, adding each one's path hash and offset to `index`. Any footer is cut off first
+/// and the records added to its tally. A time marker goes before each record hashed at a different
+/// time to the last one written, `marked_at`
+#[allow(clippy::too_many_arguments)]
+fn write_records<'a>(
+    file: &mut (impl DataOut + ?Sized),
+    root: Option<&Path>,
+    encoding: PathEncoding,
+    footer_state: &mut FooterState,
+    marked_at: &mut Option<i64>,
+    sync: SyncPolicy,
+    records: impl Iterator<Item = RecordRef<'a>>,
+    mut index: Option<&mut Vec<(u64, u64)>>,
+) -> Result<(), DataErr> {
+    let mut records = records.peekable();
+    if records.peek().is_none() {
+        return Ok(());
+    }
+    if !encoding.is_writable() {
+        return Err(DataErr::ParseErr(
+            "The data file's paths were written on another platform, compact it to append to it here"
+                .to_string(),
+        ));
+    }
+    if let Some(offset) = footer_state.stale_at.take() {
+        file.file().set_len(offset).map_err(DataErr::IOErr)?;
+    }
+
+    let mut written = match index {
+        Some(_) => file.file().stream_position().map_err(DataErr::IOErr)?,
+        None => 0,
+    };
+
+    // Records are assembled here and written in large chunks rather than a syscall per field
+    let mut buf = Vec::with_capacity(WRITE_BUF_SIZE);
+    for record in records {
+        fn write_record(
+            buf: &mut Vec<u8>,
+            record: RecordRef,
+            root: Option<&Path>,
+            encoding: PathEncoding,
+        ) -> Result<Vec<u8>, DataErr> {
+            let path = match record {
+                RecordRef::Hash(result) => &result.path,
+                RecordRef::Deleted(path) => path,
+            };
+            let (path_bytes, relative) = match stored_path(path, root, encoding) {
+                Ok(p) => p,
+                Err(p) => {
+                    return Err(DataErr::ParseErr(format!(
+                        "Couldn't convert path buf {} to bytes",
+                        p.display()
+                    )))
+                }
+            };
+            let flags = match relative {
+                true => CHECKSUM_FLAG | RELATIVE_FLAG,
+                false => CHECKSUM_FLAG,
+            };
+            let mut long_hash_buf = Vec::new();
+            let mut chunks_buf = Vec::new();
+            let checksum = match record {
+                RecordRef::Hash(&HashResult {
+                    hash,
+                    len,
+                    mtime,
+                    ref chunks,
+                    ..
+                }) => {
+                    let hlen = match (hash, chunks) {
+                        (HashValue::U64(_), None) => META_HEAD_SIZE,
+                        (HashValue::U128(_), None) => WIDE_META_HEAD_SIZE,
+                        (HashValue::U256(_), None) => LONG_META_HEAD_SIZE,
+                        (HashValue::U64(_), Some(_)) => CHUNKED_HEAD_SIZE,
+                        (HashValue::U128(_), Some(_)) => WIDE_CHUNKED_HEAD_SIZE,
+                        (HashValue::U256(_), Some(_)) => LONG_CHUNKED_HEAD_SIZE,
+                    };
+                    buf.push(hlen as u8 | flags);
+                    match hash {
+                        HashValue::U256(_) => hash.write_le(&mut long_hash_buf),
+                        _ => hash.write_le(buf),
+                    }
+                    buf.extend_from_slice(&(path_bytes.len() as u64).to_le_bytes());
+                    buf.extend_from_slice(&len.to_le_bytes());
+                    buf.extend_from_slice(&mtime.to_le_bytes());
+                    if let Some(chunks) = chunks {
+                        buf.extend_from_slice(&chunks.size.to_le_bytes());
+                        buf.extend_from_slice(&(chunks.hashes.len() as u64).to_le_bytes());
+                        for chunk_hash in &chunks.hashes {
+                            chunk_hash.write_le(&mut chunks_buf);
+                        }
+                    }
+                    record_checksum(
+                        Some(hash),
+                        Some((len, mtime)),
+                        &path_bytes,
+                        chunks.as_ref().map(|c| (c.size, &chunks_buf[..])),
+                    )
+                }
+                RecordRef::Deleted(_) => {
+                    buf.push(DELETED_HEAD_SIZE as u8 | flags);
+                    buf.extend_from_slice(&(path_bytes.len() as u64).to_le_bytes());
+                    record_checksum(None, None, &path_bytes, None)
+                }
+            };
+            buf.extend_from_slice(&path_bytes);
+            buf.extend_from_slice(&long_hash_buf);
+            buf.extend_from_slice(&chunks_buf);
+            buf.extend_from_slice(&checksum.to_le_bytes());
+            Ok(path_bytes)
+        }
+
+        if let RecordRef::Hash(result) = record {
+            if *marked_at != Some(result.hashed_at) {
+                let time = result.hashed_at.to_le_bytes();
+                buf.push(TIME_MARKER);
+                buf.extend_from_slice(&time);
+                buf.extend_from_slice(&crc32fast::hash(&time).to_le_bytes());
+                *marked_at = Some(result.hashed_at);
+            }
+        }
+        let offset = written + buf.len() as u64;
+        let path_bytes = write_record(&mut buf, record, root, encoding)?;
+        if let Some(index) = index.as_mut() {
+            index.push((path_hash(&path_bytes), offset));
+        }
+        if let Some((count, _)) = &mut footer_state.tally {
+            *count += 1;
+        }
+
+        let always = matches!(sync, SyncPolicy::Always);
+        if always || buf.len() >= WRITE_BUF_SIZE {
+            footer_state.update(&buf);
+            file.write_all(&buf).map_err(DataErr::IOErr)?;
+            if always {
+                file.file().sync_data().map_err(DataErr::IOErr)?;
+            }
+            written += buf.len() as u64;
+            buf.clear();
+        }
+    }
+    footer_state.update(&buf);
+    file.write_all(&buf).map_err(DataErr::IOErr)?;
+    file.flush().map_err(DataErr::IOErr)?;
+
+    match sync {
+        SyncPolicy::Batch => file.file().sync_data().map_err(DataErr::IOErr),
+        SyncPolicy::Never | SyncPolicy::Always => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[cfg(unix)]
+    use std::os::unix::ffi::OsStrExt;
+    #[cfg(windows)]
+    use std::os::windows::ffi::OsStrExt;
+    use std::{fs, process};
+
+    use super::*;
+
+    /// A path in the temp dir that doesn't exist yet
+    fn temp_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("xxh-diff-{}-{}", process::id(), name));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    fn read_all(data: &mut XxhDiffData) -> Vec<Record> {
+        let mut records = Vec::new();
+        loop {
+            match data.read_skip_corrupt() {
+                Ok(record) => records.push(record),
+                Err(e) if matches!(e.kind(), DataErr::Empty) => return records,
+                Err(e) => panic!("{}", e),
+            }
+        }
+    }
+
+    /// A partial last record is read as left by a killed run, but a header cut short can't be read
+    /// past, and says where it stops. The root is found by its bytes, which are only as written on
+    /// Unix
+    #[cfg(unix)]
+    #[test]
+    fn truncated_header_error() {
+        let path = temp_path("truncated-header.xxhd");
+        let root = Path::new("/truncated/header/root");
+        let mut data = XxhDiffData::new(
+            &path,
+            false,
+            Some(root),
+            HashAlgorithm::default(),
+            Duration::ZERO,
+        )
+        .unwrap();
+        data.write(&[&hashed("/truncated/header/root/a", 100)]).unwrap();
+        data.close().unwrap();
+
+        let bytes = fs::read(&path).unwrap();
+        let root_at = bytes
+            .windows(root.as_os_str().len())
+            .position(|w| w == root.as_os_str().as_encoded_bytes())
+            .unwrap();
+        fs::write(&path, &bytes[..root_at + 4]).unwrap();
+
+        let Err(err) = XxhDiffData::open(&path, Duration::ZERO) else {
+            panic!("Opened a file with a truncated header");
+        };
+        let debug = format!("{:?}", err);
+        assert_eq!(
+            debug,
+            format!(
+                "InFile {{ path: {:?}, offset: Some({}), source: Truncated {{ offset: {} }} }}",
+                path, root_at, root_at
+            )
+        );
+        assert!(matches!(err.kind(), DataErr::Truncated { offset } if *offset == root_at as u64));
+        fs::remove_file(path).unwrap();
+    }
+
+    /// Closing a file opened without write access leaves it as it was, only written ones are synced
+    #[test]
+    fn read_only_close() {
+        let path = temp_path("read-only.xxhd");
+        let data =
+            XxhDiffData::new(&path, false, None, HashAlgorithm::default(), Duration::ZERO).unwrap();
+        data.close().unwrap();
+        let written = fs::read(&path).unwrap();
+
+        let data = XxhDiffData::open(&path, Duration::ZERO).unwrap();
+        assert!(matches!(&data, XxhDiffData::Read(_, inner) if !inner.writable));
+        data.close().unwrap();
+        assert_eq!(fs::read(&path).unwrap(), written);
+
+        let data =
+            XxhDiffData::new(&path, true, None, HashAlgorithm::default(), Duration::ZERO).unwrap();
+        assert!(matches!(&data, XxhDiffData::Read(_, inner) if inner.writable));
+        data.close().unwrap();
+        assert_eq!(fs::read(&path).unwrap(), written);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn long_hashes_round_trip() {
+        let path = temp_path("long.xxhd");
+        let algorithm = HashAlgorithm::Blake3 { split: None };
+        let plain = HashResult {
+            path: PathBuf::from("/a"),
+            hash: HashValue::U256([1; 32]),
+            len: 3,
+            mtime: 4,
+            chunks: None,
+            hashed_at: 7,
+        };
+        let chunked = HashResult {
+            path: PathBuf::from("/b"),
+            hash: HashValue::U256([2; 32]),
+            len: 5,
+            mtime: 6,
+            chunks: Some(ChunkHashes {
+                size: 4,
+                hashes: vec![HashValue::U256([3; 32]), HashValue::U256([4; 32])],
+            }),
+            hashed_at: 7,
+        };
+
+        let data = XxhDiffData::new(&path, false, None, algorithm, Duration::ZERO).unwrap();
+        let (_, mut writer) = data.split().unwrap();
+        writer.write(&[&plain, &chunked]).unwrap();
+        writer.write_deleted(&[Path::new("/c")]).unwrap();
+        writer.close().unwrap();
+
+        let mut data = XxhDiffData::open(&path, Duration::ZERO).unwrap();
+        assert_eq!(data.algorithm(), algorithm);
+        let records = read_all(&mut data);
+        assert_eq!(records.len(), 3);
+        match &records[0] {
+            Record::Hash(r) => {
+                assert_eq!(
+                    (&r.path, r.hash, r.len, r.mtime),
+                    (&plain.path, plain.hash, 3, 4)
+                );
+                assert!(r.chunks.is_none());
+            }
+            Record::Deleted(_) => panic!("Expected a hash"),
+        }
+        match &records[1] {
+            Record::Hash(r) => {
+                assert_eq!((&r.path, r.hash), (&chunked.path, chunked.hash));
+                let chunks = r.chunks.as_ref().unwrap();
+                assert_eq!(chunks.size, 4);
+                assert_eq!(chunks.hashes, chunked.chunks.as_ref().unwrap().hashes);
+            }
+            Record::Deleted(_) => panic!("Expected a hash"),
+        }
+        assert!(matches!(&records[2], Record::Deleted(p) if p == Path::new("/c")));
+        data.close().unwrap();
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn wide_hashes_round_trip() {
+        let path = temp_path("wide.xxhd");
+        let algorithm = HashAlgorithm::Xxh128 {
+            seed: 5,
+            split: None,
+        };
+        let plain = HashResult {
+            path: PathBuf::from("/a"),
+            hash: HashValue::U128(u128::MAX - 1),
+            len: 3,
+            mtime: 4,
+            chunks: None,
+            hashed_at: 7,
+        };
+        let chunked = HashResult {
+            path: PathBuf::from("/b"),
+            hash: HashValue::U128(1 << 100),
+            len: 5,
+            mtime: 6,
+            chunks: Some(ChunkHashes {
+                size: 4,
+                hashes: vec![HashValue::U128(2), HashValue::U128(u128::MAX)],
+            }),
+            hashed_at: 7,
+        };
+
+        let data = XxhDiffData::new(&path, false, None, algorithm, Duration::ZERO).unwrap();
+        let (_, mut writer) = data.split().unwrap();
+        writer.write(&[&plain, &chunked]).unwrap();
+        writer.write_deleted(&[Path::new("/c")]).unwrap();
+        writer.close().unwrap();
+
+        // The width comes from the header, which is all a reader knows it by
+        let mut data = XxhDiffData::open(&path, Duration::ZERO).unwrap();
+        assert_eq!(data.algorithm(), algorithm);
+        assert_eq!(data.algorithm().bits(), 128);
+        let records = read_all(&mut data);
+        assert_eq!(
+            paths_and_hashes(&records),
+            [
+                (Path::new("/a"), Some(plain.hash)),
+                (Path::new("/b"), Some(chunked.hash)),
+                (Path::new("/c"), None),
+            ]
+        );
+        match &records[1] {
+            Record::Hash(r) => assert_eq!(
+                r.chunks.as_ref().unwrap().hashes,
+                chunked.chunks.unwrap().hashes
+            ),
+            Record::Deleted(_) => panic!("Expected a hash"),
+        }
+        data.close().unwrap();
+
+        // Hashes of either width are read back as the width the file says
+        let narrow_path = temp_path("narrow.xxhd");
+        let mut data = XxhDiffData::new(
+            &narrow_path,
+            false,
+            None,
+            HashAlgorithm::default(),
+            Duration::ZERO,
+        )
+        .unwrap();
+        data.write(&[&hashed("/a", 7)]).unwrap();
+        data.close().unwrap();
+        let mut data = XxhDiffData::open(&narrow_path, Duration::ZERO).unwrap();
+        assert_eq!(data.algorithm().bits(), 64);
+        assert_eq!(
+            paths_and_hashes(&read_all(&mut data)),
+            [(Path::new("/a"), Some(HashValue::U64(1)))]
+        );
+        data.close().unwrap();
+        fs::remove_file(path).unwrap();
+        fs::remove_file(narrow_path).unwrap();
+    }
+
+    fn hashed(path: &str, hashed_at: i64) -> HashResult {
+        HashResult {
+            path: PathBuf::from(path),
+            hash: HashValue::U64(1),
+            len: 2,
+            mtime: 3,
+            chunks: None,
+            hashed_at,
+        }
+    }
+
+    fn hashed_ats(records: &[Record]) -> Vec<Option<i64>> {
+        records
+            .iter()
+            .map(|r| match r {
+                Record::Hash(r) => Some(r.hashed_at),
+                Record::Deleted(_) => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn hash_times_round_trip() {
+        let path = temp_path("times.xxhd");
+        let algorithm = HashAlgorithm::default();
+
+        let mut data = XxhDiffData::new(&path, false, None, algorithm, Duration::ZERO).unwrap();
+        data.write(&[&hashed("/a", 100), &hashed("/b", 100), &hashed("/c", 200)])
+            .unwrap();
+        data.close().unwrap();
+        // Appended to by a later run, which marks its first record again
+        let data = XxhDiffData::new(&path, true, None, algorithm, Duration::ZERO).unwrap();
+        let (reader, mut writer) = data.split().unwrap();
+        writer.write_deleted(&[Path::new("/a")]).unwrap();
+        writer.write(&[&hashed("/d", 200)]).unwrap();
+        writer.close().unwrap();
+        drop(reader);
+
+        let mut data = XxhDiffData::open(&path, Duration::ZERO).unwrap();
+        let records = read_all(&mut data);
+        assert_eq!(
+            hashed_ats(&records),
+            [Some(100), Some(100), Some(200), None, Some(200)]
+        );
+        assert!(data.footer().is_some());
+        data.close().unwrap();
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn partial_time_marker_is_truncated() {
+        let path = temp_path("partial-marker.xxhd");
+        let mut data =
+            XxhDiffData::new(&path, false, None, HashAlgorithm::default(), Duration::ZERO).unwrap();
+        data.write(&[&hashed("/a", 100)]).unwrap();
+        let (_, writer) = data.split().unwrap();
+        // Closing writes the footer, a run killed mid-write has none
+        drop(writer);
+        let valid_len = fs::metadata(&path).unwrap().len();
+        let mut file = File::options().append(true).open(&path).unwrap();
+        file.write_all(&[TIME_MARKER, 1, 2, 3]).unwrap();
+        drop(file);
+
+        let mut data = XxhDiffData::open(&path, Duration::ZERO).unwrap();
+        assert_eq!(hashed_ats(&read_all(&mut data)), [Some(100)]);
+        assert_eq!(data.truncated_at(), Some(valid_len));
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn corrupt_time_marker() {
+        let path = temp_path("corrupt-marker.xxhd");
+        let mut data =
+            XxhDiffData::new(&path, false, None, HashAlgorithm::default(), Duration::ZERO).unwrap();
+        data.write(&[&hashed("/a", 100), &hashed("/b", 200)])
+            .unwrap();
+        let (_, writer) = data.split().unwrap();
+        drop(writer);
+
+        // Flips a bit of the second marker's time, leaving its checksum wrong
+        let mut bytes = fs::read(&path).unwrap();
+        let second = bytes.len() - 1 - bytes.iter().rev().position(|b| *b == TIME_MARKER).unwrap();
+        bytes[second + 1] ^= 1;
+        fs::write(&path, &bytes).unwrap();
+
+        let mut data = XxhDiffData::open(&path, Duration::ZERO).unwrap();
+        let records = read_all(&mut data);
+        // The record after it is skipped to, but isn't known to have been hashed since the last
+        // good marker
+        assert_eq!(hashed_ats(&records), [Some(100), Some(UNKNOWN_HASHED_AT)]);
+        assert!(matches!(&data, XxhDiffData::Read(_, inner) if inner.skipped == 1));
+        fs::remove_file(path).unwrap();
+    }
+
+    fn paths_and_hashes(records: &[Record]) -> Vec<(&Path, Option<HashValue>)> {
+        records
+            .iter()
+            .map(|r| match r {
+                Record::Hash(r) => (&*r.path, Some(r.hash)),
+                Record::Deleted(path) => (&**path, None),
+            })
+            .collect()
+    }
+
+    /// Path lengths are written as a u64 whatever the platform, right after the hash
+    #[test]
+    fn fixed_width_path_length() {
+        let path = temp_path("path-len.xxhd");
+        let mut data =
+            XxhDiffData::new(&path, false, None, HashAlgorithm::default(), Duration::ZERO).unwrap();
+        data.write(&[&hashed("/a", 100)]).unwrap();
+        data.close().unwrap();
+
+        let mut head = vec![META_HEAD_SIZE as u8 | CHECKSUM_FLAG];
+        head.extend_from_slice(&1u64.to_le_bytes());
+        head.extend_from_slice(&2u64.to_le_bytes());
+        let bytes = fs::read(&path).unwrap();
+        assert!(bytes.windows(head.len()).any(|w| w == head));
+
+        let mut data = XxhDiffData::open(&path, Duration::ZERO).unwrap();
+        let records = read_all(&mut data);
+        assert_eq!(
+            paths_and_hashes(&records),
+            [(Path::new("/a"), Some(HashValue::U64(1)))]
+        );
+        fs::remove_file(path).unwrap();
+    }
+
+    /// Files from before version 6 sized the path length as the writer's usize, so a 64-bit
+    /// machine's heads were 16 bytes and a 32-bit one's 12. Any head from 9 bytes up is read
+    #[cfg(unix)]
+    #[test]
+    fn legacy_path_lengths() {
+        let path = temp_path("legacy-path-len.xxhd");
+        let mut bytes = MAGIC.to_vec();
+        bytes.extend_from_slice(&1u16.to_le_bytes());
+        for (hlen, hash, record_path) in [(16, 1u64, "/64-bit"), (12, 2, "/32-bit"), (9, 3, "/9")] {
+            bytes.push(hlen);
+            bytes.extend_from_slice(&hash.to_le_bytes());
+            let path_len = (record_path.len() as u64).to_le_bytes();
+            bytes.extend_from_slice(&path_len[..hlen as usize - U64_BYTES as usize]);
+            bytes.extend_from_slice(record_path.as_bytes());
+        }
+        fs::write(&path, &bytes).unwrap();
+
+        let mut data = XxhDiffData::open(&path, Duration::ZERO).unwrap();
+        let records = read_all(&mut data);
+        assert_eq!(
+            paths_and_hashes(&records),
+            [
+                (Path::new("/64-bit"), Some(HashValue::U64(1))),
+                (Path::new("/32-bit"), Some(HashValue::U64(2))),
+                (Path::new("/9"), Some(HashValue::U64(3))),
+            ]
+        );
+        fs::remove_file(path).unwrap();
+    }
+
+    /// Writes records for `/a`, `/b` and `/c`, then flips a bit of `/b`'s path so its checksum no
+    /// longer matches
+    fn write_corrupt_b(path: &Path) {
+        let mut data =
+            XxhDiffData::new(path, false, None, HashAlgorithm::default(), Duration::ZERO).unwrap();
+        data.write(&[&hashed("/a", 100), &hashed("/b", 100), &hashed("/c", 100)])
+            .unwrap();
+        data.close().unwrap();
+
+        let mut bytes = fs::read(path).unwrap();
+        let b = bytes.windows(2).position(|w| w == b"/b").unwrap();
+        bytes[b + 1] ^= 1;
+        fs::write(path, &bytes).unwrap();
+    }
+
+    #[test]
+    fn corrupt_record_is_an_error() {
+        let path = temp_path("corrupt.xxhd");
+        write_corrupt_b(&path);
+
+        let data =
+            XxhDiffData::new(&path, true, None, HashAlgorithm::default(), Duration::ZERO).unwrap();
+        let (reader, writer) = data.split().unwrap();
+        let mut reader = reader.unwrap();
+        assert!(matches!(reader.read(), Ok(Record::Hash(r)) if r.path == Path::new("/a")));
+        let err = reader.read().err().unwrap();
+        assert!(matches!(err.kind(), DataErr::Corrupt { .. }), "{}", err);
+        drop(reader);
+        writer.close().unwrap();
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn corrupt_record_skipped() {
+        let path = temp_path("corrupt-skipped.xxhd");
+        write_corrupt_b(&path);
+
+        let mut data = XxhDiffData::open(&path, Duration::ZERO).unwrap();
+        let records = read_all(&mut data);
+        assert_eq!(
+            paths_and_hashes(&records),
+            [
+                (Path::new("/a"), Some(HashValue::U64(1))),
+                (Path::new("/c"), Some(HashValue::U64(1))),
+            ]
+        );
+        assert!(matches!(&data, XxhDiffData::Read(_, inner) if inner.skipped == 1));
+        fs::remove_file(path).unwrap();
+    }
+
+    /// Records from before checksums were added are read alongside checksummed ones appended since
+    #[cfg(unix)]
+    #[test]
+    fn unchecked_and_checked_records() {
+        let path = temp_path("mixed-checksums.xxhd");
+        let mut bytes = MAGIC.to_vec();
+        bytes.extend_from_slice(&2u16.to_le_bytes());
+        bytes.push(HEAD_SIZE as u8);
+        bytes.extend_from_slice(&7u64.to_le_bytes());
+        bytes.extend_from_slice(&4u64.to_le_bytes());
+        bytes.extend_from_slice(b"/old");
+        fs::write(&path, &bytes).unwrap();
+
+        let data =
+            XxhDiffData::new(&path, true, None, HashAlgorithm::default(), Duration::ZERO).unwrap();
+        let (reader, mut writer) = data.split().unwrap();
+        drop(reader);
+        writer.write(&[&hashed("/new", 100)]).unwrap();
+        writer.close().unwrap();
+
+        let mut data = XxhDiffData::open(&path, Duration::ZERO).unwrap();
+        let records = read_all(&mut data);
+        assert_eq!(
+            paths_and_hashes(&records),
+            [
+                (Path::new("/old"), Some(HashValue::U64(7))),
+                (Path::new("/new"), Some(HashValue::U64(1))),
+            ]
+        );
+        fs::remove_file(path).unwrap();
+    }
+
+    /// Lines a record and the time marker before it up so the read buffer's boundary falls on each
+    /// of their bytes in turn, by the length of the record before them
+    #[test]
+    fn records_straddle_read_buffer() {
+        let path = temp_path("straddle.xxhd");
+        let target = hashed("/target", 200);
+        // A marker, the head and the checksum, without the path
+        let overhead = TIME_MARKER_SIZE + 1 + u64::from(META_HEAD_SIZE) + CHECKSUM_SIZE;
+        for shift in 1..=overhead + target.path.as_os_str().len() as u64 {
+            let data =
+                XxhDiffData::new(&path, false, None, HashAlgorithm::default(), Duration::ZERO)
+                    .unwrap();
+            let (_, mut writer) = data.split().unwrap();
+            // Reads are buffered from where the records start
+            let boundary = fs::metadata(&path).unwrap().len() + READ_BUF_SIZE as u64;
+            let filler_len = READ_BUF_SIZE as u64 - shift - overhead;
+            let filler = hashed(&format!("/{}", "f".repeat(filler_len as usize - 1)), 100);
+            writer.write(&[&filler]).unwrap();
+            assert_eq!(fs::metadata(&path).unwrap().len(), boundary - shift);
+            writer.write(&[&target, &hashed("/after", 200)]).unwrap();
+            writer.close().unwrap();
+
+            let mut data = XxhDiffData::open(&path, Duration::ZERO).unwrap();
+            let records = read_all(&mut data);
+            let paths: Vec<_> = paths_and_hashes(&records)
+                .into_iter()
+                .map(|(p, _)| p)
+                .collect();
+            assert_eq!(
+                paths,
+                [&*filler.path, Path::new("/target"), Path::new("/after")],
+                "Boundary {} bytes into the target",
+                shift
+            );
+            assert_eq!(hashed_ats(&records), [Some(100), Some(200), Some(200)]);
+            drop(data);
+            fs::remove_file(&path).unwrap();
+        }
+    }
+
+    /// The reader half of a split file keeps its place while the writer appends to the same file,
+    /// and stops at where the file ended when it was opened
+    #[test]
+    fn read_while_appending() {
+        let path = temp_path("read-append.xxhd");
+        let mut data =
+            XxhDiffData::new(&path, false, None, HashAlgorithm::default(), Duration::ZERO).unwrap();
+        data.write(&[&hashed("/a", 100), &hashed("/b", 100), &hashed("/c", 100)])
+            .unwrap();
+        data.close().unwrap();
+
+        let data =
+            XxhDiffData::new(&path, true, None, HashAlgorithm::default(), Duration::ZERO).unwrap();
+        let (reader, mut writer) = data.split().unwrap();
+        let mut reader = reader.unwrap();
+        let mut read = Vec::new();
+        read.push(reader.read().unwrap());
+        writer.write(&[&hashed("/d", 100)]).unwrap();
+        read.push(reader.read().unwrap());
+        writer.write(&[&hashed("/e", 100)]).unwrap();
+        read.push(reader.read().unwrap());
+        assert!(matches!(
+            reader.read().err().unwrap().kind(),
+            DataErr::Empty
+        ));
+        let paths: Vec<_> = paths_and_hashes(&read)
+            .into_iter()
+            .map(|(p, _)| p)
+            .collect();
+        assert_eq!(paths, ["/a", "/b", "/c"].map(Path::new));
+        drop(reader);
+        writer.close().unwrap();
+
+        let mut data = XxhDiffData::open(&path, Duration::ZERO).unwrap();
+        assert_eq!(read_all(&mut data).len(), 5);
+        fs::remove_file(path).unwrap();
+    }
+
+    /// A run killed partway through writing `/bbbb` leaves the file cut off after its first byte,
+    /// in its head, in its path or in its checksum. Reading stops cleanly after `/a` each time, and
+    /// the file can be cut back to it and appended to
+    #[test]
+    fn truncated_final_record() {
+        let path = temp_path("truncated.xxhd");
+        let mut data =
+            XxhDiffData::new(&path, false, None, HashAlgorithm::default(), Duration::ZERO).unwrap();
+        data.write(&[&hashed("/a", 100)]).unwrap();
+        let valid_len = fs::metadata(&path).unwrap().len();
+        data.write(&[&hashed("/bbbb", 100)]).unwrap();
+        let (_, writer) = data.split().unwrap();
+        drop(writer);
+        let full = fs::read(&path).unwrap();
+        let full_len = full.len() as u64;
+
+        let path_start = valid_len + 1 + u64::from(META_HEAD_SIZE);
+        for cut in [valid_len + 1, valid_len + 10, path_start + 2, full_len - 2] {
+            fs::write(&path, &full[..cut as usize]).unwrap();
+
+            let mut data = XxhDiffData::open(&path, Duration::ZERO).unwrap();
+            let records = read_all(&mut data);
+            assert_eq!(records.len(), 1, "Cut at {}", cut);
+            assert_eq!(data.truncated_at(), Some(valid_len), "Cut at {}", cut);
+            drop(data);
+
+            let data =
+                XxhDiffData::new(&path, true, None, HashAlgorithm::default(), Duration::ZERO)
+                    .unwrap();
+            let (reader, mut writer) = data.split().unwrap();
+            let mut reader = reader.unwrap();
+            while reader.read().is_ok() {}
+            assert_eq!(writer.truncate_to_valid(&reader).unwrap(), cut - valid_len);
+            drop(reader);
+            writer.write(&[&hashed("/c", 100)]).unwrap();
+            writer.close().unwrap();
+
+            let mut data = XxhDiffData::open(&path, Duration::ZERO).unwrap();
+            let records = read_all(&mut data);
+            let paths: Vec<_> = paths_and_hashes(&records)
+                .into_iter()
+                .map(|(p, _)| p)
+                .collect();
+            assert_eq!(paths, ["/a", "/c"].map(Path::new), "Cut at {}", cut);
+            drop(data);
+            fs::remove_file(&path).unwrap();
+        }
+    }
+
+    /// A data file declaring the path encoding `encoding_id`, with a record for `path_bytes`, as
+    /// it would be written where paths are stored that way
+    fn encoded_fixture(path: &Path, encoding_id: u8, path_bytes: &[u8]) {
+        let mut bytes = Vec::new();
+        write_header(&mut bytes, None, HashAlgorithm::default()).unwrap();
+        let encoding_at = (HEADER_SIZE + INDEX_OFFSET_SIZE + ALGORITHM_SIZE + SPLIT_SIZE) as usize;
+        assert_eq!(bytes[encoding_at], PORTABLE_PATHS_ID);
+        bytes[encoding_at] = encoding_id;
+
+        bytes.push(META_HEAD_SIZE as u8 | CHECKSUM_FLAG);
+        bytes.extend_from_slice(&1u64.to_le_bytes());
+        bytes.extend_from_slice(&(path_bytes.len() as u64).to_le_bytes());
+        bytes.extend_from_slice(&2u64.to_le_bytes());
+        bytes.extend_from_slice(&3i64.to_le_bytes());
+        bytes.extend_from_slice(path_bytes);
+        let checksum = record_checksum(Some(HashValue::U64(1)), Some((2, 3)), path_bytes, None);
+        bytes.extend_from_slice(&checksum.to_le_bytes());
+        fs::write(path, bytes).unwrap();
+    }
+
+    fn read_fixture_path(path: &Path) -> PathBuf {
+        let mut data = XxhDiffData::open(path, Duration::ZERO).unwrap();
+        let records = read_all(&mut data);
+        let [Record::Hash(r)] = &records[..] else {
+            panic!("Expected a hash");
+        };
+        r.path.clone()
+    }
+
+    /// Files with Unix and Windows path encodings declared are read on either platform
+    #[test]
+    fn foreign_path_encodings() {
+        let path = temp_path("encodings.xxhd");
+        let utf16le =
+            |units: &[u16]| -> Vec<u8> { units.iter().flat_map(|u| u.to_le_bytes()).collect() };
+        let cafe: Vec<u16> = "/dir/café".encode_utf16().collect();
+
+        encoded_fixture(&path, UNIX_PATHS_ID, "/dir/café".as_bytes());
+        assert_eq!(read_fixture_path(&path), Path::new("/dir/café"));
+        encoded_fixture(&path, UTF16LE_PATHS_ID, &utf16le(&cafe));
+        assert_eq!(read_fixture_path(&path), Path::new("/dir/café"));
+
+        // What can't be a path on the other platform is kept the way it would be written there
+        encoded_fixture(&path, UNIX_PATHS_ID, b"/a\xFF");
+        let unix_invalid = read_fixture_path(&path);
+        encoded_fixture(&path, UTF16LE_PATHS_ID, &utf16le(&[0x2F, 0xD800]));
+        let unpaired = read_fixture_path(&path);
+        #[cfg(unix)]
+        {
+            assert_eq!(unix_invalid.as_os_str().as_bytes(), b"/a\xFF");
+            assert_eq!(unpaired.as_os_str().as_bytes(), b"/\xED\xA0\x80");
+        }
+        #[cfg(windows)]
+        {
+            assert_eq!(
+                unix_invalid.as_os_str().encode_wide().collect::<Vec<_>>(),
+                [0x2F, 0x61, 0xDCFF]
+            );
+            assert_eq!(
+                unpaired.as_os_str().encode_wide().collect::<Vec<_>>(),
+                [0x2F, 0xD800]
+            );
+        }
+        fs::remove_file(path).unwrap();
+    }
+
+    /// Write syscalls made by this thread so far, which other tests running at once don't add to
+    #[cfg(target_os = "linux")]
+    fn write_syscalls() -> u64 {
+        fs::read_to_string("/proc/thread-self/io")
+            .unwrap()
+            .lines()
+            .find_map(|l| l.strip_prefix("syscw: "))
+            .unwrap()
+            .parse()
+            .unwrap()
+    }
+
+    /// 100k records take a write for every `WRITE_BUF_SIZE` bytes of them, where they used to take
+    /// four each
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn writes_are_chunked() {
+        const RECORDS: usize = 100_000;
+        let path = temp_path("chunked.xxhd");
+        let results: Vec<_> = (0..RECORDS)
+            .map(|i| hashed(&format!("/dir/file-{}", i), 100))
+            .collect();
+        let results: Vec<_> = results.iter().collect();
+
+        let data =
+            XxhDiffData::new(&path, false, None, HashAlgorithm::default(), Duration::ZERO).unwrap();
+        let (_, mut writer) = data.split().unwrap();
+        let initial_len = fs::metadata(&path).unwrap().len();
+        let syscalls = write_syscalls();
+        let start = Instant::now();
+        writer.write(&results).unwrap();
+        let elapsed = start.elapsed();
+        let syscalls = write_syscalls() - syscalls;
+        let written = fs::metadata(&path).unwrap().len() - initial_len;
+
+        println!(
+            "{} records, {} bytes in {} write syscalls, {:?}",
+            RECORDS, written, syscalls, elapsed
+        );
+        assert!(syscalls <= written / WRITE_BUF_SIZE as u64 + 2);
+        writer.close().unwrap();
+        fs::remove_file(path).unwrap();
+    }
+
+    /// What each `--sync` policy costs, writing in batches like a run does. Ignored as it's slow on
+    /// a real disk and says nothing on tmpfs, run it with `--ignored --nocapture` where the data
+    /// files go. `always` pays a sync per record, `batch` one per batch of them, which is what
+    /// justifies it as the default. On an ext4 VM disk it gave about 920k records/s for `never`,
+    /// 400k for `batch` and 16k for `always`
+    #[test]
+    #[ignore]
+    fn sync_policy_cost() {
+        const RECORDS: usize = 2000;
+        const BATCH: usize = 50;
+        let results: Vec<_> = (0..RECORDS)
+            .map(|i| hashed(&format!("/dir/file-{}", i), 100))
+            .collect();
+        let results: Vec<_> = results.iter().collect();
+
+        for policy in [SyncPolicy::Never, SyncPolicy::Batch, SyncPolicy::Always] {
+            let path = temp_path(&format!("sync-{:?}.xxhd", policy));
+            let mut data =
+                XxhDiffData::new(&path, false, None, HashAlgorithm::default(), Duration::ZERO)
+                    .unwrap();
+            data.set_sync(policy);
+            let start = Instant::now();
+            for batch in results.chunks(BATCH) {
+                data.write(batch).unwrap();
+            }
+            let elapsed = start.elapsed();
+            println!(
+                "{:?}: {:?}, {:.0} records/s",
+                policy,
+                elapsed,
+                RECORDS as f64 / elapsed.as_secs_f64()
+            );
+            data.close().unwrap();
+            fs::remove_file(path).unwrap();
+        }
     }
 }
@@ -0,0 +1,158 @@
+//! OpenPGP encryption of data files to the disc-up backup key, so the directory structure they
+//! record is as protected as the backup discs they travel with
+
+use std::{
+    fs::File,
+    io::{self, BufRead, Read, Write},
+    path::Path,
+};
+
+use sequoia_openpgp::{
+    crypto::{Password, SessionKey},
+    packet::{PKESK, SKESK},
+    parse::{
+        stream::{DecryptionHelper, DecryptorBuilder, MessageStructure, VerificationHelper},
+        Parse,
+    },
+    policy::StandardPolicy,
+    serialize::stream::{Encryptor as MessageEncryptor, LiteralWriter, Message},
+    types::SymmetricAlgorithm,
+    Cert, Fingerprint, KeyHandle,
+};
+
+const POLICY: &StandardPolicy = &StandardPolicy::new();
+
+/// Reads a cert, or a key with its secret parts, from `path`. It's leaked, since an [`Encryptor`]
+/// borrows it for as long as the data file is written
+pub fn load_cert(path: &Path) -> Result<&'static Cert, String> {
+    Cert::from_file(path)
+        .map(|c| &*Box::leak(Box::new(c)))
+        .map_err(|e| format!("Error reading key file {}: {}", path.display(), e))
+}
+
+/// Asks for the password of the key's secret parts on stderr, reading it from stdin
+pub fn prompt_password() -> io::Result<String> {
+    eprint!("Password for the backup key: ");
+    io::stderr().flush()?;
+    let mut password = String::new();
+    io::stdin().lock().read_line(&mut password)?;
+    Ok(password.trim_end_matches(['\r', '\n']).to_string())
+}
+
+/// An OpenPGP message encrypted to the storage encryption subkeys of a cert, written to a file
+pub struct Encryptor {
+    message: Message<'static>,
+}
+
+impl Encryptor {
+    pub fn new(file: File, cert: &'static Cert) -> io::Result<Self> {
+        let recipients: Vec<_> = cert
+            .keys()
+            .with_policy(POLICY, None)
+            .supported()
+            .alive()
+            .revoked(false)
+            .for_storage_encryption()
+            .collect();
+        if recipients.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("No usable storage encryption key in {}", cert.fingerprint()),
+            ));
+        }
+
+        let message = MessageEncryptor::for_recipients(Message::new(file), recipients)
+            .build()
+            .and_then(|m| LiteralWriter::new(m).build())
+            .map_err(io::Error::other)?;
+        Ok(Self { message })
+    }
+
+    /// Writes out the rest of the message, which can't be read without it
+    pub fn finish(self) -> io::Result<()> {
+        self.message.finalize().map_err(io::Error::other)
+    }
+}
+
+impl Write for Encryptor {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.message.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.message.flush()
+    }
+}
+
+/// Decrypts all of `file` with `key`, calling `password` if its secret parts are protected by one
+pub fn decrypt(
+    file: &File,
+    key: &Cert,
+    password: &mut dyn FnMut() -> io::Result<String>,
+) -> io::Result<Vec<u8>> {
+    let helper = Helper { key, password };
+    let mut decryptor = DecryptorBuilder::from_reader(file)
+        .and_then(|d| d.with_policy(POLICY, None, helper))
+        .map_err(io::Error::other)?;
+    let mut plain = Vec::new();
+    decryptor.read_to_end(&mut plain)?;
+    Ok(plain)
+}
+
+struct Helper<'a> {
+    key: &'a Cert,
+    password: &'a mut dyn FnMut() -> io::Result<String>,
+}
+
+impl DecryptionHelper for Helper<'_> {
+    fn decrypt<D>(
+        &mut self,
+        pkesks: &[PKESK],
+        _skesks: &[SKESK],
+        sym_algo: Option<SymmetricAlgorithm>,
+        mut decrypt: D,
+    ) -> sequoia_openpgp::Result<Option<Fingerprint>>
+    where
+        D: FnMut(SymmetricAlgorithm, &SessionKey) -> bool,
+    {
+        for ka in self
+            .key
+            .keys()
+            .with_policy(POLICY, None)
+            .supported()
+            .secret()
+            .for_storage_encryption()
+        {
+            let Some(pkesk) = pkesks.iter().find(|p| *p.recipient() == ka.key().keyid()) else {
+                continue;
+            };
+
+            let key = ka.key().clone();
+            let key = match key.has_unencrypted_secret() {
+                true => key,
+                false => key.decrypt_secret(&Password::from((self.password)()?))?,
+            };
+            let mut pair = key.into_keypair()?;
+            if pkesk
+                .decrypt(&mut pair, sym_algo)
+                .map(|(algo, session_key)| decrypt(algo, &session_key))
+                .unwrap_or(false)
+            {
+                return Ok(Some(self.key.fingerprint()));
+            }
+        }
+
+        Ok(None)
+    }
+}
+
+impl VerificationHelper for Helper<'_> {
+    fn get_certs(&mut self, _ids: &[KeyHandle]) -> sequoia_openpgp::Result<Vec<Cert>> {
+        Ok(Vec::new())
+    }
+
+    /// Data files aren't signed
+    fn check(&mut self, _structure: MessageStructure) -> sequoia_openpgp::Result<()> {
+        Ok(())
+    }
+}
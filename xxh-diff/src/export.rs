@@ -0,0 +1,166 @@
+use std::{
+    borrow::Cow,
+    fs::File,
+    io::{self, BufWriter, ErrorKind, Write},
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use clap::{Args, ValueEnum};
+
+use crate::data_fmt::{DataErr, HashResult, ReadXxhDiffDataInner, Record, XxhDiffData};
+
+#[derive(Args, Debug)]
+pub struct ExportArgs {
+    data_file: String,
+
+    #[clap(long, value_enum, default_value = "xxhsum")]
+    format: ExportFormat,
+
+    #[clap(long, short)]
+    output: Option<String>,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum ExportFormat {
+    Xxhsum,
+    Csv,
+    Jsonl,
+}
+
+pub fn export(args: ExportArgs) -> Result<(), String> {
+    let mut data = match XxhDiffData::open(&PathBuf::from(&args.data_file), Duration::ZERO) {
+        Ok(d) => d,
+        Err(e) if matches!(e.kind(), DataErr::IOErr(e) if e.kind() == ErrorKind::NotFound) => {
+            return Err("Data file not found".to_string())
+        }
+        Err(e) => return Err(format!("Error opening data file: {}", e)),
+    };
+
+    let out: Box<dyn Write> = match &args.output {
+        Some(o) => {
+            Box::new(File::create(o).map_err(|e| format!("Error creating output file: {}", e))?)
+        }
+        None => Box::new(io::stdout().lock()),
+    };
+    let mut out = BufWriter::new(out);
+
+    let write_err = |e: io::Error| format!("Error writing export: {}", e);
+    if let ExportFormat::Csv = args.format {
+        out.write_all(b"path,hash,len,mtime\n").map_err(write_err)?;
+    }
+
+    loop {
+        let result = match data.read_skip_corrupt() {
+            Ok(Record::Hash(r)) => r,
+            // None of the formats can express a deletion, compact the data file to drop the
+            // records they cover
+            Ok(Record::Deleted(_)) => continue,
+            Err(DataErr::Empty) => break,
+            Err(e) => return Err(format!("Error reading from data file: {}", e)),
+        };
+
+        match args.format {
+            ExportFormat::Xxhsum => write_xxhsum(&mut out, &result),
+            ExportFormat::Csv => write_csv(&mut out, &result),
+            ExportFormat::Jsonl => write_jsonl(&mut out, &result),
+        }
+        .map_err(write_err)?;
+    }
+
+    out.flush().map_err(write_err)?;
+
+    match data {
+        XxhDiffData::Read(_, ReadXxhDiffDataInner { skipped, .. }) if skipped > 0 => Err(format!(
+            "Skipped {} corrupt section(s) of the data file",
+            skipped
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// `<hex hash>  <path>`, with the path's raw bytes. As with the coreutils checksum tools, a path
+/// containing a backslash or newline has them escaped and the line prefixed with a backslash
+fn write_xxhsum(out: &mut impl Write, result: &HashResult) -> io::Result<()> {
+    let path = raw_bytes(&result.path);
+    let escape = path.iter().any(|b| matches!(b, b'\\' | b'\n'));
+    if escape {
+        out.write_all(b"\\")?;
+    }
+    write!(out, "{}  ", result.hash)?;
+    if escape {
+        for b in path.iter() {
+            match b {
+                b'\\' => out.write_all(b"\\\\")?,
+                b'\n' => out.write_all(b"\\n")?,
+                b => out.write_all(&[*b])?,
+            }
+        }
+    } else {
+        out.write_all(&path)?;
+    }
+    out.write_all(b"\n")
+}
+
+fn write_csv(out: &mut impl Write, result: &HashResult) -> io::Result<()> {
+    let path = utf8_path(&result.path);
+    if path.contains(['"', ',', '\n', '\r']) {
+        write!(out, "\"{}\"", path.replace('"', "\"\""))?;
+    } else {
+        out.write_all(path.as_bytes())?;
+    }
+    write!(out, ",{},", result.hash)?;
+    if result.has_metadata() {
+        write!(out, "{},{}", result.len, result.mtime)?;
+    } else {
+        out.write_all(b",")?;
+    }
+    out.write_all(b"\n")
+}
+
+fn write_jsonl(out: &mut impl Write, result: &HashResult) -> io::Result<()> {
+    out.write_all(b"{\"path\":\"")?;
+    for c in utf8_path(&result.path).chars() {
+        match c {
+            '"' => out.write_all(b"\\\"")?,
+            '\\' => out.write_all(b"\\\\")?,
+            '\n' => out.write_all(b"\\n")?,
+            '\r' => out.write_all(b"\\r")?,
+            '\t' => out.write_all(b"\\t")?,
+            c if (c as u32) < 0x20 => write!(out, "\\u{:04x}", c as u32)?,
+            c => write!(out, "{}", c)?,
+        }
+    }
+    write!(out, "\",\"hash\":\"{}\"", result.hash)?;
+    if result.has_metadata() {
+        write!(out, ",\"len\":{},\"mtime\":{}", result.len, result.mtime)?;
+    }
+    out.write_all(b"}\n")
+}
+
+fn utf8_path(path: &Path) -> Cow<'_, str> {
+    let lossy = path.to_string_lossy();
+    if let Cow::Owned(_) = lossy {
+        eprintln!(
+            "Warning: Path {} is not valid UTF-8, exported with replacement characters",
+            path.display()
+        );
+    }
+    lossy
+}
+
+#[cfg(unix)]
+fn raw_bytes(path: &Path) -> Cow<'_, [u8]> {
+    use std::os::unix::prelude::*;
+
+    Cow::Borrowed(path.as_os_str().as_bytes())
+}
+
+/// Windows paths are UTF-16, there are no raw bytes a checksum tool would expect
+#[cfg(windows)]
+fn raw_bytes(path: &Path) -> Cow<'_, [u8]> {
+    match path.to_string_lossy() {
+        Cow::Borrowed(p) => Cow::Borrowed(p.as_bytes()),
+        Cow::Owned(p) => Cow::Owned(p.into_bytes()),
+    }
+}
@@ -0,0 +1,98 @@
+//! `--files-from`, for hashing a list of files made by something else rather than walking dirs
+
+use std::{
+    fs::{self, File},
+    io::{self, Read},
+    path::{Path, PathBuf},
+};
+
+use hashbrown::HashSet;
+
+/// Reads the list at `from`, stdin if it's `-`, with a path a line or NUL separated with `from0`.
+/// The paths are canonicalized, and ones that are under `dirs`, listed already or not files are
+/// left out. Paths that can't be found are warned about, or are errors with `fail_fast`
+pub fn read(
+    from: &str,
+    from0: bool,
+    dirs: &[PathBuf],
+    fail_fast: bool,
+) -> Result<Vec<PathBuf>, String> {
+    let mut list = Vec::new();
+    let res = match from {
+        "-" => io::stdin().lock().read_to_end(&mut list),
+        _ => File::open(from).and_then(|mut f| f.read_to_end(&mut list)),
+    };
+    if let Err(e) = res {
+        return Err(format!("Error reading --files-from {}: {}", from, e));
+    }
+
+    let separator = match from0 {
+        true => b'\0',
+        false => b'\n',
+    };
+    let mut seen = HashSet::new();
+    let mut files = Vec::new();
+    for entry in list.split(|b| *b == separator).filter(|e| !e.is_empty()) {
+        let path = match to_path(entry) {
+            Some(p) => p,
+            None => {
+                let e = io::Error::new(io::ErrorKind::InvalidData, "not valid UTF-8");
+                skip(Path::new(&*String::from_utf8_lossy(entry)), e, fail_fast)?;
+                continue;
+            }
+        };
+        let path = match fs::canonicalize(&path) {
+            Ok(p) => p,
+            Err(e) => {
+                skip(&path, e, fail_fast)?;
+                continue;
+            }
+        };
+        if dirs.iter().any(|d| path.starts_with(d)) || seen.contains(&path) {
+            continue;
+        }
+
+        match fs::metadata(&path) {
+            Ok(m) if m.is_file() => {
+                seen.insert(path.clone());
+                files.push(path);
+            }
+            Ok(_) => {}
+            Err(e) => skip(&path, e, fail_fast)?,
+        }
+    }
+    Ok(files)
+}
+
+fn skip(path: &Path, e: io::Error, fail_fast: bool) -> Result<(), String> {
+    match fail_fast {
+        true => Err(format!(
+            "Error with path {} from --files-from: {}",
+            path.display(),
+            e
+        )),
+        false => {
+            eprintln!(
+                "Warning: Skipping path {} from --files-from: {}",
+                path.display(),
+                e
+            );
+            Ok(())
+        }
+    }
+}
+
+#[cfg(unix)]
+fn to_path(entry: &[u8]) -> Option<PathBuf> {
+    use std::{ffi::OsStr, os::unix::ffi::OsStrExt};
+
+    Some(OsStr::from_bytes(entry).into())
+}
+
+/// The list has to be UTF-8. A carriage return can't be in a path, so ones from CRLF line endings
+/// are dropped
+#[cfg(windows)]
+fn to_path(entry: &[u8]) -> Option<PathBuf> {
+    let entry = entry.strip_suffix(b"\r").unwrap_or(entry);
+    std::str::from_utf8(entry).ok().map(PathBuf::from)
+}
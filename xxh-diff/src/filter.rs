@@ -0,0 +1,348 @@
+//! `--exclude` and `--include`, and ignore files, for leaving parts of the trees being hashed out
+
+use std::{
+    fs,
+    io::ErrorKind,
+    path::{is_separator, Path, PathBuf},
+    rc::Rc,
+};
+
+use glob::{MatchOptions, Pattern};
+
+use crate::paths::WalkErrors;
+
+const OPTIONS: MatchOptions = MatchOptions {
+    case_sensitive: !cfg!(windows),
+    require_literal_separator: true,
+    require_literal_leading_dot: false,
+};
+
+struct Rule {
+    include: bool,
+    /// Matched against the whole path below the root if it has a `/` in it, the file name if not
+    anchored: bool,
+    /// Ends with a `/`, so only matches dirs
+    dir_only: bool,
+    pattern: Pattern,
+    /// Each component of the pattern, for whether an anchored include could match under a dir
+    components: Vec<Pattern>,
+}
+
+impl Rule {
+    fn new(include: bool, glob: &str) -> Result<Self, String> {
+        let err = |e| format!("Invalid pattern {}: {}", glob, e);
+
+        let dir_only = glob.ends_with(is_separator);
+        let trimmed = glob.trim_end_matches(is_separator);
+        let anchored = trimmed.contains(is_separator);
+        let trimmed = trimmed.trim_start_matches(is_separator);
+        let components = trimmed
+            .split(is_separator)
+            .map(Pattern::new)
+            .collect::<Result<_, _>>()
+            .map_err(err)?;
+        Ok(Self {
+            include,
+            anchored,
+            dir_only,
+            pattern: Pattern::new(trimmed).map_err(err)?,
+            components,
+        })
+    }
+
+    fn matches(&self, path: &str, name: &str, is_dir: bool) -> bool {
+        let target = match self.anchored {
+            true => path,
+            false => name,
+        };
+        (is_dir || !self.dir_only) && self.pattern.matches_with(target, OPTIONS)
+    }
+
+    /// Whether it could match something under the dir `rel`
+    fn could_contain(&self, rel: &Path) -> bool {
+        if !self.anchored {
+            return true;
+        }
+
+        let mut components = self.components.iter();
+        for component in rel.iter() {
+            match components.next() {
+                Some(p) if p.as_str() == "**" => return true,
+                Some(p) if p.matches_with(&component.to_string_lossy(), OPTIONS) => {}
+                _ => return false,
+            }
+        }
+        components.next().is_some()
+    }
+}
+
+/// A line of an ignore file, in gitignore syntax, as whether it's a negation and its glob
+fn parse_ignore_line(line: &str) -> Option<(bool, String)> {
+    let line = line.strip_suffix('\r').unwrap_or(line);
+    // Trailing spaces don't count, unless the last is escaped
+    let trimmed = line.trim_end_matches(' ');
+    let line = match trimmed.ends_with('\\') && trimmed.len() < line.len() {
+        true => &line[..=trimmed.len()],
+        false => trimmed,
+    };
+    if line.is_empty() || line.starts_with('#') {
+        return None;
+    }
+
+    let (include, line) = match line.strip_prefix('!') {
+        Some(line) => (true, line),
+        None => (false, line),
+    };
+    // Backslashes escape the next char, which globs do with brackets
+    let mut glob = String::with_capacity(line.len());
+    let mut chars = line.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => match chars.next() {
+                Some(c @ ('*' | '?' | '[' | ']')) => glob.extend(['[', c, ']']),
+                Some(c) => glob.push(c),
+                None => {}
+            },
+            c => glob.push(c),
+        }
+    }
+    Some((include, glob))
+}
+
+/// The patterns of the ignore files in a dir, over those of the dirs it's in
+pub struct Ignores {
+    /// How far below the root the dir is
+    depth: usize,
+    /// Each is an exclude unless negated with a `!`, the last to match a path deciding
+    rules: Vec<Rule>,
+    parent: Option<Rc<Ignores>>,
+}
+
+impl Ignores {
+    /// Whether `rel`, a path below the root, is ignored, if any pattern matches it
+    fn ignored(&self, rel: &Path, is_dir: bool) -> Option<bool> {
+        let mut ignores = Some(self);
+        while let Some(i) = ignores {
+            let rel: PathBuf = rel.iter().skip(i.depth).collect();
+            let path = slash_path(&rel);
+            let name = rel.file_name().unwrap_or_default().to_string_lossy();
+            if let Some(rule) = i
+                .rules
+                .iter()
+                .rev()
+                .find(|r| r.matches(&path, &name, is_dir))
+            {
+                return Some(!rule.include);
+            }
+            ignores = i.parent.as_deref();
+        }
+        None
+    }
+}
+
+/// `path` with its components joined by `/`, whatever the platform's separator
+fn slash_path(path: &Path) -> String {
+    path.iter()
+        .map(|c| c.to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// Whether a path is hashed. Everything under an excluded dir is excluded by the rule that
+/// excluded the dir, at that rule's place in the list, so only the rules before it can include
+/// anything under it
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum State {
+    Included,
+    Excluded(usize),
+}
+
+/// The rules in the order given, the first to match a path deciding whether it's hashed, over
+/// the patterns of any ignore files
+pub struct Filter {
+    rules: Vec<Rule>,
+    /// Names of the ignore files read in each dir, the patterns of later ones going over earlier
+    ignore_names: Vec<String>,
+}
+
+impl Filter {
+    /// `rules` are whether each is an include, and its glob
+    pub fn new<'a>(
+        rules: impl IntoIterator<Item = (bool, &'a String)>,
+        ignore_names: Vec<String>,
+    ) -> Result<Self, String> {
+        Ok(Self {
+            rules: rules
+                .into_iter()
+                .map(|(include, glob)| Rule::new(include, glob))
+                .collect::<Result<_, _>>()?,
+            ignore_names,
+        })
+    }
+
+    /// Whether there's anything to check paths against, in a dir with the ignores `ignores`
+    pub fn active(&self, ignores: Option<&Ignores>) -> bool {
+        !self.rules.is_empty() || ignores.is_some()
+    }
+
+    /// The ignores for the dir `dir`, `depth` below the root, which has the ignores `parent`.
+    /// Unreadable ignore files and invalid patterns are reported to `errors` and skipped
+    pub fn read_ignores(
+        &self,
+        dir: &Path,
+        depth: usize,
+        parent: Option<Rc<Ignores>>,
+        errors: &WalkErrors,
+    ) -> Option<Rc<Ignores>> {
+        let mut rules = Vec::new();
+        for name in &self.ignore_names {
+            let path = dir.join(name);
+            let text = match fs::read_to_string(&path) {
+                Ok(t) => t,
+                Err(e) if matches!(e.kind(), ErrorKind::NotFound | ErrorKind::IsADirectory) => {
+                    continue
+                }
+                Err(e) => {
+                    errors.error(format!("Error reading ignore file {}", path.display()), e);
+                    continue;
+                }
+            };
+            for (include, glob) in text.lines().filter_map(parse_ignore_line) {
+                match Rule::new(include, &glob) {
+                    Ok(rule) => rules.push(rule),
+                    Err(e) => errors.warn(format!("Skipping pattern in {}: {}", path.display(), e)),
+                }
+            }
+        }
+
+        match rules.is_empty() {
+            true => parent,
+            false => Some(Rc::new(Ignores {
+                depth,
+                rules,
+                parent,
+            })),
+        }
+    }
+
+    /// The state of `rel`, a path below one of the roots, that's in a dir with the state `parent`
+    /// and the ignores `ignores`. Paths no rule or ignore pattern matches take their dir's. Being
+    /// ignored excludes a path after all of the rules, so any include can bring it back, but
+    /// ignore files can't bring anything back from an excluded dir
+    pub fn check(
+        &self,
+        rel: &Path,
+        is_dir: bool,
+        parent: State,
+        ignores: Option<&Ignores>,
+    ) -> State {
+        let rules = match parent {
+            State::Included => &self.rules[..],
+            State::Excluded(i) => &self.rules[..i],
+        };
+        let path = slash_path(rel);
+        let name = rel.file_name().unwrap_or_default().to_string_lossy();
+
+        match rules.iter().position(|r| r.matches(&path, &name, is_dir)) {
+            Some(i) if self.rules[i].include => State::Included,
+            Some(i) => State::Excluded(i),
+            None if parent != State::Included => parent,
+            None => match ignores.and_then(|i| i.ignored(rel, is_dir)) {
+                Some(true) => State::Excluded(self.rules.len()),
+                _ => parent,
+            },
+        }
+    }
+
+    /// Whether the dir `rel` with the state `state` needs reading. Excluded dirs only do if an
+    /// include before the rule that excluded them could match something in them
+    pub fn descend(&self, rel: &Path, state: State) -> bool {
+        match state {
+            State::Included => true,
+            State::Excluded(i) => self.rules[..i]
+                .iter()
+                .any(|r| r.include && r.could_contain(rel)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Whether each rule is an include, and its glob
+    type Rules<'a> = &'a [(bool, &'a str)];
+
+    /// What happens to `path` while walking with `rules` (includes and their globs), each dir on
+    /// the way checked and only read if it has to be, the way the walk does. A trailing `/` makes
+    /// the last component a dir
+    fn outcome(rules: Rules, path: &str) -> &'static str {
+        let globs: Vec<_> = rules.iter().map(|(_, g)| g.to_string()).collect();
+        let filter = Filter::new(rules.iter().map(|(i, _)| *i).zip(&globs), Vec::new()).unwrap();
+
+        let components: Vec<_> = path.trim_end_matches('/').split('/').collect();
+        let mut rel = PathBuf::new();
+        let mut state = State::Included;
+        for (i, component) in components.iter().enumerate() {
+            rel.push(component);
+            let is_dir = i + 1 < components.len() || path.ends_with('/');
+            state = filter.check(&rel, is_dir, state, None);
+            if i + 1 < components.len() && !filter.descend(&rel, state) {
+                return "pruned";
+            }
+        }
+        match state {
+            State::Included => "hashed",
+            State::Excluded(_) => "excluded",
+        }
+    }
+
+    #[test]
+    fn precedence() {
+        const EXCLUDE: bool = false;
+        const INCLUDE: bool = true;
+        #[rustfmt::skip]
+        let table: &[(Rules, &str, &str)] = &[
+            (&[], "a/b.txt", "hashed"),
+            // Unanchored globs match the name at any depth
+            (&[(EXCLUDE, "*.log")], "x.log", "excluded"),
+            (&[(EXCLUDE, "*.log")], "d/x.log", "excluded"),
+            (&[(EXCLUDE, "*.log")], "x.txt", "hashed"),
+            // The first rule to match wins
+            (&[(INCLUDE, "keep.log"), (EXCLUDE, "*.log")], "keep.log", "hashed"),
+            (&[(EXCLUDE, "*.log"), (INCLUDE, "keep.log")], "keep.log", "excluded"),
+            // Excluded dirs aren't read, and a trailing `/` only matches dirs
+            (&[(EXCLUDE, "node_modules/")], "node_modules/x.js", "pruned"),
+            (&[(EXCLUDE, "node_modules/")], "node_modules", "hashed"),
+            (&[(EXCLUDE, "node_modules/")], "node_modules/", "excluded"),
+            // Includes before the exclude rescue what's under an excluded dir
+            (&[(INCLUDE, "target/keep/**"), (EXCLUDE, "target/")], "target/keep/a", "hashed"),
+            (&[(INCLUDE, "target/keep/**"), (EXCLUDE, "target/")], "target/other/a", "pruned"),
+            (&[(INCLUDE, "target/keep/**"), (EXCLUDE, "target/")], "target/a", "excluded"),
+            (&[(EXCLUDE, "target/"), (INCLUDE, "target/keep/**")], "target/keep/a", "pruned"),
+            (&[(INCLUDE, "*.rs"), (EXCLUDE, "src/")], "src/deep/a.rs", "hashed"),
+            (&[(INCLUDE, "*.rs"), (EXCLUDE, "src/")], "src/deep/a.txt", "excluded"),
+            // Anchored globs match from the root, `*` not crossing dirs but `**` doing so
+            (&[(EXCLUDE, "src/*.rs")], "src/a.rs", "excluded"),
+            (&[(EXCLUDE, "src/*.rs")], "src/sub/a.rs", "hashed"),
+            (&[(EXCLUDE, "src/*.rs")], "other/src/a.rs", "hashed"),
+            (&[(EXCLUDE, "src/**/*.rs")], "src/sub/a.rs", "excluded"),
+            (&[(EXCLUDE, "**/cache/")], "a/b/cache/x", "pruned"),
+            (&[(EXCLUDE, "/build")], "build", "excluded"),
+            (&[(EXCLUDE, "/build")], "sub/build", "hashed"),
+        ];
+
+        for (rules, path, expected) in table {
+            assert_eq!(outcome(rules, path), *expected, "{} with {:?}", path, rules);
+        }
+    }
+
+    #[test]
+    fn case_sensitivity() {
+        let expected = match cfg!(windows) {
+            true => "excluded",
+            false => "hashed",
+        };
+        assert_eq!(outcome(&[(false, "*.LOG")], "x.log"), expected);
+    }
+}
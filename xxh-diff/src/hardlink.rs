@@ -0,0 +1,170 @@
+//! Hashing a file with several hard links once, rather than once for every path to it, as trees
+//! of hard linked backups would otherwise be. The ids files are told apart by also tell when
+//! following symlinks leads back to a dir
+
+use std::{
+    fs::{File, Metadata},
+    io,
+    path::Path,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+};
+
+use hashbrown::HashMap;
+
+use crate::data_fmt::HashResult;
+
+/// Identifies a file across all of its paths
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq)]
+pub struct FileId {
+    dev: u64,
+    ino: u64,
+}
+
+impl FileId {
+    /// The filesystem it's on, the volume on Windows
+    pub fn dev(&self) -> u64 {
+        self.dev
+    }
+}
+
+/// The id of an open file and how many links it has, if it has more than 1
+#[cfg(unix)]
+pub fn linked_id(file: &File, metadata: &Metadata) -> Option<(FileId, u64)> {
+    use std::os::unix::fs::MetadataExt;
+
+    let _ = file;
+    let id = FileId {
+        dev: metadata.dev(),
+        ino: metadata.ino(),
+    };
+    Some((id, metadata.nlink())).filter(|(_, links)| *links > 1)
+}
+
+#[cfg(windows)]
+#[repr(C)]
+struct ByHandleFileInformation {
+    file_attributes: u32,
+    creation_time: [u32; 2],
+    last_access_time: [u32; 2],
+    last_write_time: [u32; 2],
+    volume_serial_number: u32,
+    file_size_high: u32,
+    file_size_low: u32,
+    number_of_links: u32,
+    file_index_high: u32,
+    file_index_low: u32,
+}
+
+/// The id of an open file or dir, and how many links it has
+#[cfg(windows)]
+fn file_info(file: &File) -> Option<(FileId, u64)> {
+    use std::{ffi::c_void, mem::MaybeUninit, os::windows::io::AsRawHandle};
+
+    #[link(name = "kernel32")]
+    extern "system" {
+        fn GetFileInformationByHandle(file: *mut c_void, info: *mut ByHandleFileInformation)
+            -> i32;
+    }
+
+    let mut info = MaybeUninit::<ByHandleFileInformation>::uninit();
+    // Safety: the handle is `file`'s, which stays open, and the struct is only read once filled in
+    let info = unsafe {
+        match GetFileInformationByHandle(file.as_raw_handle(), info.as_mut_ptr()) {
+            0 => return None,
+            _ => info.assume_init(),
+        }
+    };
+    let id = FileId {
+        dev: info.volume_serial_number.into(),
+        ino: u64::from(info.file_index_high) << 32 | u64::from(info.file_index_low),
+    };
+    Some((id, info.number_of_links.into()))
+}
+
+/// The id of an open file and how many links it has, if it has more than 1
+#[cfg(windows)]
+pub fn linked_id(file: &File, metadata: &Metadata) -> Option<(FileId, u64)> {
+    let _ = metadata;
+    file_info(file).filter(|(_, links)| *links > 1)
+}
+
+/// The id of the dir at `path`, following symlinks, for telling when walking has come back to it
+#[cfg(unix)]
+pub fn dir_id(path: &Path) -> io::Result<FileId> {
+    use std::os::unix::fs::MetadataExt;
+
+    let metadata = std::fs::metadata(path)?;
+    Ok(FileId {
+        dev: metadata.dev(),
+        ino: metadata.ino(),
+    })
+}
+
+/// The id of the dir at `path`, following symlinks, for telling when walking has come back to it
+#[cfg(windows)]
+pub fn dir_id(path: &Path) -> io::Result<FileId> {
+    use std::{fs::OpenOptions, os::windows::fs::OpenOptionsExt};
+
+    /// Without it dirs can't be opened
+    const FILE_FLAG_BACKUP_SEMANTICS: u32 = 0x0200_0000;
+
+    let dir = OpenOptions::new()
+        .read(true)
+        .custom_flags(FILE_FLAG_BACKUP_SEMANTICS)
+        .open(path)?;
+    file_info(&dir)
+        .map(|(id, _)| id)
+        .ok_or_else(io::Error::last_os_error)
+}
+
+struct Linked {
+    result: HashResult,
+    /// Links that haven't been come across yet, the entry goes once they all have
+    remaining: u64,
+}
+
+/// Results of files with several links, until every link has been hashed. Only files with more
+/// than one link are kept, and only while links to them remain
+#[derive(Default)]
+pub struct HardLinks {
+    linked: Mutex<HashMap<FileId, Linked>>,
+    /// Bytes that didn't need reading, as links to them had been hashed already
+    saved: AtomicU64,
+}
+
+impl HardLinks {
+    /// The result of another link to the file, if it still has the same size and mtime
+    pub fn get(&self, id: FileId, len: u64, mtime: i64) -> Option<HashResult> {
+        let mut linked = self.linked.lock().unwrap();
+        let entry = linked.get_mut(&id)?;
+        if !entry.result.metadata_matches(len, mtime) {
+            return None;
+        }
+
+        let result = entry.result.clone();
+        entry.remaining -= 1;
+        if entry.remaining == 0 {
+            linked.remove(&id);
+        }
+        drop(linked);
+
+        self.saved.fetch_add(len, Ordering::Relaxed);
+        Some(result)
+    }
+
+    /// Records a file with `links` links having been hashed
+    pub fn insert(&self, id: FileId, links: u64, result: HashResult) {
+        let remaining = links - 1;
+        self.linked
+            .lock()
+            .unwrap()
+            .insert(id, Linked { result, remaining });
+    }
+
+    pub fn saved(&self) -> u64 {
+        self.saved.load(Ordering::Relaxed)
+    }
+}
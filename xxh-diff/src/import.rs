@@ -0,0 +1,198 @@
+use std::{
+    env,
+    fs::{self, File},
+    io::{BufRead, BufReader, ErrorKind},
+    path::PathBuf,
+    time::Duration,
+};
+
+use clap::{Args, ValueEnum};
+
+use crate::data_fmt::{
+    HashAlgorithm, HashResult, HashValue, XxhDiffData, UNKNOWN_HASHED_AT, UNKNOWN_MTIME,
+};
+
+#[derive(Args, Debug)]
+pub struct ImportArgs {
+    checksum_file: String,
+
+    #[clap(long, short)]
+    output_data: String,
+
+    #[clap(long, value_enum, default_value = "xxhsum")]
+    format: ImportFormat,
+
+    /// Fail on the first line that can't be parsed instead of skipping it
+    #[clap(long)]
+    strict: bool,
+}
+
+/// `<hex digest>  <path>` lines, as written by `xxhsum -H64` and the coreutils checksum tools
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum ImportFormat {
+    Xxhsum,
+}
+
+const BATCH_SIZE: usize = 4096;
+
+pub fn import(args: ImportArgs) -> Result<(), String> {
+    let file = match File::open(&args.checksum_file) {
+        Ok(f) => f,
+        Err(e) if e.kind() == ErrorKind::NotFound => {
+            return Err("Checksum file not found".to_string())
+        }
+        Err(e) => return Err(format!("Error opening checksum file: {}", e)),
+    };
+    let mut data = XxhDiffData::new(
+        &PathBuf::from(&args.output_data),
+        false,
+        None,
+        // xxhsum -H64 doesn't take a seed
+        HashAlgorithm::Xxh64 {
+            seed: 0,
+            split: None,
+        },
+        Duration::ZERO,
+    )
+    .map_err(|e| format!("Error opening data out file: {}", e))?;
+    let cwd = env::current_dir().map_err(|e| format!("Error getting current directory: {}", e))?;
+
+    let mut batch = Vec::with_capacity(BATCH_SIZE);
+    let mut imported = 0;
+    let mut skipped = 0;
+    for (i, line) in BufReader::new(file).split(b'\n').enumerate() {
+        let line = line.map_err(|e| format!("Error reading checksum file: {}", e))?;
+        let line = line.strip_suffix(b"\r").unwrap_or(&line);
+        if line.is_empty() {
+            continue;
+        }
+
+        let (hash, path) = match args.format {
+            ImportFormat::Xxhsum => match parse_xxhsum_line(line) {
+                Ok(l) => l,
+                Err(LineErr::Digest(bits)) => {
+                    return Err(format!(
+                        "Line {}: {}-bit digests can't be imported, only 64-bit xxhsum hashes are supported",
+                        i + 1,
+                        bits
+                    ))
+                }
+                Err(LineErr::Malformed(e)) if args.strict => {
+                    return Err(format!("Line {}: {}", i + 1, e))
+                }
+                Err(LineErr::Malformed(e)) => {
+                    eprintln!("Warning: Skipping line {}: {}", i + 1, e);
+                    skipped += 1;
+                    continue;
+                }
+            },
+        };
+
+        // Paths are stored canonicalized, as they are when hashing
+        let path = cwd.join(path);
+        let path = fs::canonicalize(&path).unwrap_or(path);
+        batch.push(HashResult {
+            path,
+            hash: HashValue::U64(hash),
+            len: 0,
+            mtime: UNKNOWN_MTIME,
+            chunks: None,
+            hashed_at: UNKNOWN_HASHED_AT,
+        });
+
+        if batch.len() == BATCH_SIZE {
+            imported += write_batch(&mut data, &mut batch)?;
+        }
+    }
+    imported += write_batch(&mut data, &mut batch)?;
+    data.close()
+        .map_err(|e| format!("Error closing data output file: {}", e))?;
+
+    eprintln!("Imported {} hashes, skipped {} lines", imported, skipped);
+    Ok(())
+}
+
+fn write_batch(data: &mut XxhDiffData, batch: &mut Vec<HashResult>) -> Result<usize, String> {
+    data.write(&batch.iter().collect::<Vec<_>>())
+        .map_err(|e| format!("Error writing hash results to data output file: {}", e))?;
+    let len = batch.len();
+    batch.clear();
+    Ok(len)
+}
+
+enum LineErr {
+    /// A well formed hex digest of this many bits, but not 64
+    Digest(usize),
+    Malformed(String),
+}
+
+/// Parses `<hex>  <path>` or `<hex> *<path>` for binary mode. A line starting with a backslash has
+/// `\\` and `\n` escapes in its path
+fn parse_xxhsum_line(line: &[u8]) -> Result<(u64, PathBuf), LineErr> {
+    let (escaped, line) = match line.strip_prefix(b"\\") {
+        Some(l) => (true, l),
+        None => (false, line),
+    };
+
+    let digest_len = line
+        .iter()
+        .position(|b| *b == b' ')
+        .ok_or_else(|| LineErr::Malformed("missing separator after digest".to_string()))?;
+    let (digest, rest) = line.split_at(digest_len);
+    if digest.is_empty() || !digest.iter().all(u8::is_ascii_hexdigit) {
+        return Err(LineErr::Malformed(format!(
+            "invalid digest {:?}",
+            String::from_utf8_lossy(digest)
+        )));
+    }
+    if digest.len() != 16 {
+        return Err(LineErr::Digest(digest.len() * 4));
+    }
+    let hash = u64::from_str_radix(std::str::from_utf8(digest).unwrap(), 16).unwrap();
+
+    let path = match rest {
+        [b' ', b' ' | b'*', path @ ..] if !path.is_empty() => path,
+        _ => return Err(LineErr::Malformed("missing path".to_string())),
+    };
+
+    let path = match escaped {
+        true => unescape(path)?,
+        false => path.to_vec(),
+    };
+    Ok((hash, path_from_bytes(path)?))
+}
+
+fn unescape(path: &[u8]) -> Result<Vec<u8>, LineErr> {
+    let mut unescaped = Vec::with_capacity(path.len());
+    let mut bytes = path.iter();
+    while let Some(b) = bytes.next() {
+        match b {
+            b'\\' => match bytes.next() {
+                Some(b'\\') => unescaped.push(b'\\'),
+                Some(b'n') => unescaped.push(b'\n'),
+                e => {
+                    return Err(LineErr::Malformed(format!(
+                        "invalid escape {:?} in path",
+                        e.map(|e| *e as char)
+                    )))
+                }
+            },
+            b => unescaped.push(*b),
+        }
+    }
+    Ok(unescaped)
+}
+
+#[cfg(unix)]
+fn path_from_bytes(path: Vec<u8>) -> Result<PathBuf, LineErr> {
+    use std::{ffi::OsString, os::unix::prelude::*};
+
+    Ok(OsString::from_vec(path).into())
+}
+
+#[cfg(windows)]
+fn path_from_bytes(path: Vec<u8>) -> Result<PathBuf, LineErr> {
+    String::from_utf8(path)
+        .map(PathBuf::from)
+        .map_err(|_| LineErr::Malformed("path is not valid UTF-8".to_string()))
+}
@@ -1,108 +1,693 @@
 use std::{
-    cell::Cell,
+    borrow::Cow,
     fs,
-    io::{self, ErrorKind, Write},
-    iter,
-    path::PathBuf,
+    io::{self, ErrorKind, IsTerminal, Write},
+    iter, mem,
+    path::{Path, PathBuf},
     sync::{
-        atomic::{AtomicBool, Ordering},
-        Arc,
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex,
     },
     thread::{self, JoinHandle},
+    time::Duration,
 };
 
-use clap::Parser;
+use bench::Bench;
+use cache::CacheMode;
+use clap::{ArgAction, CommandFactory, FromArgMatches, Parser, Subcommand, ValueEnum};
 use crossbeam_utils::sync::Unparker;
-use data_fmt::{DataErr, HashResult, ReadXxhDiffDataInner, XxhDiffData};
+use data_fmt::{
+    DataErr, DataReader, DataWriter, HashAlgorithm, HashResult, ReadXxhDiffDataInner, Record,
+    SyncPolicy, XxhDiffData,
+};
+use filter::Filter;
 use flume::{RecvError, Selector};
-use gracile::{TermHandle, TERMINATE};
-use hashbrown::HashMap;
-use parallel_hash::ParallelHash;
-use parking_lot::Mutex;
-use raw_path_bytes::RawPathBytes;
+use gracile::{ErrMsg, TermHandle, TERMINATE};
+use hardlink::HardLinks;
+use hashbrown::{HashMap, HashSet};
+use output::Output;
+use parallel_hash::{Autoscale, DebugSink, ParallelHash, SchedulerDebug};
+use paths::{Follow, SizeFilter, Walk, WalkErrors};
+use priority::{IoPriority, Priority};
+use progress::{Progress, ProgressMode};
 use sema_lot::Semaphore;
+use throttle::Throttle;
 
+mod bench;
+mod cache;
+mod check;
+mod compact;
 mod data_fmt;
+#[cfg(feature = "encrypt")]
+mod encrypt;
+mod export;
+mod files_from;
+mod filter;
+mod hardlink;
+mod import;
+#[cfg(unix)]
+mod mmap;
+mod output;
 mod parallel_hash;
 mod paths;
+mod priority;
+mod progress;
 mod raw_path_bytes;
+mod throttle;
 
 #[derive(Parser, Debug)]
-#[clap(author, version, about)]
+#[clap(author, version, about, args_conflicts_with_subcommands = true)]
 struct Args {
+    #[clap(subcommand)]
+    command: Option<Command>,
+
     #[clap(long, short)]
     data: Option<String>,
 
     #[clap(long, short)]
     output_data: Option<String>,
 
+    /// File to write the paths to rather than stdout, which - is. It's only put in place once
+    /// everything has been written to it
+    #[clap(long, value_name = "FILE", default_value = "-")]
+    output: String,
+
+    /// End each path written with a NUL rather than a newline, for paths with newlines in them
+    #[clap(long, short = '0')]
+    null: bool,
+
+    /// Write paths with newlines in them as they are, rather than it being an error, though they
+    /// can't be told apart from several paths
+    #[clap(long, conflicts_with = "null")]
+    force_delimiter: bool,
+
     #[clap(long, short = 'f', default_value = "500")]
     max_files_open: u32,
 
+    /// Hash as normal, but only measure how fast it went. No data files are read or written and no
+    /// paths are printed, the figures are printed at the end instead
+    #[clap(long, conflicts_with_all = &["data", "output-data", "itemize", "output"])]
+    bench: bool,
+
+    /// Hash results that can be waiting to be compared and written, before hashing waits for them
+    #[clap(long, default_value = "4096")]
+    result_queue: usize,
+
+    /// Paths that can be waiting to be hashed on each filesystem, before finding more waits for
+    /// them
+    #[clap(long, default_value = "4096")]
+    path_queue: usize,
+
+    /// Have files take one of the --max-files-open permits for every this many MiB of them, so a
+    /// few big files can't take up the I/O that many small ones could share. Every file takes at
+    /// least 1
+    #[clap(long)]
+    fd_weight_unit: Option<u64>,
+
+    /// Hash each filesystem with exactly this many threads, instead of scaling the count to how
+    /// fast files are being hashed
+    #[clap(long, conflicts_with_all = &["min-threads", "max-threads"])]
+    threads: Option<u32>,
+
+    /// Fewest threads each filesystem is hashed with
+    #[clap(long, default_value = "1")]
+    min_threads: u32,
+
+    /// Most threads each filesystem is hashed with, defaults to the number of logical CPUs
+    #[clap(long, visible_alias = "per-fs-max")]
+    max_threads: Option<u32>,
+
+    /// Most threads particular filesystems are hashed with, as a comma separated list of
+    /// <mount>=<count>, where the mount is a mount point or device. With --threads, they're hashed
+    /// with exactly that many. Other filesystems go by the other thread options
+    #[clap(
+        long,
+        value_name = "MOUNT=COUNT",
+        value_parser = parse_fs_threads,
+        use_value_delimiter = true
+    )]
+    fs_threads: Vec<(PathBuf, u32)>,
+
+    /// Seconds the thread count is left alone after each change, for its effect on how fast
+    /// files are hashed to show
+    #[clap(long, default_value = "1")]
+    autoscale_interval: f64,
+
+    /// Percentage hashing has to speed up by after a thread is added for another to be, or slow
+    /// down by for it to be removed again
+    #[clap(long, default_value = "5")]
+    autoscale_threshold: f64,
+
+    /// Print each change to the thread count and what it was based on
+    #[clap(long)]
+    autoscale_log: bool,
+
+    /// Write the state of each hashing pool's threads, queues and autoscaler every second, to the
+    /// file given or stderr
+    #[clap(
+        long,
+        value_name = "FILE",
+        min_values = 0,
+        require_equals = true,
+        default_missing_value = "-"
+    )]
+    debug_scheduler: Option<PathBuf>,
+
+    /// Print which filesystem each directory was found on, and the thread bounds of each
+    /// filesystem's pool
+    #[clap(long, short = 'v')]
+    verbose: bool,
+
+    /// Nice level of the hashing threads, from -20 to 19. Below 0 needs privileges
+    #[clap(long, allow_hyphen_values = true, value_parser = clap::value_parser!(i32).range(-20..=19))]
+    nice: Option<i32>,
+
+    /// I/O priority of the hashing threads: idle, or best-effort with an optional level from 0
+    /// (highest) to 7, like best-effort:6
+    #[clap(long)]
+    io_priority: Option<IoPriority>,
+
+    /// Store paths under this directory relative to it in the output data file
+    #[clap(long)]
+    relative_to: Option<String>,
+
+    /// Join the data file's relative paths to this directory instead of the one it was written with
+    #[clap(long)]
+    rebase: Option<String>,
+
+    /// Seconds to wait for another run to release the data files before giving up
+    #[clap(long, default_value = "0")]
+    wait_lock: u64,
+
+    /// When to wait for writes to the output data file to reach the disk. It's always synced once
+    /// done
+    #[clap(long, value_enum, default_value = "batch")]
+    sync: SyncPolicy,
+
+    /// Remove superseded records from the output data file once done
+    #[clap(long)]
+    compact_on_exit: bool,
+
+    /// Hash every file again, rather than skipping the ones the output data file already has to
+    /// resume a run. Their old records are superseded, for compaction to remove
+    #[clap(long, requires = "output-data")]
+    force: bool,
+
+    /// Hash the files again whose records in the output data file were hashed before this, a time
+    /// ago with an s, m, h, d or w suffix, a YYYY-MM-DD date in UTC, or a unix timestamp. Records
+    /// from before hash times were recorded are always hashed again. --force hashes every file
+    /// again, so can't be given with it
+    #[clap(
+        long,
+        requires = "output-data",
+        conflicts_with = "force",
+        value_parser = parse_cutoff
+    )]
+    refresh_older_than: Option<i64>,
+
+    /// Encrypt the output data file to the key in this cert file, such as the disc-up backup key.
+    /// It can't be read back while it's being written, so it's always written afresh
+    #[cfg(feature = "encrypt")]
+    #[clap(long, requires = "output-data", conflicts_with = "compact-on-exit")]
+    encrypt_data: Option<String>,
+
+    /// Decrypt the data file with the secret key in this key file, asking for its password if it
+    /// has one
+    #[cfg(feature = "encrypt")]
+    #[clap(long, requires = "data")]
+    decrypt_with: Option<String>,
+
+    #[clap(long, value_enum, default_value = "xxh64")]
+    algo: Algo,
+
+    /// Seed of the hash algorithm. It's recorded in the data file, and data files hashed with
+    /// different seeds can't be compared, so it can keep one deployment's data files apart from
+    /// another's
+    #[clap(long, default_value = "0")]
+    seed: u64,
+
+    /// Hash files bigger than this many MiB as ranges of this size, which idle threads hash in
+    /// parallel. It changes those files' hashes, so is recorded with the algorithm
+    #[clap(long)]
+    split_size: Option<u64>,
+
+    /// Compare against a data file hashed with a different algorithm, treating every file as changed
+    #[clap(long)]
+    allow_algo_mismatch: bool,
+
+    /// Whether empty files are included, as --include-empty=false leaves them out of the output and
+    /// output data file entirely
+    #[clap(
+        long,
+        value_name = "BOOL",
+        action = ArgAction::Set,
+        default_value = "true",
+        min_values = 0,
+        require_equals = true,
+        default_missing_value = "true"
+    )]
+    include_empty: bool,
+
+    /// Read files with several hard links once for every path to them, instead of hashing them
+    /// once and reusing the hash for their other links
+    #[clap(long)]
+    no_hardlink_dedup: bool,
+
+    /// Treat files whose size and mtime match the data file as unchanged without hashing them
+    #[clap(long)]
+    quick: bool,
+
+    /// Also hash each block of files separately, so --itemize can show which parts of a changed
+    /// file changed when the data file has them too
+    #[clap(long)]
+    chunk_hashes: bool,
+
+    /// Size of the blocks hashed by --chunk-hashes, in MiB
+    #[clap(long, default_value = "4", requires = "chunk-hashes")]
+    chunk_size: u64,
+
+    /// Write tombstones to the output data file for the files it has records of that no longer
+    /// exist, so they aren't compared against later
+    #[clap(long, requires = "output-data")]
+    record_deletions: bool,
+
+    /// Prefix paths with whether they're new (+) or changed (~) since the data file, and list the
+    /// files in it that have been deleted (-). Changed files with chunk hashes on both sides are
+    /// followed by how many chunks changed and the byte ranges they cover
+    #[clap(long)]
+    itemize: bool,
+
+    /// Once hashing is done, list the files in the data file under the paths given that no longer
+    /// exist, as --itemize does
+    #[clap(long, requires = "data")]
+    report_deleted: bool,
+
+    /// What's written before each deleted file listed by --itemize or --report-deleted
+    #[clap(long, value_name = "PREFIX", default_value = "- ")]
+    deleted_prefix: String,
+
+    /// Write the paths sorted by their bytes, all at the end rather than as they're hashed, for
+    /// the output to be the same from run to run. Every path to write is kept in memory until
+    /// then, which for a huge number of changes can be a lot
+    #[clap(long)]
+    sorted: bool,
+
+    /// Memory map files of at least this many MiB to hash them, instead of reading them
+    #[cfg(unix)]
+    #[clap(
+        long,
+        value_name = "THRESHOLD",
+        min_values = 0,
+        require_equals = true,
+        default_missing_value = "32"
+    )]
+    mmap: Option<u64>,
+
+    /// Go on hashing the other files after one can't be, reporting them all at the end. This is
+    /// the default
+    #[clap(long, overrides_with = "fail-fast")]
+    keep_going: bool,
+
+    /// End the run at the first file that can't be hashed
+    #[clap(long, overrides_with = "keep-going")]
+    fail_fast: bool,
+
+    /// Write every path that couldn't be read or hashed to this file at the end, with why
+    #[clap(long, value_name = "FILE")]
+    error_log: Option<String>,
+
+    /// Times to retry reading a file after a timeout or it being unavailable, as network
+    /// filesystems can have
+    #[clap(long, default_value = "3")]
+    io_retries: u32,
+
+    /// Size of the buffer files are read into, in bytes or with a K, M or G suffix, rounded up to
+    /// a multiple of 4 KiB. By default it's sized to each file, between 64 KiB and 1 MiB
+    #[clap(long, value_parser = parse_size)]
+    buf_size: Option<u64>,
+
+    /// Cap on how many bytes a second are read from files, across every hashing thread, with a K, M
+    /// or G suffix allowed
+    #[clap(long, value_parser = parse_size)]
+    max_throughput: Option<u64>,
+
+    /// How files being hashed use the OS page cache
+    #[clap(long, value_enum, default_value = "normal")]
+    cache_mode: CacheMode,
+
+    /// Show how many files have been hashed and how fast on stderr, when it's a terminal unless
+    /// forced
+    #[clap(
+        long,
+        value_enum,
+        min_values = 0,
+        require_equals = true,
+        default_missing_value = "auto"
+    )]
+    progress: Option<ProgressMode>,
+
+    /// Before hashing, walk the paths given only to count the files and add up their sizes, for
+    /// the progress line to show how much is done and an ETA. Only done when it's shown
+    #[clap(long, requires = "progress")]
+    pre_scan: bool,
+
+    /// Leave out paths matching the glob, which is matched against the path below the dir given
+    /// if it has a / in it, and against the file name at any depth if not. Ending in a / it only
+    /// matches dirs, and ** matches any number of dirs. The first --exclude or --include to match
+    /// a path decides, and everything in an excluded dir is excluded along with it, unless an
+    /// --include given before the --exclude matches it. Excluded dirs aren't read at all
+    #[clap(long, value_name = "GLOB")]
+    exclude: Vec<String>,
+
+    /// Hash paths matching the glob even if an --exclude given after it would leave them out,
+    /// including ones in excluded dirs
+    #[clap(long, value_name = "GLOB")]
+    include: Vec<String>,
+
+    /// Also leave out what .gitignore files say to, as well as the ignore files --ignore-file-name
+    /// names
+    #[clap(long)]
+    use_gitignore: bool,
+
+    /// Name of the ignore files in gitignore syntax that are read from each dir, their patterns
+    /// applying to everything in it. They go over .gitignore files, and under --exclude and
+    /// --include
+    #[clap(long, value_name = "NAME", default_value = ".xxhdiffignore")]
+    ignore_file_name: String,
+
+    /// Follow symlinks, hashing what they point to as if it were where the symlink is. Symlinks
+    /// leading back to a dir they're in aren't followed, and neither are dangling ones
+    #[clap(long)]
+    follow_symlinks: bool,
+
+    /// Whether symlinks to outside all of the dirs given are followed
+    #[clap(
+        long,
+        value_name = "BOOL",
+        requires = "follow-symlinks",
+        action = ArgAction::Set,
+        default_value = "true",
+        min_values = 0,
+        require_equals = true,
+        default_missing_value = "true"
+    )]
+    follow_external: bool,
+
+    /// Don't descend into dirs on other filesystems than the dir given they're under, like
+    /// mounts under /
+    #[clap(long)]
+    one_file_system: bool,
+
+    /// How many levels below the paths given to hash, 0 only hashing the files given themselves
+    /// and 1 the files directly in the dirs given. Dirs at it aren't read
+    #[clap(long, value_name = "N")]
+    max_depth: Option<usize>,
+
+    /// Skip files and dirs whose names start with a ., and on Windows ones with the hidden
+    /// attribute. Hidden dirs aren't walked, unless they were given
+    #[clap(long)]
+    skip_hidden: bool,
+
+    /// Skip files smaller than this many bytes, with a K, M or G suffix allowed. The files given
+    /// are hashed whatever their size
+    #[clap(long, value_parser = parse_size)]
+    min_size: Option<u64>,
+
+    /// Skip files bigger than this many bytes, with a K, M or G suffix allowed
+    #[clap(long, value_parser = parse_size)]
+    max_size: Option<u64>,
+
+    /// With --itemize, list the files --min-size and --max-size skipped at the end, after
+    /// "skipped (size)"
+    #[clap(long, requires = "itemize")]
+    list_skipped: bool,
+
+    /// Also hash the files listed in this file, a path a line, reading the list from stdin if it's
+    /// -. They're hashed as they are without walking any dirs, so dirs in the list are skipped, as
+    /// are files in the dirs given, which are hashed anyway
+    #[clap(long, value_name = "FILE")]
+    files_from: Option<String>,
+
+    /// The paths in the --files-from list are separated by NULs rather than newlines, as find
+    /// -print0 outputs them
+    #[clap(long, requires = "files-from")]
+    from0: bool,
+
+    /// Hash the paths given more than once, or inside of others given, once for each time. By
+    /// default they're only hashed once
+    #[clap(long)]
+    allow_overlap: bool,
+
     #[clap(multiple = true)]
     rest: Vec<String>,
 }
 
+/// What files are hashed with
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum Algo {
+    Xxh64,
+    /// XXH3 with a 64-bit hash, faster than xxh64
+    Xxh3,
+    /// XXH3 with a 128-bit hash
+    #[clap(name = "xxh3-128")]
+    Xxh128,
+    /// BLAKE3, a cryptographic hash, with a 256-bit hash. It's unseeded
+    Blake3,
+    /// SHA-256, a cryptographic hash. It's unseeded
+    Sha256,
+}
+
+const NEW_MARKER: &[u8] = b"+ ";
+const CHANGED_MARKER: &[u8] = b"~ ";
+const SKIPPED_SIZE_MARKER: &[u8] = b"skipped (size) ";
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Write the records of a data file out as xxhsum, CSV or JSON lines
+    Export(export::ExportArgs),
+    /// Add the hashes from an xxhsum checksum file to a data file, without hashing anything
+    Import(import::ImportArgs),
+    /// Rewrite a data file with only the latest record for each path
+    Compact(compact::CompactArgs),
+    /// Check a data file is internally consistent, without comparing it against any files. Exits
+    /// with 0 if it is, 2 if it wasn't cleanly closed but is readable, and 1 if it's corrupt
+    Check(check::CheckArgs),
+}
+
+/// Dirs on the same filesystem, which are hashed by the same pool of threads
+struct FsDirs {
+    /// The filesystem's device as it's mounted, or on windows the prefix of its paths
+    source: PathBuf,
+    /// Where the filesystem is mounted, of the mounts the dirs are under
+    mounts: Vec<PathBuf>,
+    dirs: Vec<PathBuf>,
+    /// From `--files-from`, hashed without walking
+    files: Vec<PathBuf>,
+}
+
+impl FsDirs {
+    /// Whether a `--fs-threads` mount refers to this filesystem
+    fn matches(&self, mount: &Path) -> bool {
+        self.source == mount || self.mounts.iter().any(|m| m == mount)
+    }
+}
+
+/// Paths are grouped by the device they're on. Mounts only name the groups, for `--verbose` and
+/// `--fs-threads`, so ones that can't be found aren't errors. Paths that can't be looked at are
+/// all put together, with a warning
 #[cfg(unix)]
-fn get_fs_dirs(dirs: Vec<PathBuf>) -> Result<Vec<Vec<PathBuf>>, String> {
+fn get_fs_dirs(dirs: Vec<PathBuf>, files: Vec<PathBuf>) -> Result<Vec<FsDirs>, String> {
+    use std::os::unix::fs::MetadataExt;
+
     use proc_mounts::MountIter;
 
     let mounts = MountIter::new()
-        .map_err(|e| format!("Error parsing proc_mounts: {}", e))?
-        .map(|m| m.map(|m| (m.dest, m.source)))
-        .collect::<Result<HashMap<_, _>, _>>()
-        .map_err(|e| format!("Error parsing proc/mounts line: {}", e))?;
-    let mut fs_dirs: HashMap<&PathBuf, Vec<_>> = HashMap::new();
-
-    'outer: for dir in dirs {
-        let mut trunc_dir = dir.clone();
-        loop {
-            if let Some(source) = mounts.get(&trunc_dir) {
-                fs_dirs.entry(source).or_default().push(dir);
-                continue 'outer;
+        .and_then(|m| {
+            m.map(|m| m.map(|m| (m.dest, m.source)))
+                .collect::<Result<HashMap<_, _>, _>>()
+        })
+        .unwrap_or_else(|e| {
+            eprintln!(
+                "Warning: Couldn't read /proc/mounts, filesystems are named by device: {}",
+                e
+            );
+            HashMap::new()
+        });
+    let dev = |path: PathBuf| {
+        let dev = match fs::metadata(&path) {
+            Ok(m) => Some(m.dev()),
+            Err(e) => {
+                eprintln!(
+                    "Warning: Couldn't get the filesystem of {}, it's hashed with any others \
+                     that couldn't be: {}",
+                    path.display(),
+                    e
+                );
+                None
             }
+        };
+        (path, dev)
+    };
+    let dirs = dirs.into_iter().map(dev).collect();
+    let files = files.into_iter().map(dev).collect();
 
-            if !trunc_dir.pop() {
-                break;
-            }
-        }
+    Ok(group_by_dev(dirs, files, &mounts))
+}
+
+/// Groups paths by their device, `None` for ones whose device isn't known, naming each group by
+/// the source of the closest mount above one of its paths out of `mounts`, by mount point
+#[cfg(unix)]
+fn group_by_dev(
+    dirs: Vec<(PathBuf, Option<u64>)>,
+    files: Vec<(PathBuf, Option<u64>)>,
+    mounts: &HashMap<PathBuf, PathBuf>,
+) -> Vec<FsDirs> {
+    let mut fs_dirs: HashMap<Option<u64>, FsDirs> = HashMap::new();
+
+    let paths = dirs.into_iter().map(|(d, dev)| (d, dev, false));
+    for (path, dev, is_file) in paths.chain(files.into_iter().map(|(f, dev)| (f, dev, true))) {
+        // The mount it's under is the closest that's an ancestor
+        let mount = path
+            .ancestors()
+            .find_map(|a| mounts.get(a).map(|source| (a.to_path_buf(), source)));
 
-        return Err(format!("Couldn't find device of path {}", dir.display()));
+        let fs = fs_dirs.entry(dev).or_insert_with(|| FsDirs {
+            source: match (&mount, dev) {
+                (Some((_, source)), _) => PathBuf::clone(source),
+                (None, Some(dev)) => PathBuf::from(format!("device {}", dev)),
+                (None, None) => PathBuf::from("unknown filesystem"),
+            },
+            mounts: Vec::new(),
+            dirs: Vec::new(),
+            files: Vec::new(),
+        });
+        if let Some((mount, _)) = mount.filter(|(m, _)| !fs.mounts.contains(m)) {
+            fs.mounts.push(mount);
+        }
+        match is_file {
+            true => fs.files.push(path),
+            false => fs.dirs.push(path),
+        }
     }
 
-    Ok(fs_dirs.into_iter().map(|(_, v)| v).collect())
+    fs_dirs.into_values().collect()
 }
 
+/// The volume `path` is on, as its volume GUID path, and the mount point it's under
 #[cfg(windows)]
-fn get_fs_dirs(dirs: Vec<PathBuf>) -> Result<Vec<Vec<PathBuf>>, String> {
+fn volume(path: &Path) -> io::Result<(PathBuf, PathBuf)> {
     use std::{
-        path::{Component, PrefixComponent},
-        rc::Rc,
+        ffi::OsString,
+        os::windows::ffi::{OsStrExt, OsStringExt},
     };
 
-    let fs_dirs: Vec<_> = {
-        let dirs: Vec<_> = dirs.into_iter().map(Rc::new).collect();
-        let mut fs_dirs: HashMap<PrefixComponent, Vec<Rc<PathBuf>>> = HashMap::new();
-        for dir in dirs.iter() {
-            match dir.components().next() {
-                Some(Component::Prefix(p)) => fs_dirs.entry(p).or_default().push(Rc::clone(dir)),
-                c => {
-                    return Err(format!(
-                        "Unexpected path component for {}: {:?}",
-                        dir.display(),
-                        c
-                    ))
+    /// Long enough for `\\?\Volume{<GUID>}\` and the NUL after it
+    const VOLUME_NAME_LEN: usize = 50;
+
+    #[link(name = "kernel32")]
+    extern "system" {
+        fn GetVolumePathNameW(file_name: *const u16, volume_path_name: *mut u16, len: u32) -> i32;
+        fn GetVolumeNameForVolumeMountPointW(
+            mount_point: *const u16,
+            volume_name: *mut u16,
+            len: u32,
+        ) -> i32;
+    }
+
+    /// The string up to the NUL written into `buf`
+    fn from_wide(buf: &[u16]) -> PathBuf {
+        let len = buf.iter().position(|c| *c == 0).unwrap_or(buf.len());
+        OsString::from_wide(&buf[..len]).into()
+    }
+
+    let wide: Vec<u16> = path
+        .as_os_str()
+        .encode_wide()
+        .chain(iter::once(0))
+        .collect();
+    // The mount point is never longer than the path it's from
+    let mut mount = vec![0; wide.len()];
+    // Safety: both buffers are as long as they're said to be, and `wide` is NUL terminated
+    if unsafe { GetVolumePathNameW(wide.as_ptr(), mount.as_mut_ptr(), mount.len() as u32) } == 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let mut name = [0; VOLUME_NAME_LEN];
+    // Safety: `mount` was NUL terminated by the call above, and `name` is as long as it's said to be
+    let res = unsafe {
+        GetVolumeNameForVolumeMountPointW(mount.as_ptr(), name.as_mut_ptr(), name.len() as u32)
+    };
+    match res {
+        0 => Err(io::Error::last_os_error()),
+        _ => Ok((from_wide(&name), from_wide(&mount))),
+    }
+}
+
+/// Local paths are grouped by their volume, whichever drive letter or folder it's mounted at, and
+/// network paths by their share. Paths whose volume can't be found are grouped by their prefix,
+/// with a warning
+#[cfg(windows)]
+fn get_fs_dirs(dirs: Vec<PathBuf>, files: Vec<PathBuf>) -> Result<Vec<FsDirs>, String> {
+    use std::path::{Component, Prefix};
+
+    let mut fs_dirs: HashMap<PathBuf, FsDirs> = HashMap::new();
+    let paths = dirs.into_iter().map(|d| (d, false));
+    for (path, is_file) in paths.chain(files.into_iter().map(|f| (f, true))) {
+        let prefix = match path.components().next() {
+            Some(Component::Prefix(p)) => p,
+            c => {
+                return Err(format!(
+                    "Unexpected path component for {}: {:?}",
+                    path.display(),
+                    c
+                ))
+            }
+        };
+        let prefix_path = PathBuf::from(prefix.as_os_str());
+
+        // The key it's grouped by, what the group is shown as, and the mounts it can be given as
+        let (key, source, mounts) = match prefix.kind() {
+            Prefix::UNC(server, share) | Prefix::VerbatimUNC(server, share) => {
+                let share = format!(
+                    r"\\{}\{}",
+                    server.to_string_lossy(),
+                    share.to_string_lossy()
+                );
+                let key = PathBuf::from(share.to_lowercase());
+                (key, PathBuf::from(share), vec![prefix_path])
+            }
+            _ => match volume(&path) {
+                Ok((name, mount)) => (name.clone(), mount.clone(), vec![name, mount, prefix_path]),
+                Err(e) => {
+                    eprintln!(
+                        "Warning: Couldn't find the volume of {}, grouping it by {} instead: {}",
+                        path.display(),
+                        prefix_path.display(),
+                        e
+                    );
+                    (prefix_path.clone(), prefix_path, Vec::new())
                 }
+            },
+        };
+
+        let fs = fs_dirs.entry(key).or_insert_with(|| FsDirs {
+            source,
+            mounts: Vec::new(),
+            dirs: Vec::new(),
+            files: Vec::new(),
+        });
+        for mount in mounts {
+            if !fs.mounts.contains(&mount) {
+                fs.mounts.push(mount);
             }
         }
+        match is_file {
+            true => fs.files.push(path),
+            false => fs.dirs.push(path),
+        }
+    }
 
-        fs_dirs.into_iter().map(|(_, v)| v).collect()
-    };
-
-    Ok(fs_dirs
-        .into_iter()
-        .map(|d| d.into_iter().map(|d| Rc::try_unwrap(d).unwrap()).collect())
-        .collect())
+    Ok(fs_dirs.into_values().collect())
 }
 
 pub struct MainThreadPool {
@@ -121,7 +706,7 @@ impl MainThreadPool {
         F: FnOnce(),
         F: Send + 'static,
     {
-        self.handles.push(thread::spawn(f));
+        self.handles.push(gracile::spawn_masked(f));
     }
 }
 
@@ -133,265 +718,1349 @@ impl Drop for MainThreadPool {
     }
 }
 
+/// Deals with how the background read of the data out file finished once it has. If it failed the
+/// file is rewritten from what was read and `new_results`, the results written to it since
+fn finish_data_out_read(
+    data_out_file: &mut DataWriter,
+    data_out_reader: &DataReader,
+    existing_hashes: &flurry::HashMap<PathBuf, HashResult>,
+    new_results: &[HashResult],
+) -> Result<(), String> {
+    if data_out_reader.status().is_err() {
+        let existing_hashes: Vec<_> = existing_hashes
+            .pin()
+            .iter()
+            .map(|(_, v)| v.clone())
+            .collect();
+        let write_hashes: Vec<_> = existing_hashes.iter().chain(new_results).collect();
+
+        if let Err(e) = data_out_file.truncate() {
+            return Err(format!("Failed to reset data output file: {}", e));
+        }
+
+        if let Err(e) = data_out_file.write(&write_hashes) {
+            return Err(format!("Failed to write to new data output file: {}", e));
+        }
+        return Ok(());
+    }
+
+    match data_out_file.truncate_to_valid(data_out_reader) {
+        Ok(0) => {}
+        Ok(discarded) => eprintln!(
+            "Warning: Discarded {} bytes of a partial record at the end of the data out file",
+            discarded
+        ),
+        Err(e) => {
+            return Err(format!(
+                "Error discarding partial record from data out file: {}",
+                e
+            ))
+        }
+    }
+    Ok(())
+}
+
+/// Whether the data file's hashes, made with `data`, can be compared against ones made with `run`.
+/// Hashes of different widths never can be, other differences only with `allow_mismatch`, every
+/// file then being treated as changed
+fn check_algorithm(
+    data: HashAlgorithm,
+    run: HashAlgorithm,
+    allow_mismatch: bool,
+) -> Result<bool, String> {
+    if data.bits() != run.bits() {
+        return Err(format!(
+            "The data file has {}-bit hashes from {}, which can't be compared against the {}-bit hashes from {}, pass --algo to hash with the same width",
+            data.bits(),
+            data,
+            run.bits(),
+            run
+        ));
+    }
+    if data == run {
+        return Ok(true);
+    }
+
+    let mismatch = format!(
+        "The data file was hashed with {}, but files are being hashed with {}",
+        data, run
+    );
+    if !allow_mismatch {
+        return Err(format!(
+            "{}, pass --allow-algo-mismatch to treat every file as changed",
+            mismatch
+        ));
+    }
+    eprintln!(
+        "Warning: {}, every file will be treated as changed",
+        mismatch
+    );
+    Ok(false)
+}
+
+/// Prints a warning and carries on, or sets [`TERMINATE`] and gives back a fatal error to end the
+/// run with
+fn handle_err_msg(msg: ErrMsg, progress: Option<&Progress>) -> Result<(), String> {
+    match msg {
+        ErrMsg::Warn(e) => {
+            let _hidden = progress.map(Progress::hide);
+            eprintln!("Warning: {}", e);
+            Ok(())
+        }
+        ErrMsg::Fatal(e) => {
+            TERMINATE.set();
+            Err(e.to_string())
+        }
+    }
+}
+
+/// [`CHANGED_MARKER`] followed by the number of chunks that changed out of `total`, and the byte
+/// ranges they cover, e.g. `~ 2/250 chunks at 0-4194304,12582912-16777216 `
+fn changed_chunks_marker(changed: usize, total: usize, ranges: &[(u64, u64)]) -> Vec<u8> {
+    let ranges: Vec<_> = ranges
+        .iter()
+        .map(|(start, end)| format!("{}-{}", start, end))
+        .collect();
+    let mut marker = CHANGED_MARKER.to_vec();
+    marker.extend_from_slice(
+        format!("{}/{} chunks at {} ", changed, total, ranges.join(",")).as_bytes(),
+    );
+    marker
+}
+
+/// Parses a number of bytes, optionally followed by a binary K, M or G suffix, or Ki, Mi or Gi
+fn parse_size(s: &str) -> Result<u64, String> {
+    // Ki, Mi and Gi are the same as K, M and G
+    let s = match s.strip_suffix('i') {
+        Some(unit) if unit.ends_with(['K', 'k', 'M', 'm', 'G', 'g']) => unit,
+        _ => s,
+    };
+    let (num, shift) = match s.char_indices().last() {
+        Some((i, 'K' | 'k')) => (&s[..i], 10),
+        Some((i, 'M' | 'm')) => (&s[..i], 20),
+        Some((i, 'G' | 'g')) => (&s[..i], 30),
+        _ => (s, 0),
+    };
+    num.parse::<u64>()
+        .map_err(|e| e.to_string())?
+        .checked_mul(1 << shift)
+        .ok_or_else(|| "size is too big".to_string())
+}
+
+/// Parses a `--refresh-older-than` cutoff to seconds since the unix epoch
+fn parse_cutoff(s: &str) -> Result<i64, String> {
+    if let [year, month, day] = s.split('-').collect::<Vec<_>>()[..] {
+        let parse = |n: &str| n.parse::<i64>().map_err(|e| e.to_string());
+        let (year, month, day) = (parse(year)?, parse(month)?, parse(day)?);
+        let leap = year % 4 == 0 && (year % 100 != 0 || year % 400 == 0);
+        let month_days = match month {
+            2 if leap => 29,
+            2 => 28,
+            4 | 6 | 9 | 11 => 30,
+            1..=12 => 31,
+            _ => return Err(format!("there's no month {}", month)),
+        };
+        if !(1..=month_days).contains(&day) {
+            return Err(format!("there's no day {} in month {}", day, month));
+        }
+        return Ok(days_from_civil(year, month, day) * 24 * 60 * 60);
+    }
+
+    let (num, unit) = match s.char_indices().last() {
+        Some((i, 's')) => (&s[..i], 1),
+        Some((i, 'm')) => (&s[..i], 60),
+        Some((i, 'h')) => (&s[..i], 60 * 60),
+        Some((i, 'd')) => (&s[..i], 24 * 60 * 60),
+        Some((i, 'w')) => (&s[..i], 7 * 24 * 60 * 60),
+        _ => return s.parse::<i64>().map_err(|e| e.to_string()),
+    };
+    num.parse::<i64>()
+        .map_err(|e| e.to_string())?
+        .checked_mul(unit)
+        .and_then(|ago| data_fmt::hashed_now().checked_sub(ago))
+        .ok_or_else(|| "time is too long ago".to_string())
+}
+
+/// Days since the unix epoch of a date in the proleptic Gregorian calendar
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    // Years start in March, for the leap day to be at the end
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
+
+/// A `--fs-threads` entry
+fn parse_fs_threads(s: &str) -> Result<(PathBuf, u32), String> {
+    let (mount, count) = s
+        .rsplit_once('=')
+        .ok_or_else(|| "expected <mount>=<count>".to_string())?;
+    match count.parse::<u32>().map_err(|e| e.to_string())? {
+        0 => Err("at least 1 hashing thread is needed".to_string()),
+        count => Ok((PathBuf::from(mount), count)),
+    }
+}
+
+/// Drops the paths given more than once, and ones inside of others given, which would otherwise be
+/// hashed twice
+fn dedup_dirs(dirs: Vec<PathBuf>) -> Vec<PathBuf> {
+    let mut kept: Vec<PathBuf> = Vec::with_capacity(dirs.len());
+    for dir in dirs {
+        if kept.contains(&dir) {
+            eprintln!(
+                "{} was given more than once, it's only hashed once",
+                dir.display()
+            );
+            continue;
+        }
+        if let Some(parent) = kept.iter().find(|k| dir.starts_with(k)) {
+            eprintln!(
+                "Warning: Leaving out {}, as it's in {}, which is being hashed",
+                dir.display(),
+                parent.display()
+            );
+            continue;
+        }
+
+        kept.retain(|k| {
+            let inside = k.starts_with(&dir);
+            if inside {
+                eprintln!(
+                    "Warning: Leaving out {}, as it's in {}, which is being hashed",
+                    k.display(),
+                    dir.display()
+                );
+            }
+            !inside
+        });
+        kept.push(dir);
+    }
+    kept
+}
+
+/// Whether `path`, recorded in a data file but not hashed this run, is under one of the `roots`
+/// that were hashed and no longer a file
+fn is_deleted(path: &Path, roots: &[PathBuf], seen: &HashSet<PathBuf>) -> bool {
+    !seen.contains(path)
+        && roots.iter().any(|r| path.starts_with(r))
+        && match path.symlink_metadata() {
+            Ok(m) => !m.is_file(),
+            Err(e) => e.kind() == ErrorKind::NotFound,
+        }
+}
+
+fn canonicalize(d: &String) -> Result<PathBuf, String> {
+    fs::canonicalize(d).map_err(|e| match e.kind() {
+        ErrorKind::NotFound => format!("Path {} does not exist", d),
+        _ => format!("Error trying to canonicalize path {}: {}", d, e),
+    })
+}
+
 fn main() -> Result<(), String> {
-    let mut term_handle = match unsafe { gracile::init_handle() } {
+    let term_handle = match gracile::init_handle() {
         Ok(s) => s,
         Err(e) => {
             eprintln!("Error adding signal handlers: {}", e);
             TermHandle::default()
         }
     };
+    gracile::terminate_on_panic(term_handle.err_handle.clone());
 
-    let args = Args::parse();
+    let matches = Args::command().get_matches();
+    let args = Args::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+
+    match args.command {
+        Some(Command::Export(export_args)) => return export::export(export_args),
+        Some(Command::Import(import_args)) => return import::import(import_args),
+        Some(Command::Compact(compact_args)) => return compact::compact(compact_args),
+        Some(Command::Check(check_args)) => return check::check(check_args),
+        None => {}
+    }
 
     let dirs = args
         .rest
         .iter()
-        .map(|d| {
-            fs::canonicalize(d).map_err(|e| match e.kind() {
-                ErrorKind::NotFound => format!("Path {} does not exist", d),
-                _ => format!("Error trying to canonicalize path {}: {}", d, e),
-            })
-        })
+        .map(canonicalize)
         .collect::<Result<Vec<_>, _>>()?;
+    let dirs = match args.allow_overlap {
+        true => dirs,
+        false => dedup_dirs(dirs),
+    };
+    let relative_to = args.relative_to.as_ref().map(canonicalize).transpose()?;
+    let rebase = args.rebase.as_ref().map(canonicalize).transpose()?;
+    let walk = Walk {
+        filter: {
+            // Which of the two flags comes first only shows in the indices of their values
+            let indices = |id| matches.indices_of(id).into_iter().flatten();
+            let mut rules: Vec<_> = indices("exclude")
+                .zip(args.exclude.iter().map(|glob| (false, glob)))
+                .chain(indices("include").zip(args.include.iter().map(|glob| (true, glob))))
+                .collect();
+            rules.sort_unstable_by_key(|(i, _)| *i);
+            let mut ignore_names = Vec::new();
+            if args.use_gitignore && args.ignore_file_name != ".gitignore" {
+                ignore_names.push(".gitignore".to_string());
+            }
+            ignore_names.push(args.ignore_file_name.clone());
+            Arc::new(Filter::new(
+                rules.into_iter().map(|(_, r)| r),
+                ignore_names,
+            )?)
+        },
+        follow: args.follow_symlinks.then(|| Follow {
+            external: args.follow_external,
+            roots: dirs.clone().into(),
+        }),
+        skipped_symlinks: Arc::new(AtomicU64::new(0)),
+        one_file_system: args.one_file_system,
+        skipped_mounts: Arc::new(AtomicU64::new(0)),
+        max_depth: args.max_depth,
+        skip_hidden: args.skip_hidden,
+        skipped_hidden: Arc::new(AtomicU64::new(0)),
+        errors: WalkErrors::new(term_handle.err_handle.clone(), !args.fail_fast),
+        size: match (args.min_size, args.max_size) {
+            (None, None) => None,
+            (Some(min), Some(max)) if min > max => {
+                return Err("--min-size can't be bigger than --max-size".to_string())
+            }
+            (min, max) => Some(Arc::new(SizeFilter {
+                min: min.unwrap_or(0),
+                max: max.unwrap_or(u64::MAX),
+                skipped: AtomicU64::new(0),
+                listed: args.list_skipped.then(|| Mutex::new(Vec::new())),
+            })),
+        },
+    };
+    let lock_wait = Duration::from_secs(args.wait_lock);
+    let chunk_size = match args.chunk_size {
+        0 => return Err("The chunk size must be at least 1 MiB".to_string()),
+        size => args.chunk_hashes.then_some(size * 1024 * 1024),
+    };
+    let (min_threads, max_threads) = match (args.threads, args.max_threads) {
+        (Some(threads), _) => (threads, threads),
+        (None, Some(max)) => (args.min_threads, max),
+        (None, None) => {
+            let cpus = thread::available_parallelism().map_or(1, |n| n.get() as u32);
+            (args.min_threads, cpus.max(args.min_threads))
+        }
+    };
+    if min_threads == 0 {
+        return Err("At least 1 hashing thread is needed".to_string());
+    }
+    if min_threads > max_threads {
+        return Err(format!(
+            "--min-threads {} is more than --max-threads {}",
+            min_threads, max_threads
+        ));
+    }
+    let autoscale = Autoscale {
+        interval: match Duration::try_from_secs_f64(args.autoscale_interval) {
+            Ok(interval) if !interval.is_zero() => interval,
+            _ => return Err("The autoscale interval must be more than 0 seconds".to_string()),
+        },
+        threshold: match args.autoscale_threshold {
+            t if t >= 0.0 => t / 100.0,
+            _ => return Err("The autoscale threshold can't be negative".to_string()),
+        },
+        log: args.autoscale_log,
+    };
+    let priority = Priority {
+        nice: args.nice,
+        io: args.io_priority,
+    };
+    #[cfg(unix)]
+    let mmap_threshold = args.mmap.map(|t| t * 1024 * 1024);
+    let throttle = match args.max_throughput {
+        Some(0) => return Err("The max throughput must be at least 1 byte a second".to_string()),
+        rate => rate.map(|r| Arc::new(Throttle::new(r))),
+    };
+    let progress = match args.progress {
+        Some(ProgressMode::Auto) if io::stderr().is_terminal() => {
+            Some(Progress::new(throttle.clone()))
+        }
+        Some(ProgressMode::Force) => Some(Progress::new(throttle.clone())),
+        _ => None,
+    };
+    let _progress_finish = progress.as_ref().map(Progress::finish_guard);
+    let split = match args.split_size {
+        Some(0) => return Err("The split size must be at least 1 MiB".to_string()),
+        Some(size) if args.chunk_hashes && size % args.chunk_size != 0 => {
+            return Err(format!(
+                "The split size {} MiB isn't a multiple of the chunk size {} MiB",
+                size, args.chunk_size
+            ))
+        }
+        size => size.map(|s| s * 1024 * 1024),
+    };
+    let seed = args.seed;
+    let algorithm = match args.algo {
+        Algo::Xxh64 => HashAlgorithm::Xxh64 { seed, split },
+        Algo::Xxh3 => HashAlgorithm::Xxh3 { seed, split },
+        Algo::Xxh128 => HashAlgorithm::Xxh128 { seed, split },
+        Algo::Blake3 | Algo::Sha256 if seed != 0 => {
+            return Err("--seed only applies to the xxhash algorithms".to_string())
+        }
+        Algo::Blake3 => HashAlgorithm::Blake3 { split },
+        Algo::Sha256 => HashAlgorithm::Sha256 { split },
+    };
 
-    let data_out_file = match args
-        .output_data
+    #[cfg(feature = "encrypt")]
+    let encrypt_cert = args
+        .encrypt_data
         .as_ref()
-        .map(|o| XxhDiffData::new(&PathBuf::from(o), false))
-    {
-        Some(Ok(d)) => Some(d),
-        None => None,
+        .map(|c| encrypt::load_cert(Path::new(c)))
+        .transpose()?;
+    #[cfg(feature = "encrypt")]
+    let decrypt_key = args
+        .decrypt_with
+        .as_ref()
+        .map(|k| encrypt::load_cert(Path::new(k)))
+        .transpose()?;
+
+    let data_out_file =
+        match args.output_data.as_ref().map(|o| {
+            #[cfg(feature = "encrypt")]
+            if let Some(cert) = encrypt_cert {
+                return XxhDiffData::new_encrypted(
+                    &PathBuf::from(o),
+                    relative_to.as_deref(),
+                    algorithm,
+                    cert,
+                    lock_wait,
+                );
+            }
+            XxhDiffData::new(
+                &PathBuf::from(o),
+                false,
+                relative_to.as_deref(),
+                algorithm,
+                lock_wait,
+            )
+        }) {
+            Some(Ok(d)) if d.algorithm() != algorithm => {
+                return Err(format!(
+                    "The data out file was hashed with {}, but files are being hashed with {}",
+                    d.algorithm(),
+                    algorithm
+                ))
+            }
+            Some(Ok(mut d)) => {
+                d.set_sync(args.sync);
+                Some(d)
+            }
+            None => None,
+            Some(Err(e)) if matches!(e.kind(), DataErr::Locked) => return Err(
+                "Another xxh-diff run is using the data out file, pass --wait-lock to wait for it"
+                    .to_string(),
+            ),
+            Some(Err(e)) => return Err(format!("Error opening data out file: {}", e)),
+        };
+
+    // Read from in the background while it's written to
+    let (data_out_reader, mut data_out_file) = match data_out_file.map(XxhDiffData::split) {
+        Some(Ok((reader, writer))) => (reader, Some(writer)),
+        None => (None, None),
         Some(Err(e)) => return Err(format!("Error opening data out file: {}", e)),
     };
+    let data_out_reader = data_out_reader.filter(|r| !r.is_done());
 
-    let read_done = Arc::new(AtomicBool::new(
-        data_out_file.as_ref().map_or(true, |o| !o.is_read()),
-    ));
-    let data_out_file = Arc::new(data_out_file.map(Cell::new).map(Mutex::new));
+    let read_done = Arc::new(AtomicBool::new(data_out_reader.is_none()));
     let existing_hashes = Arc::default();
 
-    let mut data_file = match args
-        .data
-        .map(|d| XxhDiffData::new(&PathBuf::from(d), true).map(|d| (d, HashMap::new())))
-    {
-        Some(Ok(d)) => Some(d),
+    let mut data_file = match args.data.map(|d| {
+        {
+            #[cfg(feature = "encrypt")]
+            if let Some(key) = decrypt_key {
+                return XxhDiffData::open_decrypted(
+                    &PathBuf::from(d),
+                    lock_wait,
+                    key,
+                    &mut encrypt::prompt_password,
+                );
+            }
+            XxhDiffData::open(&PathBuf::from(d), lock_wait)
+        }
+        .map(|d| (d, HashMap::new()))
+    }) {
+        Some(Ok((mut d, data_hashes))) => {
+            if check_algorithm(d.algorithm(), algorithm, args.allow_algo_mismatch)? {
+                if let Some(rebase) = rebase {
+                    d.rebase(rebase);
+                }
+                Some((d, data_hashes))
+            } else {
+                None
+            }
+        }
         None => None,
-        Some(Err(e)) => match e.kind() {
-            ErrorKind::NotFound => return Err("Data file not found".to_string()),
-            _ => return Err(format!("Error opening data file: {}", e)),
-        },
+        Some(Err(e)) if matches!(e.kind(), DataErr::IOErr(e) if e.kind() == ErrorKind::NotFound) => {
+            return Err("Data file not found".to_string())
+        }
+        Some(Err(e)) if matches!(e.kind(), DataErr::Locked) => {
+            return Err(
+                "Another xxh-diff run is writing to the data file, pass --wait-lock to wait for it"
+                    .to_string(),
+            )
+        }
+        Some(Err(e)) => return Err(format!("Error opening data file: {}", e)),
+    };
+
+    let quick = match &mut data_file {
+        Some((data_file, data_hashes)) if args.quick => {
+            let mut quick = HashMap::new();
+            loop {
+                match data_file.read_skip_corrupt() {
+                    Ok(Record::Hash(result)) => {
+                        data_hashes
+                            .insert(result.path.clone(), (result.hash, result.chunks.clone()));
+                        quick.insert(result.path.clone(), result);
+                    }
+                    Ok(Record::Deleted(path)) => {
+                        data_hashes.remove(&path);
+                        quick.remove(&path);
+                    }
+                    Err(DataErr::Empty) => break,
+                    Err(e) => return Err(format!("Error reading from data file: {}", e)),
+                }
+            }
+            Some(Arc::new(quick))
+        }
+        _ => None,
     };
 
-    let (tx, rx) = flume::unbounded();
+    if args.result_queue == 0 || args.path_queue == 0 {
+        return Err("The result and path queues must hold at least 1".to_string());
+    }
+    let (tx, rx) = flume::bounded(args.result_queue);
     let mut unparkers = Vec::new();
     let mut thread_pool = MainThreadPool::new();
-    let fd_sem = Arc::new(Semaphore::new(args.max_files_open as isize));
-    let term_rx = term_handle.rx().clone();
+    let fd_sem = Arc::new(Semaphore::with_max(
+        args.max_files_open as isize,
+        args.max_files_open as isize,
+    ));
+    let term_sub = gracile::subscribe();
+    let buf_size = match args.buf_size.map(usize::try_from) {
+        Some(Ok(0)) => return Err("The buffer size must be at least 1 byte".to_string()),
+        Some(Ok(size)) => Some(size.next_multiple_of(cache::BUF_ALIGN)),
+        Some(Err(_)) => return Err("The buffer size is too big".to_string()),
+        None => None,
+    };
+    let fd_weight_unit = match args.fd_weight_unit {
+        Some(0) => return Err("The fd weight unit must be at least 1 MiB".to_string()),
+        unit => unit.map(|u| u * 1024 * 1024),
+    };
+    let debug_sink = match args.debug_scheduler {
+        Some(p) if p.as_os_str() == "-" => Some(Arc::new(DebugSink::Stderr)),
+        Some(p) => match fs::File::create(&p) {
+            Ok(f) => Some(Arc::new(DebugSink::File(Mutex::new(f)))),
+            Err(e) => return Err(format!("Error creating scheduler debug file: {}", e)),
+        },
+        None => None,
+    };
+    let hardlinks = (!args.no_hardlink_dedup).then(|| Arc::new(HardLinks::default()));
+    let retried = Arc::new(AtomicUsize::new(0));
+    let failed = (!args.fail_fast).then(|| Arc::new(Mutex::new(Vec::new())));
+    let mut bench = args.bench.then(Bench::new);
+    // Each pool's filesystem
+    let mut pools = Vec::new();
+
+    let files = match &args.files_from {
+        Some(from) => files_from::read(from, args.from0, &dirs, args.fail_fast)?,
+        None => Vec::new(),
+    };
+    let fs_dirs = get_fs_dirs(dirs.clone(), files)?;
+    // What the walkers skip paths for already having, none with --force, and only the ones hashed
+    // since the cutoff with --refresh-older-than, which the reader fills in
+    let (skip_hashes, skip_read_done) = match (args.force, args.refresh_older_than) {
+        (true, _) => (Arc::default(), Arc::new(AtomicBool::new(true))),
+        (false, Some(_)) => (Arc::default(), Arc::clone(&read_done)),
+        (false, None) => (Arc::clone(&existing_hashes), Arc::clone(&read_done)),
+    };
+    for (mount, _) in &args.fs_threads {
+        if !fs_dirs.iter().any(|fs| fs.matches(mount)) {
+            eprintln!(
+                "Warning: --fs-threads {} isn't the filesystem of any directory being hashed",
+                mount.display()
+            );
+        }
+    }
 
-    for dirs in get_fs_dirs(dirs)? {
-        let (path_rx, unparker) =
-            paths::start_paths_thread(dirs, &existing_hashes, &read_done, &mut thread_pool);
+    if let Some(progress) = progress.as_ref().filter(|_| args.pre_scan) {
+        let mut scan_pool = MainThreadPool::new();
+        let totals: Vec<_> = fs_dirs
+            .iter()
+            .map(|fs| {
+                let paths = fs.dirs.iter().chain(&fs.files).cloned().collect();
+                paths::pre_scan(paths, walk.quiet(), args.path_queue, &mut scan_pool)
+            })
+            .collect();
+        // Waits for every filesystem to be scanned
+        drop(scan_pool);
+
+        let (mut files, mut bytes) = (0, 0);
+        for (fs, totals) in fs_dirs.iter().zip(totals) {
+            let fs_files = totals.files.load(Ordering::Relaxed);
+            let fs_bytes = totals.bytes.load(Ordering::Relaxed);
+            if args.verbose {
+                eprintln!(
+                    "Found {} file(s), {}, on {}",
+                    fs_files,
+                    progress::fmt_bytes(fs_bytes as f64),
+                    fs.source.display()
+                );
+            }
+            files += fs_files;
+            bytes += fs_bytes;
+        }
+        progress.set_total(files, bytes);
+    }
+
+    for fs in fs_dirs {
+        pools.push(fs.source.display().to_string());
+        // The last entry for a filesystem wins, as with options given more than once
+        let (min_threads, max_threads) =
+            match args.fs_threads.iter().rev().find(|(m, _)| fs.matches(m)) {
+                Some((_, count)) if args.threads.is_some() => (*count, *count),
+                Some((_, count)) => (min_threads.min(*count), *count),
+                None => (min_threads, max_threads),
+            };
+        if args.verbose {
+            let _hidden = progress.as_ref().map(|p| p.hide());
+            let mut paths: Vec<_> = fs.dirs.iter().map(|d| d.display().to_string()).collect();
+            if !fs.files.is_empty() {
+                paths.push(format!("{} file(s) from --files-from", fs.files.len()));
+            }
+            eprintln!(
+                "Hashing {} on {} with {} to {} threads",
+                paths.join(", "),
+                fs.source.display(),
+                min_threads,
+                max_threads
+            );
+        }
+
+        let (path_rx, unparker) = paths::start_paths_thread(
+            fs.dirs.into_iter().chain(fs.files).collect(),
+            walk.clone(),
+            &skip_hashes,
+            &skip_read_done,
+            args.path_queue,
+            &mut thread_pool,
+        );
         unparkers.push(unparker);
 
         thread_pool.spawn({
             let send_hash = tx.clone();
-            let term_rx = term_rx.clone();
+            let term_sub = gracile::subscribe();
             let err_handle = term_handle.err_handle.clone();
             let fd_sem = Arc::clone(&fd_sem);
+            let quick = quick.clone();
+            let progress = progress.clone();
+            let retried = Arc::clone(&retried);
+            let failed = failed.clone();
+            let throttle = throttle.clone();
+            let hardlinks = hardlinks.clone();
+            let thread_log = bench.as_ref().map(|b| b.thread_log(pools.len() - 1));
+            let debug = debug_sink.as_ref().map(|sink| SchedulerDebug {
+                pool: fs.source.display().to_string(),
+                sink: Arc::clone(sink),
+            });
             move || {
                 let parallel_hash = ParallelHash {
                     path_rx,
                     err_handle,
                     fd_sem,
+                    algorithm,
+                    quick,
+                    chunk_size,
+                    min_threads,
+                    max_threads,
+                    progress,
+                    #[cfg(unix)]
+                    mmap_threshold,
+                    cache_mode: args.cache_mode,
+                    io_retries: args.io_retries,
+                    retried,
+                    failed,
+                    buf_size,
+                    throttle,
+                    autoscale,
+                    priority,
+                    include_empty: args.include_empty,
+                    fd_weight_unit,
+                    debug,
+                    hardlinks,
+                    thread_log,
                 };
 
-                parallel_hash::hash_paths(parallel_hash, send_hash, term_rx);
+                parallel_hash::hash_paths(parallel_hash, send_hash, term_sub);
             }
         });
     }
 
     drop(tx);
 
-    let mut new_results = if let Some(data_out_file_inner) = &*data_out_file {
-        if data_out_file_inner.lock().get_mut().is_read() {
+    // The reader is sent back once it's done, for how it finished to be dealt with
+    let (reader_done_tx, reader_done_rx) = flume::bounded(1);
+    let mut new_results = match data_out_reader {
+        Some(mut data_out_reader) => {
             thread_pool.spawn({
-                let data_out_file = Arc::clone(&data_out_file);
                 let read_done = Arc::clone(&read_done);
                 let existing_hashes = Arc::clone(&existing_hashes);
+                let refresh = args
+                    .refresh_older_than
+                    .map(|cutoff| (Arc::clone(&skip_hashes), cutoff));
                 let err_handle = term_handle.err_handle.clone();
                 move || {
-                    if let Some(data_out_file) = &*data_out_file {
-                        let existing_hashes = existing_hashes.pin();
-                        loop {
-                            if TERMINATE.get() {
-                                break;
-                            }
+                    let existing_hashes = existing_hashes.pin();
+                    let refresh = refresh.as_ref().map(|(h, cutoff)| (h.pin(), *cutoff));
+                    loop {
+                        if TERMINATE.get() {
+                            break;
+                        }
 
-                            let mut data_out_file = data_out_file.lock();
-                            match data_out_file.get_mut().read() {
-                                Ok(HashResult(path, hash)) => {
-                                    existing_hashes.insert(path, hash);
-                                    unparkers.iter().for_each(Unparker::unpark);
+                        match data_out_reader.read() {
+                            Ok(Record::Hash(result)) => {
+                                // A later record for a path supersedes whether the earlier one was
+                                // recent enough
+                                if let Some((skip_hashes, cutoff)) = &refresh {
+                                    match result.hashed_at >= *cutoff {
+                                        true => {
+                                            skip_hashes.insert(result.path.clone(), result.clone())
+                                        }
+                                        false => skip_hashes.remove(&result.path),
+                                    };
                                 }
-                                Err(DataErr::Empty) => break,
-                                Err(e) => {
-                                    err_handle.term_err(format!(
-                                        "Error reading from existing data out file: {}",
-                                        e
-                                    ));
-                                    break;
+                                existing_hashes.insert(result.path.clone(), result);
+                                unparkers.iter().for_each(Unparker::unpark);
+                            }
+                            Ok(Record::Deleted(path)) => {
+                                if let Some((skip_hashes, _)) = &refresh {
+                                    skip_hashes.remove(&path);
                                 }
+                                existing_hashes.remove(&path);
+                            }
+                            Err(DataErr::Empty) => break,
+                            Err(e) => {
+                                err_handle.term_err(format!(
+                                    "Error reading from existing data out file: {}",
+                                    e
+                                ));
+                                break;
                             }
                         }
                     }
 
+                    let _ = reader_done_tx.send(data_out_reader);
+                    // Has to be stored before unparking, for walkers woken to see it
                     read_done.store(true, Ordering::Release);
                     unparkers.iter().for_each(Unparker::unpark);
                 }
             });
 
             Some(Vec::new())
-        } else {
-            None
         }
-    } else {
-        None
+        None => None,
     };
 
+    // Paths hashed this run, to tell which of the recorded ones have been deleted
+    let report_deleted = args.itemize || args.report_deleted;
+    let mut seen = (args.record_deletions || report_deleted).then(HashSet::new);
+    // With --sorted, the paths to write and their markers, until the end
+    let mut sorted = args.sorted.then(Vec::new);
+    let mut output = Output::open(&args.output, args.null, args.force_delimiter)?;
+
     loop {
         enum SelectorMsg {
             Hash(Result<HashResult, RecvError>),
-            Err(Result<String, RecvError>),
+            Err(Result<ErrMsg, RecvError>),
             Term,
         }
 
-        match Selector::new()
+        // Set on termination, once the results already hashed have been taken in
+        let mut stopping = false;
+        let mut hashes: Vec<HashResult> = match Selector::new()
             .recv(&rx, SelectorMsg::Hash)
             .recv(&term_handle.err_rx, SelectorMsg::Err)
-            .recv(&term_rx, |_| SelectorMsg::Term)
+            .recv(term_sub.rx(), |_| SelectorMsg::Term)
             .wait()
         {
-            SelectorMsg::Hash(msg) => match msg {
-                Ok(hash) => {
-                    let mut hashes: Vec<HashResult> =
-                        iter::once(hash).chain(rx.try_iter()).collect();
-                    let write_hashes: Vec<_> = hashes.iter().collect();
-
-                    for HashResult(hash_path, hash) in write_hashes.iter() {
-                        let hash_matches =
-                            if let Some((ref mut data_file, ref mut data_hashes)) = data_file {
-                                if let Some(data_hash) = data_hashes.get(hash_path) {
-                                    data_hash == hash
-                                } else {
-                                    let mut data_hash_res = data_file.read();
-                                    loop {
-                                        match data_hash_res {
-                                            Ok(HashResult(data_path, data_hash)) => {
-                                                let matches = data_path == *hash_path;
-                                                data_hashes.insert(data_path, data_hash);
-                                                if matches {
-                                                    break data_hash == *hash;
-                                                }
-                                                data_hash_res = data_file.read();
-                                            }
-                                            Err(DataErr::Empty) => break false,
-                                            Err(e) => {
-                                                return Err(format!(
-                                                    "Error reading from data file: {}",
-                                                    e
-                                                ))
-                                            }
-                                        }
+            SelectorMsg::Hash(Ok(hash)) => iter::once(hash).chain(rx.try_iter()).collect(),
+            SelectorMsg::Hash(Err(_)) => break,
+            SelectorMsg::Err(msg) => {
+                if let Ok(msg) = msg {
+                    handle_err_msg(msg, progress.as_deref())?;
+                }
+                Vec::new()
+            }
+            SelectorMsg::Term => {
+                if let Some(progress) = &progress {
+                    progress.finish();
+                }
+                if gracile::signals_received() == 1 {
+                    eprintln!("Stopping, send the signal again to force quit");
+                }
+                stopping = true;
+
+                // What was hashed before the signal is still compared and written
+                parallel_hash::drain(&rx)
+            }
+        };
+
+        if let Some(bench) = bench.as_mut() {
+            bench.hashed(&hashes);
+        } else if !hashes.is_empty() {
+            let write_hashes: Vec<_> = hashes.iter().collect();
+            // Only taken once there's a path to write, hashing pools block on it
+            let mut progress_hidden = None;
+
+            for HashResult {
+                path: hash_path,
+                hash,
+                len,
+                chunks,
+                ..
+            } in write_hashes.iter()
+            {
+                let data_hash = if let Some((ref mut data_file, ref mut data_hashes)) = data_file {
+                    if let Some(data_hash) = data_hashes.get(hash_path) {
+                        Some(data_hash.clone())
+                    } else if data_file.has_index() {
+                        match data_file.lookup(hash_path) {
+                            Ok(data_hash) => data_hash,
+                            Err(e) => return Err(format!("Error reading from data file: {}", e)),
+                        }
+                    } else {
+                        loop {
+                            match data_file.read_skip_corrupt() {
+                                Ok(Record::Hash(HashResult {
+                                    path: data_path,
+                                    hash: data_hash,
+                                    chunks: data_chunks,
+                                    ..
+                                })) => {
+                                    if data_path == *hash_path {
+                                        data_hashes
+                                            .insert(data_path, (data_hash, data_chunks.clone()));
+                                        break Some((data_hash, data_chunks));
                                     }
+                                    data_hashes.insert(data_path, (data_hash, data_chunks));
                                 }
-                            } else {
-                                false
-                            };
-
-                        if !hash_matches {
-                            if let Err(e) = io::stdout()
-                                .write_all(&match hash_path.try_as_bytes() {
-                                    Ok(p) => p,
-                                    Err(p) => {
-                                        return Err(format!(
-                                            "Couldn't convert path buf {} to bytes",
-                                            p.display()
-                                        ))
+                                Ok(Record::Deleted(data_path)) => {
+                                    data_hashes.remove(&data_path);
+                                    if data_path == *hash_path {
+                                        break None;
                                     }
-                                })
-                                .and_then(|_| io::stdout().write_all(&[0xA]))
-                            {
-                                return Err(format!("Error writing path to stdout: {}", e));
+                                }
+                                Err(DataErr::Empty) => break None,
+                                Err(e) => {
+                                    return Err(format!("Error reading from data file: {}", e))
+                                }
                             }
                         }
                     }
+                } else {
+                    None
+                };
 
-                    if let Err(e) = io::stdout().flush() {
-                        return Err(format!("Error flushing stdout: {}", e));
-                    }
-
-                    if let Some(data_out_file) = &*data_out_file {
-                        if let Err(e) = data_out_file.lock().get_mut().write(&write_hashes) {
-                            return Err(format!(
-                                "Error writing hash results to data output file: {}",
-                                e
-                            ));
+                let marker = match data_hash {
+                    Some((data_hash, _)) if data_hash == *hash => continue,
+                    Some((_, Some(data_chunks))) if args.itemize => {
+                        match chunks.as_ref().and_then(|c| c.diff(&data_chunks, *len)) {
+                            Some((changed, ranges)) => Cow::Owned(changed_chunks_marker(
+                                changed,
+                                chunks.as_ref().unwrap().hashes.len(),
+                                &ranges,
+                            )),
+                            None => Cow::Borrowed(CHANGED_MARKER),
                         }
                     }
-
-                    if let Some(results) = new_results.as_mut() {
-                        results.append(&mut hashes);
-                    }
+                    Some(_) => Cow::Borrowed(CHANGED_MARKER),
+                    None => Cow::Borrowed(NEW_MARKER),
+                };
+                if let Some(sorted) = sorted.as_mut() {
+                    sorted.push((hash_path.clone(), args.itemize.then_some(marker)));
+                    continue;
                 }
-                Err(_) => break,
-            },
-            SelectorMsg::Err(msg) => {
-                if let Ok(e) = msg {
-                    TERMINATE.set();
-                    return Err(e);
+                if let (None, Some(progress)) = (&progress_hidden, &progress) {
+                    progress_hidden = Some(progress.hide());
                 }
+                output.write_path(args.itemize.then_some(&marker), hash_path)?;
             }
-            SelectorMsg::Term => break,
-        }
-
-        if let Some(hashes) = new_results.as_ref() {
-            if read_done.load(Ordering::Acquire) {
-                if let Some(data_out_file) = &*data_out_file {
-                    let mut data_out_file = data_out_file.lock();
-                    if let XxhDiffData::Read(_, ReadXxhDiffDataInner { status, .. }) =
-                        &*data_out_file.get_mut()
-                    {
-                        if status.is_err() {
-                            let existing_hashes: Vec<_> = existing_hashes
-                                .pin()
-                                .iter()
-                                .map(|(k, v)| HashResult(k.clone(), *v))
-                                .collect();
-                            let write_hashes: Vec<_> =
-                                existing_hashes.iter().chain(hashes).collect();
-
-                            if let Some(ref output_data) = args.output_data {
-                                match XxhDiffData::reset(&PathBuf::from(output_data)) {
-                                    Ok(new_data) => drop(data_out_file.replace(new_data)),
-                                    Err(e) => return Err(format!("Failed to open data output file when attempting to reset: {}", e)),
-                                }
 
-                                if let Err(e) = data_out_file.get_mut().write(&write_hashes) {
-                                    return Err(format!(
-                                        "Failed to write to new data output file: {}",
-                                        e
-                                    ));
-                                }
-                            }
-                        }
+            if let Err(e) = output.flush() {
+                return Err(format!("Error flushing {}: {}", output.name(), e));
+            }
+            drop(progress_hidden);
+
+            if let Some(data_out_file) = data_out_file.as_mut() {
+                if let Err(e) = data_out_file.write(&write_hashes) {
+                    return Err(format!(
+                        "Error writing hash results to data output file: {}",
+                        e
+                    ));
+                }
+            }
+
+            if let Some(seen) = seen.as_mut() {
+                seen.extend(hashes.iter().map(|h| h.path.clone()));
+            }
+
+            if let Some(results) = new_results.as_mut() {
+                results.append(&mut hashes);
+            }
+        }
+        if stopping {
+            break;
+        }
+
+        if let (Some(hashes), Some(data_out_file)) = (&new_results, data_out_file.as_mut()) {
+            if let Ok(data_out_reader) = reader_done_rx.try_recv() {
+                finish_data_out_read(data_out_file, &data_out_reader, &existing_hashes, hashes)?;
+                new_results = None;
+            }
+        }
+    }
+
+    // Warnings sent as the last results came in would otherwise go unseen
+    for msg in term_handle.err_rx.try_iter() {
+        handle_err_msg(msg, progress.as_deref())?;
+    }
 
-                        new_results = None;
+    // Hashing is done, the rest of the output comes after the line
+    if let Some(progress) = &progress {
+        progress.finish();
+    }
+
+    if let Some(bench) = &bench {
+        bench.report(&algorithm.to_string(), &pools, TERMINATE.get());
+    }
+
+    if let (Some(hashes), Some(data_out_file)) = (&new_results, data_out_file.as_mut()) {
+        // Hashing can finish first if every file was already in it
+        if !TERMINATE.get() {
+            if let Ok(data_out_reader) = reader_done_rx.recv() {
+                finish_data_out_read(data_out_file, &data_out_reader, &existing_hashes, hashes)?;
+            }
+        }
+    }
+
+    if let Some(seen) = seen.filter(|_| !TERMINATE.get()) {
+        if let Some((data_file, data_hashes)) = data_file.as_mut().filter(|_| report_deleted) {
+            // The rest of the data file, for the records not yet compared against
+            loop {
+                match data_file.read_skip_corrupt() {
+                    Ok(Record::Hash(result)) => {
+                        data_hashes.insert(result.path, (result.hash, result.chunks));
+                    }
+                    Ok(Record::Deleted(path)) => {
+                        data_hashes.remove(&path);
                     }
+                    Err(DataErr::Empty) => break,
+                    Err(e) => return Err(format!("Error reading from data file: {}", e)),
                 }
             }
+
+            let mut deleted: Vec<_> = data_hashes
+                .keys()
+                .filter(|p| is_deleted(p, &dirs, &seen))
+                .collect();
+            deleted.sort_unstable();
+            let marker = args.deleted_prefix.as_bytes();
+            for path in deleted {
+                match sorted.as_mut() {
+                    Some(sorted) => sorted.push((path.clone(), Some(Cow::Borrowed(marker)))),
+                    None => output.write_path(Some(marker), path)?,
+                }
+            }
+            if let Err(e) = output.flush() {
+                return Err(format!("Error flushing {}: {}", output.name(), e));
+            }
+        }
+
+        if let Some(data_out_file) = data_out_file
+            .as_mut()
+            .filter(|_| args.record_deletions && read_done.load(Ordering::Acquire))
+        {
+            let existing_hashes = existing_hashes.pin();
+            let deleted: Vec<_> = existing_hashes
+                .keys()
+                .filter(|p| is_deleted(p, &dirs, &seen))
+                .map(PathBuf::as_path)
+                .collect();
+            if let Err(e) = data_out_file.write_deleted(&deleted) {
+                return Err(format!(
+                    "Error writing deletions to data output file: {}",
+                    e
+                ));
+            }
+        }
+    }
+
+    if let Some(listed) = walk.size.as_ref().and_then(|s| s.listed.as_ref()) {
+        let mut listed = mem::take(&mut *listed.lock().unwrap());
+        listed.sort_unstable();
+        for path in listed {
+            match sorted.as_mut() {
+                Some(sorted) => sorted.push((path, Some(Cow::Borrowed(SKIPPED_SIZE_MARKER)))),
+                None => output.write_path(Some(SKIPPED_SIZE_MARKER), &path)?,
+            }
+        }
+        if let Err(e) = output.flush() {
+            return Err(format!("Error flushing {}: {}", output.name(), e));
         }
     }
 
+    if let Some(mut sorted) = sorted {
+        sorted.sort_unstable_by(|(a, _), (b, _)| a.as_os_str().cmp(b.as_os_str()));
+        for (path, marker) in &sorted {
+            output.write_path(marker.as_deref(), path)?;
+        }
+        if let Err(e) = output.flush() {
+            return Err(format!("Error flushing {}: {}", output.name(), e));
+        }
+    }
+
+    output.finish(TERMINATE.get())?;
+
+    if let Some(data_out_file) = data_out_file.take() {
+        if let Err(e) = data_out_file.close() {
+            return Err(format!("Error closing data output file: {}", e));
+        }
+    }
+
+    if let Some(throttle) = &throttle {
+        eprintln!(
+            "Reads were held back for {:.1}s in total by --max-throughput {}/s",
+            throttle.waited().as_secs_f64(),
+            progress::fmt_bytes(throttle.rate() as f64)
+        );
+    }
+    match walk.skipped_symlinks.load(Ordering::Relaxed) {
+        0 => {}
+        skipped if args.follow_symlinks => eprintln!("Skipped {} symlink(s)", skipped),
+        skipped => eprintln!(
+            "Skipped {} symlink(s), --follow-symlinks hashes what they point to",
+            skipped
+        ),
+    }
+    if let Some(skipped) = walk
+        .size
+        .as_ref()
+        .map(|s| s.skipped.load(Ordering::Relaxed))
+        .filter(|s| *s > 0)
+    {
+        eprintln!(
+            "Skipped {} file(s) outside of --min-size and --max-size",
+            skipped
+        );
+    }
+    match walk.skipped_hidden.load(Ordering::Relaxed) {
+        0 => {}
+        skipped => eprintln!("Skipped {} hidden file(s) and dir(s)", skipped),
+    }
+    match walk.skipped_mounts.load(Ordering::Relaxed) {
+        0 => {}
+        skipped => eprintln!(
+            "Didn't descend into {} mount point(s) on other filesystems",
+            skipped
+        ),
+    }
+    if let Some(saved) = hardlinks.as_ref().map(|h| h.saved()).filter(|s| *s > 0) {
+        eprintln!(
+            "Skipped reading {} of files hard linked to ones already hashed",
+            progress::fmt_bytes(saved as f64)
+        );
+    }
+
+    match retried.load(Ordering::Relaxed) {
+        0 => {}
+        retried => eprintln!(
+            "Warning: Retried reading {} file(s) or ranges of split files after transient errors",
+            retried
+        ),
+    }
+
+    if let Some((XxhDiffData::Read(_, ReadXxhDiffDataInner { skipped, .. }), _)) = data_file {
+        if skipped > 0 {
+            eprintln!(
+                "Warning: Skipped {} corrupt section(s) of the data file, files recorded there were treated as changed",
+                skipped
+            );
+        }
+    }
+
+    if args.compact_on_exit && !TERMINATE.get() {
+        if let Some(output_data) = &args.output_data {
+            // The data file must be closed before it's replaced, the reader's handle too
+            drop(thread_pool);
+            compact::compact_file(&PathBuf::from(output_data))?;
+        }
+    }
+
+    #[cfg(feature = "metrics")]
+    eprintln!("File descriptor semaphore metrics: {:?}", fd_sem.metrics());
+
+    let unread = walk.errors.take();
+    if !unread.is_empty() {
+        eprintln!("{} path(s) couldn't be read", unread.len());
+    }
+    let failed = failed.map_or_else(Vec::new, |f| mem::take(&mut *f.lock().unwrap()));
+    if let Some(error_log) = &args.error_log {
+        let log: String = unread
+            .iter()
+            .chain(&failed)
+            .map(|e| format!("{}\n", e))
+            .collect();
+        if let Err(e) = fs::write(error_log, log) {
+            return Err(format!("Error writing error log {}: {}", error_log, e));
+        }
+    }
+    if !failed.is_empty() {
+        eprintln!("Couldn't hash {} file(s):", failed.len());
+        for e in &failed {
+            eprintln!("  {}", e);
+        }
+        return Err(format!("Failed to hash {} file(s)", failed.len()));
+    }
+
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{env, process};
+
+    use clap::ErrorKind;
+
+    use super::*;
+
+    #[test]
+    fn sizes() {
+        assert_eq!(parse_size("512"), Ok(512));
+        assert_eq!(parse_size("1K"), Ok(1024));
+        assert_eq!(parse_size("1Ki"), Ok(1024));
+        assert_eq!(parse_size("3m"), Ok(3 << 20));
+        assert_eq!(parse_size("2Gi"), Ok(2 << 30));
+        // 2^34 GiB is 2^64 bytes, just past what fits
+        assert_eq!(parse_size("17179869183G"), Ok(u64::MAX - (1 << 30) + 1));
+        assert_eq!(
+            parse_size("17179869184G"),
+            Err("size is too big".to_string())
+        );
+        for invalid in ["", "K", "1i", "1Ti", "1KiB", "-1K"] {
+            assert!(parse_size(invalid).is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn nested_dirs_deduped() {
+        let dirs = |dirs: &[&str]| dirs.iter().map(PathBuf::from).collect::<Vec<_>>();
+
+        // The child given after its parent, and before it
+        assert_eq!(dedup_dirs(dirs(&["/a", "/a/b"])), dirs(&["/a"]));
+        assert_eq!(dedup_dirs(dirs(&["/a/b", "/a"])), dirs(&["/a"]));
+        assert_eq!(
+            dedup_dirs(dirs(&["/a/b/c", "/x", "/a/b/d", "/a", "/x"])),
+            dirs(&["/x", "/a"])
+        );
+        // Sharing a prefix isn't being inside
+        assert_eq!(
+            dedup_dirs(dirs(&["/a/bc", "/a/b"])),
+            dirs(&["/a/bc", "/a/b"])
+        );
+    }
+
+    /// A symlink to a directory given alongside it is the same directory once canonicalized like
+    /// the arguments are, so only one is kept
+    #[cfg(unix)]
+    #[test]
+    fn symlinked_dir_deduped() {
+        let dir = env::temp_dir().join(format!("xxh-diff-{}-symlinked", process::id()));
+        let link = dir.with_extension("link");
+        let _ = fs::remove_dir_all(&dir);
+        let _ = fs::remove_file(&link);
+        fs::create_dir(&dir).unwrap();
+        std::os::unix::fs::symlink(&dir, &link).unwrap();
+
+        let args = [&dir, &link].map(|p| p.to_str().unwrap().to_string());
+        let dirs = args.iter().map(canonicalize).collect::<Result<_, _>>().unwrap();
+        assert_eq!(dedup_dirs(dirs), [fs::canonicalize(&dir).unwrap()]);
+        fs::remove_file(link).unwrap();
+        fs::remove_dir(dir).unwrap();
+    }
+
+    /// Paths on the same device share a group whichever mounts they're under, named by the
+    /// closest mount above the first of them. Ones on another device or an unknown one don't
+    #[cfg(unix)]
+    #[test]
+    fn grouped_by_dev() {
+        let mounts = HashMap::from([
+            (PathBuf::from("/"), PathBuf::from("/dev/root")),
+            (PathBuf::from("/mnt/a"), PathBuf::from("/dev/a")),
+        ]);
+        let paths = |paths: &[(&str, Option<u64>)]| {
+            paths
+                .iter()
+                .map(|(p, dev)| (PathBuf::from(p), *dev))
+                .collect::<Vec<_>>()
+        };
+        let mut groups = group_by_dev(
+            paths(&[
+                ("/mnt/a/x", Some(2)),
+                ("/home", Some(1)),
+                ("/mnt/a/y", Some(2)),
+                ("/mnt/b", Some(3)),
+                ("/gone", None),
+            ]),
+            paths(&[("/srv/file", Some(1)), ("/mnt/a/file", Some(2))]),
+            &mounts,
+        );
+        groups.sort_unstable_by(|a, b| a.dirs.cmp(&b.dirs));
+
+        let summary: Vec<_> = groups
+            .iter()
+            .map(|g| {
+                (
+                    g.source.to_str().unwrap(),
+                    g.dirs.iter().map(|d| d.to_str().unwrap()).collect::<Vec<_>>(),
+                    g.files.iter().map(|f| f.to_str().unwrap()).collect::<Vec<_>>(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            summary,
+            [
+                ("/dev/root", vec!["/gone"], vec![]),
+                ("/dev/root", vec!["/home"], vec!["/srv/file"]),
+                ("/dev/a", vec!["/mnt/a/x", "/mnt/a/y"], vec!["/mnt/a/file"]),
+                ("/dev/root", vec!["/mnt/b"], vec![]),
+            ]
+        );
+        assert_eq!(groups[2].mounts, [PathBuf::from("/mnt/a")]);
+        assert_eq!(groups[1].mounts, [PathBuf::from("/")]);
+    }
+
+    #[test]
+    fn cutoff_dates() {
+        assert_eq!(parse_cutoff("1970-01-01"), Ok(0));
+        assert_eq!(parse_cutoff("1970-01-02"), Ok(24 * 60 * 60));
+        assert_eq!(parse_cutoff("2000-03-01"), Ok(951868800));
+        assert_eq!(parse_cutoff("2024-02-29"), Ok(1709164800));
+        assert!(parse_cutoff("2023-02-29").is_err());
+        assert!(parse_cutoff("2023-13-01").is_err());
+    }
+
+    #[test]
+    fn cutoff_times_ago() {
+        let now = data_fmt::hashed_now();
+        let cutoff = parse_cutoff("2h").unwrap();
+        assert!((now - 2 * 60 * 60..=now - 2 * 60 * 60 + 1).contains(&cutoff));
+        let cutoff = parse_cutoff("1w").unwrap();
+        assert!((now - 7 * 24 * 60 * 60..=now - 7 * 24 * 60 * 60 + 1).contains(&cutoff));
+        assert!(parse_cutoff(&format!("{}d", i64::MAX)).is_err());
+        assert!(parse_cutoff("2x").is_err());
+    }
+
+    #[test]
+    fn cutoff_timestamp() {
+        assert_eq!(parse_cutoff("1700000000"), Ok(1700000000));
+        assert!(parse_cutoff("").is_err());
+    }
+
+    /// Set for [`in_child`] to run the test itself rather than spawn it
+    const CHILD_ENV: &str = "XXH_DIFF_TEST_CHILD";
+
+    /// Runs the test `name` in a child process, for tests that set [`TERMINATE`], which would stop
+    /// every other test's hashing. Returns whether this is the child, which should run the test
+    pub(crate) fn in_child(name: &str) -> bool {
+        if env::var_os(CHILD_ENV).is_some() {
+            return true;
+        }
+        let status = process::Command::new(env::current_exe().unwrap())
+            .args(["--exact", name, "--test-threads=1"])
+            .env(CHILD_ENV, "1")
+            .status()
+            .unwrap();
+        assert!(status.success(), "{} failed in a child process", name);
+        false
+    }
+
+    #[test]
+    fn warnings_dont_terminate() {
+        if !in_child("tests::warnings_dont_terminate") {
+            return;
+        }
+
+        let handle = TermHandle::default();
+        // A rendezvous channel, so the sends have to be received from another thread
+        let err_handle = handle.err_handle.clone();
+        let sender = thread::spawn(move || {
+            err_handle.warn("recoverable");
+            err_handle.term_err("unrecoverable");
+        });
+
+        let msg = handle.err_rx.recv().unwrap();
+        assert!(matches!(msg, ErrMsg::Warn(_)));
+        assert_eq!(handle_err_msg(msg, None), Ok(()));
+        assert!(!TERMINATE.get());
+
+        let msg = handle.err_rx.recv().unwrap();
+        sender.join().unwrap();
+        assert!(matches!(msg, ErrMsg::Fatal(_)));
+        assert_eq!(handle_err_msg(msg, None), Err("unrecoverable".to_string()));
+        assert!(TERMINATE.get());
+    }
+
+    #[test]
+    fn cross_width_is_an_error() {
+        let xxh64 = HashAlgorithm::Xxh64 {
+            seed: 0,
+            split: None,
+        };
+        let xxh128 = HashAlgorithm::Xxh128 {
+            seed: 0,
+            split: None,
+        };
+        let xxh3 = HashAlgorithm::Xxh3 {
+            seed: 0,
+            split: None,
+        };
+
+        assert_eq!(check_algorithm(xxh128, xxh128, false), Ok(true));
+        // Allowing a mismatch doesn't allow comparing across widths
+        for (data, run) in [(xxh64, xxh128), (xxh128, xxh64), (xxh128, xxh3)] {
+            let err = check_algorithm(data, run, true).unwrap_err();
+            assert!(err.contains("can't be compared"), "{}", err);
+            assert!(err.contains(&format!("{}-bit", data.bits())), "{}", err);
+        }
+        // The same width with a different algorithm or seed can be, if allowed
+        assert!(check_algorithm(xxh64, xxh3, false).is_err());
+        assert_eq!(check_algorithm(xxh64, xxh3, true), Ok(false));
+        let seeded = HashAlgorithm::Xxh128 {
+            seed: 1,
+            split: None,
+        };
+        assert_eq!(check_algorithm(xxh128, seeded, true), Ok(false));
+    }
+
+    #[test]
+    fn refresh_conflicts_with_force() {
+        let args = [
+            "xxh-diff",
+            "--output-data",
+            "out",
+            "--refresh-older-than",
+            "1d",
+        ];
+        let parsed = Args::try_parse_from(args).unwrap();
+        assert!(parsed.refresh_older_than.is_some() && !parsed.force);
+
+        let res = Args::try_parse_from(args.iter().chain(&["--force"]));
+        assert_eq!(res.unwrap_err().kind(), ErrorKind::ArgumentConflict);
+        let res = Args::try_parse_from(["xxh-diff", "--refresh-older-than", "1d"]);
+        assert_eq!(res.unwrap_err().kind(), ErrorKind::MissingRequiredArgument);
+    }
+}
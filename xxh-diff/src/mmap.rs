@@ -0,0 +1,72 @@
+//! Memory mapped reading, for hashing large files in bigger strides than the read buffer
+
+use std::{fs::File, io, os::fd::AsRawFd, ptr, slice};
+
+/// How much of a mapping is handed over at a time
+const STRIDE: usize = 8 * 1024 * 1024;
+
+/// A read only mapping of the start of a file
+struct Mmap {
+    ptr: *mut libc::c_void,
+    len: usize,
+}
+
+impl Mmap {
+    fn new(file: &File, len: usize) -> io::Result<Self> {
+        // Safety: a fresh shared read only mapping, which nothing else refers to
+        let ptr = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                len,
+                libc::PROT_READ,
+                libc::MAP_SHARED,
+                file.as_raw_fd(),
+                0,
+            )
+        };
+        if ptr == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+
+        // Safety: the range is the mapping just made. It's only advice, so failing is fine
+        unsafe { libc::madvise(ptr, len, libc::MADV_SEQUENTIAL) };
+        Ok(Self { ptr, len })
+    }
+}
+
+impl Drop for Mmap {
+    fn drop(&mut self) {
+        // Safety: the mapping is ours and no slices of it outlive `read_mapped`
+        unsafe { libc::munmap(self.ptr, self.len) };
+    }
+}
+
+/// Passes the first `len` bytes of `file` to `f` a stride at a time from a mapping of it,
+/// returning how many bytes were passed. It stops early if the file shrinks, the rest is left to
+/// be read normally, and it's 0 if the file couldn't be mapped.
+///
+/// Touching a mapped page past the end of the file raises SIGBUS, so its length is checked before
+/// every stride. A file truncated in the moment between the check and the stride being read will
+/// still crash the process.
+pub fn read_mapped(file: &File, len: u64, mut f: impl FnMut(&[u8])) -> u64 {
+    let Some(len) = usize::try_from(len).ok().filter(|l| *l > 0) else {
+        return 0;
+    };
+    let Ok(map) = Mmap::new(file, len) else {
+        return 0;
+    };
+
+    let mut pos = 0;
+    while pos < len {
+        let end = len.min(pos + STRIDE);
+        match file.metadata() {
+            Ok(m) if m.len() >= end as u64 => {}
+            _ => break,
+        }
+
+        // Safety: the range is inside the mapping, which the file currently covers
+        f(unsafe { slice::from_raw_parts((map.ptr as *const u8).add(pos), end - pos) });
+        pos = end;
+    }
+    pos as u64
+}
@@ -0,0 +1,157 @@
+//! Writing the paths, to stdout or the file `--output` names, each ended by a newline or with
+//! `--null` a NUL
+
+use std::{
+    fs::{self, File},
+    io::{self, BufWriter, Write},
+    path::PathBuf,
+};
+
+use crate::raw_path_bytes::RawPathBytes;
+
+pub struct Output {
+    dest: Dest,
+    /// Ends each path, a newline unless `--null`
+    delimiter: u8,
+    /// Set by `--force-delimiter`, for paths with the delimiter in them to be written anyway
+    force_delimiter: bool,
+}
+
+/// Where the paths go, stdout unless `--output` names a file
+enum Dest {
+    Stdout,
+    File(OutputFile),
+}
+
+/// Written to a temp file next to the file, which is only renamed over it once the run is done,
+/// for a run that fails not to leave part of a list behind
+struct OutputFile {
+    path: PathBuf,
+    tmp_path: PathBuf,
+    writer: BufWriter<File>,
+    renamed: bool,
+}
+
+impl Output {
+    /// `path` of `-` is stdout. Paths are ended by a NUL if `null`, otherwise a newline, with
+    /// ones that have newlines in them being errors unless `force_delimiter`
+    pub fn open(path: &str, null: bool, force_delimiter: bool) -> Result<Self, String> {
+        let dest = match path {
+            "-" => Dest::Stdout,
+            _ => {
+                let path = PathBuf::from(path);
+                let mut tmp_path = path.as_os_str().to_owned();
+                tmp_path.push(".tmp");
+                let tmp_path = PathBuf::from(tmp_path);
+                let file = File::create(&tmp_path).map_err(|e| {
+                    format!("Error creating output file {}: {}", tmp_path.display(), e)
+                })?;
+                Dest::File(OutputFile {
+                    path,
+                    tmp_path,
+                    writer: BufWriter::new(file),
+                    renamed: false,
+                })
+            }
+        };
+        Ok(Self {
+            dest,
+            delimiter: match null {
+                true => b'\0',
+                false => b'\n',
+            },
+            force_delimiter,
+        })
+    }
+
+    /// What it's writing to, for errors
+    pub fn name(&self) -> String {
+        match &self.dest {
+            Dest::Stdout => "stdout".to_string(),
+            Dest::File(f) => format!("output file {}", f.path.display()),
+        }
+    }
+
+    /// Writes `path` after `marker` if given, then the delimiter. A path with the delimiter in it
+    /// would read as more than one, so is an error unless forced
+    pub fn write_path(&mut self, marker: Option<&[u8]>, path: &PathBuf) -> Result<(), String> {
+        let bytes = match path.try_as_bytes() {
+            Ok(p) => p,
+            Err(p) => {
+                return Err(format!(
+                    "Couldn't convert path buf {} to bytes",
+                    p.display()
+                ))
+            }
+        };
+        if !self.force_delimiter && bytes.contains(&self.delimiter) {
+            return Err(format!(
+                "Path {:?} has a newline in it, which would split it over lines. Use --null to \
+                 end paths with NULs instead, or --force-delimiter to write it anyway",
+                path
+            ));
+        }
+
+        let delimiter = self.delimiter;
+        marker
+            .map_or(Ok(()), |m| self.write_all(m))
+            .and_then(|_| self.write_all(&bytes))
+            .and_then(|_| self.write_all(&[delimiter]))
+            .map_err(|e| format!("Error writing path to {}: {}", self.name(), e))
+    }
+
+    /// Puts the file in place, unless `interrupted`, in which case it's left out for what was
+    /// written not to be taken as everything
+    pub fn finish(&mut self, interrupted: bool) -> Result<(), String> {
+        let file = match &mut self.dest {
+            Dest::Stdout => return Ok(()),
+            Dest::File(f) => f,
+        };
+        if interrupted {
+            eprintln!(
+                "Warning: Interrupted, so {} hasn't been written",
+                file.path.display()
+            );
+            return Ok(());
+        }
+
+        file.writer
+            .flush()
+            .and_then(|_| file.writer.get_ref().sync_all())
+            .and_then(|_| fs::rename(&file.tmp_path, &file.path))
+            .map_err(|e| format!("Error writing output file {}: {}", file.path.display(), e))?;
+        file.renamed = true;
+        Ok(())
+    }
+}
+
+impl Write for Output {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match &mut self.dest {
+            Dest::Stdout => io::stdout().write(buf),
+            Dest::File(f) => f.writer.write(buf),
+        }
+    }
+
+    fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
+        match &mut self.dest {
+            Dest::Stdout => io::stdout().lock().write_all(buf),
+            Dest::File(f) => f.writer.write_all(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match &mut self.dest {
+            Dest::Stdout => io::stdout().flush(),
+            Dest::File(f) => f.writer.flush(),
+        }
+    }
+}
+
+impl Drop for OutputFile {
+    fn drop(&mut self) {
+        if !self.renamed {
+            let _ = fs::remove_file(&self.tmp_path);
+        }
+    }
+}
@@ -1,25 +1,47 @@
 use std::{
-    fs::File,
+    fs::{self, File},
     hash::Hasher,
-    io::Read,
+    io::{self, ErrorKind, Read, Seek, SeekFrom, Write},
     iter,
-    path::PathBuf,
+    path::{Path, PathBuf},
     sync::{
-        atomic::{AtomicBool, AtomicU32, Ordering},
-        Arc,
+        atomic::{AtomicBool, AtomicU32, AtomicU8, AtomicUsize, Ordering},
+        Arc, Mutex,
     },
     thread::{self, JoinHandle},
-    time::Instant,
+    time::{Duration, Instant},
 };
 
 use atomic_float::AtomicF32;
-use flume::{Receiver, Selector, Sender, TryRecvError};
-use gracile::{ErrHandle, TERMINATE};
+use flume::{Receiver, Selector, Sender, TryRecvError, TrySendError};
+use gracile::{ErrHandle, TermError, TermSubscription, TERMINATE};
 use hashbrown::HashMap;
-use sema_lot::Semaphore;
+use sema_lot::{Semaphore, SemaphoreGuard};
+use sha2::{Digest, Sha256};
 use twox_hash::XxHash64;
+use xxhash_rust::xxh3::Xxh3;
 
-use crate::data_fmt::HashResult;
+#[cfg(unix)]
+use crate::mmap;
+use crate::{
+    bench::ThreadLog,
+    cache::{self, CacheMode},
+    data_fmt::{self, ChunkHashes, HashAlgorithm, HashResult, HashValue},
+    hardlink::{self, FileId, HardLinks},
+    priority::Priority,
+    progress::{self, Progress},
+    throttle::Throttle,
+};
+
+/// Bounds of the read buffer sized to each file, when there isn't a fixed size
+const MIN_AUTO_BUF: usize = 64 * 1024;
+const MAX_AUTO_BUF: usize = 1024 * 1024;
+const RETRY_DELAY: Duration = Duration::from_millis(100);
+/// Files at least this big are hashed with blake3's multithreaded update. Smaller ones are left to
+/// one thread, for the pool of hashing threads not to be competing with blake3's own
+const BLAKE3_PARALLEL_MIN: u64 = 64 * 1024 * 1024;
+/// The delay stops doubling at this many times [`RETRY_DELAY`]
+const RETRY_MAX_FACTOR: u32 = 64;
 
 enum HashThreadMsg {
     Hash(HashResult),
@@ -30,45 +52,625 @@ pub struct ParallelHash {
     pub path_rx: Receiver<PathBuf>,
     pub err_handle: ErrHandle,
     pub fd_sem: Arc<Semaphore>,
+    pub algorithm: HashAlgorithm,
+    /// Records whose hash is reused without reading the file if its size and mtime still match
+    pub quick: Option<Arc<HashMap<PathBuf, HashResult>>>,
+    /// Size of the blocks hashed separately as well as the whole file, if they are
+    pub chunk_size: Option<u64>,
+    /// The pool of hashing threads starts with this many, which never stop until the paths run
+    /// out
+    pub min_threads: u32,
+    /// The pool of hashing threads isn't scaled up past this many
+    pub max_threads: u32,
+    pub progress: Option<Arc<Progress>>,
+    /// Files at least this big are memory mapped rather than read
+    #[cfg(unix)]
+    pub mmap_threshold: Option<u64>,
+    pub cache_mode: CacheMode,
+    /// How many times reading a file is retried after a transient error
+    pub io_retries: u32,
+    /// Files whose reading was retried, shared between the pools
+    pub retried: Arc<AtomicUsize>,
+    /// Where the errors of files that couldn't be hashed go, for the rest to still be hashed.
+    /// Without it the first one ends the run
+    pub failed: Option<Arc<Mutex<Vec<TermError>>>>,
+    /// Size of the read buffer, a multiple of [`cache::BUF_ALIGN`]. Without it, it's sized to each
+    /// file
+    pub buf_size: Option<usize>,
+    /// Shared between the pools, so the cap is on them all together
+    pub throttle: Option<Arc<Throttle>>,
+    pub autoscale: Autoscale,
+    /// Applied to each hashing thread as it starts
+    pub priority: Priority,
+    /// Whether empty files are sent on at all
+    pub include_empty: bool,
+    /// Bytes of a file that take one file descriptor permit, so big files take more. Without it
+    /// every file takes one
+    pub fd_weight_unit: Option<u64>,
+    pub debug: Option<SchedulerDebug>,
+    /// Shared between the pools, for files with several links to only be read for one of them
+    pub hardlinks: Option<Arc<HardLinks>>,
+    /// Where the thread count is recorded as it changes, for `--bench`
+    pub thread_log: Option<ThreadLog>,
+}
+
+/// Periodic lines on the state of a pool, for `--debug-scheduler`
+pub struct SchedulerDebug {
+    /// Which pool the lines are about, by its filesystem
+    pub pool: String,
+    pub sink: Arc<DebugSink>,
+}
+
+/// Where `--debug-scheduler` lines go, shared by the pools
+pub enum DebugSink {
+    Stderr,
+    File(Mutex<File>),
+}
+
+impl DebugSink {
+    fn line(&self, progress: Option<&Progress>, line: &str) {
+        match self {
+            Self::Stderr => {
+                let _hidden = progress.map(|p| p.hide());
+                eprintln!("{}", line);
+            }
+            // Only diagnostics, failing to write them doesn't stop the run
+            Self::File(file) => {
+                let _ = writeln!(file.lock().unwrap(), "{}", line);
+            }
+        }
+    }
+}
+
+const DEBUG_INTERVAL: Duration = Duration::from_secs(1);
+/// How long termination waits for results that were already hashed to be passed on
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// What a hashing thread is doing, published for `--debug-scheduler`
+#[derive(Clone, Copy)]
+enum ThreadState {
+    Hashing,
+    WaitingForPath,
+    WaitingForFd,
+    Halted,
+}
+
+impl ThreadState {
+    const ALL: [Self; 4] = [
+        Self::Hashing,
+        Self::WaitingForPath,
+        Self::WaitingForFd,
+        Self::Halted,
+    ];
+
+    fn name(self) -> &'static str {
+        match self {
+            Self::Hashing => "hashing",
+            Self::WaitingForPath => "waiting-for-path",
+            Self::WaitingForFd => "waiting-for-fd",
+            Self::Halted => "halted",
+        }
+    }
+}
+
+/// Publishes what the thread is doing, if anything is looking
+fn publish(cell: Option<&AtomicU8>, state: ThreadState) {
+    if let Some(cell) = cell {
+        cell.store(state as u8, Ordering::Relaxed);
+    }
+}
+
+/// Tuning of how the pool of hashing threads is scaled between its bounds
+#[derive(Clone, Copy)]
+pub struct Autoscale {
+    /// The thread count is left alone for this long after each change, for its effect to show
+    pub interval: Duration,
+    /// Fraction hashing has to speed up by after a change for another like it to be made, or slow
+    /// down by for it to be undone
+    pub threshold: f64,
+    /// Print each change and the figures it was based on
+    pub log: bool,
+}
+
+enum FileHasher {
+    Xxh64(XxHash64),
+    Xxh128(Box<Xxh3>),
+    Xxh3(Box<Xxh3>),
+    /// Whether it's using the multithreaded update
+    Blake3(Box<blake3::Hasher>, bool),
+    Sha256(Box<Sha256>),
+}
+
+impl FileHasher {
+    fn new(algorithm: HashAlgorithm) -> Self {
+        match algorithm {
+            HashAlgorithm::Xxh64 { seed, .. } => Self::Xxh64(XxHash64::with_seed(seed)),
+            HashAlgorithm::Xxh128 { seed, .. } => Self::Xxh128(Box::new(Xxh3::with_seed(seed))),
+            HashAlgorithm::Xxh3 { seed, .. } => Self::Xxh3(Box::new(Xxh3::with_seed(seed))),
+            HashAlgorithm::Blake3 { .. } => Self::Blake3(Box::default(), false),
+            HashAlgorithm::Sha256 { .. } => Self::Sha256(Box::default()),
+        }
+    }
+
+    /// For hashing `len` bytes, which with blake3 uses its multithreaded update if there's at
+    /// least [`BLAKE3_PARALLEL_MIN`] of them
+    fn for_len(algorithm: HashAlgorithm, len: u64) -> Self {
+        match Self::new(algorithm) {
+            Self::Blake3(h, _) => Self::Blake3(h, len >= BLAKE3_PARALLEL_MIN),
+            hasher => hasher,
+        }
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        match self {
+            Self::Xxh64(h) => h.write(bytes),
+            Self::Xxh128(h) | Self::Xxh3(h) => h.update(bytes),
+            Self::Blake3(h, true) => {
+                h.update_rayon(bytes);
+            }
+            Self::Blake3(h, false) => {
+                h.update(bytes);
+            }
+            Self::Sha256(h) => h.update(bytes),
+        }
+    }
+
+    fn finish(&self) -> HashValue {
+        match self {
+            Self::Xxh64(h) => HashValue::U64(h.finish()),
+            Self::Xxh128(h) => HashValue::U128(h.digest128()),
+            Self::Xxh3(h) => HashValue::U64(h.digest()),
+            Self::Blake3(h, _) => HashValue::U256(*h.finalize().as_bytes()),
+            Self::Sha256(h) => HashValue::U256(h.as_ref().clone().finalize().into()),
+        }
+    }
+}
+
+/// Hashes each `size` byte block of what's written to it
+struct ChunkHasher {
+    algorithm: HashAlgorithm,
+    size: u64,
+    hasher: FileHasher,
+    /// Bytes written to `hasher` so far
+    filled: u64,
+    hashes: Vec<HashValue>,
+}
+
+impl ChunkHasher {
+    fn new(algorithm: HashAlgorithm, size: u64) -> Self {
+        Self {
+            algorithm,
+            size,
+            hasher: FileHasher::new(algorithm),
+            filled: 0,
+            hashes: Vec::new(),
+        }
+    }
+
+    fn write(&mut self, mut bytes: &[u8]) {
+        while !bytes.is_empty() {
+            let take = bytes.len().min((self.size - self.filled) as usize);
+            self.hasher.write(&bytes[..take]);
+            self.filled += take as u64;
+            bytes = &bytes[take..];
+
+            if self.filled == self.size {
+                self.hashes.push(self.hasher.finish());
+                self.hasher = FileHasher::new(self.algorithm);
+                self.filled = 0;
+            }
+        }
+    }
+
+    fn finish(mut self) -> ChunkHashes {
+        if self.filled > 0 {
+            self.hashes.push(self.hasher.finish());
+        }
+        ChunkHashes {
+            size: self.size,
+            hashes: self.hashes,
+        }
+    }
+}
+
+/// A range's hash and chunk hashes
+type RangeHashes = (HashValue, Option<ChunkHashes>);
+
+/// A file hashed as ranges, by whichever threads are free
+struct SplitFile {
+    path: PathBuf,
+    len: u64,
+    mtime: i64,
+    /// Each range's hash and chunk hashes, once it's been hashed
+    ranges: Mutex<Vec<Option<RangeHashes>>>,
+    /// How many ranges haven't been hashed yet
+    remaining: AtomicUsize,
+    /// The file's id and number of links, if it has several
+    link: Option<(FileId, u64)>,
+}
+
+impl SplitFile {
+    /// Records a range's hashes, returning the file's result if it was the last one
+    fn finish_range(
+        &self,
+        index: usize,
+        hashes: RangeHashes,
+        algorithm: HashAlgorithm,
+    ) -> Option<HashResult> {
+        self.ranges.lock().unwrap()[index] = Some(hashes);
+        if self.remaining.fetch_sub(1, Ordering::AcqRel) != 1 {
+            return None;
+        }
+
+        let mut hash = FileHasher::new(algorithm);
+        let mut chunks: Option<ChunkHashes> = None;
+        let mut bytes = Vec::new();
+        for (range_hash, range_chunks) in self.ranges.lock().unwrap().drain(..).flatten() {
+            bytes.clear();
+            range_hash.write_le(&mut bytes);
+            hash.write(&bytes);
+            // Ranges are a whole number of chunks
+            if let Some(range_chunks) = range_chunks {
+                match chunks.as_mut() {
+                    Some(chunks) => chunks.hashes.extend(range_chunks.hashes),
+                    None => chunks = Some(range_chunks),
+                }
+            }
+        }
+
+        Some(HashResult {
+            path: self.path.clone(),
+            hash: hash.finish(),
+            len: self.len,
+            mtime: self.mtime,
+            chunks,
+            hashed_at: data_fmt::hashed_now(),
+        })
+    }
+}
+
+/// One range of a [`SplitFile`]
+struct RangeTask {
+    file: Arc<SplitFile>,
+    index: usize,
+}
+
+enum Task {
+    Path(PathBuf),
+    Range(RangeTask),
 }
 
 struct ThreadVars {
     parallel_hash: ParallelHash,
     path_rx_done: AtomicBool,
     thread_halt: AtomicU32,
+    /// Ranges of split files, taken before new paths
+    range_tx: Sender<RangeTask>,
+    range_rx: Receiver<RangeTask>,
+}
+
+/// Waits for `permits` file descriptor permits, unless terminating
+fn fd_access<'a>(
+    fd_sem: &'a Semaphore,
+    thread_speed: &AtomicF32,
+    state: Option<&AtomicU8>,
+    permits: usize,
+) -> Option<SemaphoreGuard<'a>> {
+    if let Some(guard) = fd_sem.try_access_many(permits) {
+        return Some(guard);
+    }
+
+    let old_speed = thread_speed.swap(-2.0, Ordering::Release);
+    publish(state, ThreadState::WaitingForFd);
+    let guard = fd_sem.access_many_interruptible(permits, || TERMINATE.get())?;
+    publish(state, ThreadState::Hashing);
+    thread_speed.store(old_speed, Ordering::Release);
+    Some(guard)
+}
+
+/// Permits reading `len` bytes takes, one for every `weight_unit` bytes of it if there is one. At
+/// least 1, and at most all `max` of them
+fn fd_permits(len: u64, weight_unit: Option<u64>, max: isize) -> usize {
+    match weight_unit {
+        Some(unit) => (len / unit).clamp(1, max.max(1) as u64) as usize,
+        None => 1,
+    }
+}
+
+/// Hashes a [`RangeTask`]'s range as it is now, returning its hashes and how many bytes were read
+fn hash_range(
+    task: &RangeTask,
+    algorithm: HashAlgorithm,
+    chunk_size: Option<u64>,
+    cache_mode: CacheMode,
+    buf: &mut [u8],
+    throttle: Option<&Throttle>,
+) -> io::Result<(RangeHashes, u64)> {
+    let split = algorithm.split().unwrap();
+    let start = task.index as u64 * split;
+    let left = split.min(task.file.len - start);
+
+    let mut file = cache::open(&task.file.path, cache_mode)?;
+    file.seek(SeekFrom::Start(start))?;
+
+    let mut hash = FileHasher::for_len(algorithm, left);
+    let mut chunks = chunk_size.map(|s| ChunkHasher::new(algorithm, s));
+    let read = read_into(&mut file, buf, &mut hash, &mut chunks, left, throttle)?;
+
+    cache::done(&file, cache_mode, start + read);
+    Ok(((hash.finish(), chunks.map(ChunkHasher::finish)), read))
+}
+
+/// The read buffer size for `len` bytes, bigger for bigger files
+fn auto_buf_size(len: u64) -> usize {
+    usize::try_from(len)
+        .unwrap_or(MAX_AUTO_BUF)
+        .clamp(MIN_AUTO_BUF, MAX_AUTO_BUF)
+        .next_multiple_of(cache::BUF_ALIGN)
+}
+
+/// Feeds up to `limit` bytes of `file` from where it is to the hashers, returning how many there
+/// were before the end of the file
+fn read_into(
+    file: &mut impl Read,
+    buf: &mut [u8],
+    hash: &mut FileHasher,
+    chunks: &mut Option<ChunkHasher>,
+    mut limit: u64,
+    throttle: Option<&Throttle>,
+) -> io::Result<u64> {
+    let mut read = 0;
+    while limit > 0 {
+        // Whole buffers are read for direct reads, they're only aligned to a block when the file ends
+        let n = match file.read(buf) {
+            Ok(0) => break,
+            Ok(n) => (n as u64).min(limit) as usize,
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        if let Some(throttle) = throttle {
+            throttle.take(n as u64);
+        }
+        hash.write(&buf[..n]);
+        if let Some(chunks) = chunks.as_mut() {
+            chunks.write(&buf[..n]);
+        }
+        limit -= n as u64;
+        read += n as u64;
+    }
+    Ok(read)
+}
+
+/// Errors a network filesystem can give that may not happen again
+fn is_transient(e: &io::Error) -> bool {
+    matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut)
+}
+
+/// Calls `f` again after transient errors, up to `retries` times with a doubling delay. A call
+/// that needed retrying is counted in `retried`
+fn with_retries<T>(
+    retries: u32,
+    retried: &AtomicUsize,
+    mut f: impl FnMut() -> io::Result<T>,
+) -> io::Result<T> {
+    let mut attempt = 0;
+    loop {
+        match f() {
+            Err(e) if attempt < retries && is_transient(&e) => {
+                if attempt == 0 {
+                    retried.fetch_add(1, Ordering::Relaxed);
+                }
+                thread::sleep(RETRY_DELAY * 2u32.saturating_pow(attempt).min(RETRY_MAX_FACTOR));
+                attempt += 1;
+            }
+            res => return res,
+        }
+    }
+}
+
+/// Moving average of samples taken `dt` seconds apart, where a sample `window` seconds old counts
+/// for about a third as much as the latest
+fn ema(avg: Option<f64>, sample: f64, dt: f64, window: f64) -> f64 {
+    match avg {
+        Some(avg) => avg + (sample - avg) * (1.0 - (-dt / window).exp()),
+        None => sample,
+    }
+}
+
+/// How much bigger `now` is than `before`, as a fraction of `before`
+fn gain(before: f64, now: f64) -> f64 {
+    match before > 0.0 {
+        true => (now - before) / before,
+        false if now > 0.0 => f64::INFINITY,
+        false => 0.0,
+    }
+}
+
+/// Where the hill climbing is at. Were an undone change followed by more the way it was undone, the
+/// speed the undo regained would keep the pool going past its fastest size and back again
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Climb {
+    Climbing,
+    /// The last change undid the one before, its effect is yet to show
+    Undone,
+    /// The pool's size is left alone until hashing speeds up or slows down by the threshold of its
+    /// own accord, when climbing starts over
+    Settled,
+}
+
+/// The hill climbing the pool is scaled by, fed how fast hashing has been going
+struct Scaler {
+    autoscale: Autoscale,
+    /// Files and bytes a second, averaged over about half the interval so each decision mostly
+    /// sees the pool as it's been since the last change
+    files_avg: Option<f64>,
+    speed_avg: Option<f64>,
+    /// The averages when the thread count was last changed, and which way it went
+    baseline: Option<(f64, f64)>,
+    scaling_up: bool,
+    climb: Climb,
+    /// Whether the change [`Scaler::sample`] last asked for undoes the one before
+    undoing: bool,
+    /// Seconds since the thread count was last changed
+    since_change: f64,
+}
+
+impl Scaler {
+    fn new(autoscale: Autoscale) -> Self {
+        Self {
+            autoscale,
+            files_avg: None,
+            speed_avg: None,
+            baseline: None,
+            scaling_up: true,
+            climb: Climb::Climbing,
+            undoing: false,
+            since_change: 0.0,
+        }
+    }
+
+    /// Takes in the files and bytes a second hashed over the last `dt` seconds, returning whether
+    /// to add a thread or remove one, and the gain since the last change that was based on. A
+    /// change that sped hashing up is followed by another the same way, one that slowed it down
+    /// is undone, and otherwise the pool is left as it is. After an undo it settles, see
+    /// [`Climb::Settled`]
+    fn sample(&mut self, files: f64, speed: f64, dt: f64) -> Option<(bool, Option<f64>)> {
+        let window = self.autoscale.interval.as_secs_f64() / 2.0;
+        let files = ema(self.files_avg, files, dt, window);
+        let speed = ema(self.speed_avg, speed, dt, window);
+        self.files_avg = Some(files);
+        self.speed_avg = Some(speed);
+        self.since_change += dt;
+
+        if self.since_change < self.autoscale.interval.as_secs_f64() {
+            return None;
+        }
+
+        let gains = self.baseline.map(|(f, s)| (gain(f, files), gain(s, speed)));
+        let change_gain = gains.map(|(f, s)| f.max(s));
+        let threshold = self.autoscale.threshold;
+        self.undoing = false;
+        let up = match (self.climb, change_gain) {
+            (_, None) => true,
+            (Climb::Undone, Some(_)) => {
+                self.climb = Climb::Settled;
+                self.baseline = Some((files, speed));
+                return None;
+            }
+            // A different mix of file sizes moves the two figures opposite ways, they only move
+            // together when hashing as a whole has sped up or slowed down
+            (Climb::Settled, Some(_)) => match gains {
+                Some((f, s)) if f.min(s) >= threshold || f.max(s) <= -threshold => true,
+                _ => return None,
+            },
+            (Climb::Climbing, Some(g)) if g >= threshold => self.scaling_up,
+            (Climb::Climbing, Some(g)) if g <= -threshold => {
+                self.undoing = true;
+                !self.scaling_up
+            }
+            (Climb::Climbing, Some(_)) => return None,
+        };
+        Some((up, change_gain))
+    }
+
+    /// The thread count was changed by `step`, as [`Scaler::sample`] asked for
+    fn changed(&mut self, step: i64) {
+        self.scaling_up = step > 0;
+        self.climb = match self.undoing {
+            true => Climb::Undone,
+            false => Climb::Climbing,
+        };
+        self.baseline = self.files_avg.zip(self.speed_avg);
+        self.since_change = 0.0;
+    }
 }
 
 pub fn hash_paths(
     parallel_hash: ParallelHash,
     send_hash: Sender<HashResult>,
-    term_rx: Receiver<()>,
+    term_sub: TermSubscription,
 ) {
     fn start_thread(
         thread_id: usize,
         thread_vars: &Arc<ThreadVars>,
         tx: &Sender<HashThreadMsg>,
         thread_speed: Arc<AtomicF32>,
+        state: Option<Arc<AtomicU8>>,
     ) -> JoinHandle<()> {
         thread::spawn({
             let thread_vars = Arc::clone(thread_vars);
             let tx = tx.clone();
             move || {
+                let state = state.as_deref();
                 let ThreadVars {
                     parallel_hash,
                     path_rx_done,
                     thread_halt,
+                    range_tx,
+                    range_rx,
                 } = &*thread_vars;
 
                 let ParallelHash {
                     path_rx,
                     err_handle,
                     fd_sem,
+                    algorithm,
+                    quick,
+                    chunk_size,
+                    min_threads,
+                    #[cfg(unix)]
+                    mmap_threshold,
+                    cache_mode,
+                    io_retries,
+                    retried,
+                    failed,
+                    buf_size,
+                    throttle,
+                    priority,
+                    include_empty,
+                    fd_weight_unit,
+                    hardlinks,
+                    ..
                 } = parallel_hash;
 
-                let mut buf = [0u8; 64 * 1024];
+                priority.apply(err_handle);
+
+                // Reports a file that couldn't be hashed. The thread goes on to the next one either
+                // way, it's never lost to the pool, and only stops with the rest once an error has
+                // ended the run
+                let fail = |err: TermError| match failed {
+                    Some(failed) => {
+                        err_handle.warn(err.to_string());
+                        failed.lock().unwrap().push(err);
+                    }
+                    None => err_handle.term_err(err),
+                };
+                // Anything else that's been swapped in for a file since it was found, like a FIFO
+                // or a device, could block or never end being read
+                let not_regular = |path: &Path| {
+                    err_handle.warn(format!(
+                        "Skipping {}, it's no longer a regular file",
+                        path.display()
+                    ));
+                };
+                let permanent = thread_id < *min_threads as usize;
+
+                // On the heap to be aligned for direct reads
+                // Reused for every file. Sized for the biggest it needs to be, files only use as much
+                // as their size calls for
+                let buf_size = *buf_size;
+                let max_buf = buf_size.unwrap_or(MAX_AUTO_BUF);
+                let mut buf = vec![0u8; max_buf + cache::BUF_ALIGN];
+                let buf = cache::aligned(&mut buf, max_buf);
+                let buf_for = |len: u64| buf_size.unwrap_or_else(|| auto_buf_size(len));
 
                 'thread_loop: loop {
-                    if thread_id != 0 {
+                    if TERMINATE.get() {
+                        break;
+                    }
+
+                    // Split files' ranges aren't left queued for threads that may have stopped
+                    if !permanent && range_rx.is_empty() {
                         let mut to_halt = thread_halt.load(Ordering::Acquire);
 
                         loop {
@@ -88,130 +690,442 @@ pub fn hash_paths(
                         }
                     }
 
-                    let file_path = match path_rx.try_recv() {
-                        Ok(f) => f,
-                        Err(TryRecvError::Disconnected) => {
-                            path_rx_done.store(true, Ordering::Release);
-                            break;
-                        }
-                        Err(TryRecvError::Empty) => {
-                            if thread_id != 0 {
+                    let task = match range_rx.try_recv() {
+                        Ok(task) => Task::Range(task),
+                        Err(_) => match path_rx.try_recv() {
+                            Ok(f) => Task::Path(f),
+                            Err(TryRecvError::Disconnected) => {
+                                path_rx_done.store(true, Ordering::Release);
                                 break;
                             }
-                            let old_speed = thread_speed.swap(-2.0, Ordering::Release);
-                            let path = match path_rx.recv() {
-                                Ok(f) => f,
-                                Err(_) => {
-                                    path_rx_done.store(true, Ordering::Release);
+                            Err(TryRecvError::Empty) => {
+                                if !permanent {
                                     break;
                                 }
+                                let old_speed = thread_speed.swap(-2.0, Ordering::Release);
+                                publish(state, ThreadState::WaitingForPath);
+                                let task = match Selector::new()
+                                    .recv(range_rx, |t| t.ok().map(Task::Range))
+                                    .recv(path_rx, |f| f.ok().map(Task::Path))
+                                    .wait()
+                                {
+                                    Some(t) => t,
+                                    // Ranges might have been queued too, which come first
+                                    None => continue,
+                                };
+                                publish(state, ThreadState::Hashing);
+                                thread_speed.store(old_speed, Ordering::Release);
+                                task
+                            }
+                        },
+                    };
+
+                    let file_path = match task {
+                        Task::Path(f) => f,
+                        Task::Range(task) => {
+                            let split = algorithm.split().unwrap();
+                            let range = split.min(task.file.len - task.index as u64 * split);
+                            let permits = fd_permits(range, *fd_weight_unit, fd_sem.max());
+                            let Some(guard) = fd_access(fd_sem, &thread_speed, state, permits)
+                            else {
+                                break;
                             };
-                            thread_speed.store(old_speed, Ordering::Release);
-                            path
+                            let before = Instant::now();
+                            let hashes = match with_retries(*io_retries, retried, || {
+                                let buf = &mut buf[..buf_for(range)];
+                                hash_range(
+                                    &task,
+                                    *algorithm,
+                                    *chunk_size,
+                                    *cache_mode,
+                                    buf,
+                                    throttle.as_deref(),
+                                )
+                            }) {
+                                Ok((hashes, read)) => {
+                                    let speed = read as f32
+                                        / Instant::now().duration_since(before).as_secs_f32();
+                                    thread_speed.store(speed, Ordering::Release);
+                                    hashes
+                                }
+                                Err(e) => {
+                                    let err = TermError::new(
+                                        format!(
+                                            "Error reading from file for hashing {}",
+                                            task.file.path.display()
+                                        ),
+                                        e,
+                                    );
+                                    fail(err);
+                                    continue 'thread_loop;
+                                }
+                            };
+                            drop(guard);
+
+                            if let Some(result) =
+                                task.file.finish_range(task.index, hashes, *algorithm)
+                            {
+                                if let (Some(hardlinks), Some((id, links))) =
+                                    (hardlinks, task.file.link)
+                                {
+                                    hardlinks.insert(id, links, result.clone());
+                                }
+                                if tx.send(HashThreadMsg::Hash(result)).is_err() {
+                                    break;
+                                }
+                            }
+                            continue;
                         }
                     };
 
-                    let (hash, before, file_size) = {
-                        let _guard = match fd_sem.try_access() {
-                            Some(g) => g,
-                            None => {
-                                let old_speed = thread_speed.swap(-2.0, Ordering::Release);
-                                let guard = fd_sem.access();
-                                thread_speed.store(old_speed, Ordering::Release);
-                                guard
-                            }
+                    let metadata = match fs::metadata(&file_path) {
+                        Ok(m) => m,
+                        Err(e) => {
+                            let err = TermError::new(
+                                format!(
+                                    "Error reading metadata of file for hashing {}",
+                                    file_path.display()
+                                ),
+                                e,
+                            );
+                            fail(err);
+                            continue 'thread_loop;
+                        }
+                    };
+                    if !metadata.is_file() {
+                        not_regular(&file_path);
+                        continue;
+                    }
+                    // Nothing to read, so not worth a file descriptor
+                    if metadata.len() == 0 {
+                        if !include_empty {
+                            continue;
+                        }
+                        let result = HashResult {
+                            path: file_path,
+                            hash: FileHasher::new(*algorithm).finish(),
+                            len: 0,
+                            mtime: data_fmt::file_mtime(&metadata),
+                            chunks: chunk_size.map(|s| ChunkHasher::new(*algorithm, s).finish()),
+                            hashed_at: data_fmt::hashed_now(),
                         };
+                        if tx.send(HashThreadMsg::Hash(result)).is_err() {
+                            break;
+                        }
+                        continue;
+                    }
 
-                        let before = Instant::now();
-                        let mut hash = XxHash64::default();
-                        let mut file_size = 0;
+                    let (hashed, chunks, len, mtime, speed) = {
+                        let permits = fd_permits(metadata.len(), *fd_weight_unit, fd_sem.max());
+                        let Some(_guard) = fd_access(fd_sem, &thread_speed, state, permits) else {
+                            break;
+                        };
 
-                        let mut file = match File::open(&file_path) {
+                        let file = match cache::open(&file_path, *cache_mode) {
                             Ok(f) => f,
                             Err(e) => {
-                                err_handle.term_err(format!(
-                                    "Error opening file for hashing {}: {}",
-                                    file_path.display(),
-                                    e
-                                ));
-                                break;
+                                let err = TermError::new(
+                                    format!(
+                                        "Error opening file for hashing {}",
+                                        file_path.display()
+                                    ),
+                                    e,
+                                );
+                                fail(err);
+                                continue 'thread_loop;
                             }
                         };
 
-                        loop {
-                            match file.read(&mut buf) {
-                                Ok(0) => break,
-                                Ok(n) => {
-                                    hash.write(&buf[..n]);
-                                    file_size += n;
+                        let metadata = match file.metadata() {
+                            Ok(m) => m,
+                            Err(e) => {
+                                let err = TermError::new(
+                                    format!(
+                                        "Error reading metadata of file for hashing {}",
+                                        file_path.display()
+                                    ),
+                                    e,
+                                );
+                                fail(err);
+                                continue 'thread_loop;
+                            }
+                        };
+                        if !metadata.is_file() {
+                            not_regular(&file_path);
+                            continue;
+                        }
+                        let len = metadata.len();
+                        if len == 0 && !include_empty {
+                            continue;
+                        }
+                        let mtime = data_fmt::file_mtime(&metadata);
+                        let link = hardlinks
+                            .as_ref()
+                            .and_then(|_| hardlink::linked_id(&file, &metadata));
+
+                        if let Some(known) = quick
+                            .as_ref()
+                            .and_then(|q| q.get(&file_path))
+                            .filter(|k| k.metadata_matches(len, mtime))
+                            // Rehashed for chunk hashes it doesn't have
+                            .filter(|k| match chunk_size {
+                                Some(size) => k.chunks.as_ref().is_some_and(|c| c.size == *size),
+                                None => true,
+                            })
+                        {
+                            let chunks = chunk_size.and(known.chunks.clone());
+                            (known.hash, chunks, len, mtime, None)
+                        } else if let Some(known) = hardlinks
+                            .as_ref()
+                            .zip(link)
+                            .and_then(|(h, (id, _))| h.get(id, len, mtime))
+                        {
+                            (known.hash, known.chunks, len, mtime, None)
+                        } else if let Some(split) = algorithm.split().filter(|s| len > *s) {
+                            // Queued for any thread to take, including this one
+                            let ranges = len.div_ceil(split) as usize;
+                            let file = Arc::new(SplitFile {
+                                path: file_path,
+                                len,
+                                mtime,
+                                ranges: Mutex::new(vec![None; ranges]),
+                                remaining: AtomicUsize::new(ranges),
+                                link,
+                            });
+                            for index in 0..ranges {
+                                let file = Arc::clone(&file);
+                                // Never disconnected, the receiver is in the thread vars too
+                                let _ = range_tx.send(RangeTask { file, index });
+                            }
+                            continue;
+                        } else {
+                            let before = Instant::now();
+                            // What was read before an error can't be trusted, a retry starts over
+                            // with the file reopened
+                            let mut opened = Some(file);
+                            let hashed = with_retries(*io_retries, retried, || {
+                                let mut file = match opened.take() {
+                                    Some(f) => f,
+                                    None => cache::open(&file_path, *cache_mode)?,
+                                };
+                                let mut hash = FileHasher::for_len(*algorithm, len);
+                                let mut chunks =
+                                    chunk_size.map(|s| ChunkHasher::new(*algorithm, s));
+                                let mut file_size = 0;
+
+                                // Mapped files go through the cache
+                                #[cfg(unix)]
+                                if mmap_threshold.is_some_and(|t| len >= t)
+                                    && *cache_mode != CacheMode::Direct
+                                {
+                                    file_size = mmap::read_mapped(&file, len, |bytes| {
+                                        if let Some(throttle) = throttle {
+                                            throttle.take(bytes.len() as u64);
+                                        }
+                                        hash.write(bytes);
+                                        if let Some(chunks) = chunks.as_mut() {
+                                            chunks.write(bytes);
+                                        }
+                                    });
+                                    // The read loop picks up from where the mapping ended, for a
+                                    // file that changed size since it was mapped
+                                    file.seek(SeekFrom::Start(file_size))?;
                                 }
+
+                                let buf = &mut buf[..buf_for(len)];
+                                file_size += read_into(
+                                    &mut file,
+                                    buf,
+                                    &mut hash,
+                                    &mut chunks,
+                                    u64::MAX,
+                                    throttle.as_deref(),
+                                )?;
+                                cache::done(&file, *cache_mode, file_size);
+                                Ok((hash.finish(), chunks.map(ChunkHasher::finish), file_size))
+                            });
+
+                            let (hashed, chunks, file_size) = match hashed {
+                                Ok(h) => h,
                                 Err(e) => {
-                                    err_handle.term_err(format!(
-                                        "Error reading from file for hashing {}: {}",
-                                        file_path.display(),
-                                        e
-                                    ));
-                                    break 'thread_loop;
+                                    let err = TermError::new(
+                                        format!(
+                                            "Error reading from file for hashing {}",
+                                            file_path.display()
+                                        ),
+                                        e,
+                                    );
+                                    fail(err);
+                                    continue 'thread_loop;
+                                }
+                            };
+
+                            // Not if it changed size while being read, as its result is then for
+                            // a file that doesn't exist any more
+                            if let (Some(hardlinks), Some((id, links))) = (hardlinks, link) {
+                                if file_size == len {
+                                    let result = HashResult {
+                                        path: file_path.clone(),
+                                        hash: hashed,
+                                        len,
+                                        mtime,
+                                        chunks: chunks.clone(),
+                                        hashed_at: data_fmt::hashed_now(),
+                                    };
+                                    hardlinks.insert(id, links, result);
                                 }
                             }
-                        }
 
-                        (hash, before, file_size)
+                            let speed = file_size as f32
+                                / Instant::now().duration_since(before).as_secs_f32();
+                            (hashed, chunks, len, mtime, Some(speed))
+                        }
                     };
 
-                    let hashed = hash.finish();
-                    let speed =
-                        file_size as f32 / Instant::now().duration_since(before).as_secs_f32();
-
-                    thread_speed.store(speed, Ordering::Release);
+                    // Reused hashes say nothing about how fast this thread reads
+                    if let Some(speed) = speed {
+                        thread_speed.store(speed, Ordering::Release);
+                    }
 
-                    if tx
-                        .send(HashThreadMsg::Hash(HashResult(file_path, hashed)))
-                        .is_err()
-                    {
+                    let result = HashResult {
+                        path: file_path,
+                        hash: hashed,
+                        len,
+                        mtime,
+                        chunks,
+                        hashed_at: data_fmt::hashed_now(),
+                    };
+                    if tx.send(HashThreadMsg::Hash(result)).is_err() {
                         break;
                     }
                 }
 
+                publish(state, ThreadState::Halted);
                 let _ = tx.send(HashThreadMsg::Halted(thread_id));
             }
         })
     }
 
+    let (range_tx, range_rx) = flume::unbounded();
     let thread_vars = Arc::new(ThreadVars {
         parallel_hash,
         path_rx_done: AtomicBool::new(false),
         thread_halt: AtomicU32::new(0),
+        range_tx,
+        range_rx,
     });
 
     let ThreadVars {
         parallel_hash,
         path_rx_done,
         thread_halt,
+        range_rx,
         ..
     } = &*thread_vars;
-    let ParallelHash { fd_sem, .. } = &parallel_hash;
+    let ParallelHash {
+        fd_sem,
+        min_threads,
+        max_threads,
+        progress,
+        throttle,
+        autoscale,
+        path_rx,
+        debug,
+        thread_log,
+        ..
+    } = &parallel_hash;
 
-    let (tx, rx) = flume::unbounded();
+    // Bounded like the results, so they back up to the hashing threads rather than pile up here
+    let (tx, rx) = match send_hash.capacity() {
+        Some(capacity) => flume::bounded(capacity),
+        None => flume::unbounded(),
+    };
 
     let mut time = Instant::now();
     let mut thread_speeds = HashMap::new();
+    // Only kept for --debug-scheduler
+    let mut thread_states = HashMap::new();
+    let new_state = |thread_states: &mut HashMap<_, _>, thread_id: usize| {
+        debug.as_ref().map(|_| {
+            let state = Arc::new(AtomicU8::new(ThreadState::Hashing as u8));
+            thread_states.insert(thread_id, Arc::clone(&state));
+            state
+        })
+    };
+
+    for thread_id in 0..*min_threads as usize {
+        let thread_speed = Arc::new(AtomicF32::new(-1.0));
+        thread_speeds.insert(thread_id, Arc::clone(&thread_speed));
+        start_thread(
+            thread_id,
+            &thread_vars,
+            &tx,
+            thread_speed,
+            new_state(&mut thread_states, thread_id),
+        );
+    }
 
-    let thread_speed = Arc::new(AtomicF32::new(-1.0));
-    thread_speeds.insert(0, Arc::clone(&thread_speed));
-    start_thread(0, &thread_vars, &tx, thread_speed);
+    if let Some(progress) = progress {
+        progress.threads_started(*min_threads);
+    }
 
-    let mut next_thread_id = 1;
-    let mut thread_count = 1;
+    let mut next_thread_id = *min_threads as usize;
+    let mut thread_count = *min_threads;
+    if let Some(thread_log) = thread_log {
+        thread_log.record(thread_count);
+    }
 
-    let mut last_num_per_sec: f64 = -1.0;
-    let mut last_speed: f32 = -1.0;
+    let mut scaler = Scaler::new(*autoscale);
+    let mut last_waited = Duration::ZERO;
     let mut thread_change: i64 = 0;
+    let mut last_decision: Option<String> = None;
+    let mut unsent = None;
+    let start = Instant::now();
+    let mut next_debug = start;
     'main_loop: loop {
         if TERMINATE.get() {
             break;
         }
 
+        if let Some(debug) = debug.as_ref().filter(|_| Instant::now() >= next_debug) {
+            let mut ids: Vec<_> = thread_states.keys().copied().collect();
+            ids.sort_unstable();
+            let mut counts = [0; ThreadState::ALL.len()];
+            let states: Vec<_> = ids
+                .iter()
+                .map(|id| {
+                    let state =
+                        ThreadState::ALL[thread_states[id].load(Ordering::Relaxed) as usize];
+                    counts[state as usize] += 1;
+                    format!("{}:{}", id, state.name())
+                })
+                .collect();
+            let counts: Vec<_> = ThreadState::ALL
+                .iter()
+                .map(|s| format!("{}={}", s.name(), counts[*s as usize]))
+                .collect();
+
+            debug.sink.line(
+                progress.as_deref(),
+                &format!(
+                    "scheduler t={:.1} pool={} threads={} {} states={} thread_halt={} thread_change={} paths_queued={} ranges_queued={} results_queued={} fd_permits={} last_decision=\"{}\"",
+                    start.elapsed().as_secs_f64(),
+                    debug.pool,
+                    thread_count,
+                    counts.join(" "),
+                    states.join(","),
+                    thread_halt.load(Ordering::Acquire),
+                    thread_change,
+                    path_rx.len(),
+                    range_rx.len(),
+                    rx.len(),
+                    fd_sem.count(),
+                    last_decision.as_deref().unwrap_or("none"),
+                ),
+            );
+            next_debug = Instant::now() + DEBUG_INTERVAL;
+        }
+
         let msg = match rx.try_recv() {
             Ok(msg) => msg,
             Err(TryRecvError::Disconnected) => break,
@@ -245,26 +1159,43 @@ pub fn hash_paths(
                                 let thread_id = next_thread_id + i;
                                 let thread_speed = Arc::new(AtomicF32::new(-1.0));
                                 thread_speeds.insert(thread_id, thread_speed.clone());
-                                start_thread(thread_id, &thread_vars, &tx, thread_speed);
+                                start_thread(
+                                    thread_id,
+                                    &thread_vars,
+                                    &tx,
+                                    thread_speed,
+                                    new_state(&mut thread_states, thread_id),
+                                );
+                            }
+
+                            if let Some(progress) = progress {
+                                progress.threads_started(tc as u32);
                             }
 
                             next_thread_id += tc as usize;
                             thread_count += tc as u32;
                             thread_change -= tc;
+                            if let Some(thread_log) = thread_log {
+                                thread_log.record(thread_count);
+                            }
                         } else {
                             thread_change = 0;
                         }
                     }
                 }
 
-                match Selector::new()
-                    .recv(&rx, |msg| match msg {
-                        Ok(msg) => Some(msg),
-                        Err(_) => None,
-                    })
-                    .recv(&term_rx, |_| None)
-                    .wait()
-                {
+                let selector = Selector::new()
+                    .recv(&rx, |msg| msg.ok())
+                    .recv(term_sub.rx(), |_| None);
+                // Woken for the next debug line even if nothing's happened
+                let msg = match debug {
+                    Some(_) => match selector.wait_deadline(next_debug) {
+                        Ok(msg) => msg,
+                        Err(_) => continue,
+                    },
+                    None => selector.wait(),
+                };
+                match msg {
                     Some(msg) => msg,
                     None => break,
                 }
@@ -276,26 +1207,60 @@ pub fn hash_paths(
             match msg {
                 HashThreadMsg::Halted(thread_id) => {
                     thread_speeds.remove(&thread_id);
+                    thread_states.remove(&thread_id);
                     thread_count -= 1;
+                    if let Some(thread_log) = thread_log {
+                        thread_log.record(thread_count);
+                    }
+                    if let Some(progress) = progress {
+                        progress.thread_stopped();
+                    }
                     if thread_count == 0 {
                         break 'main_loop;
                     }
+                    // Threads that stopped for lack of paths leave their halts unclaimed, which
+                    // only the threads above the minimum can claim
+                    thread_halt
+                        .fetch_min(thread_count.saturating_sub(*min_threads), Ordering::AcqRel);
                 }
                 HashThreadMsg::Hash(res) => {
-                    if send_hash.send(res).is_err() {
-                        break 'main_loop;
+                    if let Some(progress) = progress {
+                        progress.hashed(res.len);
+                    }
+                    // Waits while the results are behind, unless terminating. It's kept to pass on
+                    // with the rest then
+                    let res = match send_hash.try_send(res) {
+                        Ok(()) => None,
+                        Err(TrySendError::Full(res)) => Some(res),
+                        Err(TrySendError::Disconnected(_)) => break 'main_loop,
+                    };
+                    if let Some(res) = res {
+                        let sent = Selector::new()
+                            .send(&send_hash, res.clone(), |r| r.is_ok())
+                            .recv(term_sub.rx(), |_| false)
+                            .wait();
+                        if !sent {
+                            unsent = Some(res);
+                            break 'main_loop;
+                        }
                     }
                     processed_num += 1;
                 }
             }
         }
 
-        if path_rx_done.load(Ordering::Acquire) {
+        if let Some(progress) = progress {
+            progress.tick();
+        }
+
+        // Split files' ranges can still be spread over more threads
+        if path_rx_done.load(Ordering::Acquire) && range_rx.is_empty() {
             thread_speeds.clear();
             continue;
         }
 
-        let num_per_sec = processed_num as f64 / Instant::now().duration_since(time).as_secs_f64();
+        let dt = time.elapsed().as_secs_f64();
+        let num_per_sec = processed_num as f64 / dt;
 
         let mut no_speed = 0;
         let mut total_speed = 0.0;
@@ -319,18 +1284,634 @@ pub fn hash_paths(
             total_speed /= 1.0 - perc_no_speed;
         }
 
-        if last_num_per_sec >= 0.0 && last_speed >= 0.0 && total_speed <= last_speed {
-            if num_per_sec < last_num_per_sec {
-                if thread_count > 1 {
-                    thread_change -= 1;
+        // Reads being held back by the throttle, more threads would only wait on it too
+        let waited = throttle.as_ref().map_or(Duration::ZERO, |t| t.waited());
+        let throttled = waited > last_waited;
+        last_waited = waited;
+
+        let decision = scaler.sample(num_per_sec, total_speed as f64, dt);
+        time = Instant::now();
+        let Some((up, change_gain)) = decision else {
+            continue;
+        };
+
+        // What the pool will be once the pending changes and halts are applied
+        let target =
+            thread_count as i64 - thread_halt.load(Ordering::Acquire) as i64 + thread_change;
+        let step = match up {
+            true if target < *max_threads as i64 && fd_sem.count() > 0 && !throttled => 1,
+            false if target > *min_threads as i64 => -1,
+            _ => 0,
+        };
+        if step == 0 {
+            continue;
+        }
+
+        if autoscale.log || debug.is_some() {
+            let decision = format!(
+                "{} -> {} threads, {:.1} files/s, {}/s, {}",
+                target,
+                target + step,
+                scaler.files_avg.unwrap_or_default(),
+                progress::fmt_bytes(scaler.speed_avg.unwrap_or_default()),
+                match change_gain {
+                    Some(g) => format!("{:+.1}% since the last change", g * 100.0),
+                    None => "first change".to_string(),
                 }
-            } else if fd_sem.count() > 0 {
-                thread_change += 1;
+            );
+            if autoscale.log {
+                let _hidden = progress.as_ref().map(|p| p.hide());
+                eprintln!("Autoscale: {}", decision);
             }
+            last_decision = Some(decision);
         }
 
-        last_num_per_sec = num_per_sec;
-        last_speed = total_speed;
-        time = Instant::now();
+        thread_change += step;
+        scaler.changed(step);
+    }
+
+    if TERMINATE.get() {
+        fd_sem.wake_all();
+
+        // Results already hashed still reach the data out file, hashing in progress isn't waited
+        // for
+        let deadline = Instant::now() + DRAIN_TIMEOUT;
+        let finished = rx.try_iter().filter_map(|msg| match msg {
+            HashThreadMsg::Hash(res) => Some(res),
+            HashThreadMsg::Halted(_) => None,
+        });
+        for res in unsent.into_iter().chain(finished) {
+            if send_hash.send_deadline(res, deadline).is_err() {
+                break;
+            }
+        }
+    }
+}
+
+/// Takes the results passed on after termination, which the pools had already hashed. Hashing in
+/// progress isn't waited for, and nothing is after [`DRAIN_TIMEOUT`]
+pub fn drain(rx: &Receiver<HashResult>) -> Vec<HashResult> {
+    let deadline = Instant::now() + DRAIN_TIMEOUT;
+    iter::from_fn(|| rx.recv_deadline(deadline).ok()).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::Cell, process};
+
+    use super::*;
+    use crate::data_fmt::{DataErr, Record, XxhDiffData};
+
+    /// Writes `bytes` to a file in the temp dir, for hashing as it would be from disk
+    fn fixture(name: &str, bytes: &[u8]) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("xxh-diff-{}-{}", process::id(), name));
+        fs::write(&path, bytes).unwrap();
+        path
+    }
+
+    fn hash_file(path: &Path, mut hash: FileHasher) -> String {
+        let mut file = File::open(path).unwrap();
+        let mut buf = vec![0; MIN_AUTO_BUF];
+        read_into(&mut file, &mut buf, &mut hash, &mut None, u64::MAX, None).unwrap();
+        hash.finish().to_string()
+    }
+
+    /// Gives `data` in reads of at most `max_read` bytes, failing with `kind` once past `fail_at`
+    /// while `failures` is above 0, as a network filesystem might mid-file
+    struct Flaky<'a> {
+        data: &'a [u8],
+        pos: usize,
+        max_read: usize,
+        fail_at: usize,
+        kind: ErrorKind,
+        failures: &'a Cell<u32>,
+    }
+
+    impl Read for Flaky<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if self.pos >= self.fail_at && self.failures.get() > 0 {
+                self.failures.set(self.failures.get() - 1);
+                return Err(self.kind.into());
+            }
+            let n = buf.len().min(self.max_read).min(self.data.len() - self.pos);
+            buf[..n].copy_from_slice(&self.data[self.pos..self.pos + n]);
+            self.pos += n;
+            Ok(n)
+        }
+    }
+
+    /// Hashes `data` like a worker does, reopening it for every attempt, through a reader failing
+    /// `failures` times with `kind` partway in. Returns the result and how many attempts it took
+    fn hash_flaky(
+        data: &[u8],
+        kind: ErrorKind,
+        failures: u32,
+        retries: u32,
+        retried: &AtomicUsize,
+    ) -> (io::Result<String>, u32) {
+        let failures = Cell::new(failures);
+        let mut attempts = 0;
+        let res = with_retries(retries, retried, || {
+            attempts += 1;
+            let mut file = Flaky {
+                data,
+                pos: 0,
+                max_read: 7,
+                fail_at: data.len() / 2,
+                kind,
+                failures: &failures,
+            };
+            let mut hash = FileHasher::for_len(HashAlgorithm::default(), data.len() as u64);
+            let mut buf = vec![0; MIN_AUTO_BUF];
+            read_into(&mut file, &mut buf, &mut hash, &mut None, u64::MAX, None)?;
+            Ok(hash.finish().to_string())
+        });
+        (res, attempts)
+    }
+
+    #[test]
+    fn transient_errors_retried() {
+        let data: Vec<u8> = (0..=255).collect();
+        let path = fixture("flaky", &data);
+        let expected = hash_file(&path, FileHasher::for_len(HashAlgorithm::default(), 256));
+        fs::remove_file(path).unwrap();
+
+        // What was hashed before the error doesn't end up in the hash
+        let retried = AtomicUsize::new(0);
+        let (res, attempts) = hash_flaky(&data, ErrorKind::TimedOut, 2, 3, &retried);
+        assert_eq!(res.unwrap(), expected);
+        assert_eq!(attempts, 3);
+        assert_eq!(retried.load(Ordering::Relaxed), 1);
+
+        let (res, attempts) = hash_flaky(&data, ErrorKind::WouldBlock, 1, 3, &retried);
+        assert_eq!(res.unwrap(), expected);
+        assert_eq!(attempts, 2);
+        assert_eq!(retried.load(Ordering::Relaxed), 2);
+
+        // Interruptions are retried by the read loop itself, without starting over
+        let (res, attempts) = hash_flaky(&data, ErrorKind::Interrupted, 5, 0, &retried);
+        assert_eq!(res.unwrap(), expected);
+        assert_eq!(attempts, 1);
+        assert_eq!(retried.load(Ordering::Relaxed), 2);
+    }
+
+    #[test]
+    fn retries_give_up() {
+        let data = [1; 64];
+        let retried = AtomicUsize::new(0);
+
+        let (res, attempts) = hash_flaky(&data, ErrorKind::TimedOut, 3, 2, &retried);
+        assert_eq!(res.unwrap_err().kind(), ErrorKind::TimedOut);
+        assert_eq!(attempts, 3);
+        assert_eq!(retried.load(Ordering::Relaxed), 1);
+
+        // Errors that will happen again aren't retried at all
+        let (res, attempts) = hash_flaky(&data, ErrorKind::PermissionDenied, 1, 2, &retried);
+        assert_eq!(res.unwrap_err().kind(), ErrorKind::PermissionDenied);
+        assert_eq!(attempts, 1);
+        assert_eq!(retried.load(Ordering::Relaxed), 1);
+    }
+
+    /// How fast a pool of `threads` hashes at `t` seconds in, on a disk where each thread adds
+    /// `per_thread` until there are `best`, after which each one more slows it down. Bursts of
+    /// tiny files alternate with big files every tenth of a second, swinging both figures far more
+    /// than a thread more or less changes them
+    fn simulated_speed(threads: i64, t: f64, best: i64, per_thread: f64) -> (f64, f64) {
+        let capacity = match threads {
+            t if t <= best => t as f64 * per_thread,
+            t => (best as f64 - (t - best) as f64 * 0.8) * per_thread,
+        };
+        match (t * 10.0) as u64 % 2 {
+            0 => (capacity * 3.0, capacity * 0.7e6),
+            _ => (capacity * 0.2, capacity * 1.3e6),
+        }
+    }
+
+    /// Runs [`Scaler`] with the pool between 1 and 16 threads, hashing at [`simulated_speed`] with
+    /// the disk changing to be fastest with 10 threads half way through. Returns the thread
+    /// counts it changed to, and when
+    fn simulate(autoscale: Autoscale, secs: f64) -> Vec<(i64, f64)> {
+        let dt = 0.05;
+        let mut scaler = Scaler::new(autoscale);
+        let mut threads = 1;
+        let mut counts = Vec::new();
+        for tick in 0..(secs / dt) as u64 {
+            let t = tick as f64 * dt;
+            let (best, per_thread) = match t < secs / 2.0 {
+                true => (6, 100.0),
+                false => (10, 150.0),
+            };
+            let (files, speed) = simulated_speed(threads, t, best, per_thread);
+            let step = match scaler.sample(files, speed, dt) {
+                Some((true, _)) if threads < 16 => 1,
+                Some((false, _)) if threads > 1 => -1,
+                _ => continue,
+            };
+            threads += step;
+            scaler.changed(step);
+            counts.push((threads, t));
+        }
+        counts
+    }
+
+    /// The pool climbs straight to its fastest size, goes one past it and comes back, then stays
+    /// put through the bursts until the disk speeding up starts it climbing again
+    #[test]
+    fn autoscale_converges() {
+        let autoscale = Autoscale {
+            interval: Duration::from_secs(1),
+            threshold: 0.05,
+            log: false,
+        };
+        let changes = simulate(autoscale, 120.0);
+        let (before, after): (Vec<_>, Vec<_>) = changes.iter().partition(|(_, t)| *t < 60.0);
+        let counts =
+            |changes: Vec<&(i64, f64)>| changes.iter().map(|(c, _)| *c).collect::<Vec<_>>();
+        assert_eq!(counts(before), [2, 3, 4, 5, 6, 7, 6]);
+        assert_eq!(counts(after), [7, 8, 9, 10, 11, 10]);
+        // Then it stays settled for the rest of the run
+        assert!(changes.last().unwrap().1 < 80.0);
+    }
+
+    /// Known digests of `abc` and of nothing, which xxhsum, b3sum and sha256sum give too
+    #[test]
+    fn pinned_digests() {
+        let seed = 0;
+        let split = None;
+        let pinned = [
+            (
+                HashAlgorithm::Xxh64 { seed, split },
+                "44bc2cf5ad770999",
+                "ef46db3751d8e999",
+            ),
+            (
+                HashAlgorithm::Xxh3 { seed, split },
+                "78af5f94892f3950",
+                "2d06800538d394c2",
+            ),
+            (
+                HashAlgorithm::Xxh128 { seed, split },
+                "06b05ab6733a618578af5f94892f3950",
+                "99aa06d3014798d86001c324468d497f",
+            ),
+            (
+                HashAlgorithm::Blake3 { split },
+                "6437b3ac38465133ffb63b75273a8db548c558465d79db03fd359c6cd5bd9d85",
+                "af1349b9f5f9a1a6a0404dea36dcc9499bcb25c9adc112b7cc9a93cae41f3262",
+            ),
+            (
+                HashAlgorithm::Sha256 { split },
+                "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad",
+                "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855",
+            ),
+        ];
+
+        let abc = fixture("abc", b"abc");
+        let empty = fixture("empty", b"");
+        for (algorithm, abc_digest, empty_digest) in pinned {
+            assert_eq!(
+                hash_file(&abc, FileHasher::new(algorithm)),
+                abc_digest,
+                "{}",
+                algorithm
+            );
+            assert_eq!(
+                hash_file(&empty, FileHasher::new(algorithm)),
+                empty_digest,
+                "{}",
+                algorithm
+            );
+        }
+        fs::remove_file(abc).unwrap();
+        fs::remove_file(empty).unwrap();
+    }
+
+    /// Read in many buffers, the digests are the same as hashing the bytes in one go
+    #[test]
+    fn streamed_matches_one_shot() {
+        let bytes: Vec<u8> = (0..3 * 1024 * 1024 + 7).map(|i| (i % 251) as u8).collect();
+        let path = fixture("streamed", &bytes);
+        let seed = 7;
+        let split = None;
+
+        assert_eq!(
+            hash_file(&path, FileHasher::new(HashAlgorithm::Xxh3 { seed, split })),
+            format!(
+                "{:016x}",
+                xxhash_rust::xxh3::xxh3_64_with_seed(&bytes, seed)
+            )
+        );
+        assert_eq!(
+            hash_file(
+                &path,
+                FileHasher::new(HashAlgorithm::Xxh128 { seed, split })
+            ),
+            format!(
+                "{:032x}",
+                xxhash_rust::xxh3::xxh3_128_with_seed(&bytes, seed)
+            )
+        );
+        assert_eq!(
+            hash_file(&path, FileHasher::new(HashAlgorithm::Sha256 { split })),
+            format!("{:x}", Sha256::digest(&bytes))
+        );
+
+        let algorithm = HashAlgorithm::Blake3 { split };
+        let hasher = FileHasher::for_len(algorithm, BLAKE3_PARALLEL_MIN);
+        assert!(matches!(hasher, FileHasher::Blake3(_, true)));
+        assert!(matches!(
+            FileHasher::for_len(algorithm, BLAKE3_PARALLEL_MIN - 1),
+            FileHasher::Blake3(_, false)
+        ));
+        assert_eq!(
+            hash_file(&path, hasher),
+            blake3::hash(&bytes).to_hex().as_str()
+        );
+        fs::remove_file(path).unwrap();
+    }
+
+    /// One thread with nothing else, for a file it couldn't hash to be seen not to cost the pool it
+    fn single_thread_pool(
+        path_rx: Receiver<PathBuf>,
+        err_handle: ErrHandle,
+        failed: Arc<Mutex<Vec<TermError>>>,
+    ) -> ParallelHash {
+        ParallelHash {
+            path_rx,
+            err_handle,
+            fd_sem: Arc::new(Semaphore::new(4)),
+            algorithm: HashAlgorithm::default(),
+            quick: None,
+            chunk_size: None,
+            min_threads: 1,
+            max_threads: 1,
+            progress: None,
+            #[cfg(unix)]
+            mmap_threshold: None,
+            cache_mode: CacheMode::Normal,
+            io_retries: 0,
+            retried: Arc::default(),
+            failed: Some(failed),
+            buf_size: None,
+            throttle: None,
+            autoscale: Autoscale {
+                interval: Duration::from_secs(1),
+                threshold: 0.1,
+                log: false,
+            },
+            priority: Priority {
+                nice: None,
+                io: None,
+            },
+            include_empty: true,
+            fd_weight_unit: None,
+            debug: None,
+            hardlinks: None,
+            thread_log: None,
+        }
+    }
+
+    /// Results the pool had hashed when termination came, whether waiting to be sent or already
+    /// sent, are drained into the data file along with the ones written before it
+    #[test]
+    fn terminate_drains_finished_results() {
+        if !crate::tests::in_child("parallel_hash::tests::terminate_drains_finished_results") {
+            return;
+        }
+
+        let dir = std::env::temp_dir().join(format!("xxh-diff-{}-drain", process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir(&dir).unwrap();
+        let (path_tx, path_rx) = flume::unbounded();
+        for i in 0..100 {
+            let path = dir.join(format!("file{}", i));
+            fs::write(&path, format!("contents {}", i)).unwrap();
+            path_tx.send(path).unwrap();
+        }
+        drop(path_tx);
+
+        let handle = gracile::TermHandle::default();
+        // Bounded so finished results back up into the pool while the consumer is behind
+        let (hash_tx, hash_rx) = flume::bounded(1);
+        let pool = single_thread_pool(path_rx, handle.err_handle.clone(), Arc::default());
+        let pool = thread::spawn(move || hash_paths(pool, hash_tx, gracile::subscribe()));
+
+        let data_path = dir.join("out.xxhd");
+        let data = XxhDiffData::new(
+            &data_path,
+            false,
+            None,
+            HashAlgorithm::default(),
+            Duration::ZERO,
+        )
+        .unwrap();
+        let (_, mut writer) = data.split().unwrap();
+        let mut written = Vec::new();
+        for res in hash_rx.iter().take(3) {
+            writer.write(&[&res]).unwrap();
+            written.push(res.path);
+        }
+        // For the next results to be waiting in the channel and the pool
+        thread::sleep(Duration::from_millis(200));
+        assert!(hash_rx.is_full());
+
+        TERMINATE.set();
+        let drained = drain(&hash_rx);
+        writer.write(&drained.iter().collect::<Vec<_>>()).unwrap();
+        writer.close().unwrap();
+        pool.join().unwrap();
+        // The one in the channel, and the one the pool was waiting to send
+        assert!(drained.len() >= 2, "{} drained", drained.len());
+        written.extend(drained.into_iter().map(|r| r.path));
+        assert!(written.len() < 100);
+
+        let mut data = XxhDiffData::open(&data_path, Duration::ZERO).unwrap();
+        let mut recorded = Vec::new();
+        loop {
+            match data.read_skip_corrupt() {
+                Ok(Record::Hash(res)) => recorded.push(res.path),
+                Ok(Record::Deleted(_)) => panic!("Expected a hash"),
+                Err(e) if matches!(e.kind(), DataErr::Empty) => break,
+                Err(e) => panic!("{}", e),
+            }
+        }
+        assert_eq!(recorded, written);
+        data.close().unwrap();
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    /// Throughput of hashing one large file with the smallest and largest automatic read buffers.
+    /// Ignored as it's slow and only means something where the files being hashed are, run it with
+    /// `--release -- --ignored --nocapture` there. The file is read once first, so both sizes see
+    /// it cached and only the per read cost differs
+    #[test]
+    #[ignore]
+    fn buf_size_throughput() {
+        const LEN: usize = 512 * 1024 * 1024;
+        const ROUNDS: u32 = 3;
+
+        let path = std::env::temp_dir().join(format!("xxh-diff-{}-buf-size", process::id()));
+        let mut file = File::create(&path).unwrap();
+        let block: Vec<u8> = (0..MAX_AUTO_BUF).map(|i| (i % 251) as u8).collect();
+        for _ in 0..LEN / block.len() {
+            file.write_all(&block).unwrap();
+        }
+        drop(file);
+        let algorithm = HashAlgorithm::default();
+        let digest = hash_file(&path, FileHasher::new(algorithm));
+
+        for buf_size in [MIN_AUTO_BUF, MAX_AUTO_BUF] {
+            let mut buf = vec![0; buf_size];
+            let mut best = Duration::MAX;
+            for _ in 0..ROUNDS {
+                let mut file = File::open(&path).unwrap();
+                let mut hash = FileHasher::new(algorithm);
+                let start = Instant::now();
+                let read =
+                    read_into(&mut file, &mut buf, &mut hash, &mut None, u64::MAX, None).unwrap();
+                best = best.min(start.elapsed());
+                assert_eq!(read, LEN as u64);
+                assert_eq!(hash.finish().to_string(), digest);
+            }
+            println!(
+                "{} KiB buffer: {:?}, {}/s",
+                buf_size / 1024,
+                best,
+                progress::fmt_bytes(LEN as f64 / best.as_secs_f64())
+            );
+        }
+        fs::remove_file(path).unwrap();
+    }
+
+    /// The scheduler's own once a second samples, while paths are still coming, never see the
+    /// pool outside its bounds however eagerly the autoscaler probes
+    #[test]
+    fn autoscale_stays_in_bounds() {
+        const RUN: Duration = Duration::from_millis(3500);
+
+        let path = fixture("bounds", &[7; 64 * 1024]);
+        let debug_path = path.with_extension("debug");
+        let (path_tx, path_rx) = flume::bounded(64);
+        let feeder = thread::spawn({
+            let path = path.clone();
+            move || {
+                let start = Instant::now();
+                while start.elapsed() < RUN {
+                    path_tx.send(path.clone()).unwrap();
+                }
+            }
+        });
+
+        let handle = gracile::TermHandle::default();
+        let mut pool = single_thread_pool(path_rx, handle.err_handle.clone(), Arc::default());
+        pool.min_threads = 2;
+        pool.max_threads = 3;
+        pool.autoscale = Autoscale {
+            interval: Duration::from_millis(100),
+            threshold: 0.01,
+            log: false,
+        };
+        pool.debug = Some(SchedulerDebug {
+            pool: "bounds".to_string(),
+            sink: Arc::new(DebugSink::File(Mutex::new(
+                File::create(&debug_path).unwrap(),
+            ))),
+        });
+        let (hash_tx, hash_rx) = flume::bounded(64);
+        let pool = thread::spawn(move || hash_paths(pool, hash_tx, gracile::subscribe()));
+        assert!(hash_rx.iter().count() > 0);
+        pool.join().unwrap();
+        feeder.join().unwrap();
+
+        let lines = fs::read_to_string(&debug_path).unwrap();
+        let samples: Vec<_> = lines
+            .lines()
+            .map(|line| {
+                let field = |name: &str| {
+                    line.split(' ')
+                        .find_map(|f| f.strip_prefix(name))
+                        .unwrap_or_else(|| panic!("No {} in {:?}", name, line))
+                };
+                let threads: u32 = field("threads=").parse().unwrap();
+                let running = field("states=").split(',').count() as u32;
+                (threads, running)
+            })
+            .collect();
+        assert!(samples.len() >= 3, "{:?}", samples);
+        for (threads, running) in samples {
+            assert!((2..=3).contains(&threads), "{}", lines);
+            assert!((2..=3).contains(&running), "{}", lines);
+        }
+        fs::remove_file(path).unwrap();
+        fs::remove_file(debug_path).unwrap();
+    }
+
+    /// A file that can't be opened or no longer exists is reported, and the same thread goes on
+    /// to hash the rest of the directory
+    #[cfg(unix)]
+    #[test]
+    fn unreadable_file_doesnt_stop_the_thread() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = std::env::temp_dir().join(format!("xxh-diff-{}-unreadable", process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir(&dir).unwrap();
+        let mut paths: Vec<_> = (0..5)
+            .map(|i| {
+                let path = dir.join(format!("file{}", i));
+                fs::write(&path, format!("contents {}", i)).unwrap();
+                path
+            })
+            .collect();
+        let locked = dir.join("locked");
+        fs::write(&locked, "locked").unwrap();
+        fs::set_permissions(&locked, fs::Permissions::from_mode(0o000)).unwrap();
+        // Root can open it anyway, then only the missing file fails
+        let locked_readable = File::open(&locked).is_ok();
+        let missing = dir.join("missing");
+        // Failures come first, for the readable files to be after them on the one thread
+        paths.insert(0, missing.clone());
+        paths.insert(1, locked.clone());
+        paths.insert(4, dir.join("missing too"));
+
+        let (path_tx, path_rx) = flume::unbounded();
+        paths.iter().for_each(|p| path_tx.send(p.clone()).unwrap());
+        drop(path_tx);
+        let handle = gracile::TermHandle::default();
+        let err_rx = handle.err_rx.clone();
+        let warnings = thread::spawn(move || err_rx.iter().count());
+        let failed = Arc::new(Mutex::new(Vec::new()));
+        let (hash_tx, hash_rx) = flume::unbounded();
+        hash_paths(
+            single_thread_pool(path_rx, handle.err_handle.clone(), Arc::clone(&failed)),
+            hash_tx,
+            gracile::subscribe(),
+        );
+        drop(handle);
+
+        let mut hashed: Vec<_> = hash_rx.iter().map(|r| r.path).collect();
+        hashed.sort();
+        let mut expected: Vec<_> = (0..5).map(|i| dir.join(format!("file{}", i))).collect();
+        let mut expected_failed = vec![missing, dir.join("missing too")];
+        match locked_readable {
+            true => expected.push(locked.clone()),
+            false => expected_failed.push(locked.clone()),
+        }
+        expected.sort();
+        assert_eq!(hashed, expected);
+        let failed = failed.lock().unwrap();
+        assert_eq!(failed.len(), expected_failed.len());
+        for path in &expected_failed {
+            let path = path.display().to_string();
+            assert!(
+                failed.iter().any(|e| e.context.ends_with(&path)),
+                "{}",
+                path
+            );
+        }
+        assert_eq!(warnings.join().unwrap(), expected_failed.len());
+        assert!(!TERMINATE.get());
+
+        fs::remove_dir_all(dir).unwrap();
     }
 }
@@ -1,26 +1,354 @@
 use std::{
+    error::Error,
+    fs::{self, DirEntry, Metadata},
     io::ErrorKind,
-    path::PathBuf,
+    mem,
+    path::{Path, PathBuf},
+    rc::Rc,
     sync::{
-        atomic::{AtomicBool, Ordering},
-        Arc,
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex,
     },
+    time::Duration,
 };
 
 use crossbeam_utils::sync::{Parker, Unparker};
-use flume::Receiver;
-use flurry::HashMap;
-use gracile::TERMINATE;
+use flume::{Receiver, Selector};
+use flurry::{HashMap, HashMapRef};
+use gracile::{ErrHandle, TermError, TERMINATE};
 
-use crate::MainThreadPool;
+use crate::{
+    data_fmt::HashResult,
+    filter::{Filter, Ignores, State},
+    hardlink::{self, FileId},
+    MainThreadPool,
+};
+
+/// Most symlinks followed to get to a dir, in case a cycle isn't caught
+const MAX_LINKS: usize = 40;
+
+/// Longest a walker parks before checking on the reader again, so a lost wakeup costs this
+/// rather than the walk
+const READ_WAIT_BACKSTOP: Duration = Duration::from_millis(50);
+
+/// How the dirs are walked, the same for every pool
+#[derive(Clone)]
+pub struct Walk {
+    pub filter: Arc<Filter>,
+    /// Set by `--follow-symlinks`, symlinks are skipped without it
+    pub follow: Option<Follow>,
+    /// Symlinks that weren't followed, for the summary
+    pub skipped_symlinks: Arc<AtomicU64>,
+    /// Whether dirs on other filesystems than the root they're under are skipped. On Windows
+    /// that's junctions to other volumes, when they're followed
+    pub one_file_system: bool,
+    /// Dirs skipped for being on another filesystem, for the summary
+    pub skipped_mounts: Arc<AtomicU64>,
+    /// How deep below the roots paths are looked for, dirs at it aren't read
+    pub max_depth: Option<usize>,
+    /// Whether hidden files and dirs are skipped, other than the roots
+    pub skip_hidden: bool,
+    /// Files and dirs skipped for being hidden, for the summary
+    pub skipped_hidden: Arc<AtomicU64>,
+    /// Set by `--min-size` and `--max-size`
+    pub size: Option<Arc<SizeFilter>>,
+    pub errors: WalkErrors,
+}
+
+impl Walk {
+    /// A copy for `--pre-scan`, which counts nothing for the summary and reports no errors, as
+    /// the run after it does
+    pub fn quiet(&self) -> Self {
+        Self {
+            skipped_symlinks: Arc::default(),
+            skipped_mounts: Arc::default(),
+            skipped_hidden: Arc::default(),
+            size: self.size.as_ref().map(|s| {
+                Arc::new(SizeFilter {
+                    min: s.min,
+                    max: s.max,
+                    skipped: AtomicU64::new(0),
+                    listed: None,
+                })
+            }),
+            errors: WalkErrors {
+                quiet: true,
+                ..self.errors.clone()
+            },
+            ..self.clone()
+        }
+    }
+}
+
+/// Where what goes wrong walking is reported, rather than printing over the progress line
+#[derive(Clone)]
+pub struct WalkErrors {
+    err_handle: ErrHandle,
+    /// Every path that couldn't be read, for the report at the end, unless the first ends the run
+    kept: Option<Arc<Mutex<Vec<TermError>>>>,
+    /// Whether an error has ended the run, after which walking stops. Only one is sent, as nothing
+    /// receives any more after it
+    ended: Arc<AtomicBool>,
+    /// Set for `--pre-scan`, nothing is reported
+    quiet: bool,
+}
+
+impl WalkErrors {
+    /// `keep` is whether errors are kept for the end rather than the first ending the run
+    pub fn new(err_handle: ErrHandle, keep: bool) -> Self {
+        Self {
+            err_handle,
+            kept: keep.then(Arc::default),
+            ended: Arc::default(),
+            quiet: false,
+        }
+    }
+
+    /// A path that couldn't be read, which ends the run unless errors are being kept
+    pub fn error(&self, context: String, e: impl Into<Box<dyn Error + Send + Sync>>) {
+        if self.quiet {
+            return;
+        }
+
+        let err = TermError::new(context, e);
+        match &self.kept {
+            Some(kept) => {
+                self.err_handle.warn(err.to_string());
+                kept.lock().unwrap().push(err);
+            }
+            None if !self.ended.swap(true, Ordering::Relaxed) => self.err_handle.term_err(err),
+            None => {}
+        }
+    }
+
+    pub fn ended(&self) -> bool {
+        self.ended.load(Ordering::Relaxed)
+    }
+
+    /// The errors kept, taking them
+    pub fn take(&self) -> Vec<TermError> {
+        self.kept
+            .as_ref()
+            .map_or_else(Vec::new, |k| mem::take(&mut *k.lock().unwrap()))
+    }
+
+    pub fn warn(&self, msg: String) {
+        if !self.quiet {
+            self.err_handle.warn(msg);
+        }
+    }
+}
+
+/// The sizes of files that are hashed, others being skipped. The paths given aren't checked
+pub struct SizeFilter {
+    pub min: u64,
+    pub max: u64,
+    /// Files skipped for their size, for the summary
+    pub skipped: AtomicU64,
+    /// The paths of the files skipped, kept with `--list-skipped`
+    pub listed: Option<Mutex<Vec<PathBuf>>>,
+}
+
+impl SizeFilter {
+    /// Whether the file at `path`, `len` long, is hashed, counting it as skipped if not
+    fn check(&self, path: &Path, len: u64) -> bool {
+        if (self.min..=self.max).contains(&len) {
+            return true;
+        }
+
+        self.skipped.fetch_add(1, Ordering::Relaxed);
+        if let Some(listed) = &self.listed {
+            listed.lock().unwrap().push(path.to_path_buf());
+        }
+        false
+    }
+}
+
+#[derive(Clone)]
+pub struct Follow {
+    /// Whether symlinks to outside all of the dirs given are followed
+    pub external: bool,
+    /// The dirs given, canonicalized
+    pub roots: Arc<[PathBuf]>,
+}
+
+/// The dirs a dir is in, back to its root, through any symlinks followed to get to it
+struct Ancestor {
+    id: FileId,
+    parent: Option<Rc<Ancestor>>,
+}
+
+impl Ancestor {
+    fn contains(&self, id: FileId) -> bool {
+        let mut ancestor = Some(self);
+        while let Some(a) = ancestor {
+            if a.id == id {
+                return true;
+            }
+            ancestor = a.parent.as_deref();
+        }
+        false
+    }
+}
+
+/// A dir waiting to be read
+struct Dir {
+    path: PathBuf,
+    /// How many components the root it's under has
+    root_len: usize,
+    /// How far below the root it is
+    depth: usize,
+    state: State,
+    /// Symlinks followed to get to it
+    links: usize,
+    /// Only kept when following symlinks
+    ancestors: Option<Rc<Ancestor>>,
+    /// The patterns of the ignore files in the dirs it's in
+    ignores: Option<Rc<Ignores>>,
+    /// The filesystem of the root it's under, only kept with `--one-file-system`
+    dev: Option<u64>,
+}
+
+/// Whether the name of `entry` starts with a `.`
+#[cfg(unix)]
+fn is_hidden(entry: &DirEntry) -> bool {
+    use std::os::unix::ffi::OsStrExt;
+
+    entry.file_name().as_bytes().starts_with(b".")
+}
+
+/// Whether the name of `entry` starts with a `.`, or it has the hidden attribute
+#[cfg(windows)]
+fn is_hidden(entry: &DirEntry) -> bool {
+    use std::os::windows::{ffi::OsStrExt, fs::MetadataExt};
 
+    const FILE_ATTRIBUTE_HIDDEN: u32 = 0x2;
+
+    // The metadata of dir entries comes from reading the dir, without another syscall
+    entry.file_name().encode_wide().next() == Some(u16::from(b'.'))
+        || entry
+            .metadata()
+            .is_ok_and(|m| m.file_attributes() & FILE_ATTRIBUTE_HIDDEN != 0)
+}
+
+/// What `link` points to, if it's to be followed
+fn follow_link(
+    link: &Path,
+    follow: &Follow,
+    links: usize,
+    errors: &WalkErrors,
+) -> Option<Metadata> {
+    if links >= MAX_LINKS {
+        errors.warn(format!(
+            "Not following symlink {}, {} symlinks have been followed to get to it already",
+            link.display(),
+            links
+        ));
+        return None;
+    }
+
+    let metadata = match fs::metadata(link) {
+        Ok(m) => m,
+        Err(e) if e.kind() == ErrorKind::NotFound => {
+            errors.warn(format!("Skipping dangling symlink {}", link.display()));
+            return None;
+        }
+        Err(e) => {
+            errors.error(
+                format!("Error getting metadata for symlink {}", link.display()),
+                e,
+            );
+            return None;
+        }
+    };
+
+    if !follow.external {
+        match fs::canonicalize(link) {
+            Ok(target) if follow.roots.iter().any(|r| target.starts_with(r)) => {}
+            Ok(_) => return None,
+            Err(e) => {
+                errors.error(format!("Error resolving symlink {}", link.display()), e);
+                return None;
+            }
+        }
+    }
+    Some(metadata)
+}
+
+/// What `--pre-scan` found walking a filesystem's paths
+#[derive(Default)]
+pub struct Totals {
+    pub files: AtomicU64,
+    pub bytes: AtomicU64,
+}
+
+/// Walks `paths` as they will be for hashing, only adding up the files found and their sizes.
+/// `walk` should be [`Walk::quiet`], for nothing to be reported twice
+pub fn pre_scan(
+    paths: Vec<PathBuf>,
+    walk: Walk,
+    capacity: usize,
+    thread_pool: &mut MainThreadPool,
+) -> Arc<Totals> {
+    let (path_rx, _) = start_paths_thread(
+        paths,
+        walk,
+        &Arc::default(),
+        &Arc::new(AtomicBool::new(true)),
+        capacity,
+        thread_pool,
+    );
+    let totals = Arc::<Totals>::default();
+
+    thread_pool.spawn({
+        let totals = Arc::clone(&totals);
+        move || {
+            // Ends when the walk does, which it does on termination
+            for path in path_rx {
+                if let Ok(metadata) = fs::metadata(&path) {
+                    totals.files.fetch_add(1, Ordering::Relaxed);
+                    totals.bytes.fetch_add(metadata.len(), Ordering::Relaxed);
+                }
+            }
+        }
+    });
+    totals
+}
+
+/// Whether `path` has to be hashed, which waits until either the reader has read a hash for it or
+/// it's done. The reader stores `read_done` before its last unpark, and the park is timed for
+/// both to be checked again even if that unpark is missed
+fn needs_hash(
+    path: &Path,
+    existing_hashes: &HashMapRef<'_, PathBuf, HashResult>,
+    read_done: &AtomicBool,
+    parker: &Parker,
+) -> bool {
+    loop {
+        if existing_hashes.contains_key(path) {
+            return false;
+        }
+        if read_done.load(Ordering::Acquire) {
+            // The check above could have been from before the reader's last inserts
+            return !existing_hashes.contains_key(path);
+        }
+        parker.park_timeout(READ_WAIT_BACKSTOP);
+    }
+}
+
+/// Walks `paths`, leaving out what the filter excludes, which it's matched against relative to
+/// the root each path is under. Excluded dirs aren't read unless something in them could be
+/// included
 pub fn start_paths_thread(
     paths: Vec<PathBuf>,
-    existing_hashes: &Arc<HashMap<PathBuf, u64>>,
+    walk: Walk,
+    existing_hashes: &Arc<HashMap<PathBuf, HashResult>>,
     read_done: &Arc<AtomicBool>,
+    capacity: usize,
     thread_pool: &mut MainThreadPool,
 ) -> (Receiver<PathBuf>, Unparker) {
-    let (tx, rx) = flume::unbounded();
+    let (tx, rx) = flume::bounded(capacity);
+    let term_sub = gracile::subscribe();
 
     let parker = Parker::new();
     let unparker = parker.unparker().clone();
@@ -31,20 +359,33 @@ pub fn start_paths_thread(
         move || {
             let existing_hashes = existing_hashes.pin();
 
-            let maybe_send = |path| {
-                loop {
-                    if existing_hashes.contains_key(&path) {
-                        return false;
-                    }
-                    if read_done.load(Ordering::Acquire) {
-                        break;
-                    }
-                    parker.park();
+            let maybe_send = |path: PathBuf| {
+                if !needs_hash(&path, &existing_hashes, &read_done, &parker) {
+                    return false;
                 }
-                let _ = tx.send(path);
+                // Waits while the hashing threads are behind, unless terminating
+                Selector::new()
+                    .send(&tx, path, |_| ())
+                    .recv(term_sub.rx(), |_| ())
+                    .wait();
                 true
             };
 
+            let Walk {
+                filter,
+                follow,
+                skipped_symlinks,
+                one_file_system,
+                skipped_mounts,
+                max_depth,
+                skip_hidden,
+                skipped_hidden,
+                size,
+                errors,
+            } = walk;
+            // Getting dirs' ids takes a syscall, so it's only done when needed
+            let dir_ids = follow.is_some() || one_file_system;
+
             let mut paths: Vec<_> = paths
                 .into_iter()
                 .filter_map(|p| match p.symlink_metadata() {
@@ -52,37 +393,78 @@ pub fn start_paths_thread(
                         maybe_send(p);
                         None
                     }
-                    Ok(_) => Some(p),
+                    Ok(_) if max_depth == Some(0) => None,
+                    Ok(_) => {
+                        let id = match dir_ids {
+                            true => match hardlink::dir_id(&p) {
+                                Ok(id) => Some(id),
+                                Err(e) => {
+                                    errors.error(
+                                        format!("Error getting the id of dir {}", p.display()),
+                                        e,
+                                    );
+                                    return None;
+                                }
+                            },
+                            false => None,
+                        };
+                        Some(Dir {
+                            root_len: p.components().count(),
+                            depth: 0,
+                            path: p,
+                            state: State::Included,
+                            links: 0,
+                            ancestors: id
+                                .filter(|_| follow.is_some())
+                                .map(|id| Rc::new(Ancestor { id, parent: None })),
+                            dev: id.filter(|_| one_file_system).map(|id| id.dev()),
+                            ignores: None,
+                        })
+                    }
                     Err(e) => {
-                        eprintln!("Error getting metadata for path {}: {}", p.display(), e);
+                        errors.error(
+                            format!("Error getting metadata for path {}", p.display()),
+                            e,
+                        );
                         None
                     }
                 })
                 .collect();
 
-            while let Some(path) = paths.pop() {
-                if TERMINATE.get() {
+            while let Some(dir) = paths.pop() {
+                if TERMINATE.get() || errors.ended() {
                     break;
                 }
 
-                let dir = match path.read_dir() {
+                let path = &dir.path;
+                let entries = match path.read_dir() {
                     Ok(d) => d,
                     Err(e) => {
-                        eprintln!("Error reading dir {}: {}", path.display(), e);
+                        errors.error(format!("Error reading dir {}", path.display()), e);
                         continue;
                     }
                 };
+                // Nothing in an excluded dir can be brought back by ignore files, so they aren't
+                // read there
+                let ignores = match dir.state {
+                    State::Included => {
+                        filter.read_ignores(path, dir.depth, dir.ignores.clone(), &errors)
+                    }
+                    State::Excluded(_) => dir.ignores.clone(),
+                };
 
-                for file in dir {
-                    if TERMINATE.get() {
+                for file in entries {
+                    if TERMINATE.get() || errors.ended() {
                         break;
                     }
 
                     let file = match file {
                         Ok(f) => f,
                         Err(e) => {
-                            eprintln!("Error getting dir entry of {}: {}", path.display(), e);
-                            if e.kind() == ErrorKind::InvalidInput {
+                            let invalid = e.kind() == ErrorKind::InvalidInput;
+                            errors
+                                .error(format!("Error getting dir entry of {}", path.display()), e);
+                            if invalid {
                                 break;
                             }
                             continue;
@@ -92,19 +474,126 @@ pub fn start_paths_thread(
                     let file_type = match file.file_type() {
                         Ok(ft) => ft,
                         Err(e) => {
-                            eprintln!(
-                                "Error getting file type of {}: {}",
-                                file.path().display(),
-                                e
+                            errors.error(
+                                format!("Error getting file type of {}", file.path().display()),
+                                e,
                             );
                             continue;
                         }
                     };
 
-                    if file_type.is_file() {
-                        maybe_send(file.path());
-                    } else if file_type.is_dir() {
-                        paths.push(file.path());
+                    if skip_hidden && is_hidden(&file) {
+                        skipped_hidden.fetch_add(1, Ordering::Relaxed);
+                        continue;
+                    }
+
+                    let path = file.path();
+                    let mut links = dir.links;
+                    // Kept for its size, so followed symlinks' files aren't looked up again
+                    let mut target = None;
+                    let (is_file, is_dir) = match file_type.is_symlink() {
+                        true => match follow
+                            .as_ref()
+                            .and_then(|f| follow_link(&path, f, links, &errors))
+                        {
+                            Some(metadata) => {
+                                links += 1;
+                                let kind = (metadata.is_file(), metadata.is_dir());
+                                target = Some(metadata);
+                                kind
+                            }
+                            None => {
+                                skipped_symlinks.fetch_add(1, Ordering::Relaxed);
+                                continue;
+                            }
+                        },
+                        false => (file_type.is_file(), file_type.is_dir()),
+                    };
+
+                    let state = match filter.active(ignores.as_deref()) {
+                        true => {
+                            let rel: PathBuf = path.components().skip(dir.root_len).collect();
+                            let state = filter.check(&rel, is_dir, dir.state, ignores.as_deref());
+                            if is_dir && !filter.descend(&rel, state) {
+                                continue;
+                            }
+                            state
+                        }
+                        false => dir.state,
+                    };
+
+                    if is_file {
+                        if state != State::Included {
+                            continue;
+                        }
+                        // Only looked up once the filters have passed the file
+                        if let Some(size) = &size {
+                            let len = match target.map_or_else(|| file.metadata(), Ok) {
+                                Ok(m) => m.len(),
+                                Err(e) => {
+                                    errors.error(
+                                        format!(
+                                            "Error getting metadata for path {}",
+                                            path.display()
+                                        ),
+                                        e,
+                                    );
+                                    continue;
+                                }
+                            };
+                            if !size.check(&path, len) {
+                                continue;
+                            }
+                        }
+                        maybe_send(path);
+                    } else if is_dir && max_depth.is_none_or(|max| dir.depth + 1 < max) {
+                        let id = match dir_ids {
+                            true => match hardlink::dir_id(&path) {
+                                Ok(id) => Some(id),
+                                Err(e) => {
+                                    errors.error(
+                                        format!("Error getting the id of dir {}", path.display()),
+                                        e,
+                                    );
+                                    continue;
+                                }
+                            },
+                            false => None,
+                        };
+                        if let (Some(id), Some(dev)) = (id, dir.dev) {
+                            if id.dev() != dev {
+                                skipped_mounts.fetch_add(1, Ordering::Relaxed);
+                                continue;
+                            }
+                        }
+
+                        let ancestors = match (&dir.ancestors, id) {
+                            (Some(ancestors), Some(id)) if ancestors.contains(id) => {
+                                errors.warn(format!(
+                                    "Not walking {}, it leads back to a dir it's in",
+                                    path.display()
+                                ));
+                                if file_type.is_symlink() {
+                                    skipped_symlinks.fetch_add(1, Ordering::Relaxed);
+                                }
+                                continue;
+                            }
+                            (Some(ancestors), Some(id)) => Some(Rc::new(Ancestor {
+                                id,
+                                parent: Some(Rc::clone(ancestors)),
+                            })),
+                            _ => None,
+                        };
+                        paths.push(Dir {
+                            path,
+                            root_len: dir.root_len,
+                            depth: dir.depth + 1,
+                            state,
+                            links,
+                            ancestors,
+                            dev: dir.dev,
+                            ignores: ignores.clone(),
+                        });
                     }
                 }
             }
@@ -113,3 +602,82 @@ pub fn start_paths_thread(
 
     (rx, unparker)
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::mpsc, thread, time::Duration};
+
+    use super::*;
+    use crate::data_fmt::{HashValue, UNKNOWN_HASHED_AT, UNKNOWN_MTIME};
+
+    fn result(path: PathBuf) -> HashResult {
+        HashResult {
+            path,
+            hash: HashValue::U64(0),
+            len: 0,
+            mtime: UNKNOWN_MTIME,
+            chunks: None,
+            hashed_at: UNKNOWN_HASHED_AT,
+        }
+    }
+
+    /// Walkers checking paths while the reader reads its last hashes and finishes, over and over
+    /// for the wakeup to be raced. Each check has to end, and agree with what the reader read
+    #[test]
+    fn walkers_race_reader_finishing() {
+        const ROUNDS: usize = 1000;
+        const WALKERS: usize = 4;
+        const PATHS: usize = 8;
+
+        let (done_tx, done_rx) = mpsc::channel();
+        thread::spawn(move || {
+            for _ in 0..ROUNDS {
+                let existing_hashes = Arc::new(HashMap::new());
+                let read_done = Arc::new(AtomicBool::new(false));
+
+                let mut unparkers = Vec::new();
+                let walkers = (0..WALKERS)
+                    .map(|w| {
+                        let parker = Parker::new();
+                        unparkers.push(parker.unparker().clone());
+                        let existing_hashes = Arc::clone(&existing_hashes);
+                        let read_done = Arc::clone(&read_done);
+                        thread::spawn(move || {
+                            let existing_hashes = existing_hashes.pin();
+                            for p in 0..PATHS {
+                                let path = PathBuf::from(format!("{}/{}", w, p));
+                                let needed =
+                                    needs_hash(&path, &existing_hashes, &read_done, &parker);
+                                // The reader reads hashes for the even paths
+                                assert_eq!(needed, p % 2 == 1, "{}", path.display());
+                            }
+                        })
+                    })
+                    .collect::<Vec<_>>();
+
+                {
+                    let existing_hashes = existing_hashes.pin();
+                    for p in (0..PATHS).step_by(2) {
+                        for w in 0..WALKERS {
+                            let path = PathBuf::from(format!("{}/{}", w, p));
+                            existing_hashes.insert(path.clone(), result(path));
+                        }
+                        unparkers.iter().for_each(Unparker::unpark);
+                    }
+                }
+                read_done.store(true, Ordering::Release);
+                unparkers.iter().for_each(Unparker::unpark);
+
+                for walker in walkers {
+                    walker.join().unwrap();
+                }
+            }
+            let _ = done_tx.send(());
+        });
+
+        // The rounds take well under a second, so this is only reached if a walker never wakes
+        done_rx
+            .recv_timeout(Duration::from_secs(120))
+            .expect("walkers hung waiting on the reader");
+    }
+}
@@ -0,0 +1,176 @@
+//! `--nice` and `--io-priority`, so hashing in the background can yield to interactive work
+
+use std::{
+    str::FromStr,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use gracile::ErrHandle;
+
+#[derive(Clone, Copy, Debug)]
+pub enum IoPriority {
+    /// Only gets the disk when nothing else wants it
+    Idle,
+    /// The normal class, at a level from 0 (highest) to 7
+    BestEffort(u8),
+}
+
+impl FromStr for IoPriority {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(':') {
+            None if s == "idle" => Ok(Self::Idle),
+            None if s == "best-effort" => Ok(Self::BestEffort(4)),
+            Some(("best-effort", level)) => match level.parse() {
+                Ok(level @ 0..=7) => Ok(Self::BestEffort(level)),
+                _ => Err("the best-effort level must be from 0 to 7".to_string()),
+            },
+            _ => Err("expected idle, best-effort or best-effort:<0-7>".to_string()),
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+pub struct Priority {
+    pub nice: Option<i32>,
+    pub io: Option<IoPriority>,
+}
+
+/// Whether failing to apply a priority has been warned about, so it isn't by every thread
+static WARNED: AtomicBool = AtomicBool::new(false);
+
+impl Priority {
+    /// Applies it to the calling thread. Not being allowed to is only a warning, the thread runs
+    /// at whatever priority it has
+    pub fn apply(&self, err_handle: &ErrHandle) {
+        // Both are tried even if the first fails
+        let nice = self
+            .nice
+            .map_or(Ok(()), sys::set_nice)
+            .map_err(|e| format!("Couldn't set the nice level of hashing threads: {}", e));
+        let io = self
+            .io
+            .map_or(Ok(()), sys::set_io)
+            .map_err(|e| format!("Couldn't set the I/O priority of hashing threads: {}", e));
+
+        if let Err(e) = nice.and(io) {
+            if !WARNED.swap(true, Ordering::Relaxed) {
+                err_handle.warn(e);
+            }
+        }
+    }
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+mod sys {
+    use std::io;
+
+    use super::IoPriority;
+
+    const IOPRIO_WHO_PROCESS: libc::c_int = 1;
+    const IOPRIO_CLASS_SHIFT: libc::c_int = 13;
+    const IOPRIO_CLASS_BE: libc::c_int = 2;
+    const IOPRIO_CLASS_IDLE: libc::c_int = 3;
+
+    /// On Linux the nice level is per thread, taking a thread id in place of the process id
+    pub fn set_nice(nice: i32) -> io::Result<()> {
+        // Safety: neither call takes anything but plain values
+        let res = unsafe {
+            let tid = libc::syscall(libc::SYS_gettid) as libc::id_t;
+            libc::setpriority(libc::PRIO_PROCESS, tid, nice)
+        };
+        match res {
+            -1 => Err(io::Error::last_os_error()),
+            _ => Ok(()),
+        }
+    }
+
+    pub fn set_io(io: IoPriority) -> io::Result<()> {
+        let prio = match io {
+            IoPriority::Idle => IOPRIO_CLASS_IDLE << IOPRIO_CLASS_SHIFT,
+            IoPriority::BestEffort(level) => {
+                IOPRIO_CLASS_BE << IOPRIO_CLASS_SHIFT | libc::c_int::from(level)
+            }
+        };
+        // Safety: only takes plain values. A `who` of 0 is the calling thread
+        match unsafe { libc::syscall(libc::SYS_ioprio_set, IOPRIO_WHO_PROCESS, 0, prio) } {
+            -1 => Err(io::Error::last_os_error()),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(all(unix, not(any(target_os = "linux", target_os = "android"))))]
+mod sys {
+    use std::io;
+
+    use super::IoPriority;
+
+    /// Elsewhere the nice level is per process, which every thread setting it again doesn't change
+    pub fn set_nice(nice: i32) -> io::Result<()> {
+        // Safety: only takes plain values
+        match unsafe { libc::setpriority(libc::PRIO_PROCESS, 0, nice) } {
+            -1 => Err(io::Error::last_os_error()),
+            _ => Ok(()),
+        }
+    }
+
+    pub fn set_io(_: IoPriority) -> io::Result<()> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "not supported on this platform",
+        ))
+    }
+}
+
+#[cfg(windows)]
+mod sys {
+    use std::{ffi::c_void, io};
+
+    use super::IoPriority;
+
+    const THREAD_PRIORITY_LOWEST: i32 = -2;
+    const THREAD_PRIORITY_BELOW_NORMAL: i32 = -1;
+    const THREAD_PRIORITY_NORMAL: i32 = 0;
+    const THREAD_PRIORITY_ABOVE_NORMAL: i32 = 1;
+    const THREAD_PRIORITY_HIGHEST: i32 = 2;
+    /// Lowers the thread's I/O and memory priority along with its scheduling priority
+    const THREAD_MODE_BACKGROUND_BEGIN: i32 = 0x0001_0000;
+
+    #[link(name = "kernel32")]
+    extern "system" {
+        fn GetCurrentThread() -> *mut c_void;
+        fn SetThreadPriority(thread: *mut c_void, priority: i32) -> i32;
+    }
+
+    fn set_thread_priority(priority: i32) -> io::Result<()> {
+        // Safety: the handle is a pseudo handle to the calling thread, which needs no closing
+        match unsafe { SetThreadPriority(GetCurrentThread(), priority) } {
+            0 => Err(io::Error::last_os_error()),
+            _ => Ok(()),
+        }
+    }
+
+    /// Nice levels are mapped onto the nearest of the thread priorities
+    pub fn set_nice(nice: i32) -> io::Result<()> {
+        set_thread_priority(match nice {
+            10.. => THREAD_PRIORITY_LOWEST,
+            1..=9 => THREAD_PRIORITY_BELOW_NORMAL,
+            0 => THREAD_PRIORITY_NORMAL,
+            -9..=-1 => THREAD_PRIORITY_ABOVE_NORMAL,
+            _ => THREAD_PRIORITY_HIGHEST,
+        })
+    }
+
+    /// Only idle has an equivalent, best-effort at any level is what threads already get
+    pub fn set_io(io: IoPriority) -> io::Result<()> {
+        match io {
+            IoPriority::Idle => set_thread_priority(THREAD_MODE_BACKGROUND_BEGIN),
+            IoPriority::BestEffort(level) => {
+                let _ = level;
+                Ok(())
+            }
+        }
+    }
+}
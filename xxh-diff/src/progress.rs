@@ -0,0 +1,233 @@
+//! The `--progress` line on stderr, kept up to date by the hashing pools
+
+use std::{
+    io::{self, Write},
+    sync::{
+        atomic::{AtomicU32, AtomicU64, Ordering},
+        Arc, Mutex, MutexGuard, OnceLock, PoisonError,
+    },
+    time::{Duration, Instant},
+};
+
+use clap::ValueEnum;
+
+use crate::throttle::Throttle;
+
+/// When the progress line is shown
+#[derive(ValueEnum, Clone, Copy, Debug)]
+pub enum ProgressMode {
+    /// Only when stderr is a terminal
+    Auto,
+    Force,
+}
+
+const REFRESH: Duration = Duration::from_millis(250);
+
+pub struct Progress {
+    files: AtomicU64,
+    bytes: AtomicU64,
+    threads: AtomicU32,
+    start: Instant,
+    throttle: Option<Arc<Throttle>>,
+    total: OnceLock<Total>,
+    line: Mutex<Line>,
+}
+
+/// What `--pre-scan` found there is to hash
+struct Total {
+    files: u64,
+    bytes: u64,
+    /// When hashing started, for the ETA to go by the speed since
+    at: Instant,
+}
+
+pub struct Line {
+    drawn_at: Instant,
+    /// Bytes hashed as of the last draw, for the speed since then
+    drawn_bytes: u64,
+    /// Length of the line on screen, 0 if there isn't one
+    len: usize,
+    finished: bool,
+}
+
+impl Progress {
+    pub fn new(throttle: Option<Arc<Throttle>>) -> Arc<Self> {
+        let now = Instant::now();
+        Arc::new(Self {
+            files: AtomicU64::new(0),
+            bytes: AtomicU64::new(0),
+            threads: AtomicU32::new(0),
+            start: now,
+            throttle,
+            total: OnceLock::new(),
+            line: Mutex::new(Line {
+                drawn_at: now,
+                drawn_bytes: 0,
+                len: 0,
+                finished: false,
+            }),
+        })
+    }
+
+    /// Sets what there is to hash, for the line to show how much is done and an ETA. Anything
+    /// hashed before it's set doesn't count towards the ETA's speed
+    pub fn set_total(&self, files: u64, bytes: u64) {
+        let _ = self.total.set(Total {
+            files,
+            bytes,
+            at: Instant::now(),
+        });
+    }
+
+    pub fn threads_started(&self, n: u32) {
+        self.threads.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub fn thread_stopped(&self) {
+        self.threads.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    pub fn hashed(&self, len: u64) {
+        self.files.fetch_add(1, Ordering::Relaxed);
+        self.bytes.fetch_add(len, Ordering::Relaxed);
+    }
+
+    /// Redraws the line if it hasn't been for a while
+    pub fn tick(&self) {
+        let mut line = self.lock();
+        if !line.finished && line.drawn_at.elapsed() >= REFRESH {
+            self.draw(&mut line);
+        }
+    }
+
+    /// Erases the line until the guard is dropped, so other output doesn't run into it
+    pub fn hide(&self) -> MutexGuard<'_, Line> {
+        let mut line = self.lock();
+        erase(&mut line);
+        line
+    }
+
+    /// Draws the line one last time and moves past it, it's not redrawn after this
+    pub fn finish(&self) {
+        let mut line = self.lock();
+        if line.finished {
+            return;
+        }
+
+        self.draw(&mut line);
+        line.finished = true;
+        line.len = 0;
+        let mut stderr = io::stderr().lock();
+        let _ = stderr.write_all(b"\n").and_then(|_| stderr.flush());
+    }
+
+    /// Calls [`Progress::finish`] when dropped, for the line to end before any error is printed
+    pub fn finish_guard(self: &Arc<Self>) -> FinishGuard {
+        FinishGuard(Arc::clone(self))
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Line> {
+        self.line.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn draw(&self, line: &mut Line) {
+        let bytes = self.bytes.load(Ordering::Relaxed);
+        let since = line.drawn_at.elapsed().as_secs_f64();
+        let speed = match since > 0.0 {
+            true => (bytes - line.drawn_bytes) as f64 / since,
+            false => 0.0,
+        };
+
+        let files = self.files.load(Ordering::Relaxed);
+        let elapsed = fmt_secs(self.start.elapsed().as_secs());
+        let threads = self.threads.load(Ordering::Relaxed);
+        let mut text = match self.total.get() {
+            Some(total) => {
+                // Files can be added after the pre-scan, which shouldn't go past what it found
+                let done = match total.bytes {
+                    0 => 1.0,
+                    total_bytes => (bytes as f64 / total_bytes as f64).min(1.0),
+                };
+                let eta = match bytes {
+                    0 => "?".to_string(),
+                    _ => {
+                        let secs = total.at.elapsed().as_secs_f64() * (1.0 - done) / done;
+                        fmt_secs(secs as u64)
+                    }
+                };
+                format!(
+                    "{}/{} files, {} of {} hashed ({:.0}%), {}/s, {} threads, {}, ETA {}",
+                    files.min(total.files),
+                    total.files,
+                    fmt_bytes(bytes.min(total.bytes) as f64),
+                    fmt_bytes(total.bytes as f64),
+                    done * 100.0,
+                    fmt_bytes(speed),
+                    threads,
+                    elapsed,
+                    eta,
+                )
+            }
+            None => format!(
+                "{} files, {} hashed, {}/s, {} threads, {}",
+                files,
+                fmt_bytes(bytes as f64),
+                fmt_bytes(speed),
+                threads,
+                elapsed,
+            ),
+        };
+        if let Some(throttle) = &self.throttle {
+            text += &format!(
+                ", capped at {}/s, {:.1}s waited",
+                fmt_bytes(throttle.rate() as f64),
+                throttle.waited().as_secs_f64()
+            );
+        }
+
+        // Padded over whatever is left of a longer line, rather than relying on escape codes
+        let mut stderr = io::stderr().lock();
+        let _ = write!(stderr, "\r{:1$}", text, line.len).and_then(|_| stderr.flush());
+        line.len = text.len();
+        line.drawn_at = Instant::now();
+        line.drawn_bytes = bytes;
+    }
+}
+
+pub struct FinishGuard(Arc<Progress>);
+
+impl Drop for FinishGuard {
+    fn drop(&mut self) {
+        self.0.finish();
+    }
+}
+
+fn erase(line: &mut Line) {
+    if line.len == 0 {
+        return;
+    }
+
+    let mut stderr = io::stderr().lock();
+    let _ = write!(stderr, "\r{:1$}\r", "", line.len).and_then(|_| stderr.flush());
+    line.len = 0;
+}
+
+/// As h:mm:ss
+fn fmt_secs(secs: u64) -> String {
+    format!("{}:{:02}:{:02}", secs / 3600, secs / 60 % 60, secs % 60)
+}
+
+pub fn fmt_bytes(bytes: f64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+
+    let mut value = bytes;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    match unit {
+        0 => format!("{:.0} {}", value, UNITS[unit]),
+        _ => format!("{:.1} {}", value, UNITS[unit]),
+    }
+}
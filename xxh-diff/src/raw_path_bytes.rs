@@ -45,3 +45,221 @@ impl RawPathBytes for PathBuf {
         Ok(OsString::from_vec(bytes).into())
     }
 }
+
+/// Paths as WTF-8, which can be read on any platform. The bytes of a Unix path that aren't valid
+/// UTF-8 are stored as the unpaired surrogates U+DC80 to U+DCFF, which a Windows path keeps as
+/// they are, and are read back as those bytes on Unix
+pub trait PortablePathBytes {
+    fn to_portable_bytes(&self) -> Vec<u8>;
+    fn from_portable_bytes(bytes: Vec<u8>) -> Result<Self, Vec<u8>>
+    where
+        Self: Sized;
+}
+
+#[cfg(windows)]
+impl PortablePathBytes for PathBuf {
+    fn to_portable_bytes(&self) -> Vec<u8> {
+        utf16_to_portable(self.as_os_str().encode_wide())
+    }
+
+    fn from_portable_bytes(bytes: Vec<u8>) -> Result<Self, Vec<u8>> {
+        let code_points = match decode_wtf8(&bytes) {
+            Some(c) => c,
+            None => return Err(bytes),
+        };
+        let mut wide = Vec::with_capacity(code_points.len());
+        for code_point in code_points {
+            match char::from_u32(code_point) {
+                Some(c) => wide.extend_from_slice(c.encode_utf16(&mut [0; 2])),
+                // An unpaired surrogate
+                None => wide.push(code_point as u16),
+            }
+        }
+        Ok(OsString::from_wide(&wide).into())
+    }
+}
+
+#[cfg(unix)]
+impl PortablePathBytes for PathBuf {
+    fn to_portable_bytes(&self) -> Vec<u8> {
+        unix_to_portable(self.as_os_str().as_bytes())
+    }
+
+    fn from_portable_bytes(bytes: Vec<u8>) -> Result<Self, Vec<u8>> {
+        let code_points = match decode_wtf8(&bytes) {
+            Some(c) => c,
+            None => return Err(bytes),
+        };
+        let mut raw = Vec::with_capacity(bytes.len());
+        for code_point in code_points {
+            match code_point.checked_sub(ESCAPE_BASE) {
+                Some(b @ 0x80..=0xFF) => raw.push(b as u8),
+                _ => push_wtf8(&mut raw, code_point),
+            }
+        }
+        Ok(OsString::from_vec(raw).into())
+    }
+}
+
+/// Added to the Unix path bytes that aren't valid UTF-8, all from 0x80, to escape them
+const ESCAPE_BASE: u32 = 0xDC00;
+
+/// [`PortablePathBytes`] from a Unix path's raw bytes
+pub fn unix_to_portable(bytes: &[u8]) -> Vec<u8> {
+    let mut portable = Vec::with_capacity(bytes.len());
+    for chunk in bytes.utf8_chunks() {
+        portable.extend_from_slice(chunk.valid().as_bytes());
+        for b in chunk.invalid() {
+            push_wtf8(&mut portable, ESCAPE_BASE + u32::from(*b));
+        }
+    }
+    portable
+}
+
+/// [`PortablePathBytes`] from a Windows path's UTF-16LE bytes, `None` if there's an odd number
+pub fn utf16le_to_portable(bytes: &[u8]) -> Option<Vec<u8>> {
+    if !bytes.len().is_multiple_of(2) {
+        return None;
+    }
+    Some(utf16_to_portable(
+        bytes
+            .chunks_exact(2)
+            .map(|u| u16::from_le_bytes([u[0], u[1]])),
+    ))
+}
+
+fn utf16_to_portable(units: impl Iterator<Item = u16>) -> Vec<u8> {
+    let mut portable = Vec::new();
+    for c in char::decode_utf16(units) {
+        match c {
+            Ok(c) => portable.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes()),
+            Err(e) => push_wtf8(&mut portable, u32::from(e.unpaired_surrogate())),
+        }
+    }
+    portable
+}
+
+/// UTF-8 generalised to surrogates
+fn push_wtf8(buf: &mut Vec<u8>, code_point: u32) {
+    let continuation = |shift: u32| 0x80 | (code_point >> shift & 0x3F) as u8;
+    match code_point {
+        0..=0x7F => buf.push(code_point as u8),
+        0x80..=0x7FF => buf.extend_from_slice(&[0xC0 | (code_point >> 6) as u8, continuation(0)]),
+        0x800..=0xFFFF => buf.extend_from_slice(&[
+            0xE0 | (code_point >> 12) as u8,
+            continuation(6),
+            continuation(0),
+        ]),
+        _ => buf.extend_from_slice(&[
+            0xF0 | (code_point >> 18) as u8,
+            continuation(12),
+            continuation(6),
+            continuation(0),
+        ]),
+    }
+}
+
+/// The code points of WTF-8 `bytes`, `None` if they aren't well formed
+fn decode_wtf8(bytes: &[u8]) -> Option<Vec<u32>> {
+    let mut code_points = Vec::with_capacity(bytes.len());
+    let mut bytes = bytes.iter();
+    while let Some(&first) = bytes.next() {
+        // The number of continuation bytes, and the smallest code point that needs them
+        let (continuations, min, lead) = match first {
+            0x00..=0x7F => {
+                code_points.push(u32::from(first));
+                continue;
+            }
+            0xC2..=0xDF => (1, 0x80, first & 0x1F),
+            0xE0..=0xEF => (2, 0x800, first & 0x0F),
+            0xF0..=0xF4 => (3, 0x10000, first & 0x07),
+            _ => return None,
+        };
+
+        let mut code_point = u32::from(lead);
+        for _ in 0..continuations {
+            match bytes.next() {
+                Some(b) if b & 0xC0 == 0x80 => code_point = code_point << 6 | u32::from(b & 0x3F),
+                _ => return None,
+            }
+        }
+        if code_point < min || code_point > char::MAX as u32 {
+            return None;
+        }
+        code_points.push(code_point);
+    }
+    Some(code_points)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unix_bytes_escaped() {
+        assert_eq!(
+            unix_to_portable("/dir/café".as_bytes()),
+            "/dir/café".as_bytes()
+        );
+        // 0xFF as U+DCFF
+        assert_eq!(unix_to_portable(b"/a\xFFb"), b"/a\xED\xB3\xBFb");
+    }
+
+    #[test]
+    fn utf16le_bytes() {
+        let utf16le =
+            |s: &str| -> Vec<u8> { s.encode_utf16().flat_map(u16::to_le_bytes).collect() };
+        assert_eq!(
+            utf16le_to_portable(&utf16le("/dir/café")).unwrap(),
+            "/dir/café".as_bytes()
+        );
+        // An unpaired U+D800 is kept as it is
+        assert_eq!(
+            utf16le_to_portable(&[b'/', 0, 0x00, 0xD8]).unwrap(),
+            b"/\xED\xA0\x80"
+        );
+        assert_eq!(utf16le_to_portable(&[b'/', 0, b'a']), None);
+    }
+
+    #[test]
+    fn malformed_wtf8() {
+        assert_eq!(
+            decode_wtf8("é\u{10000}".as_bytes()),
+            Some(vec![0xE9, 0x10000])
+        );
+        // Overlong, cut short, a stray continuation byte, and past U+10FFFF
+        for bytes in [&b"\xC0\xAF"[..], b"\xE2\x82", b"\x80", b"\xF4\x90\x80\x80"] {
+            assert_eq!(decode_wtf8(bytes), None, "{:?}", bytes);
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn unix_round_trip() {
+        for raw in [&b"/dir/caf\xC3\xA9"[..], b"/a\xFFb\x80", b"/\xED\xB3\xBF"] {
+            let path = PathBuf::from(OsString::from_vec(raw.to_vec()));
+            let portable = path.to_portable_bytes();
+            assert_eq!(
+                PathBuf::from_portable_bytes(portable).unwrap(),
+                path,
+                "{:?}",
+                raw
+            );
+        }
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn windows_round_trip() {
+        for wide in [&[0x2F, 0x61][..], &[0x2F, 0xD800, 0x61], &[0xD83D, 0xDE00]] {
+            let path = PathBuf::from(OsString::from_wide(wide));
+            let portable = path.to_portable_bytes();
+            assert_eq!(
+                PathBuf::from_portable_bytes(portable).unwrap(),
+                path,
+                "{:?}",
+                wide
+            );
+        }
+    }
+}
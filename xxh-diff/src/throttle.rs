@@ -0,0 +1,67 @@
+//! The `--max-throughput` cap on how fast files are read, shared by every hashing thread
+
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+pub struct Throttle {
+    /// Bytes per second
+    rate: u64,
+    bucket: Mutex<Bucket>,
+    /// Nanoseconds threads have slept for, all of them added up
+    waited: AtomicU64,
+}
+
+struct Bucket {
+    /// Bytes that can be read without waiting. Negative when reads have run ahead of the rate,
+    /// which the next read waits out
+    tokens: f64,
+    filled_at: Instant,
+}
+
+impl Throttle {
+    pub fn new(rate: u64) -> Self {
+        Self {
+            rate,
+            bucket: Mutex::new(Bucket {
+                tokens: 0.0,
+                filled_at: Instant::now(),
+            }),
+            waited: AtomicU64::new(0),
+        }
+    }
+
+    pub fn rate(&self) -> u64 {
+        self.rate
+    }
+
+    /// Accounts for `bytes` having been read, sleeping for as long as reads are ahead of the rate.
+    /// At most a second's worth of reads can happen in a burst
+    pub fn take(&self, bytes: u64) {
+        let wait = {
+            let mut bucket = self.bucket.lock().unwrap();
+            let now = Instant::now();
+            let filled = now.duration_since(bucket.filled_at).as_secs_f64() * self.rate as f64;
+            bucket.tokens = (bucket.tokens + filled).min(self.rate as f64) - bytes as f64;
+            bucket.filled_at = now;
+            match bucket.tokens < 0.0 {
+                true => Duration::from_secs_f64(-bucket.tokens / self.rate as f64),
+                false => return,
+            }
+        };
+
+        thread::sleep(wait);
+        self.waited
+            .fetch_add(wait.as_nanos() as u64, Ordering::Relaxed);
+    }
+
+    /// How long threads have waited for it, added up
+    pub fn waited(&self) -> Duration {
+        Duration::from_nanos(self.waited.load(Ordering::Relaxed))
+    }
+}